@@ -1,4 +1,6 @@
+use crate::peer::{EncryptionPolicy, TransportPolicy};
 use clap::Parser;
+use std::path::PathBuf;
 
 /// Command line arguments for the TDS BitTorrent Downloader client.
 #[derive(Parser, Debug)]
@@ -16,6 +18,50 @@ pub struct Args {
     /// If not specified, downloads will save to a `downloads` folder in the current directory.
     #[arg(short, long)]
     pub output: Option<String>,
+
+    /// Maximum number of 16 KiB block requests kept in flight per peer.
+    ///
+    /// If not specified, the downloader default is used.
+    #[arg(long)]
+    pub pipeline_depth: Option<usize>,
+
+    /// Maximum download rate in KiB/s.
+    ///
+    /// If not specified, downloads are not limited.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub download_limit: Option<u64>,
+
+    /// Maximum upload rate in KiB/s.
+    ///
+    /// If not specified, the torrent is only held to the global upload limit.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_limit: Option<u64>,
+
+    /// A `host:port` DHT node to join the network through. Repeat for several nodes.
+    ///
+    /// If not specified, the public BitTorrent routers are used.
+    #[arg(long = "dht-bootstrap", value_name = "HOST:PORT")]
+    pub dht_bootstrap: Vec<String>,
+
+    /// File the DHT routing table is saved to on exit and loaded from on start.
+    #[arg(long, default_value = "dht.dat")]
+    pub dht_state: PathBuf,
+
+    /// Do not join the DHT; find peers through trackers and the peers named in a magnet link only.
+    #[arg(long)]
+    pub no_dht: bool,
+
+    /// Encryption of peer connections: `disabled`, `preferred` or `required`.
+    ///
+    /// `preferred` encrypts whenever the peer supports it; `required` refuses plaintext peers.
+    #[arg(long, default_value = "preferred")]
+    pub encryption: EncryptionPolicy,
+
+    /// Transports of peer connections: `tcp`, `prefer-tcp`, `prefer-utp` or `utp`.
+    ///
+    /// uTP backs off when other traffic shares the link; `prefer-utp` dials over uTP first.
+    #[arg(long, default_value = "prefer-tcp")]
+    pub transport: TransportPolicy,
 }
 
 #[cfg(test)]
@@ -27,4 +73,12 @@ mod tests {
         use clap::CommandFactory;
         Args::command().debug_assert();
     }
+
+    #[test]
+    fn test_zero_rate_limits_are_rejected() {
+        assert!(Args::try_parse_from(["client", "--download-limit", "0"]).is_err());
+        assert!(Args::try_parse_from(["client", "--upload-limit", "0"]).is_err());
+        let args = Args::try_parse_from(["client", "--upload-limit", "1"]).unwrap();
+        assert_eq!(args.upload_limit, Some(1));
+    }
 }
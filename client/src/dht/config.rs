@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+
+/// The public routers a new node joins the network through.
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Settings of a DHT node.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// The UDP port to bind to. Use `0` to let the OS choose.
+    pub port: u16,
+    /// `host:port` addresses queried to join the network, besides the nodes of the state file.
+    pub bootstrap_nodes: Vec<String>,
+    /// Where our node ID and routing table are kept between runs, or `None` to start
+    /// from scratch every time.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            port: 6882,
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES.map(String::from).to_vec(),
+            state_file: Some(PathBuf::from("dht.dat")),
+        }
+    }
+}
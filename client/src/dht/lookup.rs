@@ -0,0 +1,332 @@
+//! Iterative `get_peers` lookups.
+//!
+//! A lookup starts from the closest nodes in the routing table and keeps `ALPHA`
+//! queries in flight. Every response may name nodes closer to the info hash, which
+//! are queried in turn. The lookup has converged once the `K` closest nodes it
+//! knows of have all answered (or timed out).
+
+use super::routing::distance;
+use super::{K, Node};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Number of queries a lookup keeps in flight.
+pub const ALPHA: usize = 3;
+
+/// A response to one of our `get_peers` queries.
+#[derive(Debug, Clone)]
+pub(super) struct Reply {
+    /// The node that answered.
+    pub from: Node,
+    /// Nodes closer to the info hash, from the `nodes` key.
+    pub nodes: Vec<Node>,
+    /// Peers for the info hash, from the `values` key.
+    pub values: Vec<SocketAddr>,
+    /// The write token the node expects in an `announce_peer`.
+    pub token: Option<Vec<u8>>,
+}
+
+/// A node answering a lookup, with the token needed to announce to it.
+#[derive(Debug, Clone)]
+pub struct TokenNode {
+    /// The node that answered.
+    pub node: Node,
+    /// The token from its `get_peers` response.
+    pub token: Vec<u8>,
+}
+
+/// A running `get_peers` lookup, returned by `Dht::get_peers`.
+///
+/// Peers are yielded as the responses come in. Dropping the handle lets the lookup
+/// run to completion in the background.
+pub struct PeerLookup {
+    pub(super) peers: mpsc::Receiver<SocketAddr>,
+    pub(super) done: oneshot::Receiver<Vec<TokenNode>>,
+}
+
+impl PeerLookup {
+    /// Waits for the next peer found by the lookup.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<SocketAddr>` - A peer not returned before, or `None` once the lookup has converged.
+    pub async fn next(&mut self) -> Option<SocketAddr> {
+        self.peers.recv().await
+    }
+
+    /// Waits for the lookup to converge.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<TokenNode>` - Up to `K` of the closest nodes that answered with a token,
+    ///   closest first. These are the nodes to send `announce_peer` to.
+    pub async fn finish(mut self) -> Vec<TokenNode> {
+        // Peers not read yet would otherwise block the lookup
+        self.peers.close();
+        while self.peers.recv().await.is_some() {}
+        self.done.await.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    NotQueried,
+    Waiting(Instant),
+    Responded,
+    Failed,
+}
+
+struct Candidate {
+    node: Node,
+    state: State,
+    token: Option<Vec<u8>>,
+}
+
+/// The progress of one lookup, separate from the network so it can be tested alone.
+pub(super) struct Lookup {
+    target: [u8; 20],
+    own_id: [u8; 20],
+    /// Every node heard of, by distance from the target.
+    candidates: BTreeMap<[u8; 20], Candidate>,
+    /// Peers already reported.
+    seen_peers: HashSet<SocketAddr>,
+}
+
+impl Lookup {
+    /// Starts a lookup for `target` from the given nodes.
+    pub fn new(target: [u8; 20], own_id: [u8; 20], start: Vec<Node>) -> Self {
+        let mut lookup = Self {
+            target,
+            own_id,
+            candidates: BTreeMap::new(),
+            seen_peers: HashSet::new(),
+        };
+        for node in start {
+            lookup.add_candidate(node);
+        }
+        lookup
+    }
+
+    fn add_candidate(&mut self, node: Node) {
+        if node.id == self.own_id {
+            return;
+        }
+        self.candidates
+            .entry(distance(&node.id, &self.target))
+            .or_insert(Candidate {
+                node,
+                state: State::NotQueried,
+                token: None,
+            });
+    }
+
+    /// The `K` closest nodes that did not fail.
+    fn closest(&self) -> impl Iterator<Item = &Candidate> {
+        self.candidates
+            .values()
+            .filter(|c| c.state != State::Failed)
+            .take(K)
+    }
+
+    /// Returns the nodes to query next, and marks them as waiting until `deadline`.
+    ///
+    /// At most `ALPHA` queries are in flight at once, and only the `K` closest nodes
+    /// that did not fail are queried.
+    pub fn next_queries(&mut self, deadline: Instant) -> Vec<Node> {
+        let waiting = self
+            .candidates
+            .values()
+            .filter(|c| matches!(c.state, State::Waiting(_)))
+            .count();
+        let picked: Vec<[u8; 20]> = self
+            .candidates
+            .iter()
+            .filter(|(_, c)| c.state != State::Failed)
+            .take(K)
+            .filter(|(_, c)| c.state == State::NotQueried)
+            .map(|(d, _)| *d)
+            .take(ALPHA.saturating_sub(waiting))
+            .collect();
+        picked
+            .iter()
+            .map(|d| {
+                let c = self.candidates.get_mut(d).unwrap();
+                c.state = State::Waiting(deadline);
+                c.node.clone()
+            })
+            .collect()
+    }
+
+    /// Returns `true` once the `K` closest nodes have all answered or failed.
+    pub fn is_done(&self) -> bool {
+        self.closest().all(|c| c.state == State::Responded)
+    }
+
+    /// Returns the earliest deadline of the queries in flight.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.candidates
+            .values()
+            .filter_map(|c| match c.state {
+                State::Waiting(deadline) => Some(deadline),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Marks every query whose deadline has passed as failed.
+    pub fn expire(&mut self, now: Instant) {
+        for c in self.candidates.values_mut() {
+            if matches!(c.state, State::Waiting(deadline) if deadline <= now) {
+                c.state = State::Failed;
+            }
+        }
+    }
+
+    /// Applies a response.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<SocketAddr>` - The peers in the response that were not reported before.
+    pub fn on_reply(&mut self, reply: Reply) -> Vec<SocketAddr> {
+        let Some(c) = self
+            .candidates
+            .values_mut()
+            .find(|c| c.node.addr == reply.from.addr && c.state != State::Responded)
+        else {
+            return Vec::new();
+        };
+        c.state = State::Responded;
+        c.token = reply.token;
+
+        for node in reply.nodes {
+            self.add_candidate(node);
+        }
+        reply
+            .values
+            .into_iter()
+            .filter(|p| self.seen_peers.insert(*p))
+            .collect()
+    }
+
+    /// Returns the closest nodes that answered with a token.
+    pub fn tokens(&self) -> Vec<TokenNode> {
+        self.candidates
+            .values()
+            .filter(|c| c.state == State::Responded)
+            .filter_map(|c| {
+                c.token.clone().map(|token| TokenNode {
+                    node: c.node.clone(),
+                    token,
+                })
+            })
+            .take(K)
+            .collect()
+    }
+}
+
+/// Drives a lookup until it converges.
+///
+/// # Arguments
+///
+/// * `lookup` - The lookup state, seeded with the closest known nodes.
+/// * `send_query` - Sends a `get_peers` query to a node; its response must be delivered to `replies`.
+/// * `replies` - Responses to our queries.
+/// * `timeout` - How long a node has to answer.
+/// * `peers` - Receives every new peer.
+///
+/// # Returns
+///
+/// * `Vec<TokenNode>` - The closest nodes that answered with a token.
+pub(super) async fn run<F, Fut>(
+    mut lookup: Lookup,
+    mut send_query: F,
+    mut replies: mpsc::UnboundedReceiver<Reply>,
+    timeout: Duration,
+    peers: mpsc::Sender<SocketAddr>,
+) -> Vec<TokenNode>
+where
+    F: FnMut(Node) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        for node in lookup.next_queries(Instant::now() + timeout) {
+            send_query(node).await;
+        }
+        if lookup.is_done() {
+            break;
+        }
+        let Some(deadline) = lookup.next_deadline() else {
+            break;
+        };
+
+        tokio::select! {
+            reply = replies.recv() => match reply {
+                Some(reply) => {
+                    for peer in lookup.on_reply(reply) {
+                        // The caller may have stopped reading peers; the tokens are still wanted
+                        let _ = peers.send(peer).await;
+                    }
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(deadline) => lookup.expire(Instant::now()),
+        }
+    }
+    lookup.tokens()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn node(first: u8) -> Node {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        Node {
+            id,
+            addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), first as u16),
+        }
+    }
+
+    #[test]
+    fn test_lookup_converges_on_closer_nodes() {
+        let target = [0u8; 20];
+        let now = Instant::now();
+        let mut lookup = Lookup::new(target, [0xff; 20], vec![node(0x80), node(0x40)]);
+
+        let queried = lookup.next_queries(now);
+        assert_eq!(queried.len(), 2);
+        assert!(lookup.next_queries(now).is_empty());
+
+        // 0x40 knows a closer node, and a peer
+        let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881);
+        let peers = lookup.on_reply(Reply {
+            from: node(0x40),
+            nodes: vec![node(0x01)],
+            values: vec![peer, peer],
+            token: Some(b"tok".to_vec()),
+        });
+        assert_eq!(peers, vec![peer]);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(lookup.next_queries(later)[0].id, node(0x01).id);
+        assert_eq!(lookup.next_deadline(), Some(now));
+
+        // 0x80 never answers; 0x01 does
+        lookup.expire(now);
+        assert!(!lookup.is_done());
+        lookup.on_reply(Reply {
+            from: node(0x01),
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: Some(b"tik".to_vec()),
+        });
+        assert!(lookup.is_done());
+
+        let tokens: Vec<_> = lookup.tokens().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens, vec![b"tik".to_vec(), b"tok".to_vec()]);
+    }
+}
@@ -1,10 +1,43 @@
+mod config;
+mod lookup;
+mod routing;
+mod server;
+mod state;
+mod store;
+mod transactions;
+
+pub use config::{DEFAULT_BOOTSTRAP_NODES, DhtConfig};
+pub use lookup::{ALPHA, PeerLookup, TokenNode};
+pub use routing::{K, NodeStatus, RoutingTable};
+pub use server::KrpcError;
+pub use state::DhtState;
+
+use lookup::{Lookup, Reply};
+use server::QueryServer;
+use transactions::{Pending, QueryKind, Rejected, Transactions};
+
 use rand::Rng;
-use std::collections::{BTreeMap, HashMap};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tds_core::bencoding::{Bencode, decode};
+use tds_core::compact;
+use tds_core::net::{self, DualStack};
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// How often the routing table is maintained: questionable nodes are pinged and stale
+/// buckets refreshed. Expired announces are dropped too.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a node has to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often queries past their deadline are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Represents a node in the DHT network.
 #[derive(Clone, Debug)]
@@ -17,26 +50,39 @@ pub struct Node {
 
 /// A simpler implementation of a Distributed Hash Table (DHT) node (Kademlia-like).
 ///
-/// This struct manages the UDP socket for DHT communication, maintains a routing table
-/// (list of nodes), and handles peer discovery via `get_peers` and `find_node` queries.
+/// This struct manages the UDP socket for DHT communication, maintains a Kademlia
+/// routing table, and handles peer discovery via `get_peers` and `find_node` queries.
+/// It answers the queries of other nodes, and stores the peers announced to it.
+///
+/// The node listens for IPv4 and IPv6 where the system has both, so it takes part in the
+/// IPv4 and the IPv6 DHT (BEP 32) with one routing table holding nodes of both families.
 ///
 /// Note: This is a partial implementation focusing on bootstrapping and basic peer discovery.
+/// A single node can run lookups for several torrents at once.
 pub struct Dht {
     /// The UDP socket used for messaging.
-    socket: Arc<UdpSocket>,
+    socket: Arc<DualStack<UdpSocket>>,
     /// Our own Node ID (randomly generated).
     node_id: [u8; 20],
-    /// Known DHT nodes (routing table).
-    nodes: Arc<Mutex<Vec<Node>>>,
-    /// Discovered peers (IP:Port of peers that have the infohash we are looking for).
-    peers: Arc<Mutex<Vec<SocketAddrV4>>>,
-    /// Active transactions to map responses to queries (Transaction ID -> Query Type).
-    transactions: Arc<Mutex<HashMap<Vec<u8>, String>>>, 
+    /// Known DHT nodes, bucketed by distance from `node_id`.
+    nodes: Arc<Mutex<RoutingTable>>,
+    /// Queries waiting for their response, by transaction ID.
+    transactions: Arc<Mutex<Transactions>>,
+    /// Answers queries from other nodes, and holds the peers they announced.
+    server: Arc<Mutex<QueryServer>>,
+    /// Routers queried by `bootstrap`.
+    bootstrap_nodes: Vec<String>,
+    /// Where `save_state` writes the routing table.
+    state_file: Option<PathBuf>,
+    /// The listening and maintenance tasks, aborted by `shutdown`.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl Dht {
     /// Creates a new `Dht` node bound to the specified port.
     ///
+    /// The node bootstraps through the default routers and keeps no state file.
+    ///
     /// # Arguments
     ///
     /// * `port` - The UDP port to bind to. Use `0` to let the OS choose a random port.
@@ -45,32 +91,89 @@ impl Dht {
     ///
     /// * `Result<Self, ...>` - The created DHT node or an error if binding fails.
     pub async fn new(port: u16) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
-        let mut rng = rand::rng();
-        let mut node_id = [0u8; 20];
-        rng.fill(&mut node_id);
+        Self::with_config(DhtConfig {
+            port,
+            state_file: None,
+            ..DhtConfig::default()
+        })
+        .await
+    }
+
+    /// Creates a new `Dht` node from a configuration.
+    ///
+    /// If the state file exists, the node takes over the node ID saved in it and starts
+    /// with its nodes in the routing table. A state file that cannot be read is ignored.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, ...>` - The created DHT node or an error if binding fails.
+    pub async fn with_config(
+        config: DhtConfig,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let socket = net::bind_udp(config.port)?;
+
+        let saved = match &config.state_file {
+            Some(path) => state::load(path).await.unwrap_or_else(|e| {
+                eprintln!("Ignoring DHT state file {}: {}", path.display(), e);
+                None
+            }),
+            None => None,
+        };
+        let node_id = saved
+            .as_ref()
+            .map(|s| s.node_id)
+            .unwrap_or_else(|| rand::rng().random());
+        let mut table = RoutingTable::new(node_id);
+        if let Some(saved) = saved {
+            let now = Instant::now();
+            for node in saved.nodes {
+                table.learned_of(node, now);
+            }
+        }
 
         Ok(Self {
             socket: Arc::new(socket),
             node_id,
-            nodes: Arc::new(Mutex::new(Vec::new())),
-            peers: Arc::new(Mutex::new(Vec::new())),
-            transactions: Arc::new(Mutex::new(HashMap::new())),
+            nodes: Arc::new(Mutex::new(table)),
+            transactions: Arc::new(Mutex::new(Transactions::new())),
+            server: Arc::new(Mutex::new(QueryServer::new(node_id))),
+            bootstrap_nodes: config.bootstrap_nodes,
+            state_file: config.state_file,
+            tasks: Mutex::new(Vec::new()),
         })
     }
 
+    /// Writes our node ID and the nodes of the routing table to the state file, if there is one.
+    pub async fn save_state(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let nodes = {
+            let table = self.nodes.lock().await;
+            table.closest(&self.node_id, table.len(), Instant::now())
+        };
+        let state = DhtState {
+            node_id: self.node_id,
+            nodes,
+        };
+        state::save(path, &state).await
+    }
+
     /// Starts the DHT node's listening loop in a background task.
     ///
     /// This task listens for incoming UDP messages, parses them, and updates
-    /// the internal state (nodes and peers) or responds to queries (ping).
+    /// the internal state (nodes and peers) or responds to queries.
+    /// A second task maintains the routing table.
     pub async fn start(&self) {
+        let maintenance = self.spawn_maintenance();
+
         let socket = self.socket.clone();
         let nodes = self.nodes.clone();
-        let peers = self.peers.clone();
         let transactions = self.transactions.clone();
+        let server = self.server.clone();
         let my_id = self.node_id;
 
-        tokio::spawn(async move {
+        let listen = tokio::spawn(async move {
             let mut buf = [0u8; 65536];
             loop {
                 match socket.recv_from(&mut buf).await {
@@ -81,267 +184,764 @@ impl Dht {
                                 bencode,
                                 src,
                                 &nodes,
-                                &peers,
                                 &transactions,
+                                &server,
                                 &socket,
                                 my_id,
                             )
                             .await;
                         }
                     }
-                    Err(e) => {
+                    Err(_e) => {
                         // eprintln!("DHT UDP read error: {}", e);
                     }
                 }
             }
         });
+        self.tasks.lock().await.extend([listen, maintenance]);
+    }
+
+    /// Stops the tasks started by `start`.
+    ///
+    /// Their handles on the socket are released, so the port is freed once the last
+    /// reference to the node is dropped. Lookups still running get no more responses.
+    pub async fn shutdown(&self) {
+        for task in self.tasks.lock().await.drain(..) {
+            task.abort();
+        }
+    }
+
+    /// Keeps the routing table healthy in a background task.
+    ///
+    /// Queries not answered within `QUERY_TIMEOUT` count as failures of their node. Every
+    /// `MAINTENANCE_INTERVAL`, questionable nodes are pinged, and buckets that did not
+    /// change for 15 minutes are refreshed with a `find_node` for a random ID in their range.
+    fn spawn_maintenance(&self) -> JoinHandle<()> {
+        let socket = self.socket.clone();
+        let nodes = self.nodes.clone();
+        let transactions = self.transactions.clone();
+        let server = self.server.clone();
+        let my_id = self.node_id;
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+            let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = expiry.tick() => {
+                        let now = Instant::now();
+                        let expired = transactions.lock().await.expire(now);
+                        let mut table = nodes.lock().await;
+                        for query in expired {
+                            table.failed(query.addr, now);
+                        }
+                        continue;
+                    }
+                    _ = interval.tick() => {}
+                }
+
+                let now = Instant::now();
+                server.lock().await.store.expire(now);
+                let (ping, refresh) = {
+                    let mut table = nodes.lock().await;
+                    let ping = table.questionable(now);
+                    let refresh: Vec<_> = table
+                        .refresh_targets(now)
+                        .into_iter()
+                        .map(|target| (target, table.closest(&target, K, now)))
+                        .collect();
+                    for node in ping.iter().chain(refresh.iter().flat_map(|(_, n)| n)) {
+                        table.queried(node.addr, now);
+                    }
+                    (ping, refresh)
+                };
+
+                for node in ping {
+                    Self::send_ping(&socket, &transactions, &node, my_id).await;
+                }
+                for (target, closest) in refresh {
+                    for node in closest {
+                        Self::send_find_node(
+                            &socket,
+                            &transactions,
+                            node.addr,
+                            Some(node.id),
+                            my_id,
+                            target,
+                        )
+                        .await;
+                    }
+                }
+            }
+        })
     }
 
     /// Handles an incoming decoded KRPC message.
     ///
     /// Dispatches based on message type ('y'):
-    /// * 'r' (response): Updates routing table or peer list.
-    /// * 'q' (query): Responds to pings.
+    /// * 'r' (response): Matched to the query it answers and checked against it; stray,
+    ///   spoofed or malformed responses are dropped. A valid response updates the routing
+    ///   table, and `get_peers` responses are handed to their lookup.
+    /// * 'e' (error): Ends the query it answers.
+    /// * 'q' (query): Answers `ping`, `find_node`, `get_peers` and `announce_peer`, or
+    ///   sends back a KRPC error if the query is malformed or unknown.
+    ///
+    /// The sender of every valid response or query is recorded in the routing table.
     async fn handle_message(
         msg: Bencode,
         src: SocketAddr,
-        nodes: &Arc<Mutex<Vec<Node>>>,
-        peers: &Arc<Mutex<Vec<SocketAddrV4>>>,
-        _transactions: &Arc<Mutex<HashMap<Vec<u8>, String>>>,
-        socket: &Arc<UdpSocket>,
+        nodes: &Arc<Mutex<RoutingTable>>,
+        transactions: &Arc<Mutex<Transactions>>,
+        server: &Arc<Mutex<QueryServer>>,
+        socket: &Arc<DualStack<UdpSocket>>,
         my_id: [u8; 20],
     ) {
-        if let Bencode::Dict(dict) = msg {
-            // Check transaction ID
-            let t = match dict.get(&b"t"[..]) {
-                Some(Bencode::Bytes(b)) => b.clone(),
-                _ => return,
-            };
+        let Bencode::Dict(dict) = msg else {
+            return;
+        };
+        // Check transaction ID
+        let t = match dict.get(&b"t"[..]) {
+            Some(Bencode::Bytes(b)) => b.clone(),
+            _ => return,
+        };
 
-            // Check message type
-            let y = match dict.get(&b"y"[..]) {
-                Some(Bencode::Bytes(b)) => b,
-                _ => return,
-            };
+        // Check message type
+        let y = match dict.get(&b"y"[..]) {
+            Some(Bencode::Bytes(b)) => b,
+            _ => return,
+        };
 
-            if y == b"r" {
-                // Response
-                if let Some(Bencode::Dict(r)) = dict.get(&b"r"[..]) {
-                    // Extract nodes or peers
-                    if let Some(Bencode::Bytes(nodes_bytes)) = r.get(&b"nodes"[..]) {
-                        Self::parse_nodes(nodes_bytes, nodes).await;
-                    }
-                    if let Some(Bencode::List(values)) = r.get(&b"values"[..]) {
-                        Self::parse_peers(values, peers).await;
+        // The sender's ID is in the arguments of a query and the body of a response
+        let body = match (y.as_slice(), dict.get(&b"a"[..]), dict.get(&b"r"[..])) {
+            (b"q", Some(Bencode::Dict(body)), _) | (b"r", _, Some(Bencode::Dict(body))) => {
+                Some(body)
+            }
+            _ => None,
+        };
+        let sender = match body.and_then(|b| b.get(&b"id"[..])) {
+            Some(Bencode::Bytes(id)) => <[u8; 20]>::try_from(id.as_slice())
+                .ok()
+                .map(|id| Node { id, addr: src }),
+            _ => None,
+        };
+
+        match y.as_slice() {
+            b"r" => {
+                let (Some(r), Some(from)) = (body, sender) else {
+                    return;
+                };
+                let query = match transactions.lock().await.answer(&t, &from) {
+                    Ok(query) => query,
+                    Err(Rejected::WrongId) => {
+                        nodes.lock().await.failed(src, Instant::now());
+                        return;
                     }
+                    Err(Rejected::Unknown | Rejected::WrongAddress) => return,
+                };
+                let Some(reply) = Self::parse_response(query.kind, r, &from) else {
+                    nodes.lock().await.failed(src, Instant::now());
+                    return;
+                };
+
+                Self::record_contact(from, nodes, transactions, socket, my_id).await;
+                let now = Instant::now();
+                let mut table = nodes.lock().await;
+                for node in &reply.nodes {
+                    table.learned_of(node.clone(), now);
+                }
+                drop(table);
+                if let Some(lookup) = query.lookup {
+                    let _ = lookup.send(reply);
                 }
-            } else if y == b"q" {
-                // Query (we should respond to ping at least)
-                if let Some(Bencode::Bytes(q)) = dict.get(&b"q"[..]) {
-                    if q == b"ping" {
-                        Self::send_ping_response(socket, src, &t, my_id).await;
+            }
+            b"e" => {
+                transactions.lock().await.error(&t, src);
+            }
+            b"q" => {
+                if let Some(node) = sender {
+                    Self::record_contact(node, nodes, transactions, socket, my_id).await;
+                }
+                let reply = match (dict.get(&b"q"[..]), body) {
+                    (Some(Bencode::Bytes(q)), Some(a)) => {
+                        let table = nodes.lock().await;
+                        server
+                            .lock()
+                            .await
+                            .answer(q, a, src, &table, Instant::now())
                     }
+                    _ => Err(KrpcError::protocol("Malformed query")),
+                };
+                match reply {
+                    Ok(r) => Self::send_response(socket, src, &t, r).await,
+                    Err(e) => Self::send_error(socket, src, &t, &e).await,
                 }
             }
+            _ => {}
         }
     }
 
-    /// Parses the compact node info string (26 bytes per node) and updates the routing table.
-    async fn parse_nodes(data: &[u8], nodes: &Arc<Mutex<Vec<Node>>>) {
-        // Each node is 26 bytes: 20 bytes ID + 6 bytes IP/Port
-        let mut guard = nodes.lock().await;
-        for chunk in data.chunks(26) {
-            if chunk.len() == 26 {
-                let mut id = [0u8; 20];
-                id.copy_from_slice(&chunk[0..20]);
-                let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
-                let port = u16::from_be_bytes([chunk[24], chunk[25]]);
-                let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
-
-                // Simple add if not exists
-                if !guard.iter().any(|n| n.addr == addr) {
-                    guard.push(Node { id, addr });
-                }
+    /// Records a node that answered us or queried us, and pings the node it may replace.
+    async fn record_contact(
+        node: Node,
+        nodes: &Mutex<RoutingTable>,
+        transactions: &Mutex<Transactions>,
+        socket: &DualStack<UdpSocket>,
+        my_id: [u8; 20],
+    ) {
+        let now = Instant::now();
+        let ping = {
+            let mut table = nodes.lock().await;
+            let ping = table.heard_from(node, now);
+            if let Some(node) = &ping {
+                table.queried(node.addr, now);
             }
+            ping
+        };
+        if let Some(node) = ping {
+            Self::send_ping(socket, transactions, &node, my_id).await;
         }
     }
 
-    /// Parses a list of compact peer info strings (6 bytes per peer) and updates the peer list.
-    async fn parse_peers(values: &[Bencode], peers: &Arc<Mutex<Vec<SocketAddrV4>>>) {
-        let mut guard = peers.lock().await;
-        for val in values {
-            if let Bencode::Bytes(b) = val {
-                if b.len() == 6 {
-                    let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
-                    let port = u16::from_be_bytes([b[4], b[5]]);
-                    let addr = SocketAddrV4::new(ip, port);
-                    if !guard.contains(&addr) {
-                        guard.push(addr);
-                    }
+    /// Checks that a response carries what its query asks for.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Reply>` - The nodes, peers and token of the response, or `None` if it is malformed.
+    fn parse_response(
+        kind: QueryKind,
+        r: &BTreeMap<Vec<u8>, Bencode>,
+        from: &Node,
+    ) -> Option<Reply> {
+        let mut nodes = None;
+        for (key, ipv6) in [(&b"nodes"[..], false), (&b"nodes6"[..], true)] {
+            match r.get(key) {
+                Some(Bencode::Bytes(b)) if b.len().is_multiple_of(20 + node_addr_len(ipv6)) => {
+                    nodes
+                        .get_or_insert_with(Vec::new)
+                        .extend(Self::parse_nodes(b, ipv6))
                 }
+                Some(_) => return None,
+                None => {}
             }
         }
+        let values = match r.get(&b"values"[..]) {
+            Some(Bencode::List(values)) => Some(Self::parse_values(values)),
+            Some(_) => return None,
+            None => None,
+        };
+        let token = match r.get(&b"token"[..]) {
+            Some(Bencode::Bytes(token)) => Some(token.clone()),
+            Some(_) => return None,
+            None => None,
+        };
+
+        let valid = match kind {
+            QueryKind::Ping | QueryKind::AnnouncePeer => true,
+            QueryKind::FindNode => nodes.is_some(),
+            QueryKind::GetPeers => token.is_some() && (nodes.is_some() || values.is_some()),
+        };
+        valid.then(|| Reply {
+            from: from.clone(),
+            nodes: nodes.unwrap_or_default(),
+            values: values.unwrap_or_default(),
+            token,
+        })
+    }
+
+    /// Parses a compact node info string of one family: the `nodes` key (26 bytes per
+    /// IPv4 node) or the `nodes6` key (38 bytes per IPv6 node).
+    fn parse_nodes(data: &[u8], ipv6: bool) -> Vec<Node> {
+        // Each node is a 20 byte ID followed by its compact address
+        data.chunks_exact(20 + node_addr_len(ipv6))
+            .filter_map(|chunk| {
+                let mut id = [0u8; 20];
+                id.copy_from_slice(&chunk[0..20]);
+                let addr = compact::decode_peer(&chunk[20..])?;
+                Some(Node { id, addr })
+            })
+            .collect()
+    }
+
+    /// Parses a list of compact peer info strings (6 bytes per IPv4 peer, 18 bytes per IPv6 peer).
+    fn parse_values(values: &[Bencode]) -> Vec<SocketAddr> {
+        values
+            .iter()
+            .filter_map(|val| match val {
+                Bencode::Bytes(b) => compact::decode_peer(b),
+                _ => None,
+            })
+            .collect()
     }
 
-    /// Sends a 'ping' response.
+    /// Sends a packet through the socket of the destination's family.
+    async fn send_packet(socket: &DualStack<UdpSocket>, msg: &[u8], to: SocketAddr) {
+        let _ = socket.send_to(msg, to).await;
+    }
+
+    /// The `want` argument of `find_node` and `get_peers` (BEP 32): a dual-stack node
+    /// asks for nodes of both families, others get the family they queried from.
+    fn want(socket: &DualStack<UdpSocket>) -> Option<Bencode> {
+        socket.is_dual_stack().then(|| {
+            Bencode::List(vec![
+                Bencode::Bytes(b"n4".to_vec()),
+                Bencode::Bytes(b"n6".to_vec()),
+            ])
+        })
+    }
+
+    /// Sends a response to a query.
     ///
     /// # Arguments
     ///
     /// * `socket` - The UDP socket.
-    /// * `to` - The address of the pinging node.
+    /// * `to` - The address of the querying node.
     /// * `t` - The transaction ID from the query.
-    /// * `my_id` - Our node ID.
-    async fn send_ping_response(
-        socket: &Arc<UdpSocket>,
+    /// * `r` - The response body, including our node ID.
+    async fn send_response(
+        socket: &DualStack<UdpSocket>,
         to: SocketAddr,
         t: &[u8],
-        my_id: [u8; 20],
+        r: BTreeMap<Vec<u8>, Bencode>,
     ) {
         let mut dict = BTreeMap::new();
         dict.insert(b"t".to_vec(), Bencode::Bytes(t.to_vec()));
         dict.insert(b"y".to_vec(), Bencode::Bytes(b"r".to_vec()));
-
-        let mut r = BTreeMap::new();
-        r.insert(b"id".to_vec(), Bencode::Bytes(my_id.to_vec()));
         dict.insert(b"r".to_vec(), Bencode::Dict(r));
 
         let msg = Bencode::Dict(dict).encode();
-        let _ = socket.send_to(&msg, to).await;
+        Self::send_packet(socket, &msg, to).await;
     }
 
-    /// Bootstraps the DHT by querying known public bootstrap nodes.
-    ///
-    /// This populates the routing table with initial nodes.
-    pub async fn bootstrap(&self) {
-        let routers = vec![
-            "router.bittorrent.com:6881",
-            "dht.transmissionbt.com:6881",
-            "router.utorrent.com:6881",
-        ];
-
-        for router in routers {
-            if let Ok(addrs) = tokio::net::lookup_host(router).await {
-                for addr in addrs {
-                    self.find_node(addr, self.node_id).await;
-                }
-            }
-        }
+    /// Sends a KRPC error in reply to a query.
+    async fn send_error(
+        socket: &DualStack<UdpSocket>,
+        to: SocketAddr,
+        t: &[u8],
+        error: &KrpcError,
+    ) {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"t".to_vec(), Bencode::Bytes(t.to_vec()));
+        dict.insert(b"y".to_vec(), Bencode::Bytes(b"e".to_vec()));
+        dict.insert(b"e".to_vec(), error.to_bencode());
+
+        let msg = Bencode::Dict(dict).encode();
+        Self::send_packet(socket, &msg, to).await;
     }
 
-    /// Sends `get_peers` queries to all known nodes in the routing table for the given info hash.
-    ///
-    /// # Arguments
+    /// Sends a `ping` query.
+    async fn send_ping(
+        socket: &DualStack<UdpSocket>,
+        transactions: &Mutex<Transactions>,
+        to: &Node,
+        my_id: [u8; 20],
+    ) {
+        let mut a = BTreeMap::new();
+        a.insert(b"id".to_vec(), Bencode::Bytes(my_id.to_vec()));
+        let query = Self::pending(QueryKind::Ping, to.addr, Some(to.id), None);
+        Self::send_query(socket, transactions, query, a).await;
+    }
+
+    /// Sends a `find_node` query for `target`.
     ///
-    /// * `info_hash` - The target info hash to find peers for.
-    pub async fn get_peers(&self, info_hash: [u8; 20]) {
-        // Query all known nodes for peers
-        // In a real implementation, we would query closest nodes iteratively
-        let nodes = {
-            let guard = self.nodes.lock().await;
-            guard.clone()
-        };
+    /// `node_id` is the ID of the queried node, or `None` for a bootstrap router.
+    async fn send_find_node(
+        socket: &DualStack<UdpSocket>,
+        transactions: &Mutex<Transactions>,
+        to: SocketAddr,
+        node_id: Option<[u8; 20]>,
+        my_id: [u8; 20],
+        target: [u8; 20],
+    ) {
+        let mut a = BTreeMap::new();
+        a.insert(b"id".to_vec(), Bencode::Bytes(my_id.to_vec()));
+        a.insert(b"target".to_vec(), Bencode::Bytes(target.to_vec()));
+        if let Some(want) = Self::want(socket) {
+            a.insert(b"want".to_vec(), want);
+        }
+        let query = Self::pending(QueryKind::FindNode, to, node_id, None);
+        Self::send_query(socket, transactions, query, a).await;
+    }
 
-        for node in nodes {
-            self.send_get_peers(node.addr, info_hash).await;
+    /// Describes a query about to be sent, due `QUERY_TIMEOUT` from now.
+    fn pending(
+        kind: QueryKind,
+        addr: SocketAddr,
+        node_id: Option<[u8; 20]>,
+        lookup: Option<mpsc::UnboundedSender<Reply>>,
+    ) -> Pending {
+        Pending {
+            kind,
+            addr,
+            node_id,
+            deadline: Instant::now() + QUERY_TIMEOUT,
+            lookup,
         }
     }
 
-    /// Sends a `find_node` query to a specific address.
-    async fn find_node(&self, addr: SocketAddr, target: [u8; 20]) {
-        let t: [u8; 2] = {
-            let mut rng = rand::rng();
-            rng.random()
+    /// Registers a KRPC query and sends it, unless `MAX_PENDING` queries are already
+    /// outstanding.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The UDP socket.
+    /// * `transactions` - Where the query is recorded until it is answered or times out.
+    /// * `query` - The method, destination and deadline of the query.
+    /// * `a` - The query arguments.
+    async fn send_query(
+        socket: &DualStack<UdpSocket>,
+        transactions: &Mutex<Transactions>,
+        query: Pending,
+        a: BTreeMap<Vec<u8>, Bencode>,
+    ) {
+        let (to, q) = (query.addr, query.kind.method());
+        // A lookup waiting on a dropped query times it out like an unanswered one
+        let Some(t) = transactions.lock().await.register(query) else {
+            return;
         };
 
         let mut dict = BTreeMap::new();
         dict.insert(b"t".to_vec(), Bencode::Bytes(t.to_vec()));
         dict.insert(b"y".to_vec(), Bencode::Bytes(b"q".to_vec()));
-        dict.insert(b"q".to_vec(), Bencode::Bytes(b"find_node".to_vec()));
-
-        let mut a = BTreeMap::new();
-        a.insert(b"id".to_vec(), Bencode::Bytes(self.node_id.to_vec()));
-        a.insert(b"target".to_vec(), Bencode::Bytes(target.to_vec()));
+        dict.insert(b"q".to_vec(), Bencode::Bytes(q.to_vec()));
         dict.insert(b"a".to_vec(), Bencode::Dict(a));
 
         let msg = Bencode::Dict(dict).encode();
-        let _ = self.socket.send_to(&msg, addr).await;
+        Self::send_packet(socket, &msg, to).await;
     }
 
-    /// Sends a `get_peers` query to a specific address.
-    async fn send_get_peers(&self, addr: SocketAddr, info_hash: [u8; 20]) {
-        let t: [u8; 2] = {
-            let mut rng = rand::rng();
-            rng.random()
-        };
+    /// Bootstraps the DHT by looking up our own ID.
+    ///
+    /// The nodes restored from the state file are asked first, then the configured
+    /// bootstrap routers. The answers populate the routing table.
+    pub async fn bootstrap(&self) {
+        let known = self
+            .nodes
+            .lock()
+            .await
+            .closest(&self.node_id, K, Instant::now());
+        for node in known {
+            self.nodes.lock().await.queried(node.addr, Instant::now());
+            Self::send_find_node(
+                &self.socket,
+                &self.transactions,
+                node.addr,
+                Some(node.id),
+                self.node_id,
+                self.node_id,
+            )
+            .await;
+        }
 
-        let mut dict = BTreeMap::new();
-        dict.insert(b"t".to_vec(), Bencode::Bytes(t.to_vec()));
-        dict.insert(b"y".to_vec(), Bencode::Bytes(b"q".to_vec()));
-        dict.insert(b"q".to_vec(), Bencode::Bytes(b"get_peers".to_vec()));
+        for router in &self.bootstrap_nodes {
+            match tokio::net::lookup_host(router.as_str()).await {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        self.find_node(addr, self.node_id).await;
+                    }
+                }
+                Err(e) => eprintln!("Failed to resolve DHT bootstrap node {}: {}", router, e),
+            }
+        }
+    }
 
-        let mut a = BTreeMap::new();
-        a.insert(b"id".to_vec(), Bencode::Bytes(self.node_id.to_vec()));
-        a.insert(b"info_hash".to_vec(), Bencode::Bytes(info_hash.to_vec()));
-        dict.insert(b"a".to_vec(), Bencode::Dict(a));
+    /// Starts an iterative `get_peers` lookup for the given info hash.
+    ///
+    /// The lookup begins at the closest nodes of the routing table and moves towards
+    /// the info hash until it converges; it runs in a background task.
+    ///
+    /// # Arguments
+    ///
+    /// * `info_hash` - The target info hash to find peers for.
+    ///
+    /// # Returns
+    ///
+    /// * `PeerLookup` - Yields peers as they are found, then the tokens of the closest nodes.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> PeerLookup {
+        let start = self
+            .nodes
+            .lock()
+            .await
+            .closest(&info_hash, K, Instant::now());
+        let lookup = Lookup::new(info_hash, self.node_id, start);
 
-        let msg = Bencode::Dict(dict).encode();
-        let _ = self.socket.send_to(&msg, addr).await;
+        let (peer_tx, peers) = mpsc::channel(64);
+        let (done_tx, done) = oneshot::channel();
+        let (reply_tx, replies) = mpsc::unbounded_channel();
+
+        let socket = self.socket.clone();
+        let nodes = self.nodes.clone();
+        let transactions = self.transactions.clone();
+        let my_id = self.node_id;
+        tokio::spawn(async move {
+            let send_query = |node: Node| {
+                let (socket, nodes, transactions) =
+                    (socket.clone(), nodes.clone(), transactions.clone());
+                let reply_tx = reply_tx.clone();
+                async move {
+                    nodes.lock().await.queried(node.addr, Instant::now());
+                    Self::send_get_peers(&socket, &transactions, &node, my_id, info_hash, reply_tx)
+                        .await;
+                }
+            };
+            // Queries still unanswered when the lookup ends expire on their own
+            let tokens = lookup::run(lookup, send_query, replies, QUERY_TIMEOUT, peer_tx).await;
+            let _ = done_tx.send(tokens);
+        });
+
+        PeerLookup { peers, done }
     }
 
-    /// Retrieves and clears the list of newly discovered peers.
+    /// Announces that we accept peers for `info_hash`.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// * `Vec<SocketAddrV4>` - A list of peer addresses.
-    pub async fn get_found_peers(&self) -> Vec<SocketAddrV4> {
-        let mut guard = self.peers.lock().await;
-        let peers = guard.clone();
-        guard.clear();
-        peers
+    /// * `info_hash` - The torrent we are in the swarm of.
+    /// * `port` - The port peers should connect to.
+    /// * `implied_port` - Asks the nodes to use the source port of the query instead of
+    ///   `port`, which is right behind a NAT when peers are accepted on the DHT port.
+    /// * `nodes` - The nodes to announce to, with their tokens, from `PeerLookup::finish`.
+    pub async fn announce_peer(
+        &self,
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        nodes: &[TokenNode],
+    ) {
+        for TokenNode { node, token } in nodes {
+            self.nodes.lock().await.queried(node.addr, Instant::now());
+            let mut a = BTreeMap::new();
+            a.insert(b"id".to_vec(), Bencode::Bytes(self.node_id.to_vec()));
+            a.insert(b"info_hash".to_vec(), Bencode::Bytes(info_hash.to_vec()));
+            a.insert(b"port".to_vec(), Bencode::Int(port as i64));
+            a.insert(b"token".to_vec(), Bencode::Bytes(token.clone()));
+            if implied_port {
+                a.insert(b"implied_port".to_vec(), Bencode::Int(1));
+            }
+            let query = Self::pending(QueryKind::AnnouncePeer, node.addr, Some(node.id), None);
+            Self::send_query(&self.socket, &self.transactions, query, a).await;
+        }
+    }
+
+    /// Returns the number of nodes in the routing table.
+    pub async fn node_count(&self) -> usize {
+        self.nodes.lock().await.len()
+    }
+
+    /// Returns the UDP port the node is bound to.
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Sends a `find_node` query to a node known only by its address, such as a bootstrap router.
+    async fn find_node(&self, addr: SocketAddr, target: [u8; 20]) {
+        self.nodes.lock().await.queried(addr, Instant::now());
+        Self::send_find_node(
+            &self.socket,
+            &self.transactions,
+            addr,
+            None,
+            self.node_id,
+            target,
+        )
+        .await;
+    }
+
+    /// Sends a `get_peers` query whose response goes to the lookup behind `lookup`.
+    async fn send_get_peers(
+        socket: &DualStack<UdpSocket>,
+        transactions: &Mutex<Transactions>,
+        to: &Node,
+        my_id: [u8; 20],
+        info_hash: [u8; 20],
+        lookup: mpsc::UnboundedSender<Reply>,
+    ) {
+        let mut a = BTreeMap::new();
+        a.insert(b"id".to_vec(), Bencode::Bytes(my_id.to_vec()));
+        a.insert(b"info_hash".to_vec(), Bencode::Bytes(info_hash.to_vec()));
+        if let Some(want) = Self::want(socket) {
+            a.insert(b"want".to_vec(), want);
+        }
+        let query = Self::pending(QueryKind::GetPeers, to.addr, Some(to.id), Some(lookup));
+        Self::send_query(socket, transactions, query, a).await;
+    }
+}
+
+/// The length of a compact node address: 6 bytes for IPv4, 18 bytes for IPv6.
+fn node_addr_len(ipv6: bool) -> usize {
+    if ipv6 {
+        compact::IPV6_PEER_LEN
+    } else {
+        compact::IPV4_PEER_LEN
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::IpAddr;
 
     #[tokio::test]
     async fn test_parse_nodes() {
-         // Construct 26 bytes of node info
-         // 20 bytes ID (all 1s)
-         // 4 bytes IP (127.0.0.1)
-         // 2 bytes Port (8080)
-         let mut data = vec![1u8; 20];
-         data.extend_from_slice(&[127, 0, 0, 1]);
-         data.extend_from_slice(&8080u16.to_be_bytes());
-         
-         let nodes = Arc::new(Mutex::new(Vec::new()));
-         Dht::parse_nodes(&data, &nodes).await;
-         
-         let guard = nodes.lock().await;
-         assert_eq!(guard.len(), 1);
-         assert_eq!(guard[0].id, [1u8; 20]);
-         if let SocketAddr::V4(v4) = guard[0].addr {
-             assert_eq!(v4.ip().to_string(), "127.0.0.1");
-             assert_eq!(v4.port(), 8080);
-         } else {
-             panic!("Address is not V4");
-         }
+        // Construct 26 bytes of node info
+        // 20 bytes ID (all 1s)
+        // 4 bytes IP (127.0.0.1)
+        // 2 bytes Port (8080)
+        let mut data = vec![1u8; 20];
+        data.extend_from_slice(&[127, 0, 0, 1]);
+        data.extend_from_slice(&8080u16.to_be_bytes());
+
+        let nodes = Dht::parse_nodes(&data, false);
+
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, [1u8; 20]);
+        if let SocketAddr::V4(v4) = nodes[0].addr {
+            assert_eq!(v4.ip().to_string(), "127.0.0.1");
+            assert_eq!(v4.port(), 8080);
+        } else {
+            panic!("Address is not V4");
+        }
+
+        // 38 bytes per node in nodes6
+        let node6 = Node {
+            id: [2u8; 20],
+            addr: "[2001:db8::1]:8080".parse().unwrap(),
+        };
+        let data6 = server::encode_nodes(&[nodes[0].clone(), node6.clone()], true);
+        assert_eq!(data6.len(), 38);
+        let nodes6 = Dht::parse_nodes(&data6, true);
+        assert_eq!(nodes6.len(), 1);
+        assert_eq!(nodes6[0].addr, node6.addr);
     }
 
     #[tokio::test]
     async fn test_parse_peers() {
-         // 6 bytes compact info
-         // 1.1.1.1:6969
-         let data = vec![1, 1, 1, 1, 0x1B, 0x39]; 
-         let bencode_val = Bencode::Bytes(data);
-         let list = vec![bencode_val];
-         
-         let peers = Arc::new(Mutex::new(Vec::new()));
-         Dht::parse_peers(&list, &peers).await;
-         
-         let guard = peers.lock().await;
-         assert_eq!(guard.len(), 1);
-         assert_eq!(guard[0].to_string(), "1.1.1.1:6969");
+        // 6 bytes compact info
+        // 1.1.1.1:6969
+        let data = vec![1, 1, 1, 1, 0x1B, 0x39];
+        let bencode_val = Bencode::Bytes(data);
+        let list = vec![bencode_val];
+
+        let peers = Dht::parse_values(&list);
+
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].to_string(), "1.1.1.1:6969");
+    }
+
+    /// Has one node look up and announce on another, reached at `ip`.
+    async fn lookup_then_announce(ip: IpAddr) {
+        let a = Dht::new(0).await.unwrap();
+        let b = Dht::new(0).await.unwrap();
+        a.start().await;
+        b.start().await;
+        let b_addr = SocketAddr::new(ip, b.port());
+
+        // Once b answers, it is in a's routing table
+        a.find_node(b_addr, a.node_id).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while a.node_count().await == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let info_hash = [7u8; 20];
+        let lookup = a.get_peers(info_hash).await;
+        let tokens = tokio::time::timeout(Duration::from_secs(5), lookup.finish())
+            .await
+            .unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].node.id, b.node_id);
+
+        a.announce_peer(info_hash, 6881, false, &tokens).await;
+        let announced = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let peers = b
+                    .server
+                    .lock()
+                    .await
+                    .store
+                    .peers(&info_hash, Instant::now());
+                if !peers.is_empty() {
+                    return peers;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(announced, vec![SocketAddr::new(ip, 6881)]);
+
+        // The next lookup finds us
+        let mut lookup = a.get_peers(info_hash).await;
+        let found = tokio::time::timeout(Duration::from_secs(5), lookup.next())
+            .await
+            .unwrap();
+        assert_eq!(found, Some(SocketAddr::new(ip, 6881)));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_then_announce_reaches_the_other_node() {
+        lookup_then_announce("127.0.0.1".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_lookup_then_announce_over_ipv6() {
+        lookup_then_announce("::1".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_state_file_restores_id_and_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DhtConfig {
+            port: 0,
+            bootstrap_nodes: Vec::new(),
+            state_file: Some(dir.path().join("dht.dat")),
+        };
+        let first = Dht::with_config(config.clone()).await.unwrap();
+        let known = Node {
+            id: [5u8; 20],
+            addr: "10.0.0.5:6881".parse().unwrap(),
+        };
+        let known6 = Node {
+            id: [6u8; 20],
+            addr: "[2001:db8::5]:6881".parse().unwrap(),
+        };
+        for node in [&known, &known6] {
+            first
+                .nodes
+                .lock()
+                .await
+                .heard_from(node.clone(), Instant::now());
+        }
+        first.save_state().await.unwrap();
+
+        let second = Dht::with_config(config).await.unwrap();
+        assert_eq!(second.node_id, first.node_id);
+        let nodes = second
+            .nodes
+            .lock()
+            .await
+            .closest(&known.id, K, Instant::now());
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].addr, known.addr);
+        assert_eq!(nodes[1].addr, known6.addr);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_releases_the_socket() {
+        let dht = Dht::new(0).await.unwrap();
+        dht.start().await;
+        assert_eq!(Arc::strong_count(&dht.socket), 3);
+
+        dht.shutdown().await;
+        // Aborted tasks drop their state the next time the runtime gets to them
+        for _ in 0..100 {
+            if Arc::strong_count(&dht.socket) == 1 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(Arc::strong_count(&dht.socket), 1);
     }
 }
@@ -0,0 +1,421 @@
+//! The Kademlia routing table (BEP 5).
+//!
+//! Nodes are sorted into 160 buckets by the length of the prefix their ID shares
+//! with ours, so the table knows many nodes close to us and a few far away. Each
+//! bucket holds at most `K` nodes plus a cache of up to `K` replacements that take
+//! over when a node goes bad.
+
+use super::Node;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of nodes per bucket, and of replacements cached per bucket.
+pub const K: usize = 8;
+
+/// Number of buckets: one per bit of a node ID.
+pub const BUCKET_COUNT: usize = 160;
+
+/// A node not heard from for this long becomes questionable, and a bucket without
+/// changes for this long is refreshed.
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Number of unanswered queries after which a node is bad.
+pub const MAX_FAILURES: u32 = 2;
+
+/// How a routing table entry is doing, as defined by BEP 5.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    /// Responded to us, or queried us, within the last 15 minutes.
+    Good,
+    /// Not heard from recently, or never heard from directly.
+    Questionable,
+    /// Failed to respond to several queries in a row.
+    Bad,
+}
+
+/// A node in the routing table, with its liveness information.
+#[derive(Debug, Clone)]
+struct Entry {
+    node: Node,
+    /// When the node last responded to us or queried us. `None` if we only learned of
+    /// it from another node.
+    last_seen: Option<Instant>,
+    /// Queries in a row the node did not answer.
+    failures: u32,
+    /// When we sent the node a query it has not answered yet.
+    queried: Option<Instant>,
+}
+
+impl Entry {
+    fn new(node: Node, last_seen: Option<Instant>) -> Self {
+        Self {
+            node,
+            last_seen,
+            failures: 0,
+            queried: None,
+        }
+    }
+
+    fn status(&self, now: Instant) -> NodeStatus {
+        if self.failures >= MAX_FAILURES {
+            NodeStatus::Bad
+        } else if self
+            .last_seen
+            .is_some_and(|t| now.duration_since(t) < QUESTIONABLE_AFTER)
+        {
+            NodeStatus::Good
+        } else {
+            NodeStatus::Questionable
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    nodes: Vec<Entry>,
+    /// Candidates for the bucket once one of its nodes goes bad, most recent last.
+    replacements: VecDeque<Entry>,
+    /// When a node was last added to the bucket or heard from.
+    last_changed: Instant,
+}
+
+/// The buckets of nodes known to a DHT node.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: [u8; 20],
+    buckets: Vec<Bucket>,
+}
+
+/// XOR distance between two IDs, compared as big-endian numbers.
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+impl RoutingTable {
+    /// Creates an empty table for the node with ID `own_id`.
+    pub fn new(own_id: [u8; 20]) -> Self {
+        let now = Instant::now();
+        Self {
+            own_id,
+            buckets: (0..BUCKET_COUNT)
+                .map(|_| Bucket {
+                    nodes: Vec::new(),
+                    replacements: VecDeque::new(),
+                    last_changed: now,
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the bucket of `id`: the number of leading bits it shares with our ID.
+    ///
+    /// Returns `None` for our own ID.
+    fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+        let d = distance(&self.own_id, id);
+        let byte = d.iter().position(|&b| b != 0)?;
+        Some(byte * 8 + d[byte].leading_zeros() as usize)
+    }
+
+    /// Records a node that responded to us or sent us a query.
+    ///
+    /// The node becomes good. If its bucket is full, it replaces a bad node, or is
+    /// cached as a replacement otherwise.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Node>` - A questionable node of the full bucket that should be pinged;
+    ///   if it fails to answer it goes bad and makes room.
+    pub fn heard_from(&mut self, node: Node, now: Instant) -> Option<Node> {
+        self.insert(node, Some(now), now)
+    }
+
+    /// Records a node we learned about from another node, without contacting it.
+    ///
+    /// It is added as questionable if its bucket has room, and cached as a
+    /// replacement otherwise.
+    pub fn learned_of(&mut self, node: Node, now: Instant) {
+        self.insert(node, None, now);
+    }
+
+    fn insert(&mut self, node: Node, seen: Option<Instant>, now: Instant) -> Option<Node> {
+        let index = self.bucket_index(&node.id)?;
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.nodes.iter_mut().find(|e| e.node.id == node.id) {
+            if seen.is_some() {
+                entry.node.addr = node.addr;
+                entry.last_seen = seen;
+                entry.failures = 0;
+                entry.queried = None;
+                bucket.last_changed = now;
+            }
+            return None;
+        }
+
+        let entry = Entry::new(node, seen);
+        if bucket.nodes.len() < K {
+            bucket.nodes.push(entry);
+            bucket.last_changed = now;
+            return None;
+        }
+        if let Some(bad) = bucket
+            .nodes
+            .iter_mut()
+            .find(|e| e.status(now) == NodeStatus::Bad)
+        {
+            *bad = entry;
+            bucket.last_changed = now;
+            return None;
+        }
+
+        // Full of good or questionable nodes: keep the newcomer for later
+        bucket.replacements.retain(|e| e.node.id != entry.node.id);
+        if bucket.replacements.len() == K {
+            bucket.replacements.pop_front();
+        }
+        bucket.replacements.push_back(entry);
+
+        // The least recently seen questionable node that we are not already waiting on
+        bucket
+            .nodes
+            .iter()
+            .filter(|e| e.status(now) == NodeStatus::Questionable && e.queried.is_none())
+            .min_by_key(|e| e.last_seen)
+            .map(|e| e.node.clone())
+    }
+
+    /// Records that we sent a query to the node at `addr`.
+    pub fn queried(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(entry) = self.entry_by_addr(addr)
+            && entry.queried.is_none()
+        {
+            entry.queried = Some(now);
+        }
+    }
+
+    /// Records that the node at `addr` did not answer a query.
+    ///
+    /// A node that goes bad is replaced by the most recent node of its bucket's
+    /// replacement cache, if there is one.
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let Some(entry) = self.entry_by_addr(addr) else {
+            return;
+        };
+        entry.failures += 1;
+        entry.queried = None;
+        if entry.status(now) != NodeStatus::Bad {
+            return;
+        }
+
+        let id = entry.node.id;
+        let Some(index) = self.bucket_index(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(replacement) = bucket.replacements.pop_back() {
+            bucket.nodes.retain(|e| e.node.id != id);
+            bucket.nodes.push(replacement);
+            bucket.last_changed = now;
+        }
+    }
+
+    fn entry_by_addr(&mut self, addr: SocketAddr) -> Option<&mut Entry> {
+        self.buckets
+            .iter_mut()
+            .flat_map(|b| b.nodes.iter_mut())
+            .find(|e| e.node.addr == addr)
+    }
+
+    /// Returns the status of the node with ID `id`, if it is in the table.
+    pub fn status(&self, id: &[u8; 20], now: Instant) -> Option<NodeStatus> {
+        let index = self.bucket_index(id)?;
+        self.buckets[index]
+            .nodes
+            .iter()
+            .find(|e| e.node.id == *id)
+            .map(|e| e.status(now))
+    }
+
+    /// Returns up to `count` nodes that are not bad, closest to `target` first.
+    pub fn closest(&self, target: &[u8; 20], count: usize, now: Instant) -> Vec<Node> {
+        self.closest_matching(target, count, now, |_| true)
+    }
+
+    /// Returns up to `count` nodes of one address family that are not bad, closest to
+    /// `target` first.
+    pub fn closest_by_family(
+        &self,
+        target: &[u8; 20],
+        count: usize,
+        now: Instant,
+        ipv6: bool,
+    ) -> Vec<Node> {
+        self.closest_matching(target, count, now, |node| node.addr.is_ipv6() == ipv6)
+    }
+
+    fn closest_matching(
+        &self,
+        target: &[u8; 20],
+        count: usize,
+        now: Instant,
+        keep: impl Fn(&Node) -> bool,
+    ) -> Vec<Node> {
+        let mut nodes: Vec<&Entry> = self
+            .buckets
+            .iter()
+            .flat_map(|b| &b.nodes)
+            .filter(|e| e.status(now) != NodeStatus::Bad && keep(&e.node))
+            .collect();
+        nodes.sort_by_key(|e| distance(&e.node.id, target));
+        nodes
+            .into_iter()
+            .take(count)
+            .map(|e| e.node.clone())
+            .collect()
+    }
+
+    /// Returns the questionable nodes that are not waiting on a query already.
+    pub fn questionable(&self, now: Instant) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flat_map(|b| &b.nodes)
+            .filter(|e| e.status(now) == NodeStatus::Questionable && e.queried.is_none())
+            .map(|e| e.node.clone())
+            .collect()
+    }
+
+    /// Returns a random target ID for each bucket that has not changed in 15 minutes.
+    ///
+    /// Looking up such a target fills the bucket with fresh nodes. Buckets deeper than
+    /// the deepest non-empty one are skipped; nodes that close to us are rare.
+    pub fn refresh_targets(&mut self, now: Instant) -> Vec<[u8; 20]> {
+        let Some(deepest) = self.buckets.iter().rposition(|b| !b.nodes.is_empty()) else {
+            return Vec::new();
+        };
+        let mut targets = Vec::new();
+        for index in 0..=deepest {
+            let bucket = &mut self.buckets[index];
+            if now.duration_since(bucket.last_changed) >= QUESTIONABLE_AFTER {
+                bucket.last_changed = now;
+                targets.push(random_id_in_bucket(&self.own_id, index));
+            }
+        }
+        targets
+    }
+
+    /// Returns the number of nodes in the table, not counting replacements.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    /// Returns `true` if the table has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Returns a random ID that falls into bucket `index` of the node with ID `own_id`.
+fn random_id_in_bucket(own_id: &[u8; 20], index: usize) -> [u8; 20] {
+    let mut id: [u8; 20] = rand::rng().random();
+    for bit in 0..=index {
+        let (byte, mask) = (bit / 8, 0x80u8 >> (bit % 8));
+        // Share the first `index` bits and differ in the next one
+        let own = own_id[byte] & mask;
+        let wanted = if bit == index { own ^ mask } else { own };
+        id[byte] = (id[byte] & !mask) | wanted;
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn node(id: [u8; 20], port: u16) -> Node {
+        Node {
+            id,
+            addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)),
+        }
+    }
+
+    /// An ID in bucket 0 of a table whose own ID is all zeros.
+    fn far_id(i: u8) -> [u8; 20] {
+        let mut id = [0u8; 20];
+        id[0] = 0x80;
+        id[19] = i;
+        id
+    }
+
+    #[test]
+    fn test_bucket_full_keeps_replacements_and_pings_questionable() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0u8; 20]);
+        for i in 0..K as u8 {
+            assert!(
+                table
+                    .heard_from(node(far_id(i), 1000 + i as u16), now)
+                    .is_none()
+            );
+        }
+        assert_eq!(table.len(), K);
+
+        // All good: the newcomer is cached and nothing needs a ping
+        assert!(table.heard_from(node(far_id(100), 2000), now).is_none());
+        assert_eq!(table.len(), K);
+
+        // Later, the oldest node is questionable and gets pinged
+        let later = now + QUESTIONABLE_AFTER;
+        let ping = table.heard_from(node(far_id(101), 2001), later).unwrap();
+        table.queried(ping.addr, later);
+        table.failed(ping.addr, later);
+        table.queried(ping.addr, later);
+        table.failed(ping.addr, later);
+
+        // It went bad and the most recent replacement took its place
+        assert_eq!(table.status(&ping.id, later), None);
+        assert_eq!(table.status(&far_id(101), later), Some(NodeStatus::Good));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_closest_orders_by_xor_distance() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0u8; 20]);
+        let mut ids = Vec::new();
+        for b in [0x01u8, 0x10, 0x40, 0x80] {
+            let mut id = [0u8; 20];
+            id[0] = b;
+            ids.push(id);
+            table.learned_of(node(id, b as u16), now);
+        }
+        let mut target = [0u8; 20];
+        target[0] = 0x50;
+        let closest: Vec<_> = table
+            .closest(&target, 2, now)
+            .into_iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(closest, vec![ids[2], ids[1]]);
+        assert_eq!(table.status(&ids[0], now), Some(NodeStatus::Questionable));
+    }
+
+    #[test]
+    fn test_refresh_targets_land_in_stale_buckets() {
+        let own: [u8; 20] = rand::rng().random();
+        let mut table = RoutingTable::new(own);
+        let now = Instant::now();
+        for index in [0, 5, 37] {
+            let target = random_id_in_bucket(&own, index);
+            assert_eq!(table.bucket_index(&target), Some(index));
+        }
+
+        table.heard_from(node(random_id_in_bucket(&own, 3), 1), now);
+        assert!(table.refresh_targets(now).is_empty());
+        let targets = table.refresh_targets(now + QUESTIONABLE_AFTER);
+        assert_eq!(targets.len(), 4);
+        assert!(table.refresh_targets(now + QUESTIONABLE_AFTER).is_empty());
+    }
+}
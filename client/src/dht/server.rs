@@ -0,0 +1,407 @@
+//! Answering queries from other nodes.
+//!
+//! Nodes that only ask and never answer get dropped from other routing tables, so we
+//! reply to `ping`, `find_node`, `get_peers` and `announce_peer` as BEP 5 describes.
+//! The `token` handed out with `get_peers` is a hash of the querying node's IP and a
+//! secret that rotates every `SECRET_ROTATION`; tokens from the current and the previous
+//! secret are accepted in `announce_peer`.
+//!
+//! Nodes are returned per address family as BEP 32 describes: IPv4 nodes in `nodes`
+//! and IPv6 nodes in `nodes6`, for the families the query asks for in `want`, or else
+//! for the family of the querying node.
+
+use super::store::PeerStore;
+use super::{K, Node, RoutingTable};
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tds_core::bencoding::Bencode;
+use tds_core::compact;
+
+/// How often the token secret changes. A token stays valid for up to twice as long.
+pub const SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+type Dict = BTreeMap<Vec<u8>, Bencode>;
+
+/// A KRPC error, sent back in an `e` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcError {
+    /// The error code, one of the constants below.
+    pub code: i64,
+    /// A human readable description.
+    pub message: String,
+}
+
+impl KrpcError {
+    /// Generic error.
+    pub const GENERIC: i64 = 201;
+    /// Server error.
+    pub const SERVER: i64 = 202;
+    /// Protocol error: a malformed packet, invalid arguments or a bad token.
+    pub const PROTOCOL: i64 = 203;
+    /// Method unknown.
+    pub const METHOD_UNKNOWN: i64 = 204;
+
+    /// Creates a protocol error.
+    pub fn protocol(message: &str) -> Self {
+        Self {
+            code: Self::PROTOCOL,
+            message: message.to_string(),
+        }
+    }
+
+    /// Encodes the error as the value of the `e` key.
+    pub fn to_bencode(&self) -> Bencode {
+        Bencode::List(vec![
+            Bencode::Int(self.code),
+            Bencode::Bytes(self.message.as_bytes().to_vec()),
+        ])
+    }
+}
+
+/// Issues and checks `announce_peer` tokens.
+struct TokenSecret {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl TokenSecret {
+    fn new(now: Instant) -> Self {
+        let secret = rand::rng().random();
+        Self {
+            current: secret,
+            previous: secret,
+            rotated: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= SECRET_ROTATION {
+            self.previous = self.current;
+            self.current = rand::rng().random();
+            self.rotated = now;
+        }
+    }
+
+    fn token(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(secret);
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn issue(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        Self::token(&self.current, ip)
+    }
+
+    fn is_valid(&mut self, token: &[u8], ip: IpAddr, now: Instant) -> bool {
+        self.rotate(now);
+        token == Self::token(&self.current, ip) || token == Self::token(&self.previous, ip)
+    }
+}
+
+/// Our side of the DHT: answers queries from the routing table and the peer store.
+pub(super) struct QueryServer {
+    my_id: [u8; 20],
+    secret: TokenSecret,
+    /// Peers announced to us.
+    pub store: PeerStore,
+}
+
+impl QueryServer {
+    /// Creates a server answering as node `my_id`.
+    pub fn new(my_id: [u8; 20]) -> Self {
+        Self {
+            my_id,
+            secret: TokenSecret::new(Instant::now()),
+            store: PeerStore::new(),
+        }
+    }
+
+    /// Answers a query.
+    ///
+    /// # Arguments
+    ///
+    /// * `q` - The query method name.
+    /// * `a` - The query arguments.
+    /// * `src` - The address the query came from.
+    /// * `table` - Our routing table, to answer with the closest nodes.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Dict, KrpcError>` - The body of the `r` response, or the error to send back.
+    pub fn answer(
+        &mut self,
+        q: &[u8],
+        a: &Dict,
+        src: SocketAddr,
+        table: &RoutingTable,
+        now: Instant,
+    ) -> Result<Dict, KrpcError> {
+        id_arg(a, b"id")?;
+
+        let mut r = Dict::new();
+        r.insert(b"id".to_vec(), Bencode::Bytes(self.my_id.to_vec()));
+        match q {
+            b"ping" => {}
+            b"find_node" => {
+                let target = id_arg(a, b"target")?;
+                insert_closest(&mut r, a, src, table, &target, now);
+            }
+            b"get_peers" => {
+                let info_hash = id_arg(a, b"info_hash")?;
+                let token = self.secret.issue(src.ip(), now);
+                r.insert(b"token".to_vec(), Bencode::Bytes(token));
+
+                // Peers of the querying node's family, which is the one it can connect to
+                let peers: Vec<SocketAddr> = self
+                    .store
+                    .peers(&info_hash, now)
+                    .into_iter()
+                    .filter(|peer| peer.is_ipv6() == src.is_ipv6())
+                    .collect();
+                let values = encode_values(&peers);
+                if !values.is_empty() {
+                    r.insert(b"values".to_vec(), Bencode::List(values));
+                }
+                insert_closest(&mut r, a, src, table, &info_hash, now);
+            }
+            b"announce_peer" => {
+                let info_hash = id_arg(a, b"info_hash")?;
+                let token = match a.get(&b"token"[..]) {
+                    Some(Bencode::Bytes(token)) => token,
+                    _ => return Err(KrpcError::protocol("Missing token")),
+                };
+                if !self.secret.is_valid(token, src.ip(), now) {
+                    return Err(KrpcError::protocol("Bad token"));
+                }
+                let implied_port =
+                    matches!(a.get(&b"implied_port"[..]), Some(Bencode::Int(i)) if *i != 0);
+                let port = match a.get(&b"port"[..]) {
+                    _ if implied_port => src.port(),
+                    Some(Bencode::Int(port)) if (1..=65535).contains(port) => *port as u16,
+                    _ => return Err(KrpcError::protocol("Invalid port")),
+                };
+                self.store
+                    .announce(info_hash, SocketAddr::new(src.ip(), port), now);
+            }
+            _ => {
+                return Err(KrpcError {
+                    code: KrpcError::METHOD_UNKNOWN,
+                    message: "Method Unknown".to_string(),
+                });
+            }
+        }
+        Ok(r)
+    }
+}
+
+/// Reads a 20-byte ID argument.
+fn id_arg(a: &Dict, key: &[u8]) -> Result<[u8; 20], KrpcError> {
+    match a.get(key) {
+        Some(Bencode::Bytes(b)) => <[u8; 20]>::try_from(b.as_slice())
+            .map_err(|_| KrpcError::protocol(&format!("Invalid {}", String::from_utf8_lossy(key)))),
+        _ => Err(KrpcError::protocol(&format!(
+            "Missing {}",
+            String::from_utf8_lossy(key)
+        ))),
+    }
+}
+
+/// Adds the nodes closest to `target` to a `find_node` or `get_peers` response, under
+/// `nodes` and `nodes6` for the families the querying node wants.
+fn insert_closest(
+    r: &mut Dict,
+    a: &Dict,
+    src: SocketAddr,
+    table: &RoutingTable,
+    target: &[u8; 20],
+    now: Instant,
+) {
+    let (want4, want6) = match a.get(&b"want"[..]) {
+        Some(Bencode::List(want)) => (
+            want.contains(&Bencode::Bytes(b"n4".to_vec())),
+            want.contains(&Bencode::Bytes(b"n6".to_vec())),
+        ),
+        _ => (src.is_ipv4(), src.is_ipv6()),
+    };
+    if want4 {
+        let closest = table.closest_by_family(target, K, now, false);
+        r.insert(
+            b"nodes".to_vec(),
+            Bencode::Bytes(encode_nodes(&closest, false)),
+        );
+    }
+    if want6 {
+        let closest = table.closest_by_family(target, K, now, true);
+        r.insert(
+            b"nodes6".to_vec(),
+            Bencode::Bytes(encode_nodes(&closest, true)),
+        );
+    }
+}
+
+/// Encodes the nodes of one family in compact node info format: the ID followed by the
+/// compact address, 26 bytes per IPv4 node and 38 bytes per IPv6 node.
+///
+/// Nodes of the other family are left out.
+pub fn encode_nodes(nodes: &[Node], ipv6: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    for node in nodes.iter().filter(|n| n.addr.is_ipv6() == ipv6) {
+        out.extend_from_slice(&node.id);
+        out.extend_from_slice(&compact::encode_peer(&node.addr));
+    }
+    out
+}
+
+/// Encodes peers as a list of compact peer info strings, 6 bytes per IPv4 peer and
+/// 18 bytes per IPv6 peer.
+pub fn encode_values(peers: &[SocketAddr]) -> Vec<Bencode> {
+    peers
+        .iter()
+        .map(|peer| Bencode::Bytes(compact::encode_peer(peer)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn args(pairs: &[(&[u8], Bencode)]) -> Dict {
+        pairs.iter().map(|(k, v)| (k.to_vec(), v.clone())).collect()
+    }
+
+    #[test]
+    fn test_get_peers_token_allows_announce() {
+        let mut server = QueryServer::new([0; 20]);
+        let table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        let src = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 7000));
+        let id = (&b"id"[..], Bencode::Bytes(vec![1; 20]));
+        let info_hash = (&b"info_hash"[..], Bencode::Bytes(vec![9; 20]));
+
+        let r = server
+            .answer(
+                b"get_peers",
+                &args(&[id.clone(), info_hash.clone()]),
+                src,
+                &table,
+                now,
+            )
+            .unwrap();
+        let Some(Bencode::Bytes(token)) = r.get(&b"token"[..]).cloned() else {
+            panic!("no token");
+        };
+        assert!(!r.contains_key(&b"values"[..]));
+
+        // A token is bound to the IP it was issued to
+        let announce = args(&[
+            id.clone(),
+            info_hash.clone(),
+            (b"port", Bencode::Int(6881)),
+            (b"token", Bencode::Bytes(token.clone())),
+        ]);
+        let other = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 7000));
+        let err = server
+            .answer(b"announce_peer", &announce, other, &table, now)
+            .unwrap_err();
+        assert_eq!(err.code, KrpcError::PROTOCOL);
+
+        // ...and outlives one rotation of the secret
+        let later = now + SECRET_ROTATION;
+        server
+            .answer(b"announce_peer", &announce, src, &table, later)
+            .unwrap();
+        let r = server
+            .answer(b"get_peers", &args(&[id, info_hash]), src, &table, later)
+            .unwrap();
+        assert_eq!(
+            r.get(&b"values"[..]),
+            Some(&Bencode::List(vec![Bencode::Bytes(vec![
+                10, 0, 0, 2, 0x1a, 0xe1
+            ])]))
+        );
+    }
+
+    #[test]
+    fn test_nodes_follow_want_or_the_querying_family() {
+        let mut server = QueryServer::new([0; 20]);
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        for (id, addr) in [(1, "10.0.0.1:6881"), (2, "[2001:db8::2]:6881")] {
+            let node = Node {
+                id: [id; 20],
+                addr: addr.parse().unwrap(),
+            };
+            table.heard_from(node, now);
+        }
+        let find = |want: Option<Vec<&[u8]>>| {
+            let mut a = args(&[
+                (b"id", Bencode::Bytes(vec![9; 20])),
+                (b"target", Bencode::Bytes(vec![1; 20])),
+            ]);
+            if let Some(want) = want {
+                let want = want.iter().map(|w| Bencode::Bytes(w.to_vec())).collect();
+                a.insert(b"want".to_vec(), Bencode::List(want));
+            }
+            a
+        };
+        let src4: SocketAddr = "10.0.0.9:7000".parse().unwrap();
+        let src6: SocketAddr = "[2001:db8::9]:7000".parse().unwrap();
+
+        let r = server
+            .answer(b"find_node", &find(None), src4, &table, now)
+            .unwrap();
+        assert!(matches!(r.get(&b"nodes"[..]), Some(Bencode::Bytes(b)) if b.len() == 26));
+        assert!(!r.contains_key(&b"nodes6"[..]));
+
+        let r = server
+            .answer(b"find_node", &find(None), src6, &table, now)
+            .unwrap();
+        assert!(!r.contains_key(&b"nodes"[..]));
+        assert!(matches!(r.get(&b"nodes6"[..]), Some(Bencode::Bytes(b)) if b.len() == 38));
+
+        let want = Some(vec![&b"n4"[..], &b"n6"[..]]);
+        let r = server
+            .answer(b"find_node", &find(want), src4, &table, now)
+            .unwrap();
+        assert!(r.contains_key(&b"nodes"[..]) && r.contains_key(&b"nodes6"[..]));
+    }
+
+    #[test]
+    fn test_malformed_queries_get_errors() {
+        let mut server = QueryServer::new([0; 20]);
+        let table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        let src = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7000));
+        let id = (&b"id"[..], Bencode::Bytes(vec![1; 20]));
+
+        let err = server
+            .answer(b"ping", &Dict::new(), src, &table, now)
+            .unwrap_err();
+        assert_eq!(err.code, KrpcError::PROTOCOL);
+        let err = server
+            .answer(
+                b"find_node",
+                &args(&[id.clone(), (b"target", Bencode::Bytes(vec![1; 3]))]),
+                src,
+                &table,
+                now,
+            )
+            .unwrap_err();
+        assert_eq!(err.message, "Invalid target");
+        let err = server
+            .answer(b"vote", &args(&[id]), src, &table, now)
+            .unwrap_err();
+        assert_eq!(err.code, KrpcError::METHOD_UNKNOWN);
+    }
+}
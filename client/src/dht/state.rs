@@ -0,0 +1,81 @@
+//! The DHT state file, kept between runs.
+//!
+//! It records our node ID and the nodes of the routing table, so that the next start
+//! can rejoin the network through them instead of the bootstrap routers. Other nodes
+//! know us by our ID, which is why it is kept as well.
+
+use super::Dht;
+use super::Node;
+use super::server::encode_nodes;
+use crate::storage::write_atomic;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tds_core::bencoding::{Bencode, decode};
+use tokio::fs;
+
+type StateResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// The contents of a DHT state file.
+#[derive(Debug, Clone)]
+pub struct DhtState {
+    /// Our node ID.
+    pub node_id: [u8; 20],
+    /// The nodes of the routing table, closest to us first.
+    pub nodes: Vec<Node>,
+}
+
+impl DhtState {
+    /// Encodes the state as a bencoded dictionary, with the nodes in compact node info
+    /// format: IPv4 nodes under `nodes` and IPv6 nodes under `nodes6`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"id".to_vec(), Bencode::Bytes(self.node_id.to_vec()));
+        dict.insert(
+            b"nodes".to_vec(),
+            Bencode::Bytes(encode_nodes(&self.nodes, false)),
+        );
+        dict.insert(
+            b"nodes6".to_vec(),
+            Bencode::Bytes(encode_nodes(&self.nodes, true)),
+        );
+        Bencode::Dict(dict).encode()
+    }
+
+    /// Decodes a state written by `encode`.
+    pub fn decode(buf: &[u8]) -> StateResult<Self> {
+        let Bencode::Dict(dict) = decode(buf, &mut 0)? else {
+            return Err("DHT state is not a dictionary".into());
+        };
+        let node_id = match dict.get(&b"id"[..]) {
+            Some(Bencode::Bytes(id)) => <[u8; 20]>::try_from(id.as_slice())?,
+            _ => return Err("DHT state has no node ID".into()),
+        };
+        let mut nodes = Vec::new();
+        if let Some(Bencode::Bytes(b)) = dict.get(&b"nodes"[..]) {
+            nodes.extend(Dht::parse_nodes(b, false));
+        }
+        if let Some(Bencode::Bytes(b)) = dict.get(&b"nodes6"[..]) {
+            nodes.extend(Dht::parse_nodes(b, true));
+        }
+        Ok(Self { node_id, nodes })
+    }
+}
+
+/// Writes a state file, with `write_atomic` so a crash never leaves it truncated.
+pub async fn save(path: &Path, state: &DhtState) -> StateResult<()> {
+    write_atomic(path, &state.encode()).await?;
+    Ok(())
+}
+
+/// Reads a state file.
+///
+/// # Returns
+///
+/// * `Ok(None)` - There is no state file yet.
+pub async fn load(path: &Path) -> StateResult<Option<DhtState>> {
+    match fs::read(path).await {
+        Ok(buf) => Ok(Some(DhtState::decode(&buf)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,119 @@
+//! Peers announced to us with `announce_peer`.
+//!
+//! The store is bounded so that a flood of announces cannot exhaust memory: it keeps
+//! at most `MAX_TORRENTS` info hashes and `MAX_PEERS_PER_TORRENT` peers for each, and
+//! forgets peers that did not announce again within `PEER_TTL`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of info hashes peers are stored for.
+pub const MAX_TORRENTS: usize = 2000;
+
+/// Maximum number of peers stored per info hash.
+pub const MAX_PEERS_PER_TORRENT: usize = 200;
+
+/// Maximum number of peers returned in one `get_peers` response, to keep it in one packet.
+pub const MAX_VALUES: usize = 50;
+
+/// How long an announce is kept. Peers re-announce well before this.
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Announced peers, by info hash.
+#[derive(Debug, Default)]
+pub(super) struct PeerStore {
+    /// Peers of each info hash with the time of their last announce, oldest first.
+    torrents: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+}
+
+impl PeerStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `peer` is in the swarm of `info_hash`.
+    ///
+    /// A peer announcing again is moved to the back. When the swarm is full its oldest
+    /// peer is dropped; when the store is full, announces for new info hashes are ignored.
+    pub fn announce(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: Instant) {
+        if !self.torrents.contains_key(&info_hash) {
+            if self.torrents.len() >= MAX_TORRENTS {
+                self.expire(now);
+            }
+            if self.torrents.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+
+        let peers = self.torrents.entry(info_hash).or_default();
+        peers.retain(|(addr, _)| *addr != peer);
+        if peers.len() >= MAX_PEERS_PER_TORRENT {
+            peers.remove(0);
+        }
+        peers.push((peer, now));
+    }
+
+    /// Returns up to `MAX_VALUES` of the most recently announced peers of `info_hash`.
+    pub fn peers(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        self.torrents
+            .get(info_hash)
+            .map(|peers| {
+                peers
+                    .iter()
+                    .rev()
+                    .filter(|(_, seen)| now.duration_since(*seen) < PEER_TTL)
+                    .map(|(addr, _)| *addr)
+                    .take(MAX_VALUES)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drops the peers older than `PEER_TTL`, and the info hashes left without peers.
+    pub fn expire(&mut self, now: Instant) {
+        self.torrents.retain(|_, peers| {
+            peers.retain(|(_, seen)| now.duration_since(*seen) < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn peer(i: u16) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), i))
+    }
+
+    #[test]
+    fn test_store_is_bounded_and_expires() {
+        let mut store = PeerStore::new();
+        let now = Instant::now();
+        for i in 0..(MAX_PEERS_PER_TORRENT as u16 + 10) {
+            store.announce([1; 20], peer(i), now);
+        }
+        store.announce([1; 20], peer(0), now);
+
+        let peers = store.peers(&[1; 20], now);
+        assert_eq!(peers.len(), MAX_VALUES);
+        assert_eq!(peers[0], peer(0));
+        assert_eq!(store.torrents[&[1; 20]].len(), MAX_PEERS_PER_TORRENT);
+
+        for i in 0..MAX_TORRENTS {
+            let mut info_hash = [0u8; 20];
+            info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            store.announce(info_hash, peer(1), now);
+        }
+        assert_eq!(store.torrents.len(), MAX_TORRENTS);
+        assert!(store.peers(&[0xff; 20], now).is_empty());
+
+        let later = now + PEER_TTL;
+        assert!(store.peers(&[1; 20], later).is_empty());
+        store.announce([0xff; 20], peer(1), later);
+        assert_eq!(store.torrents.len(), 1);
+    }
+}
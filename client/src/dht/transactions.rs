@@ -0,0 +1,238 @@
+//! Outstanding queries, by transaction ID.
+//!
+//! Every query we send is recorded with its method, the node it went to and a
+//! deadline. A response is only accepted if it echoes a known transaction ID, comes
+//! from the address the query went to and carries the expected node ID; anything else
+//! is a stray or spoofed packet. Queries past their deadline are handed back so that
+//! the node can be marked as failed.
+
+use super::Node;
+use super::lookup::Reply;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// Most queries outstanding at once. The 2-byte transaction IDs allow 65,536; keeping
+/// well below that, a free ID is found in a few random draws.
+pub(super) const MAX_PENDING: usize = 4096;
+
+/// The KRPC query methods we send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum QueryKind {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+}
+
+impl QueryKind {
+    /// The method name sent in the `q` key.
+    pub fn method(self) -> &'static [u8] {
+        match self {
+            QueryKind::Ping => b"ping",
+            QueryKind::FindNode => b"find_node",
+            QueryKind::GetPeers => b"get_peers",
+            QueryKind::AnnouncePeer => b"announce_peer",
+        }
+    }
+}
+
+/// A query waiting for its response.
+#[derive(Debug)]
+pub(super) struct Pending {
+    pub kind: QueryKind,
+    /// Where the query went.
+    pub addr: SocketAddr,
+    /// The ID of the queried node, unless it is a bootstrap router we know only by address.
+    pub node_id: Option<[u8; 20]>,
+    /// When the query counts as unanswered.
+    pub deadline: Instant,
+    /// The lookup to hand the response to, for `get_peers`.
+    pub lookup: Option<mpsc::UnboundedSender<Reply>>,
+}
+
+/// Why a response was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Rejected {
+    /// No query with this transaction ID is outstanding; the packet is ignored.
+    Unknown,
+    /// The response came from another address than the query went to. The query stays
+    /// outstanding, as the real node may still answer.
+    WrongAddress,
+    /// The response carries another node ID than the one we queried.
+    WrongId,
+}
+
+/// The queries we are waiting on.
+#[derive(Debug, Default)]
+pub(super) struct Transactions {
+    pending: HashMap<[u8; 2], Pending>,
+}
+
+impl Transactions {
+    /// Creates an empty set of transactions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a query and returns its transaction ID, unique among outstanding queries.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<[u8; 2]>` - The transaction ID, or `None` if `MAX_PENDING` queries are
+    ///   already outstanding. The query is then not to be sent.
+    pub fn register(&mut self, pending: Pending) -> Option<[u8; 2]> {
+        if self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        let t = loop {
+            let t: [u8; 2] = rand::rng().random();
+            if !self.pending.contains_key(&t) {
+                break t;
+            }
+        };
+        self.pending.insert(t, pending);
+        Some(t)
+    }
+
+    /// Matches a response to its query.
+    ///
+    /// # Arguments
+    ///
+    /// * `t` - The transaction ID of the response.
+    /// * `from` - The sender of the response, with the ID from its body.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Pending, Rejected>` - The query answered, which is no longer outstanding.
+    pub fn answer(&mut self, t: &[u8], from: &Node) -> Result<Pending, Rejected> {
+        let t: [u8; 2] = t.try_into().map_err(|_| Rejected::Unknown)?;
+        let pending = self.pending.get(&t).ok_or(Rejected::Unknown)?;
+        if pending.addr != from.addr {
+            return Err(Rejected::WrongAddress);
+        }
+        let id_matches = pending.node_id.is_none_or(|id| id == from.id);
+        let pending = self.pending.remove(&t).unwrap();
+        if id_matches {
+            Ok(pending)
+        } else {
+            Err(Rejected::WrongId)
+        }
+    }
+
+    /// Removes the query answered with an error by the node at `from`.
+    pub fn error(&mut self, t: &[u8], from: SocketAddr) -> Option<Pending> {
+        let t: [u8; 2] = t.try_into().ok()?;
+        if self.pending.get(&t)?.addr != from {
+            return None;
+        }
+        self.pending.remove(&t)
+    }
+
+    /// Removes and returns every query past its deadline.
+    pub fn expire(&mut self, now: Instant) -> Vec<Pending> {
+        let expired: Vec<[u8; 2]> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(t, _)| *t)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|t| self.pending.remove(t))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn node(id: u8, port: u16) -> Node {
+        Node {
+            id: [id; 20],
+            addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+        }
+    }
+
+    fn pending(to: &Node, deadline: Instant) -> Pending {
+        Pending {
+            kind: QueryKind::FindNode,
+            addr: to.addr,
+            node_id: Some(to.id),
+            deadline,
+            lookup: None,
+        }
+    }
+
+    #[test]
+    fn test_responses_are_validated_against_the_query() {
+        let now = Instant::now();
+        let mut transactions = Transactions::new();
+        let target = node(1, 1000);
+        let t = transactions.register(pending(&target, now)).unwrap();
+
+        assert_eq!(
+            transactions.answer(b"??", &target).unwrap_err(),
+            Rejected::Unknown
+        );
+        // A spoofed response leaves the query outstanding for the real node
+        assert_eq!(
+            transactions.answer(&t, &node(1, 1001)).unwrap_err(),
+            Rejected::WrongAddress
+        );
+        let answered = transactions.answer(&t, &target).unwrap();
+        assert_eq!(answered.kind, QueryKind::FindNode);
+        assert_eq!(
+            transactions.answer(&t, &target).unwrap_err(),
+            Rejected::Unknown
+        );
+
+        let t = transactions.register(pending(&target, now)).unwrap();
+        assert_eq!(
+            transactions.answer(&t, &node(2, 1000)).unwrap_err(),
+            Rejected::WrongId
+        );
+    }
+
+    #[test]
+    fn test_expire_returns_overdue_queries() {
+        let now = Instant::now();
+        let mut transactions = Transactions::new();
+        transactions.register(pending(&node(1, 1000), now));
+        transactions.register(pending(&node(2, 1001), now + Duration::from_secs(10)));
+
+        let expired = transactions.expire(now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].node_id, Some([1; 20]));
+        assert!(transactions.expire(now).is_empty());
+    }
+
+    #[test]
+    fn test_register_refuses_queries_past_the_cap() {
+        let now = Instant::now();
+        let mut transactions = Transactions::new();
+        for _ in 0..MAX_PENDING {
+            assert!(
+                transactions
+                    .register(pending(&node(1, 1000), now))
+                    .is_some()
+            );
+        }
+        assert!(
+            transactions
+                .register(pending(&node(2, 1001), now))
+                .is_none()
+        );
+
+        transactions.expire(now);
+        assert!(
+            transactions
+                .register(pending(&node(2, 1001), now))
+                .is_some()
+        );
+    }
+}
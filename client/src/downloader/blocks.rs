@@ -0,0 +1,428 @@
+//! Block-level bookkeeping for pieces that are being downloaded.
+//!
+//! Pieces are split into 16 KiB blocks. Every in-progress piece keeps a buffer
+//! and the state of each of its blocks, so several peers can work on the same
+//! piece at once and a slow peer only ever holds the blocks it was asked for.
+//!
+//! Once every wanted block has been requested, the download enters end-game mode:
+//! blocks that are still outstanding may be requested from several peers at once,
+//! so the last pieces do not wait on a single slow peer.
+
+use super::picker::{PiecePicker, PiecePriority};
+use super::state::PieceStatus;
+use crate::storage::FileLayout;
+use std::collections::HashMap;
+
+/// The size of a block request in bytes (16 KiB).
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// A single block request, as sent in a `Request` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    /// The zero-based piece index.
+    pub index: u32,
+    /// The byte offset within the piece.
+    pub begin: u32,
+    /// The length of the block.
+    pub length: u32,
+}
+
+/// What happened to a block handed to `BlockTracker::block_received`.
+#[derive(Debug, PartialEq)]
+pub enum BlockOutcome {
+    /// The block was not expected (unknown piece, bad offset or already received).
+    Ignored,
+    /// The block was stored; the piece still has missing blocks.
+    Stored,
+    /// The block completed its piece. Contains the assembled, unverified piece data.
+    PieceComplete(Vec<u8>),
+}
+
+/// The state of one block of an in-progress piece.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    Missing,
+    /// Requested from the given number of peers.
+    Requested(u32),
+    Received,
+}
+
+/// A piece that has at least one block requested or received.
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+    received: usize,
+}
+
+/// Tracks the blocks of all in-progress pieces, shared by every peer task.
+pub struct BlockTracker {
+    layout: FileLayout,
+    partial: HashMap<usize, PartialPiece>,
+}
+
+impl BlockTracker {
+    /// Creates a tracker for a torrent laid out as `layout`.
+    pub fn new(layout: FileLayout) -> Self {
+        Self {
+            layout,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Returns the layout of the torrent, which gives the length of every piece.
+    pub fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    /// Picks the next block to request from a peer.
+    ///
+    /// Blocks of pieces that are already in progress are preferred, so partially
+    /// downloaded pieces get finished before new ones are started. Otherwise the
+    /// picker chooses a missing piece that the peer has, which is then marked `InProgress`.
+    ///
+    /// # Arguments
+    ///
+    /// * `piece_status` - The download status of every piece.
+    /// * `picker` - Chooses which new piece to start.
+    /// * `has_piece` - Whether the peer has the piece at the given index.
+    pub fn next_request(
+        &mut self,
+        piece_status: &mut [PieceStatus],
+        picker: &PiecePicker,
+        has_piece: impl Fn(usize) -> bool,
+    ) -> Option<BlockRequest> {
+        let mut in_progress: Vec<usize> = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|&i| has_piece(i))
+            .collect();
+        in_progress.sort_unstable();
+        for index in in_progress {
+            if let Some(req) = self.request_missing_block(index) {
+                return Some(req);
+            }
+        }
+
+        let index = picker.pick(piece_status, has_piece)?;
+        piece_status[index] = PieceStatus::InProgress;
+        self.start_piece(index);
+        self.request_missing_block(index)
+    }
+
+    /// Allocates the buffer and block states for a newly started piece.
+    fn start_piece(&mut self, index: usize) {
+        let len = self.layout.piece_len(index);
+        let block_count = len.div_ceil(BLOCK_SIZE as u64) as usize;
+        self.partial.insert(
+            index,
+            PartialPiece {
+                data: vec![0u8; len as usize],
+                blocks: vec![BlockState::Missing; block_count],
+                received: 0,
+            },
+        );
+    }
+
+    /// Marks the first missing block of `index` as requested and returns it.
+    fn request_missing_block(&mut self, index: usize) -> Option<BlockRequest> {
+        let piece_len = self.layout.piece_len(index);
+        let piece = self.partial.get_mut(&index)?;
+        let block = piece
+            .blocks
+            .iter()
+            .position(|&b| b == BlockState::Missing)?;
+        piece.blocks[block] = BlockState::Requested(1);
+        let begin = block as u64 * BLOCK_SIZE as u64;
+        let length = (piece_len - begin).min(BLOCK_SIZE as u64);
+        Some(BlockRequest {
+            index: index as u32,
+            begin: begin as u32,
+            length: length as u32,
+        })
+    }
+
+    /// Returns `true` when the download is in end-game mode.
+    ///
+    /// That is the case once no wanted piece is left to start and every block of the
+    /// in-progress pieces has been requested or received. Pieces that are skipped or
+    /// that no connected peer has do not hold back the end game.
+    pub fn is_endgame(&self, piece_status: &[PieceStatus], picker: &PiecePicker) -> bool {
+        let all_started = piece_status.iter().enumerate().all(|(i, &s)| {
+            s != PieceStatus::Missing
+                || picker.priority(i) == PiecePriority::Skip
+                || picker.availability(i) == 0
+        });
+        all_started
+            && !self.partial.is_empty()
+            && self
+                .partial
+                .values()
+                .all(|p| !p.blocks.contains(&BlockState::Missing))
+    }
+
+    /// Picks a block that is already requested from another peer, for end-game mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `has_piece` - Whether the peer has the piece at the given index.
+    /// * `already_requested` - Whether this peer already has the block requested.
+    pub fn next_endgame_request(
+        &mut self,
+        has_piece: impl Fn(usize) -> bool,
+        already_requested: impl Fn(&BlockRequest) -> bool,
+    ) -> Option<BlockRequest> {
+        let mut indices: Vec<usize> = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|&i| has_piece(i))
+            .collect();
+        indices.sort_unstable();
+
+        for index in indices {
+            let piece_len = self.layout.piece_len(index);
+            let piece = self.partial.get_mut(&index)?;
+            for (block, state) in piece.blocks.iter_mut().enumerate() {
+                let BlockState::Requested(count) = state else {
+                    continue;
+                };
+                let begin = block as u64 * BLOCK_SIZE as u64;
+                let req = BlockRequest {
+                    index: index as u32,
+                    begin: begin as u32,
+                    length: (piece_len - begin).min(BLOCK_SIZE as u64) as u32,
+                };
+                if !already_requested(&req) {
+                    *count += 1;
+                    return Some(req);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns how many peers the block at `begin` of piece `index` is requested from.
+    pub fn requesters(&self, index: u32, begin: u32) -> u32 {
+        let block = (begin / BLOCK_SIZE) as usize;
+        match self
+            .partial
+            .get(&(index as usize))
+            .and_then(|p| p.blocks.get(block))
+        {
+            Some(BlockState::Requested(count)) => *count,
+            _ => 0,
+        }
+    }
+
+    /// Stores a received block.
+    ///
+    /// A block that is not exactly the one we request at `begin`, being shorter or
+    /// longer, is ignored. When the block completes its piece, the piece is removed
+    /// from the tracker and its data returned so the caller can verify and write it.
+    pub fn block_received(&mut self, index: u32, begin: u32, data: &[u8]) -> BlockOutcome {
+        let index = index as usize;
+        let Some(piece) = self.partial.get_mut(&index) else {
+            return BlockOutcome::Ignored;
+        };
+        if !begin.is_multiple_of(BLOCK_SIZE) {
+            return BlockOutcome::Ignored;
+        }
+        let block = (begin / BLOCK_SIZE) as usize;
+        let begin = begin as usize;
+        if block >= piece.blocks.len()
+            || piece.blocks[block] == BlockState::Received
+            || data.len() != (piece.data.len() - begin).min(BLOCK_SIZE as usize)
+        {
+            return BlockOutcome::Ignored;
+        }
+
+        piece.data[begin..begin + data.len()].copy_from_slice(data);
+        piece.blocks[block] = BlockState::Received;
+        piece.received += 1;
+
+        if piece.received == piece.blocks.len() {
+            let piece = self.partial.remove(&index).unwrap();
+            BlockOutcome::PieceComplete(piece.data)
+        } else {
+            BlockOutcome::Stored
+        }
+    }
+
+    /// Returns a requested block to the pool so another peer can request it.
+    ///
+    /// Used when a peer chokes us or disconnects with requests outstanding. In end-game
+    /// mode the block stays requested while other peers still have it outstanding.
+    pub fn release(&mut self, req: &BlockRequest) {
+        if let Some(piece) = self.partial.get_mut(&(req.index as usize)) {
+            let block = (req.begin / BLOCK_SIZE) as usize;
+            if let Some(state) = piece.blocks.get_mut(block) {
+                *state = match *state {
+                    BlockState::Requested(count) if count > 1 => BlockState::Requested(count - 1),
+                    BlockState::Requested(_) => BlockState::Missing,
+                    other => other,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Returns a tracker for `piece_count` pieces of `piece_length` bytes, `total_length` in all.
+    fn tracker(piece_length: u64, total_length: u64, piece_count: usize) -> BlockTracker {
+        BlockTracker::new(FileLayout {
+            root: PathBuf::new(),
+            files: Vec::new(),
+            piece_length,
+            piece_count,
+            total_length,
+        })
+    }
+
+    #[test]
+    fn test_pieces_are_split_into_blocks() {
+        // Two pieces of 40 KiB, the last one 20 KiB
+        let mut tracker = tracker(40 * 1024, 60 * 1024, 2);
+        let picker = PiecePicker::new(2);
+        let mut status = vec![PieceStatus::Missing, PieceStatus::Have];
+
+        let reqs: Vec<_> = (0..4)
+            .map(|_| tracker.next_request(&mut status, &picker, |_| true))
+            .collect();
+        assert_eq!(status[0], PieceStatus::InProgress);
+        assert_eq!(
+            reqs[0],
+            Some(BlockRequest {
+                index: 0,
+                begin: 0,
+                length: BLOCK_SIZE
+            })
+        );
+        assert_eq!(reqs[2].unwrap().length, 8 * 1024);
+        assert_eq!(reqs[3], None);
+    }
+
+    #[test]
+    fn test_blocks_shared_between_peers() {
+        let mut tracker = tracker(32 * 1024, 32 * 1024, 1);
+        let picker = PiecePicker::new(1);
+        let mut status = vec![PieceStatus::Missing];
+
+        let slow = tracker
+            .next_request(&mut status, &picker, |_| true)
+            .unwrap();
+        let fast = tracker
+            .next_request(&mut status, &picker, |_| true)
+            .unwrap();
+        assert_ne!(slow.begin, fast.begin);
+
+        // The slow peer disconnects; its block becomes available again.
+        tracker.release(&slow);
+        let retry = tracker
+            .next_request(&mut status, &picker, |_| true)
+            .unwrap();
+        assert_eq!(retry, slow);
+
+        let block = vec![1u8; BLOCK_SIZE as usize];
+        assert_eq!(
+            tracker.block_received(0, fast.begin, &block),
+            BlockOutcome::Stored
+        );
+        assert_eq!(
+            tracker.block_received(0, fast.begin, &block),
+            BlockOutcome::Ignored
+        );
+        match tracker.block_received(0, retry.begin, &block) {
+            BlockOutcome::PieceComplete(data) => assert_eq!(data.len(), 32 * 1024),
+            other => panic!("Expected complete piece, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_blocks_of_the_wrong_length_are_ignored() {
+        // One piece of 24 KiB: a full block and a final 8 KiB one
+        let mut tracker = tracker(24 * 1024, 24 * 1024, 1);
+        let picker = PiecePicker::new(1);
+        let mut status = vec![PieceStatus::Missing];
+        let first = tracker
+            .next_request(&mut status, &picker, |_| true)
+            .unwrap();
+        let last = tracker
+            .next_request(&mut status, &picker, |_| true)
+            .unwrap();
+
+        let short = vec![1u8; BLOCK_SIZE as usize - 1];
+        assert_eq!(
+            tracker.block_received(0, first.begin, &short),
+            BlockOutcome::Ignored
+        );
+        let long = vec![1u8; BLOCK_SIZE as usize];
+        assert_eq!(
+            tracker.block_received(0, last.begin, &long),
+            BlockOutcome::Ignored
+        );
+
+        let block = vec![1u8; first.length as usize];
+        assert_eq!(
+            tracker.block_received(0, first.begin, &block),
+            BlockOutcome::Stored
+        );
+        let block = vec![1u8; last.length as usize];
+        match tracker.block_received(0, last.begin, &block) {
+            BlockOutcome::PieceComplete(data) => assert_eq!(data.len(), 24 * 1024),
+            other => panic!("Expected complete piece, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_only_requests_pieces_the_peer_has() {
+        let mut tracker = tracker(BLOCK_SIZE as u64, 2 * BLOCK_SIZE as u64, 2);
+        let picker = PiecePicker::new(2);
+        let mut status = vec![PieceStatus::Missing; 2];
+        let req = tracker
+            .next_request(&mut status, &picker, |i| i == 1)
+            .unwrap();
+        assert_eq!(req.index, 1);
+        assert_eq!(tracker.next_request(&mut status, &picker, |i| i == 1), None);
+    }
+
+    #[test]
+    fn test_endgame_requests_outstanding_blocks_again() {
+        let mut tracker = tracker(32 * 1024, 32 * 1024, 1);
+        let mut picker = PiecePicker::new(1);
+        picker.add_piece(0);
+        let mut status = vec![PieceStatus::Missing];
+
+        let slow: Vec<_> = (0..2)
+            .map(|_| {
+                tracker
+                    .next_request(&mut status, &picker, |_| true)
+                    .unwrap()
+            })
+            .collect();
+        assert!(tracker.is_endgame(&status, &picker));
+        assert_eq!(tracker.next_request(&mut status, &picker, |_| true), None);
+
+        // A second peer duplicates both outstanding blocks, then has nothing left to ask for
+        let fast = tracker.next_endgame_request(|_| true, |_| false).unwrap();
+        assert_eq!(fast, slow[0]);
+        assert_eq!(tracker.requesters(0, fast.begin), 2);
+        assert_eq!(
+            tracker.next_endgame_request(|_| true, |r| *r == fast),
+            Some(slow[1])
+        );
+        assert_eq!(
+            tracker.next_endgame_request(|_| true, |r| slow.contains(r)),
+            None
+        );
+
+        // The slow peer goes away; the fast peer still holds the block
+        tracker.release(&slow[0]);
+        assert_eq!(tracker.requesters(0, fast.begin), 1);
+        assert!(tracker.is_endgame(&status, &picker));
+    }
+}
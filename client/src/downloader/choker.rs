@@ -0,0 +1,204 @@
+//! Choking: deciding which peers may download from us.
+//!
+//! Every round, `RECHOKE_INTERVAL` apart by default, the interested peers that gave
+//! us the most data (or, while seeding, took the most from us) are unchoked; everyone
+//! else is choked. One more interested peer is unchoked at random and kept for three
+//! rounds, `OPTIMISTIC_UNCHOKE_INTERVAL` by default, so newcomers get a chance to
+//! prove themselves.
+
+use rand::seq::IndexedRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How often the unchoked peers are chosen again, unless the torrent's configuration
+/// says otherwise.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long an optimistic unchoke lasts before another peer gets the slot.
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Transfer counters of a connected peer, updated by its task and read by the choker.
+#[derive(Default)]
+pub(super) struct PeerStats {
+    /// Block bytes received from the peer.
+    pub downloaded: AtomicU64,
+    /// Block bytes sent to the peer.
+    pub uploaded: AtomicU64,
+    /// Whether the peer is interested in our pieces.
+    pub interested: AtomicBool,
+}
+
+/// A peer known to the choker.
+struct ChokerPeer {
+    stats: Arc<PeerStats>,
+    /// `true` while the peer should be choked.
+    choke_tx: watch::Sender<bool>,
+    /// Counters at the previous round, to compute rates from.
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+/// Chooses which peers of a torrent are unchoked.
+pub(super) struct Choker {
+    /// Number of peers unchoked for their rate, not counting the optimistic unchoke.
+    slots: usize,
+    peers: HashMap<SocketAddr, ChokerPeer>,
+    optimistic: Option<SocketAddr>,
+    /// Rounds run so far; the optimistic unchoke rotates every few rounds.
+    rounds: u64,
+}
+
+impl Choker {
+    /// Creates a choker that unchokes the best `slots` peers plus one optimistic unchoke.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            peers: HashMap::new(),
+            optimistic: None,
+            rounds: 0,
+        }
+    }
+
+    /// Adds a connected peer. It starts out choked.
+    ///
+    /// # Returns
+    ///
+    /// * The counters the peer's task must keep up to date.
+    /// * A receiver telling the task whether the peer should be choked.
+    pub fn register(&mut self, addr: SocketAddr) -> (Arc<PeerStats>, watch::Receiver<bool>) {
+        let stats = Arc::new(PeerStats::default());
+        let (choke_tx, choke_rx) = watch::channel(true);
+        self.peers.insert(
+            addr,
+            ChokerPeer {
+                stats: stats.clone(),
+                choke_tx,
+                last_downloaded: 0,
+                last_uploaded: 0,
+            },
+        );
+        (stats, choke_rx)
+    }
+
+    /// Removes a disconnected peer.
+    pub fn unregister(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        if self.optimistic.as_ref() == Some(addr) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Runs one choking round, every rechoke interval.
+    ///
+    /// # Arguments
+    ///
+    /// * `seeding` - Whether we have every piece. Peers are then ranked by how fast
+    ///   they download from us rather than how fast we download from them.
+    pub fn rechoke(&mut self, seeding: bool) {
+        let mut rates = Vec::new();
+        for (addr, peer) in self.peers.iter_mut() {
+            let downloaded = peer.stats.downloaded.load(Ordering::Relaxed);
+            let uploaded = peer.stats.uploaded.load(Ordering::Relaxed);
+            let rate = if seeding {
+                uploaded - peer.last_uploaded
+            } else {
+                downloaded - peer.last_downloaded
+            };
+            peer.last_downloaded = downloaded;
+            peer.last_uploaded = uploaded;
+            if peer.stats.interested.load(Ordering::Relaxed) {
+                rates.push((*addr, rate));
+            }
+        }
+
+        // Fastest first
+        rates.sort_by_key(|&(_, rate)| std::cmp::Reverse(rate));
+        let mut unchoked: Vec<SocketAddr> =
+            rates.iter().take(self.slots).map(|&(a, _)| a).collect();
+
+        let rotate = self
+            .rounds
+            .is_multiple_of(OPTIMISTIC_UNCHOKE_INTERVAL.as_secs() / RECHOKE_INTERVAL.as_secs());
+        let current = self
+            .optimistic
+            .filter(|addr| !rotate && rates.iter().any(|(a, _)| a == addr));
+        self.optimistic = current.or_else(|| {
+            let others: Vec<SocketAddr> = rates
+                .iter()
+                .map(|&(a, _)| a)
+                .filter(|a| !unchoked.contains(a))
+                .collect();
+            others.choose(&mut rand::rng()).copied()
+        });
+        unchoked.extend(self.optimistic);
+        self.rounds += 1;
+
+        for (addr, peer) in &self.peers {
+            let choke = !unchoked.contains(addr);
+            peer.choke_tx
+                .send_if_modified(|c| std::mem::replace(c, choke) != choke);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(i: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, i).into(), 6881)
+    }
+
+    #[test]
+    fn test_unchokes_fastest_interested_peers_plus_one() {
+        let mut choker = Choker::new(2);
+        let mut peers = Vec::new();
+        for i in 1..=5 {
+            let (stats, rx) = choker.register(addr(i));
+            stats.interested.store(i != 5, Ordering::Relaxed);
+            stats.downloaded.store(i as u64 * 1000, Ordering::Relaxed);
+            peers.push(rx);
+        }
+
+        choker.rechoke(false);
+        let unchoked: Vec<bool> = peers.iter().map(|rx| !*rx.borrow()).collect();
+        // Peers 4 and 3 are fastest; one of 1 and 2 is unchoked optimistically;
+        // peer 5 is not interested.
+        assert!(unchoked[3] && unchoked[2]);
+        assert!(unchoked[0] ^ unchoked[1]);
+        assert!(!unchoked[4]);
+
+        // While seeding only the upload rate counts
+        let (stats, rx) = choker.register(addr(6));
+        stats.interested.store(true, Ordering::Relaxed);
+        stats.uploaded.store(50_000, Ordering::Relaxed);
+        choker.rechoke(true);
+        assert!(!*rx.borrow());
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_is_kept_between_rotations() {
+        let mut choker = Choker::new(0);
+        for i in 1..=10 {
+            let (stats, _) = choker.register(addr(i));
+            stats.interested.store(true, Ordering::Relaxed);
+        }
+
+        choker.rechoke(false);
+        let first = choker.optimistic;
+        assert!(first.is_some());
+        choker.rechoke(false);
+        choker.rechoke(false);
+        assert_eq!(choker.optimistic, first);
+
+        choker.unregister(&first.unwrap());
+        choker.rechoke(false);
+        assert!(choker.optimistic.is_some());
+        assert_ne!(choker.optimistic, first);
+    }
+}
@@ -0,0 +1,51 @@
+use super::choker::RECHOKE_INTERVAL;
+use std::time::Duration;
+
+/// Tunable settings of a `Downloader`.
+///
+/// A default configuration is created by `Downloader::new` and `Downloader::from_torrent`;
+/// adjust the public fields before calling `run`.
+#[derive(Debug, Clone)]
+pub struct DownloaderConfig {
+    /// Maximum number of 16 KiB block requests kept in flight per peer.
+    pub pipeline_depth: usize,
+    /// How long a peer may leave a block request unanswered. Its requests are then
+    /// handed to other peers, and it is sent one request at a time until it delivers.
+    pub request_timeout: Duration,
+    /// Number of peers unchoked for their transfer rate, besides the optimistic unchoke.
+    pub unchoke_slots: usize,
+    /// How often the unchoked peers are chosen again. The optimistic unchoke moves on
+    /// every third round.
+    pub rechoke_interval: Duration,
+    /// Maximum download rate of this torrent in bytes per second, or `None` for no limit.
+    ///
+    /// The session-wide limit applies as well.
+    pub download_rate_limit: Option<f64>,
+    /// Maximum upload rate of this torrent in bytes per second, or `None` for no limit.
+    ///
+    /// The session-wide limit applies as well.
+    pub upload_rate_limit: Option<f64>,
+}
+
+impl Default for DownloaderConfig {
+    fn default() -> Self {
+        Self {
+            pipeline_depth: 16,
+            request_timeout: Duration::from_secs(60),
+            unchoke_slots: 4,
+            rechoke_interval: RECHOKE_INTERVAL,
+            download_rate_limit: None,
+            upload_rate_limit: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_pipeline_depth() {
+        assert_eq!(DownloaderConfig::default().pipeline_depth, 16);
+    }
+}
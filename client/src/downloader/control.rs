@@ -0,0 +1,94 @@
+use tokio::sync::watch;
+
+/// The requested state of a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadState {
+    /// Downloading and uploading normally.
+    Running,
+    /// Connected to peers, but choking all of them and requesting nothing.
+    Paused,
+    /// Shutting down: peers are disconnected, trackers told we stopped and state saved.
+    Stopped,
+}
+
+/// Controls a running `Downloader` from another task.
+///
+/// Handles are cheap to clone; all clones control the same download.
+/// Obtain one with `Downloader::handle`.
+#[derive(Clone)]
+pub struct DownloadHandle {
+    tx: watch::Sender<DownloadState>,
+}
+
+impl DownloadHandle {
+    pub(super) fn new(tx: watch::Sender<DownloadState>) -> Self {
+        Self { tx }
+    }
+
+    /// Returns the current state of the download.
+    pub fn state(&self) -> DownloadState {
+        *self.tx.borrow()
+    }
+
+    /// Pauses a running download. Has no effect once the download is stopped.
+    pub fn pause(&self) {
+        self.transition(DownloadState::Running, DownloadState::Paused);
+    }
+
+    /// Resumes a paused download. Has no effect once the download is stopped.
+    pub fn resume(&self) {
+        self.transition(DownloadState::Paused, DownloadState::Running);
+    }
+
+    /// Stops the download. `Downloader::run` returns once shutdown is complete.
+    ///
+    /// A stopped download cannot be started again; running it returns immediately.
+    pub fn stop(&self) {
+        self.tx.send_replace(DownloadState::Stopped);
+    }
+
+    /// Returns a receiver that observes state changes.
+    pub(super) fn subscribe(&self) -> watch::Receiver<DownloadState> {
+        self.tx.subscribe()
+    }
+
+    fn transition(&self, from: DownloadState, to: DownloadState) {
+        self.tx.send_if_modified(|state| {
+            if *state == from {
+                *state = to;
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+/// Resolves once the watched value satisfies `f` (or its sender is gone).
+pub(super) async fn wait_until<T>(rx: &mut watch::Receiver<T>, f: impl FnMut(&T) -> bool) {
+    // The returned guard is not `Send`, so it must not be held by a `select!`
+    let _ = rx.wait_for(f).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        let (tx, rx) = watch::channel(DownloadState::Running);
+        let handle = DownloadHandle::new(tx);
+
+        handle.resume();
+        assert_eq!(handle.state(), DownloadState::Running);
+        handle.pause();
+        assert_eq!(*rx.borrow(), DownloadState::Paused);
+        handle.resume();
+        assert_eq!(handle.state(), DownloadState::Running);
+
+        handle.stop();
+        handle.resume();
+        handle.pause();
+        assert_eq!(handle.state(), DownloadState::Stopped);
+    }
+}
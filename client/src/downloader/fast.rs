@@ -0,0 +1,77 @@
+//! The Fast extension (BEP 6).
+//!
+//! Each peer is offered an allowed fast set: a few pieces, derived from its address
+//! and the info hash, that it may download from us even while choked. A new peer can
+//! thus get its first pieces, and something to trade, before the choker picks it.
+
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+/// Number of pieces in the allowed fast set we offer a peer.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Maximum number of pieces we remember from a peer's `AllowedFast` or `Suggest`
+/// messages, so a peer cannot make us keep an unbounded list.
+pub const MAX_FAST_PIECES: usize = 32;
+
+/// Computes the allowed fast set of a peer, with the canonical algorithm of BEP 6.
+///
+/// # Arguments
+///
+/// * `ip` - The peer's address. Only IPv4 is defined; IPv6 peers get no set.
+/// * `info_hash` - The info hash of the torrent.
+/// * `piece_count` - The number of pieces of the torrent.
+/// * `count` - The size of the set; it is capped at `piece_count`.
+///
+/// # Returns
+///
+/// * `Vec<u32>` - The piece indices, in the order the algorithm produces them.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8; 20],
+    piece_count: u32,
+    count: usize,
+) -> Vec<u32> {
+    let IpAddr::V4(ip) = ip.to_canonical() else {
+        return Vec::new();
+    };
+    let count = count.min(piece_count as usize);
+
+    // Peers of the same /24 network get the same set
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    let mut set = Vec::with_capacity(count);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % piece_count;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_fast_set_matches_bep_6() {
+        let ip: IpAddr = "80.4.4.200".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 3, 10).len(), 3);
+        assert!(allowed_fast_set("::1".parse().unwrap(), &[0xaa; 20], 1313, 7).is_empty());
+    }
+}
@@ -1,12 +1,15 @@
+use super::config::DownloaderConfig;
+use super::control::{DownloadHandle, DownloadState};
+use super::picker::PiecePicker;
+use super::resume;
 use super::state::{Downloader, PieceStatus};
-use crate::storage::Storage;
+use crate::storage::{Storage, TorrentFiles};
 use rand::Rng;
 use sha1::{Digest, Sha1};
-use std::io::SeekFrom;
+use std::collections::HashSet;
 use std::sync::Arc;
 use tds_core::Torrent;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, watch};
 
 /// Initializes a `Downloader` instance from a parsed `Torrent`.
 ///
@@ -14,8 +17,9 @@ use tokio::sync::Mutex;
 /// 1. Initializes the `Storage` for the download directory.
 /// 2. Generates a random Peer ID.
 /// 3. Calculates the total length of the torrent content.
-/// 4. Opens (or creates) the target file(s) for writing.
-/// 5. Pre-allocates the file size if necessary.
+/// 4. Opens (or creates) the target file(s) for writing, laid out under
+///    `<download_dir>/<name>/...` for multi-file torrents.
+/// 5. Pre-allocates the file sizes if necessary.
 /// 6. Initializes the piece status vector to `Missing`.
 ///
 /// # Arguments
@@ -48,35 +52,16 @@ pub async fn from_torrent(
         id
     };
 
-    let total_length = if let Some(len) = torrent.length {
-        len
-    } else if let Some(files) = &torrent.files {
-        files.iter().map(|f| f.length).sum()
-    } else {
-        0
-    };
+    let layout = storage.layout(&torrent)?;
+    let total_length = layout.total_length;
 
     println!("Total length: {}", total_length);
-
-    let file_path = if torrent.length.is_none() {
-        println!("Multi-file torrents not fully supported yet, writing to 'output.bin'");
-        storage.get_file_path("output.bin")
-    } else {
-        storage.get_file_path(&torrent.name)
-    };
-
-    let mut file = tokio::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&file_path)
-        .await?;
-
-    let file_len = file.metadata().await?.len();
-    if file_len != total_length {
-        file.set_len(total_length).await?;
+    if torrent.files.is_some() {
+        println!("Multi-file torrent with {} files", layout.files.len());
     }
 
+    let files = TorrentFiles::new(layout);
+
     let piece_count = torrent.pieces.len();
     let piece_status_vec = vec![PieceStatus::Missing; piece_count];
 
@@ -84,18 +69,25 @@ pub async fn from_torrent(
         torrent: Arc::new(torrent),
         peer_id,
         storage,
-        file: Arc::new(Mutex::new(file)),
+        files: Arc::new(Mutex::new(files)),
         piece_status: Arc::new(Mutex::new(piece_status_vec)),
+        picker: Arc::new(Mutex::new(PiecePicker::new(piece_count))),
         downloaded_bytes: Arc::new(Mutex::new(0)),
         uploaded_bytes: Arc::new(Mutex::new(0)),
+        known_peers: Arc::new(Mutex::new(HashSet::new())),
+        connections: Mutex::new(Vec::new()),
         total_length,
+        config: DownloaderConfig::default(),
+        control: DownloadHandle::new(watch::channel(DownloadState::Running).0),
     })
 }
 
 /// Checks for existing data on disk and updates piece status.
 ///
-/// This function iterates through all pieces defined in the torrent:
-/// 1. Reads the corresponding byte range from the file.
+/// If a resume file exists and the files on disk are unchanged since it was saved,
+/// its piece bitfield, totals and known peers are trusted as is. Otherwise this
+/// function iterates through all pieces defined in the torrent:
+/// 1. Reads the corresponding byte range through the torrent's file layout.
 /// 2. Computes the SHA-1 hash.
 /// 3. Compares it with the hash in the torrent metadata.
 /// 4. If they match, marks the piece as `Have` and updates `downloaded_bytes`.
@@ -106,39 +98,38 @@ pub async fn from_torrent(
 pub async fn check_existing_data(
     downloader: &Downloader,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("Checking existing data...");
     let piece_count = downloader.torrent.pieces.len();
-    let mut file = downloader.file.lock().await;
-    let file_len = file.metadata().await?.len();
+    match resume::load(downloader).await {
+        Ok(true) => {
+            let status = downloader.piece_status.lock().await;
+            println!(
+                "Loaded resume data. Found {}/{} pieces.",
+                status.iter().filter(|&&s| s == PieceStatus::Have).count(),
+                piece_count
+            );
+            return Ok(());
+        }
+        Ok(false) => {}
+        Err(e) => eprintln!("Ignoring invalid resume data: {}", e),
+    }
+
+    println!("Checking existing data...");
+    let mut files = downloader.files.lock().await;
     let mut piece_status = downloader.piece_status.lock().await;
+    let mut picker = downloader.picker.lock().await;
 
     for i in 0..piece_count {
-        let offset = i as u64 * downloader.torrent.piece_length;
-        let len = if i == piece_count - 1 {
-            let rem = downloader.total_length % downloader.torrent.piece_length;
-            if rem == 0 {
-                downloader.torrent.piece_length
-            } else {
-                rem
-            }
-        } else {
-            downloader.torrent.piece_length
+        let buf = match files.read_piece(i).await {
+            Ok(buf) => buf,
+            Err(_) => continue,
         };
-
-        if offset + len <= file_len {
-            if let Err(_) = file.seek(SeekFrom::Start(offset)).await {
-                continue;
-            }
-            let mut buf = vec![0u8; len as usize];
-            if file.read_exact(&mut buf).await.is_ok() {
-                let mut hasher = Sha1::new();
-                hasher.update(&buf);
-                let hash = hasher.finalize();
-                if hash.as_slice() == &downloader.torrent.pieces[i] {
-                    piece_status[i] = PieceStatus::Have;
-                    *downloader.downloaded_bytes.lock().await += len;
-                }
-            }
+        let mut hasher = Sha1::new();
+        hasher.update(&buf);
+        let hash = hasher.finalize();
+        if hash.as_slice() == downloader.torrent.pieces[i] {
+            piece_status[i] = PieceStatus::Have;
+            picker.piece_verified(i);
+            *downloader.downloaded_bytes.lock().await += buf.len() as u64;
         }
     }
     println!(
@@ -155,6 +146,7 @@ pub async fn check_existing_data(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::downloader::PiecePriority;
     use tempfile::tempdir;
 
     #[tokio::test]
@@ -171,6 +163,9 @@ mod tests {
             piece_length: 1024,
             length: Some(1024),
             files: None,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         let result = from_torrent(torrent, Some(path_str.clone())).await;
@@ -179,10 +174,17 @@ mod tests {
         let downloader = result.unwrap();
         assert_eq!(downloader.total_length, 1024);
         assert_eq!(downloader.piece_status.lock().await.len(), 1);
-        
-        // File should exist and be 1024 bytes
+
+        // The file is created, at its full size, once data is written to it
         let file_path = dir.path().join("test_file.txt");
-        assert!(file_path.exists());
+        assert!(!file_path.exists());
+        downloader
+            .files
+            .lock()
+            .await
+            .write_at(0, b"data")
+            .await
+            .unwrap();
         let metadata = tokio::fs::metadata(file_path).await.unwrap();
         assert_eq!(metadata.len(), 1024);
     }
@@ -209,6 +211,9 @@ mod tests {
             piece_length: 10,
             length: Some(10),
             files: None,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
         };
 
         // Write the valid data to the file first
@@ -216,7 +221,7 @@ mod tests {
         tokio::fs::write(&file_path, &piece_data).await.unwrap();
 
         let downloader = from_torrent(torrent, Some(path_str)).await.unwrap();
-        
+
         // Status should be missing initially
         {
             let status = downloader.piece_status.lock().await;
@@ -232,4 +237,178 @@ mod tests {
             assert_eq!(status[0], PieceStatus::Have);
         }
     }
+
+    #[tokio::test]
+    async fn test_check_existing_data_multi_file() {
+        let dir = tempdir().unwrap();
+        let path_str = dir.path().to_str().unwrap().to_string();
+
+        // One 8-byte piece spanning two files
+        let mut hasher = Sha1::new();
+        hasher.update(b"AAAABBBB");
+        let mut hash_arr = [0u8; 20];
+        hash_arr.copy_from_slice(&hasher.finalize());
+
+        let torrent = Torrent {
+            announce: "http://tracker.com".to_string(),
+            announce_list: None,
+            info_hash: [0u8; 20],
+            name: "pack".to_string(),
+            pieces: vec![hash_arr],
+            piece_length: 8,
+            length: None,
+            files: Some(vec![
+                tds_core::FileInfo {
+                    length: 4,
+                    path: vec!["a.bin".to_string()],
+                },
+                tds_core::FileInfo {
+                    length: 4,
+                    path: vec!["sub".to_string(), "b.bin".to_string()],
+                },
+            ]),
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        };
+
+        let root = dir.path().join("pack");
+        tokio::fs::create_dir_all(root.join("sub")).await.unwrap();
+        tokio::fs::write(root.join("a.bin"), b"AAAA").await.unwrap();
+        tokio::fs::write(root.join("sub/b.bin"), b"BBBB")
+            .await
+            .unwrap();
+
+        let downloader = from_torrent(torrent, Some(path_str)).await.unwrap();
+        assert_eq!(downloader.total_length, 8);
+        assert!(!dir.path().join("output.bin").exists());
+
+        check_existing_data(&downloader).await.unwrap();
+        assert_eq!(downloader.piece_status.lock().await[0], PieceStatus::Have);
+        assert_eq!(*downloader.downloaded_bytes.lock().await, 8);
+    }
+
+    #[tokio::test]
+    async fn test_select_files_skips_pieces_of_other_files() {
+        let dir = tempdir().unwrap();
+        let file = |length, name: &str| tds_core::FileInfo {
+            length,
+            path: vec![name.to_string()],
+        };
+        // Pieces of 4 bytes: the last one is shared by b.bin and c.bin
+        let torrent = Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: [0u8; 20],
+            name: "pack".to_string(),
+            pieces: vec![[0u8; 20]; 3],
+            piece_length: 4,
+            length: None,
+            files: Some(vec![file(4, "a.bin"), file(6, "b.bin"), file(2, "c.bin")]),
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        };
+        let downloader = from_torrent(torrent, Some(dir.path().to_str().unwrap().to_string()))
+            .await
+            .unwrap();
+
+        assert!(downloader.select_files(&[2, 3]).await.is_err());
+        downloader.select_files(&[2]).await.unwrap();
+        let picker = downloader.picker.lock().await;
+        let priorities: Vec<_> = (0..3).map(|i| picker.priority(i)).collect();
+        assert_eq!(
+            priorities,
+            vec![
+                PiecePriority::Skip,
+                PiecePriority::Skip,
+                PiecePriority::Normal
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bytes_left_counts_missing_wanted_pieces() {
+        let dir = tempdir().unwrap();
+        // Pieces of 4, 4 and 2 bytes
+        let torrent = Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: [0u8; 20],
+            name: "left.bin".to_string(),
+            pieces: vec![[0u8; 20]; 3],
+            piece_length: 4,
+            length: Some(10),
+            files: None,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        };
+        let downloader = from_torrent(torrent, Some(dir.path().to_str().unwrap().to_string()))
+            .await
+            .unwrap();
+        assert_eq!(downloader.bytes_left().await, 10);
+
+        // Bytes downloaded in earlier runs, or twice, have no say
+        *downloader.downloaded_bytes.lock().await = 100;
+        downloader.piece_status.lock().await[0] = PieceStatus::Have;
+        assert_eq!(downloader.bytes_left().await, 6);
+
+        downloader.set_piece_priority(1, PiecePriority::Skip).await;
+        assert_eq!(downloader.bytes_left().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_resume_data_skips_recheck_until_files_change() {
+        let dir = tempdir().unwrap();
+        let path_str = dir.path().to_str().unwrap().to_string();
+
+        // The hash never matches the zeroed file, so only resume data can mark the piece
+        let torrent = Torrent {
+            announce: "http://tracker.com".to_string(),
+            announce_list: None,
+            info_hash: [4u8; 20],
+            name: "resumed.bin".to_string(),
+            pieces: vec![[0u8; 20]],
+            piece_length: 10,
+            length: Some(10),
+            files: None,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        };
+
+        let first = from_torrent(torrent.clone(), Some(path_str.clone()))
+            .await
+            .unwrap();
+        first
+            .files
+            .lock()
+            .await
+            .write_piece(0, &[0u8; 10])
+            .await
+            .unwrap();
+        first.piece_status.lock().await[0] = PieceStatus::Have;
+        *first.uploaded_bytes.lock().await = 5;
+        first.save_resume_data().await.unwrap();
+        assert!(dir.path().join("resumed.bin.resume").exists());
+
+        let second = from_torrent(torrent.clone(), Some(path_str.clone()))
+            .await
+            .unwrap();
+        check_existing_data(&second).await.unwrap();
+        assert_eq!(second.piece_status.lock().await[0], PieceStatus::Have);
+        assert_eq!(*second.uploaded_bytes.lock().await, 5);
+
+        // Touching the file invalidates the resume data
+        std::fs::File::options()
+            .write(true)
+            .open(dir.path().join("resumed.bin"))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000))
+            .unwrap();
+        let third = from_torrent(torrent, Some(path_str)).await.unwrap();
+        check_existing_data(&third).await.unwrap();
+        assert_eq!(third.piece_status.lock().await[0], PieceStatus::Missing);
+    }
 }
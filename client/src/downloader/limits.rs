@@ -0,0 +1,126 @@
+//! Download and upload rate limits.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tds_core::rate_limit::TokenBucket;
+use tokio::sync::Mutex;
+
+/// Creates the bucket of a configured limit.
+///
+/// Limits set through a `Session` or the command line are checked when they are set;
+/// a configuration that slipped through with a rate `TokenBucket::check_rate` rejects
+/// is reported and runs without that limit.
+///
+/// # Arguments
+///
+/// * `rate` - The limit in bytes per second, or `None` for no limit.
+/// * `what` - Names the limit in the warning.
+pub(crate) fn bucket(rate: Option<f64>, what: &str) -> TokenBucket {
+    match TokenBucket::check_rate(rate) {
+        Ok(()) => TokenBucket::with_rate(rate),
+        Err(e) => {
+            eprintln!("Ignoring the {} limit: {}", what, e);
+            TokenBucket::unlimited()
+        }
+    }
+}
+
+/// The rate limit of one direction of traffic for a torrent.
+///
+/// Every byte counts against both the session-wide bucket, shared with other
+/// torrents, and the torrent's own bucket.
+#[derive(Clone)]
+pub(super) struct RateLimit {
+    global: Arc<Mutex<TokenBucket>>,
+    torrent: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimit {
+    /// Creates a limit from the session-wide bucket and the torrent's own rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `global` - The bucket shared by every torrent of the session.
+    /// * `torrent_rate` - The torrent's limit in bytes per second, or `None` for no limit.
+    pub fn new(global: Arc<Mutex<TokenBucket>>, torrent_rate: Option<f64>) -> Self {
+        Self {
+            global,
+            torrent: Arc::new(Mutex::new(bucket(torrent_rate, "torrent rate"))),
+        }
+    }
+
+    /// Counts `bytes` against both limits without waiting.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - How long to hold off before the bytes may be transferred; zero if
+    ///   both buckets had them.
+    pub async fn reserve(&self, bytes: usize) -> Duration {
+        let torrent = self.torrent.lock().await.reserve(bytes as f64);
+        let global = self.global.lock().await.reserve(bytes as f64);
+        torrent.max(global)
+    }
+
+    /// Waits until `bytes` may be transferred under both limits.
+    ///
+    /// The bytes are reserved from both buckets up front and the wait happens with
+    /// neither locked, so other transfers and limit changes are not held up meanwhile.
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = self.reserve(bytes).await;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_torrent_and_global_limits_both_apply() {
+        let global = Arc::new(Mutex::new(TokenBucket::with_rate(None)));
+        let limit = RateLimit::new(global.clone(), Some(1000.0));
+        let start = tokio::time::Instant::now();
+
+        limit.acquire(1000).await;
+        limit.acquire(500).await;
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        *global.lock().await = TokenBucket::new(100.0, 100.0);
+        let start = tokio::time::Instant::now();
+        limit.acquire(300).await;
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waiting_does_not_hold_the_buckets() {
+        let global = Arc::new(Mutex::new(TokenBucket::new(100.0, 100.0)));
+        let limit = RateLimit::new(global.clone(), None);
+        limit.acquire(100).await;
+
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire(1000).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        // Changing the limit goes through while the transfer above still waits
+        let start = tokio::time::Instant::now();
+        *global.lock().await = TokenBucket::with_rate(None);
+        assert!(start.elapsed() < Duration::from_millis(10));
+        limit.acquire(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_rate_does_not_stall_or_panic() {
+        let global = Arc::new(Mutex::new(bucket(Some(0.0), "download")));
+        let limit = RateLimit::new(global, Some(0.0));
+        let start = tokio::time::Instant::now();
+        limit.acquire(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+    }
+}
@@ -1,31 +1,74 @@
-use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
-use std::io::SeekFrom;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tds_core::bencoding::{Bencode, decode};
-use tds_core::rate_limit::TokenBucket;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::{Mutex, Semaphore, broadcast, mpsc};
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast, mpsc, watch};
 use tracker::{TrackerEvent, TrackerRequest, get_tracker_client};
 
-use super::state::{Downloader, PieceStatus};
-use crate::dht::Dht;
-use crate::peer::{Message, PeerConnection};
+use super::blocks::BlockTracker;
+use super::choker::Choker;
+use super::control::{self, DownloadState};
+use super::limits::RateLimit;
+use super::peer_task::{self, PeerContext};
+use super::pex::PexFlags;
+use super::state::Downloader;
+use super::web_seed::{self, WebSeed};
+use crate::listener::{self, DEFAULT_LISTEN_PORT};
+use crate::peer::PeerConnection;
+use crate::session::SharedResources;
+
+/// How often we look up and announce ourselves on the DHT once the swarm was found.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 /// The main execution loop of the downloader.
 ///
 /// This function:
 /// 1. Announces to the Tracker to get an initial list of peers.
-/// 2. Starts the DHT service to find more peers (for magnet support or redundancy).
-/// 3. Spawns tasks to connect to peers.
-/// 4. Manages the download loop: requesting pieces, verifying hashes, and writing to disk.
-/// 5. Uploads pieces to other interested peers (tit-for-tat).
+/// 2. Queries the DHT to find more peers (for magnet support or redundancy), and announces
+///    the listen port to the DHT so that others can find us.
+/// 3. Spawns tasks to connect to peers, and accepts inbound peers on the listen port.
+///    Web seeds listed in the torrent are downloaded from alongside the peers.
+///    Connections made while resolving a magnet link are taken over as they are.
+///    Peers that support PEX are told which peers we are connected to, and peers they
+///    tell us about are queued for connection, except seeds while we are seeding.
+/// 4. Manages the download loop: pipelining block requests, verifying hashes, and writing to disk.
+///    Near the end, outstanding blocks are requested from several peers and cancelled once one arrives.
+/// 5. Uploads pieces to other interested peers (tit-for-tat): every 10 seconds the peers
+///    we download from fastest are unchoked, plus one optimistic unchoke rotated every 30 seconds.
+/// 6. Saves the resume file periodically and when the loop ends.
+///
+/// Once every wanted piece is downloaded, trackers receive a `completed` announce and
+/// the torrent seeds: peers stay connected and are served, but nothing more is
+/// requested, and the choker ranks peers by how fast they download from us. A torrent
+/// that starts complete seeds from the start.
+///
+/// While the download is paused, peers stay connected but are choked and nothing is
+/// requested from them. The loop ends only when the download is stopped; then every
+/// peer is disconnected, trackers receive a `stopped` announce, the files are flushed
+/// and the resume file is saved.
 ///
 /// # Arguments
 ///
 /// * `downloader` - The shared downloader state.
-pub async fn run(downloader: &Downloader) {
+/// * `shared` - The listener, DHT node and global limits to use.
+pub async fn run(downloader: &Downloader, shared: &SharedResources) {
+    let mut state_rx = downloader.handle().subscribe();
+    if *state_rx.borrow() == DownloadState::Stopped {
+        return;
+    }
+    // Trackers hear about this session's transfers; the totals of earlier sessions
+    // only go into the resume file
+    let totals_at_start = transferred(downloader, (0, 0)).await;
+
+    // --- Inbound Connections ---
+    let listener = shared.listener.as_ref();
+    let mut inbound_rx =
+        listener.map(|l| l.register(downloader.torrent.info_hash, downloader.peer_id));
+    let listen_port = listener.map(|l| l.port()).unwrap_or(DEFAULT_LISTEN_PORT);
+    let local_ipv6 = match listener {
+        Some(_) => listener::local_ipv6().await,
+        None => None,
+    };
+
     let mut tracker_urls = Vec::new();
     tracker_urls.push(downloader.torrent.announce.clone());
     if let Some(list) = &downloader.torrent.announce_list {
@@ -42,10 +85,10 @@ pub async fn run(downloader: &Downloader) {
     let request = TrackerRequest {
         info_hash: downloader.torrent.info_hash,
         peer_id: downloader.peer_id,
-        port: 6881,
+        port: listen_port,
         uploaded: 0,
         downloaded: 0,
-        left: downloader.total_length - *downloader.downloaded_bytes.lock().await,
+        left: downloader.bytes_left().await,
         compact: true,
         no_peer_id: false,
         event: Some(TrackerEvent::Started),
@@ -57,6 +100,21 @@ pub async fn run(downloader: &Downloader) {
 
     let (peer_tx, mut peer_rx) = mpsc::channel(100);
 
+    // --- Task: Peers remembered from the previous run ---
+    let known: Vec<_> = downloader
+        .known_peers
+        .lock()
+        .await
+        .iter()
+        .copied()
+        .collect();
+    let known_tx = peer_tx.clone();
+    tokio::spawn(async move {
+        for peer in known {
+            let _ = known_tx.send(peer.into()).await;
+        }
+    });
+
     // --- Task: Tracker Discovery ---
     let tracker_tx = peer_tx.clone();
     let request_clone = request.clone();
@@ -84,401 +142,825 @@ pub async fn run(downloader: &Downloader) {
                     response.peers.len()
                 );
                 for peer in response.peers {
-                    let _ = tracker_tx.send(peer).await;
+                    let _ = tracker_tx.send(peer.into()).await;
                 }
             }
         }
     });
 
     // --- Task: DHT Discovery ---
-    let dht_tx = peer_tx.clone();
-    let info_hash = downloader.torrent.info_hash;
-    tokio::spawn(async move {
-        match Dht::new(6882).await {
-            Ok(dht) => {
-                println!("DHT started on port 6882");
-                dht.start().await;
-                dht.bootstrap().await;
-
-                loop {
-                    // Regularly query DHT for peers
-                    dht.get_peers(info_hash).await;
-                    let peers = dht.get_found_peers().await;
-                    if !peers.is_empty() {
-                        println!("DHT found {} peers", peers.len());
-                        for peer in peers {
-                            let _ = dht_tx.send(peer).await;
-                        }
+    if let Some(dht) = shared.dht.clone() {
+        let dht_tx = peer_tx.clone();
+        let info_hash = downloader.torrent.info_hash;
+        // Only announce if peers can reach us
+        let announce_port = listener.map(|l| l.port());
+        tokio::spawn(async move {
+            // Runs until the main loop below ends and drops the receiver
+            while !dht_tx.is_closed() {
+                let mut lookup = dht.get_peers(info_hash).await;
+                let mut found = 0;
+                while let Some(peer) = lookup.next().await {
+                    found += 1;
+                    if dht_tx.send(peer.into()).await.is_err() {
+                        return;
                     }
-                    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
                 }
+                println!("DHT lookup found {} peers", found);
+
+                let closest = lookup.finish().await;
+                let announced = match announce_port {
+                    Some(port) if !closest.is_empty() && !dht_tx.is_closed() => {
+                        // Peers accepted on the DHT port are reachable at the query's source port
+                        let implied_port = port == dht.port();
+                        dht.announce_peer(info_hash, port, implied_port, &closest)
+                            .await;
+                        true
+                    }
+                    _ => false,
+                };
+
+                // Look again soon if the swarm could not be found yet; otherwise the
+                // next lookup re-announces us before the nodes forget.
+                let pause = if found > 0 || announced {
+                    DHT_ANNOUNCE_INTERVAL
+                } else {
+                    Duration::from_secs(10)
+                };
+                tokio::time::sleep(pause).await;
             }
-            Err(e) => eprintln!("Failed to start DHT: {}", e),
-        }
-    });
+        });
+    }
 
     // --- Main Peer Management Loop ---
-    let mut handles = Vec::new();
-    let (tx, _) = broadcast::channel(1);
-    let mut completion_rx = tx.subscribe();
-    
-    // Limits
-    let upload_limiter = Arc::new(Mutex::new(TokenBucket::new(2_000_000.0, 2_000_000.0))); // 2 MB/s
-    let uploaded_total = downloader.uploaded_bytes.clone();
-    let downloaded_total = downloader.downloaded_bytes.clone();
-    // Max 50 active peer connections
-    let semaphore = Arc::new(Semaphore::new(50));
-    let connected_peers = Arc::new(Mutex::new(std::collections::HashSet::new()));
+    let shutdown_tx = watch::channel(false).0;
+    let semaphore = shared.connection_limit.clone();
+
+    let ctx = PeerContext {
+        torrent: downloader.torrent.clone(),
+        piece_status: downloader.piece_status.clone(),
+        picker: downloader.picker.clone(),
+        blocks: Arc::new(Mutex::new(BlockTracker::new(
+            downloader.files.lock().await.layout().clone(),
+        ))),
+        files: downloader.files.clone(),
+        uploaded_total: downloader.uploaded_bytes.clone(),
+        downloaded_total: downloader.downloaded_bytes.clone(),
+        download_limit: RateLimit::new(
+            shared.download_limiter.clone(),
+            downloader.config.download_rate_limit,
+        ),
+        upload_limit: RateLimit::new(
+            shared.upload_limiter.clone(),
+            downloader.config.upload_rate_limit,
+        ),
+        state: state_rx.clone(),
+        choker: Arc::new(Mutex::new(Choker::new(downloader.config.unchoke_slots))),
+        pipeline_depth: downloader.config.pipeline_depth.max(1),
+        request_timeout: downloader.config.request_timeout,
+        listen_port: listener.map(|l| l.port()),
+        local_ipv6,
+        peer_tx: peer_tx.clone(),
+        shutdown: shutdown_tx.clone(),
+        completed: watch::channel(false).0,
+        block_tx: broadcast::channel(256).0,
+        connected_peers: Arc::new(Mutex::new(HashSet::new())),
+        pex_swarm: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    let mut seeding = ctx.is_complete().await;
+    ctx.completed.send_replace(seeding);
+    let mut completed_rx = ctx.completed.subscribe();
+    if seeding {
+        println!(
+            "Seeding torrent {}",
+            hex::encode(downloader.torrent.info_hash)
+        );
+    }
+
+    let resume_period = Duration::from_secs(60);
+    let mut resume_timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + resume_period, resume_period);
+
+    let mut rechoke_timer = tokio::time::interval(
+        downloader
+            .config
+            .rechoke_interval
+            .max(Duration::from_millis(1)),
+    );
+
+    // --- Tasks: Web Seeds ---
+    for seed in WebSeed::from_torrent(&downloader.torrent) {
+        tokio::spawn(web_seed::run(ctx.clone(), seed));
+    }
+
+    // --- Connections made before the download started ---
+    let connections = std::mem::take(&mut *downloader.connections.lock().await);
+    for peer in connections {
+        let peer_addr = peer.addr();
+        if !ctx.connected_peers.lock().await.insert(peer_addr) {
+            continue;
+        }
+        let Ok(permit) = semaphore.clone().try_acquire_owned() else {
+            eprintln!("Connection limit reached, dropping peer {}", peer_addr);
+            ctx.connected_peers.lock().await.remove(&peer_addr);
+            continue;
+        };
+        downloader.known_peers.lock().await.insert(peer_addr);
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            peer_task::run(ctx, peer).await;
+        });
+    }
 
     loop {
-        // Wait for a new peer or completion of existing tasks
-        // Note: For simplicity, we just loop on receiving peers mostly.
         tokio::select! {
             res = peer_rx.recv() => {
-                if let Some(peer_addr) = res {
-                    let mut connected = connected_peers.lock().await;
-                    if connected.contains(&peer_addr) {
+                if let Some(candidate) = res {
+                    let peer_addr = candidate.addr;
+                    // A seed has nothing to gain from other seeds
+                    if candidate.flags.contains(PexFlags::SEED) && seeding {
+                        continue;
+                    }
+                    if !ctx.connected_peers.lock().await.insert(peer_addr) {
                         continue;
                     }
-                    connected.insert(peer_addr);
-                    drop(connected);
 
-                    let piece_status = downloader.piece_status.clone();
-                    let file = downloader.file.clone();
-                    let torrent = downloader.torrent.clone();
-                    let peer_id = downloader.peer_id;
-                    let mut rx = tx.subscribe();
-                    let tx = tx.clone();
-                    let new_peer_tx = peer_tx.clone();
-                    let uploaded_total = uploaded_total.clone();
-                    let downloaded_total = downloaded_total.clone();
+                    let ctx = ctx.clone();
                     let semaphore = semaphore.clone();
-                    let connected_peers = connected_peers.clone();
-                    let upload_limiter = upload_limiter.clone();
-                    let total_length = downloader.total_length;
-                    let piece_count = torrent.pieces.len();
+                    let peer_id = downloader.peer_id;
+                    let known_peers = downloader.known_peers.clone();
+                    let encryption = shared.encryption.outbound;
+                    let transport = shared.transport;
+                    let utp = listener.and_then(|l| l.utp()).cloned();
 
                     // Spawn a task for each peer connection
-                    handles.push(tokio::spawn(async move {
+                    tokio::spawn(async move {
                         // Rate limit active connections
                         let _permit = semaphore.acquire_owned().await.unwrap();
+                        if *ctx.shutdown.borrow() {
+                            // The torrent stopped while we waited for a connection slot
+                            ctx.connected_peers.lock().await.remove(&peer_addr);
+                            return;
+                        }
                         println!("Connecting to {}", peer_addr);
 
-                        let mut peer =
-                            match PeerConnection::connect(peer_addr, &torrent.info_hash, &peer_id).await {
-                                Ok(p) => p,
-                                Err(e) => {
-                                    eprintln!("Failed to connect to {}: {}", peer_addr, e);
-                                    connected_peers.lock().await.remove(&peer_addr);
-                                    return;
-                                }
-                            };
-                        println!("Connected to {}", peer_addr);
-
-                        // 1. Send Bitfield (if we have pieces)
-                        {
-                            let status = piece_status.lock().await;
-                            if status.iter().any(|&s| s == PieceStatus::Have) {
-                                let mut bitfield = vec![0u8; (status.len() + 7) / 8];
-                                for (i, s) in status.iter().enumerate() {
-                                    if *s == PieceStatus::Have {
-                                        let byte_idx = i / 8;
-                                        let bit_idx = 7 - (i % 8);
-                                        bitfield[byte_idx] |= 1 << bit_idx;
-                                    }
-                                }
-                                if let Err(e) = peer.send_message(Message::Bitfield(bitfield)).await {
-                                    eprintln!("Error sending bitfield to {}: {}", peer_addr, e);
-                                    return;
-                                }
+                        match PeerConnection::dial(
+                            peer_addr,
+                            &ctx.torrent.info_hash,
+                            &peer_id,
+                            encryption,
+                            transport,
+                            utp.as_ref(),
+                        )
+                        .await {
+                            Ok(peer) => {
+                                println!("Connected to {}", peer_addr);
+                                known_peers.lock().await.insert(peer_addr);
+                                peer_task::run(ctx, peer).await;
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to connect to {}: {}", peer_addr, e);
+                                ctx.connected_peers.lock().await.remove(&peer_addr);
                             }
                         }
+                    });
+                }
+            }
+            Some(peer) = recv_inbound(&mut inbound_rx) => {
+                let peer_addr = peer.addr();
+                if !ctx.connected_peers.lock().await.insert(peer_addr) {
+                    continue;
+                }
+                let permit = match semaphore.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        eprintln!("Connection limit reached, dropping inbound peer {}", peer_addr);
+                        ctx.connected_peers.lock().await.remove(&peer_addr);
+                        continue;
+                    }
+                };
 
-                        // 2. Send Interested
-                        if let Err(e) = peer.send_message(Message::Interested).await {
-                            eprintln!("Error sending interested to {}: {}", peer_addr, e);
-                            return;
-                        }
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    peer_task::run(ctx, peer).await;
+                });
+            }
+            _ = rechoke_timer.tick() => {
+                // Skipping the last wanted pieces completes the download as well
+                if !seeding && ctx.is_complete().await {
+                    ctx.completed.send_replace(true);
+                }
+                ctx.choker.lock().await.rechoke(seeding);
+            }
+            _ = resume_timer.tick() => {
+                if let Err(e) = downloader.save_resume_data().await {
+                    eprintln!("Failed to save resume data: {}", e);
+                }
+            }
+            _ = control::wait_until(&mut completed_rx, |&done| done), if !seeding => {
+                println!("All pieces downloaded! Seeding.");
+                seeding = true;
+                let (uploaded, downloaded) = transferred(downloader, totals_at_start).await;
+                let completed = TrackerRequest {
+                    uploaded,
+                    downloaded,
+                    left: 0,
+                    event: Some(TrackerEvent::Completed),
+                    ..request.clone()
+                };
+                let urls = tracker_urls.clone();
+                tokio::spawn(async move { announce_event(&urls, completed).await });
+            }
+            _ = control::wait_until(&mut state_rx, |&s| s == DownloadState::Stopped) => {
+                println!("Stopping torrent {}", hex::encode(downloader.torrent.info_hash));
+                break;
+            }
+        }
+    }
 
-                        // 3. Send Extended Handshake (PEX support)
-                        let mut m = BTreeMap::new();
-                        m.insert(b"ut_pex".to_vec(), Bencode::Int(1));
-                        let mut handshake = BTreeMap::new();
-                        handshake.insert(b"m".to_vec(), Bencode::Dict(m));
-                        let payload = Bencode::Dict(handshake).encode();
-                        if let Err(e) = peer.send_message(Message::Extended { id: 0, payload }).await {
-                            eprintln!("Error sending extended handshake to {}: {}", peer_addr, e);
-                        }
+    // Disconnect every peer of this torrent
+    shutdown_tx.send_replace(true);
+
+    let (uploaded, downloaded) = transferred(downloader, totals_at_start).await;
+    let stopped = TrackerRequest {
+        uploaded,
+        downloaded,
+        left: downloader.bytes_left().await,
+        event: Some(TrackerEvent::Stopped),
+        numwant: Some(0),
+        ..request
+    };
+    announce_event(&tracker_urls, stopped).await;
 
-                        let mut peer_pex_id = None;
-                        let mut current_piece_idx: Option<usize> = None;
-                        let mut current_piece_data: Vec<u8> = Vec::new();
-                        let mut uploaded_session: u64 = 0;
-                        let mut blocks_received: usize = 0;
-                        let mut blocks_total: usize = 0;
-
-                        loop {
-                            let msg = tokio::select! {
-                                res = peer.read_message() => {
-                                    match res {
-                                        Ok(m) => m,
-                                        Err(e) => {
-                                            eprintln!("Error reading from {}: {}", peer_addr, e);
-                                            // Make sure to reset status if we crash middle of a piece
-                                            if let Some(idx) = current_piece_idx {
-                                                let mut status = piece_status.lock().await;
-                                                if status[idx] == PieceStatus::InProgress {
-                                                    status[idx] = PieceStatus::Missing;
-                                                }
-                                            }
-                                            break;
-                                        }
-                                    }
-                                }
-                                _ = rx.recv() => {
-                                    // Broadcast received to stop/cancel
-                                    break;
-                                }
-                            };
-
-                            match msg {
-                                Message::Unchoke => {
-                                    println!("{} unchoked us", peer_addr);
-                                }
-                                Message::Request { index, begin, length } => {
-                                    // Handle upload request
-                                    if length > 128 * 1024 {
-                                        eprintln!("Requested block too large: {}", length);
-                                        continue;
-                                    }
-
-                                    let status = piece_status.lock().await;
-                                    if status.get(index as usize).map(|&s| s == PieceStatus::Have).unwrap_or(false) {
-                                        drop(status);
-                                        
-                                        // Global Upload Rate Limiting
-                                        let mut bucket = upload_limiter.lock().await;
-                                        while !bucket.consume(length as f64) {
-                                            drop(bucket);
-                                            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                                            bucket = upload_limiter.lock().await;
-                                        }
-                                        drop(bucket);
-
-                                        let mut f = file.lock().await;
-                                        let offset = (index as u64 * torrent.piece_length) + begin as u64;
-                                        if let Err(e) = f.seek(SeekFrom::Start(offset)).await {
-                                            eprintln!("Seek error: {}", e);
-                                            continue;
-                                        }
-                                        let mut block = vec![0u8; length as usize];
-                                        if let Err(e) = f.read_exact(&mut block).await {
-                                            eprintln!("Read error: {}", e);
-                                            continue;
-                                        }
-                                        drop(f);
-
-                                        if let Err(e) = peer.send_message(Message::Piece { index, begin, block }).await {
-                                            eprintln!("Error sending piece to {}: {}", peer_addr, e);
-                                            break;
-                                        }
-
-                                        let mut uploaded = uploaded_total.lock().await;
-                                        *uploaded += length as u64;
-                                        uploaded_session += length as u64;
-                                        println!(
-                                            "Uploaded {} bytes to {} (Session: {}, Total: {})",
-                                            length, peer_addr, uploaded_session, *uploaded
-                                        );
-                                    }
-                                }
-                                Message::Cancel { .. } => {
-                                    // TODO: Implement cancel strategy if we queue requests
-                                }
-                                Message::Piece {
-                                    index,
-                                    begin,
-                                    block,
-                                } => {
-                                    if let Some(curr) = current_piece_idx {
-                                        if curr == index as usize {
-                                            let begin = begin as usize;
-                                            if begin + block.len() <= current_piece_data.len() {
-                                                current_piece_data[begin..begin + block.len()]
-                                                    .copy_from_slice(&block);
-                                                blocks_received += 1;
-
-                                                if blocks_received == blocks_total {
-                                                    // Piece Complete, Verify Hash
-                                                    let mut hasher = Sha1::new();
-                                                    hasher.update(&current_piece_data);
-                                                    let hash = hasher.finalize();
-
-                                                    if hash.as_slice() == &torrent.pieces[curr] {
-                                                        println!("Piece {} verified from {}!", curr, peer_addr);
-                                                        // Write to disk
-                                                        let mut f = file.lock().await;
-                                                        let offset = curr as u64 * torrent.piece_length;
-                                                        if let Err(e) = f.seek(SeekFrom::Start(offset)).await {
-                                                            eprintln!("Seek error: {}", e);
-                                                            break;
-                                                        }
-                                                        if let Err(e) = f.write_all(&current_piece_data).await {
-                                                            eprintln!("Write error: {}", e);
-                                                            break;
-                                                        }
-                                                        // Don't forget to sync!
-                                                        if let Err(e) = f.sync_all().await {
-                                                            eprintln!("Sync error: {}", e);
-                                                        }
-
-                                                        let mut status = piece_status.lock().await;
-                                                        status[curr] = PieceStatus::Have;
-
-                                                        let mut d_total = downloaded_total.lock().await;
-                                                        *d_total += current_piece_data.len() as u64;
-                                                        println!(
-                                                            "Downloaded piece {} from {} (Total: {})",
-                                                            curr, peer_addr, *d_total
-                                                        );
-
-                                                        current_piece_idx = None;
-
-                                                        if let Err(e) =
-                                                            peer.send_message(Message::Have(curr as u32)).await
-                                                        {
-                                                            eprintln!(
-                                                                "Error sending Have to {}: {}",
-                                                                peer_addr, e
-                                                            );
-                                                        }
-                                                    } else {
-                                                        eprintln!(
-                                                            "Piece {} hash mismatch from {}!",
-                                                            curr, peer_addr
-                                                        );
-                                                        let mut status = piece_status.lock().await;
-                                                        status[curr] = PieceStatus::Missing;
-                                                        current_piece_idx = None;
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                Message::Extended { id, payload } => {
-                                    if id == 0 {
-                                        let mut pos = 0;
-                                        if let Ok(Bencode::Dict(dict)) = decode(&payload, &mut pos) {
-                                            if let Some(Bencode::Dict(m)) = dict.get(&b"m"[..]) {
-                                                if let Some(Bencode::Int(pex_id)) = m.get(&b"ut_pex"[..]) {
-                                                    peer_pex_id = Some(*pex_id as u8);
-                                                    println!("Peer {} supports PEX with ID {}", peer_addr, pex_id);
-                                                }
-                                            }
-                                        }
-                                    } else if Some(id) == peer_pex_id {
-                                        let mut pos = 0;
-                                        if let Ok(Bencode::Dict(dict)) = decode(&payload, &mut pos) {
-                                            if let Some(Bencode::Bytes(added)) = dict.get(&b"added"[..]) {
-                                                for chunk in added.chunks(6) {
-                                                    if chunk.len() == 6 {
-                                                        let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
-                                                        let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                                                        let addr = SocketAddrV4::new(ip, port);
-                                                        println!("PEX found peer: {}", addr);
-                                                        let _ = new_peer_tx.send(addr).await;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-
-                                _ => {}
-                            }
+    if let Err(e) = downloader.files.lock().await.flush().await {
+        eprintln!("Failed to flush files: {}", e);
+    }
+    if let Err(e) = downloader.save_resume_data().await {
+        eprintln!("Failed to save resume data: {}", e);
+    }
 
-                            if !peer.peer_choking && current_piece_idx.is_none() {
-                                let mut idx = None;
-                                {
-                                    let mut status = piece_status.lock().await;
-                                    if status.iter().all(|&s| s == PieceStatus::Have) {
-                                        println!("All pieces downloaded!");
-                                        let _ = tx.send(());
-                                        break;
-                                    }
-
-                                    let mut available_pieces = Vec::new();
-                                    for (i, s) in status.iter().enumerate() {
-                                        if *s == PieceStatus::Missing {
-                                            if peer.has_piece(i as u32) {
-                                                available_pieces.push(i);
-                                            }
-                                        }
-                                    }
-
-                                    if !available_pieces.is_empty() {
-                                        use rand::Rng;
-                                        let mut rng = rand::rng();
-                                        let random_idx = rng.random_range(0..available_pieces.len());
-                                        let i = available_pieces[random_idx];
-                                        status[i] = PieceStatus::InProgress;
-                                        idx = Some(i);
-                                    }
-                                }
-
-                                if let Some(i) = idx {
-                                    current_piece_idx = Some(i);
-                                    let p_len = if i == piece_count - 1 {
-                                        let rem = total_length % torrent.piece_length;
-                                        if rem == 0 { torrent.piece_length } else { rem }
-                                    } else {
-                                        torrent.piece_length
-                                    };
-                                    current_piece_data = vec![0u8; p_len as usize];
-
-                                    let block_size = 16384;
-                                    blocks_total = (p_len as usize + block_size - 1) / block_size;
-                                    blocks_received = 0;
-
-                                    for b in 0..blocks_total {
-                                        let begin = b * block_size;
-                                        let len = if begin + block_size > p_len as usize {
-                                            p_len as usize - begin
-                                        } else {
-                                            block_size
-                                        };
-                                        if let Err(e) = peer
-                                            .send_message(Message::Request {
-                                                index: i as u32,
-                                                begin: begin as u32,
-                                                length: len as u32,
-                                            })
-                                            .await
-                                        {
-                                            eprintln!("Error sending request to {}: {}", peer_addr, e);
-                                            let mut status = piece_status.lock().await;
-                                            status[i] = PieceStatus::Missing;
-                                            current_piece_idx = None;
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        connected_peers.lock().await.remove(&peer_addr);
-                    }));
+    if let Some(listener) = listener {
+        listener.unregister(&downloader.torrent.info_hash);
+    }
+}
+
+/// Returns the bytes uploaded and downloaded since the totals were `since`.
+///
+/// # Arguments
+///
+/// * `downloader` - The downloader whose totals are read.
+/// * `since` - Earlier totals, uploaded then downloaded; `(0, 0)` for the totals themselves.
+///
+/// # Returns
+///
+/// * `(u64, u64)` - The bytes uploaded and downloaded since then.
+async fn transferred(downloader: &Downloader, since: (u64, u64)) -> (u64, u64) {
+    let uploaded = *downloader.uploaded_bytes.lock().await;
+    let downloaded = *downloader.downloaded_bytes.lock().await;
+    (
+        uploaded.saturating_sub(since.0),
+        downloaded.saturating_sub(since.1),
+    )
+}
+
+/// Sends an announce with an event, such as `completed` or `stopped`, to every tracker.
+///
+/// Announces run in parallel; trackers that do not answer within a few seconds are given up on.
+async fn announce_event(urls: &[String], request: TrackerRequest) {
+    let tasks: Vec<_> = urls
+        .iter()
+        .cloned()
+        .map(|url| {
+            let request = request.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Some(client) = get_tracker_client(&url)
+                    && let Err(e) = client.announce(&request)
+                {
+                    eprintln!("Announce to {} failed: {}", url, e);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        let _ = tokio::time::timeout(Duration::from_secs(5), task).await;
+    }
+}
+
+/// Receives the next inbound connection, or never resolves if there is no listener.
+async fn recv_inbound(rx: &mut Option<mpsc::Receiver<PeerConnection>>) -> Option<PeerConnection> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::{EncryptionPolicy, Message};
+    use crate::session::{Session, SessionConfig};
+    use sha1::{Digest, Sha1};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tds_core::Torrent;
+    use tempfile::tempdir;
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    /// Returns a single-file torrent of `content`, without trackers.
+    fn test_torrent(content: &[u8]) -> Torrent {
+        Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: Sha1::digest(content).into(),
+            piece_length: PIECE_LENGTH as u64,
+            pieces: content
+                .chunks(PIECE_LENGTH)
+                .map(|chunk| Sha1::digest(chunk).into())
+                .collect(),
+            name: "seed.bin".to_string(),
+            length: Some(content.len() as u64),
+            files: None,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        }
+    }
+
+    /// Writes `content` as the single file of a torrent into `dir`, and returns the
+    /// torrent with a downloader that found every piece on disk.
+    async fn complete_downloader(dir: &std::path::Path, content: &[u8]) -> Downloader {
+        std::fs::write(dir.join("seed.bin"), content).unwrap();
+        let downloader = Downloader::from_torrent(
+            test_torrent(content),
+            Some(dir.to_str().unwrap().to_string()),
+        )
+        .await
+        .unwrap();
+        downloader.check_existing_data().await.unwrap();
+        downloader
+    }
+
+    #[tokio::test]
+    async fn test_complete_torrent_serves_inbound_peers() {
+        let content: Vec<u8> = (0..40_000u32).map(|i| (i % 247) as u8).collect();
+        let dir = tempdir().unwrap();
+        let downloader = complete_downloader(dir.path(), &content).await;
+        let info_hash = downloader.torrent.info_hash;
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        let downloader = session.add(downloader).await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), session.listen_port().unwrap());
+        let mut peer =
+            PeerConnection::connect(addr, &info_hash, &[7u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        peer.send_message(Message::Interested).await.unwrap();
+        // Our address is in the seed's allowed fast set, so it serves us while choking us
+        peer.send_message(Message::Request {
+            index: 1,
+            begin: 0,
+            length: PIECE_LENGTH as u32,
+        })
+        .await
+        .unwrap();
+
+        let block = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Message::Piece {
+                    index: 1,
+                    begin: 0,
+                    block,
+                } = peer.read_message().await.unwrap()
+                {
+                    break block;
                 }
             }
-            _ = completion_rx.recv() => {
-                println!("All pieces downloaded! Stopping.");
-                break;
+        })
+        .await
+        .unwrap();
+        assert!(block == content[PIECE_LENGTH..2 * PIECE_LENGTH]);
+        assert_eq!(downloader.handle().state(), DownloadState::Running);
+
+        session.shutdown().await;
+    }
+
+    /// Reads what the seed sent until it goes quiet, so the peer's choke state is current.
+    async fn drain(peer: &mut PeerConnection) {
+        while let Ok(res) =
+            tokio::time::timeout(Duration::from_millis(100), peer.read_message()).await
+        {
+            res.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seed_unchokes_the_peers_it_uploads_to_fastest() {
+        let content: Vec<u8> = (0..3 * PIECE_LENGTH as u32)
+            .map(|i| (i % 241) as u8)
+            .collect();
+        let dir = tempdir().unwrap();
+        let mut downloader = complete_downloader(dir.path(), &content).await;
+        downloader.config.unchoke_slots = 1;
+        let interval = Duration::from_secs(2);
+        downloader.config.rechoke_interval = interval;
+        let info_hash = downloader.torrent.info_hash;
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        let start = tokio::time::Instant::now();
+        session.add(downloader).await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), session.listen_port().unwrap());
+        let mut peers = Vec::new();
+        for i in 0..3 {
+            let mut peer =
+                PeerConnection::connect(addr, &info_hash, &[i; 20], EncryptionPolicy::Disabled)
+                    .await
+                    .unwrap();
+            peer.send_message(Message::Interested).await.unwrap();
+            peers.push(peer);
+        }
+
+        // With every rate still zero, a round unchokes one peer for its slot and one
+        // optimistically, leaving the third choked
+        tokio::time::sleep_until(start + interval + Duration::from_millis(500)).await;
+        for peer in &mut peers {
+            drain(peer).await;
+        }
+        let choked: Vec<usize> = (0..3).filter(|&i| peers[i].peer_choking).collect();
+        assert_eq!(choked.len(), 1);
+
+        // The choked peer downloads the whole torrent through its allowed fast set
+        let fastest = &mut peers[choked[0]];
+        for index in 0..3 {
+            fastest
+                .send_message(Message::Request {
+                    index,
+                    begin: 0,
+                    length: PIECE_LENGTH as u32,
+                })
+                .await
+                .unwrap();
+        }
+        let mut received = 0;
+        while received < 3 {
+            if let Message::Piece { .. } = fastest.read_message().await.unwrap() {
+                received += 1;
             }
-            _ = tokio::signal::ctrl_c() => {
-                println!("Ctrl+C received, shutting down.");
-                break;
+        }
+
+        // While seeding, the next round ranks peers by how fast we upload to them
+        tokio::time::sleep_until(start + 2 * interval + Duration::from_millis(500)).await;
+        for peer in &mut peers {
+            drain(peer).await;
+        }
+        assert!(!peers[choked[0]].peer_choking);
+        assert_eq!(peers.iter().filter(|p| p.peer_choking).count(), 1);
+
+        session.shutdown().await;
+    }
+
+    /// Reads until the peer sends a message `pick` accepts, and returns what it picked.
+    async fn read_until<T>(
+        peer: &mut PeerConnection,
+        mut pick: impl FnMut(Message) -> Option<T>,
+    ) -> T {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(found) = pick(peer.read_message().await.unwrap()) {
+                    break found;
+                }
             }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requests_survive_a_fast_choke_until_rejected() {
+        let content: Vec<u8> = (0..2 * PIECE_LENGTH as u32)
+            .map(|i| (i % 239) as u8)
+            .collect();
+        let dir = tempdir().unwrap();
+        let torrent = test_torrent(&content);
+        let info_hash = torrent.info_hash;
+        let downloader =
+            Downloader::from_torrent(torrent, Some(dir.path().to_str().unwrap().to_string()))
+                .await
+                .unwrap();
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        session.add(downloader).await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), session.listen_port().unwrap());
+        let mut seed =
+            PeerConnection::connect(addr, &info_hash, &[9u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        seed.send_message(Message::HaveAll).await.unwrap();
+        seed.send_message(Message::Unchoke).await.unwrap();
+        let mut requested = Vec::new();
+        while requested.len() < 2 {
+            requested.push(
+                read_until(&mut seed, |msg| match msg {
+                    Message::Request { index, .. } => Some(index),
+                    _ => None,
+                })
+                .await,
+            );
         }
+        requested.sort();
+        assert_eq!(requested, [0, 1]);
+
+        // The choke drops nothing by itself: the block still served is stored, and
+        // only the rejected one is asked for again
+        seed.send_message(Message::Choke).await.unwrap();
+        seed.send_message(Message::Piece {
+            index: 0,
+            begin: 0,
+            block: content[..PIECE_LENGTH].to_vec(),
+        })
+        .await
+        .unwrap();
+        seed.send_message(Message::Reject {
+            index: 1,
+            begin: 0,
+            length: PIECE_LENGTH as u32,
+        })
+        .await
+        .unwrap();
+        read_until(&mut seed, |msg| {
+            matches!(msg, Message::Have(0)).then_some(())
+        })
+        .await;
+
+        seed.send_message(Message::Unchoke).await.unwrap();
+        let (index, begin, length) = read_until(&mut seed, |msg| match msg {
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => Some((index, begin, length)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((index, begin, length), (1, 0, PIECE_LENGTH as u32));
+
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_requests_time_out() {
+        let content: Vec<u8> = (0..4 * PIECE_LENGTH as u32)
+            .map(|i| (i % 227) as u8)
+            .collect();
+        let dir = tempdir().unwrap();
+        let torrent = test_torrent(&content);
+        let info_hash = torrent.info_hash;
+        let mut downloader =
+            Downloader::from_torrent(torrent, Some(dir.path().to_str().unwrap().to_string()))
+                .await
+                .unwrap();
+        downloader.config.pipeline_depth = 2;
+        downloader.config.request_timeout = Duration::from_millis(300);
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        session.add(downloader).await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), session.listen_port().unwrap());
+        let mut seed =
+            PeerConnection::connect(addr, &info_hash, &[9u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        seed.send_message(Message::HaveAll).await.unwrap();
+        seed.send_message(Message::Unchoke).await.unwrap();
+        // The seed chokes and, although it supports the Fast extension, never rejects
+        let is_request = |msg| match msg {
+            Message::Request { index, .. } => Some(index),
+            _ => None,
+        };
+        let mut requested = vec![
+            read_until(&mut seed, is_request).await,
+            read_until(&mut seed, is_request).await,
+        ];
+        seed.send_message(Message::Choke).await.unwrap();
+
+        let mut cancelled = Vec::new();
+        while cancelled.len() < 2 {
+            cancelled.push(
+                read_until(&mut seed, |msg| match msg {
+                    Message::Cancel { index, .. } => Some(index),
+                    _ => None,
+                })
+                .await,
+            );
+        }
+        requested.sort();
+        cancelled.sort();
+        assert_eq!(cancelled, requested);
+
+        // Once it unchokes again, the snubbing seed gets one request at a time
+        seed.send_message(Message::Unchoke).await.unwrap();
+        read_until(&mut seed, is_request).await;
+        let second = tokio::time::timeout(
+            Duration::from_millis(200),
+            read_until(&mut seed, is_request),
+        )
+        .await;
+        assert!(second.is_err());
+
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_held_back_by_the_download_limit() {
+        let content: Vec<u8> = (0..8 * PIECE_LENGTH as u32)
+            .map(|i| (i % 233) as u8)
+            .collect();
+        let dir = tempdir().unwrap();
+        let torrent = test_torrent(&content);
+        let info_hash = torrent.info_hash;
+        let mut downloader =
+            Downloader::from_torrent(torrent, Some(dir.path().to_str().unwrap().to_string()))
+                .await
+                .unwrap();
+        // The bucket holds one block, so the second request empties it for a second
+        downloader.config.download_rate_limit = Some(PIECE_LENGTH as f64);
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        session.add(downloader).await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), session.listen_port().unwrap());
+        let mut seed =
+            PeerConnection::connect(addr, &info_hash, &[9u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        seed.send_message(Message::HaveAll).await.unwrap();
+        seed.send_message(Message::Unchoke).await.unwrap();
+        let is_request = |msg| matches!(msg, Message::Request { .. }).then_some(());
+        read_until(&mut seed, is_request).await;
+        read_until(&mut seed, is_request).await;
+        let third = tokio::time::timeout(
+            Duration::from_millis(500),
+            read_until(&mut seed, is_request),
+        )
+        .await;
+        assert!(third.is_err());
+        read_until(&mut seed, is_request).await;
+
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_uploads_waiting_for_the_limit_can_be_cancelled() {
+        let content: Vec<u8> = (0..3 * PIECE_LENGTH as u32)
+            .map(|i| (i % 229) as u8)
+            .collect();
+        let dir = tempdir().unwrap();
+        let mut downloader = complete_downloader(dir.path(), &content).await;
+        downloader.config.upload_rate_limit = Some(PIECE_LENGTH as f64);
+        let info_hash = downloader.torrent.info_hash;
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        session.add(downloader).await.unwrap();
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), session.listen_port().unwrap());
+        let mut peer =
+            PeerConnection::connect(addr, &info_hash, &[7u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        peer.send_message(Message::Interested).await.unwrap();
+        for index in 0..3 {
+            peer.send_message(Message::Request {
+                index,
+                begin: 0,
+                length: PIECE_LENGTH as u32,
+            })
+            .await
+            .unwrap();
+        }
+        let served = |msg| match msg {
+            Message::Piece { index, .. } => Some(index),
+            _ => None,
+        };
+        assert_eq!(read_until(&mut peer, served).await, 0);
+
+        // Piece 1 waits for the limit to refill; the cancel of piece 2 is answered meanwhile
+        let start = tokio::time::Instant::now();
+        peer.send_message(Message::Cancel {
+            index: 2,
+            begin: 0,
+            length: PIECE_LENGTH as u32,
+        })
+        .await
+        .unwrap();
+        let answer = read_until(&mut peer, |msg| match msg {
+            Message::Reject { index, .. } => Some(Err(index)),
+            Message::Piece { index, .. } => Some(Ok(index)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(answer, Err(2));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(read_until(&mut peer, served).await, 1);
+
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_announces_report_this_sessions_transfers() {
+        let tracker = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        let (query_tx, mut query_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            loop {
+                let (mut stream, _) = tracker.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let body = b"d8:intervali1800e5:peers0:e";
+                let head = format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+                let line = String::from_utf8_lossy(&request)
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                let _ = query_tx.send(line);
+            }
+        });
+
+        let content: Vec<u8> = (0..2 * PIECE_LENGTH as u32)
+            .map(|i| (i % 223) as u8)
+            .collect();
+        let dir = tempdir().unwrap();
+        let mut torrent = test_torrent(&content);
+        torrent.announce = format!("http://{}/announce", tracker_addr);
+        let downloader =
+            Downloader::from_torrent(torrent, Some(dir.path().to_str().unwrap().to_string()))
+                .await
+                .unwrap();
+        // Totals of earlier sessions, as restored from the resume file
+        *downloader.uploaded_bytes.lock().await = 5000;
+        *downloader.downloaded_bytes.lock().await = 7000;
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        let downloader = session.add(downloader).await.unwrap();
+
+        let next_query = async |rx: &mut mpsc::UnboundedReceiver<String>| {
+            tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        let started = next_query(&mut query_rx).await;
+        assert!(started.contains("event=started"));
+        assert!(started.contains("uploaded=0&downloaded=0"));
+
+        downloader.handle().stop();
+        let stopped = next_query(&mut query_rx).await;
+        assert!(stopped.contains("event=stopped"));
+        assert!(stopped.contains("uploaded=0&downloaded=0"));
+        assert!(stopped.contains(&format!("left={}", content.len())));
+
+        session.shutdown().await;
     }
 }
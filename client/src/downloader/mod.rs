@@ -2,12 +2,31 @@
 //!
 //! It manages state, initialization, peer connections, and the main event loop.
 
+mod blocks;
+mod choker;
+mod config;
+mod control;
+mod fast;
 mod init;
+mod limits;
 mod manager;
+mod peer_task;
+mod pex;
+mod picker;
+mod resume;
 mod state;
+mod web_seed;
 
+pub use config::DownloaderConfig;
+pub use control::{DownloadHandle, DownloadState};
+pub(crate) use limits::bucket;
+pub use pex::PexFlags;
+pub use picker::{PiecePicker, PiecePriority};
 pub use state::{Downloader, PieceStatus};
 
+use crate::magnet::ResolvedMagnet;
+use crate::session::{SessionConfig, SharedResources};
+
 impl Downloader {
     /// Creates a new `Downloader` from a torrent file.
     ///
@@ -32,20 +51,161 @@ impl Downloader {
         init::from_torrent(torrent, output_path).await
     }
 
+    /// Creates a new `Downloader` from a resolved magnet link.
+    ///
+    /// The download keeps the peer ID the magnet link was resolved with and takes over
+    /// its peer connections when it starts. Only the files the link selects are downloaded.
+    pub async fn from_magnet(
+        resolved: ResolvedMagnet,
+        output_path: Option<String>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let ResolvedMagnet {
+            magnet,
+            torrent,
+            peer_id,
+            connections,
+            peers,
+        } = resolved;
+        let mut downloader = init::from_torrent(torrent, output_path).await?;
+        downloader.peer_id = peer_id;
+        downloader.known_peers.lock().await.extend(peers);
+        *downloader.connections.lock().await = connections;
+
+        if let Some(ranges) = &magnet.select_only {
+            let file_count = downloader.files.lock().await.layout().files.len();
+            let selected: Vec<usize> = (0..file_count).filter(|&i| magnet.selects(i)).collect();
+            if selected.is_empty() {
+                return Err(format!(
+                    "The magnet link selects none of the torrent's {} files",
+                    file_count
+                )
+                .into());
+            }
+            if ranges.iter().any(|r| *r.end() >= file_count) {
+                eprintln!(
+                    "Ignoring file indices past the torrent's {} files in the magnet link",
+                    file_count
+                );
+            }
+            downloader.select_files(&selected).await?;
+        }
+        Ok(downloader)
+    }
+
     /// Checks the integrity of existing file data.
     ///
-    /// If the output file already exists, this function verifies the hashes of 
-    /// the pieces and marks them as `Have` if they are correct.
+    /// If a resume file matches the files on disk, its state is restored without
+    /// rehashing. Otherwise, if the output file already exists, this function verifies
+    /// the hashes of the pieces and marks them as `Have` if they are correct.
     pub async fn check_existing_data(
         &self,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         init::check_existing_data(self).await
     }
 
+    /// Saves the resume file next to the download, so the next start can skip the recheck.
+    pub async fn save_resume_data(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        resume::save(self).await
+    }
+
+    /// Sets the download priority of a piece.
+    ///
+    /// Pieces with `PiecePriority::Skip` are never requested; higher priorities are
+    /// requested before lower ones. Takes effect for pieces that have not been started yet.
+    pub async fn set_piece_priority(&self, index: usize, priority: PiecePriority) {
+        self.picker.lock().await.set_priority(index, priority);
+    }
+
+    /// Downloads only the given files, by their index in the torrent's file list.
+    ///
+    /// Pieces that hold no byte of a selected file are skipped. The download completes
+    /// once every other piece is verified; pieces shared with a skipped file are still
+    /// downloaded whole.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and skips nothing, if an index is past the torrent's files.
+    pub async fn select_files(
+        &self,
+        files: &[usize],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let layout = self.files.lock().await.layout().clone();
+        if let Some(&index) = files.iter().find(|&&i| i >= layout.files.len()) {
+            return Err(format!(
+                "No file {} in a torrent of {} files",
+                index,
+                layout.files.len()
+            )
+            .into());
+        }
+        let mut picker = self.picker.lock().await;
+        for index in 0..layout.piece_count {
+            let wanted = layout
+                .piece_slices(index)
+                .iter()
+                .any(|slice| files.contains(&slice.file_index));
+            if !wanted {
+                picker.set_priority(index, PiecePriority::Skip);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes of the wanted pieces are still missing, the `left` of
+    /// tracker announces.
+    ///
+    /// Skipped pieces do not count, so a download of selected files is left with
+    /// nothing once those files are complete.
+    pub async fn bytes_left(&self) -> u64 {
+        let layout = self.files.lock().await.layout().clone();
+        let status = self.piece_status.lock().await;
+        let picker = self.picker.lock().await;
+        (0..layout.piece_count)
+            .filter(|&i| {
+                status[i] != PieceStatus::Have && picker.priority(i) != PiecePriority::Skip
+            })
+            .map(|i| layout.piece_len(i))
+            .sum()
+    }
+
+    /// Returns a handle to pause, resume or stop the download from another task.
+    pub fn handle(&self) -> DownloadHandle {
+        self.control.clone()
+    }
+
     /// Starts the main download loop.
     ///
-    /// This function blocks until the download is complete or an error occurs.
+    /// This function blocks until the download is stopped through its `DownloadHandle`
+    /// or Ctrl+C is pressed; once complete, the torrent keeps seeding until then. It
+    /// binds its own listen port and DHT node; use a `Session` to run several torrents
+    /// with shared resources.
     pub async fn run(&self) {
-        manager::run(self).await
+        self.run_with_config(SessionConfig {
+            max_connections: 50,
+            ..SessionConfig::default()
+        })
+        .await
+    }
+
+    /// Starts the main download loop like `run`, with the listen port, DHT node and
+    /// global limits set up from `config`.
+    pub async fn run_with_config(&self, config: SessionConfig) {
+        let shared = SharedResources::start(&config).await;
+        let handle = self.handle();
+        let ctrl_c = tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("Ctrl+C received, shutting down.");
+                handle.stop();
+            }
+        });
+        manager::run(self, &shared).await;
+        ctrl_c.abort();
+        shared.shutdown().await;
+    }
+
+    /// Runs the main download loop with resources shared with other torrents, until
+    /// the download is stopped.
+    pub(crate) async fn run_with(&self, shared: &SharedResources) {
+        manager::run(self, shared).await
     }
 }
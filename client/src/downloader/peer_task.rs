@@ -0,0 +1,1066 @@
+//! The per-peer task shared by outgoing and inbound connections.
+
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tds_core::Torrent;
+use tds_core::bencoding::{Bencode, decode};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, broadcast, mpsc, watch};
+use tokio::time::Instant;
+
+use super::blocks::{BlockOutcome, BlockRequest, BlockTracker};
+use super::choker::{Choker, PeerStats};
+use super::control::{self, DownloadState};
+use super::fast::{self, ALLOWED_FAST_COUNT, MAX_FAST_PIECES};
+use super::limits::RateLimit;
+use super::pex::{self, Candidate, PEX_INTERVAL, PexFlags, PexState, UT_PEX_ID};
+use super::picker::PiecePicker;
+use super::state::PieceStatus;
+use crate::magnet::{METADATA_PIECE_LEN, UT_METADATA_ID};
+use crate::peer::{Message, PeerConnection};
+use crate::storage::TorrentFiles;
+
+type PeerResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Requests of one peer we queue for upload; further ones are rejected.
+const MAX_QUEUED_UPLOADS: usize = 256;
+
+/// Shared download state handed to every peer task.
+#[derive(Clone)]
+pub(super) struct PeerContext {
+    pub torrent: Arc<Torrent>,
+    pub piece_status: Arc<Mutex<Vec<PieceStatus>>>,
+    /// Swarm availability and priorities used to choose new pieces.
+    pub picker: Arc<Mutex<PiecePicker>>,
+    /// Blocks of the pieces currently being downloaded, shared by all peers.
+    pub blocks: Arc<Mutex<BlockTracker>>,
+    pub files: Arc<Mutex<TorrentFiles>>,
+    pub uploaded_total: Arc<Mutex<u64>>,
+    pub downloaded_total: Arc<Mutex<u64>>,
+    /// Limits the rate of the blocks we request.
+    pub download_limit: RateLimit,
+    /// Limits the rate of blocks we upload.
+    pub upload_limit: RateLimit,
+    /// Whether the download is running, paused or stopped.
+    pub state: watch::Receiver<DownloadState>,
+    /// Decides which peers are unchoked.
+    pub choker: Arc<Mutex<Choker>>,
+    /// Maximum number of block requests in flight per peer.
+    pub pipeline_depth: usize,
+    /// How long a peer may leave a request unanswered before it counts as snubbing us.
+    pub request_timeout: Duration,
+    /// The port we accept peers on, if the listener is running.
+    pub listen_port: Option<u16>,
+    /// Our global IPv6 address, offered to IPv4 peers in the extended handshake.
+    pub local_ipv6: Option<Ipv6Addr>,
+    /// Queue of newly discovered peers (fed by PEX).
+    pub peer_tx: mpsc::Sender<Candidate>,
+    /// Set to `true` when the torrent stops; every peer task then exits.
+    pub shutdown: watch::Sender<bool>,
+    /// Set to `true` once every wanted piece is verified. The torrent then seeds: peers
+    /// stay connected and are served, but nothing more is requested from them.
+    pub completed: watch::Sender<bool>,
+    /// Announces end-game blocks as they arrive, so other peers cancel their duplicate requests.
+    pub block_tx: broadcast::Sender<BlockRequest>,
+    /// Addresses of peers we currently have a task for.
+    pub connected_peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    /// The connected peers we tell others about in PEX messages, at the address they
+    /// accept connections on.
+    pub pex_swarm: Arc<Mutex<HashMap<SocketAddr, PexFlags>>>,
+}
+
+impl PeerContext {
+    /// Verifies a fully assembled piece and writes it to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    /// * `data` - The assembled piece.
+    /// * `from` - Where the piece was downloaded from, for the log.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the piece was stored, `false` if its hash did not match and it
+    ///   is to be downloaded again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the piece could not be written; it is then downloaded again.
+    pub async fn store_piece(
+        &self,
+        index: usize,
+        data: &[u8],
+        from: impl std::fmt::Display,
+    ) -> PeerResult<bool> {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        let hash = hasher.finalize();
+
+        if hash.as_slice() != self.torrent.pieces[index] {
+            eprintln!("Piece {} hash mismatch, requesting it again", index);
+            self.piece_status.lock().await[index] = PieceStatus::Missing;
+            return Ok(false);
+        }
+
+        println!("Piece {} verified from {}!", index, from);
+        // Split the piece across the torrent's files
+        if let Err(e) = self.files.lock().await.write_piece(index, data).await {
+            self.piece_status.lock().await[index] = PieceStatus::Missing;
+            return Err(format!("Write error: {}", e).into());
+        }
+
+        self.piece_status.lock().await[index] = PieceStatus::Have;
+        let complete = {
+            let mut picker = self.picker.lock().await;
+            picker.piece_verified(index);
+            picker.remaining() == 0
+        };
+
+        let mut d_total = self.downloaded_total.lock().await;
+        *d_total += data.len() as u64;
+        println!(
+            "Downloaded piece {} from {} (Total: {})",
+            index, from, *d_total
+        );
+        drop(d_total);
+
+        if complete {
+            self.completed.send_replace(true);
+        }
+        Ok(true)
+    }
+
+    /// Returns `true` once every piece not skipped is downloaded and verified.
+    pub async fn is_complete(&self) -> bool {
+        self.picker.lock().await.remaining() == 0
+    }
+}
+
+/// Drives a single, already handshaken peer connection until it closes or the torrent stops.
+///
+/// The task is the same for connections we dialed and connections accepted by the listener.
+/// When it returns, any blocks still requested from the peer are handed back to the
+/// shared pool, the peer's pieces are removed from the picker's availability and
+/// the peer's address is released from `connected_peers`, the choker and PEX.
+pub(super) async fn run(ctx: PeerContext, peer: PeerConnection) {
+    let addr = peer.addr();
+    // Peers we dialed accept connections; others tell us their port in the extended handshake
+    let listen_addr = peer.is_outbound().then_some(addr);
+    let piece_count = ctx.torrent.pieces.len();
+    let (stats, choke_rx) = ctx.choker.lock().await.register(addr);
+    let offered_fast = if peer.supports_fast() {
+        fast::allowed_fast_set(
+            addr.ip(),
+            &ctx.torrent.info_hash,
+            piece_count as u32,
+            ALLOWED_FAST_COUNT,
+        )
+    } else {
+        Vec::new()
+    };
+    let mut session = PeerSession {
+        ctx,
+        peer,
+        addr,
+        counted: vec![false; piece_count],
+        requests: Vec::new(),
+        snubbed: false,
+        paused: false,
+        unchoked: false,
+        stats,
+        pex_id: None,
+        pex: PexState::default(),
+        listen_addr,
+        metadata_id: None,
+        offered_fast,
+        allowed_fast: HashSet::new(),
+        suggested: HashSet::new(),
+        uploaded_session: 0,
+        request_due: None,
+        uploads: VecDeque::new(),
+        upload_due: None,
+    };
+
+    if let Err(e) = session.exchange(choke_rx).await {
+        eprintln!("Closing connection to {}: {}", addr, e);
+    }
+
+    session.release_requests().await;
+    session.forget_availability().await;
+    session.ctx.choker.lock().await.unregister(&addr);
+    if let Some(listen_addr) = session.listen_addr {
+        session.ctx.pex_swarm.lock().await.remove(&listen_addr);
+    }
+    session.ctx.connected_peers.lock().await.remove(&addr);
+}
+
+/// The state of one peer connection, from our point of view.
+struct PeerSession {
+    ctx: PeerContext,
+    peer: PeerConnection,
+    addr: SocketAddr,
+    /// Pieces of this peer that are counted in the picker's availability.
+    counted: Vec<bool>,
+    /// Our block requests that the peer has not answered yet, with when they were sent.
+    requests: Vec<(BlockRequest, Instant)>,
+    /// Whether the peer let a request time out; until it sends a block again, only one
+    /// request at a time is sent to it.
+    snubbed: bool,
+    /// Whether the download is paused: the peer is choked and we request nothing.
+    paused: bool,
+    /// Whether the choker picked this peer to be unchoked.
+    unchoked: bool,
+    /// Transfer counters read by the choker.
+    stats: Arc<PeerStats>,
+    /// The peer's extended message ID for `ut_pex`, if it supports PEX.
+    pex_id: Option<u8>,
+    /// What we told the peer in our PEX messages.
+    pex: PexState,
+    /// Where the peer accepts connections, once known; it is advertised there in PEX.
+    listen_addr: Option<SocketAddr>,
+    /// The peer's extended message ID for `ut_metadata`, if it fetches metadata from us.
+    metadata_id: Option<u8>,
+    /// Our allowed fast set for the peer: pieces it may download while choked.
+    offered_fast: Vec<u32>,
+    /// Pieces the peer lets us download while it chokes us.
+    allowed_fast: HashSet<u32>,
+    /// Pieces the peer suggested we download.
+    suggested: HashSet<u32>,
+    /// Bytes uploaded to this peer.
+    uploaded_session: u64,
+    /// Until when the download limit holds off new requests.
+    request_due: Option<Instant>,
+    /// Requests of the peer waiting for the upload limit, in the order they came in.
+    uploads: VecDeque<BlockRequest>,
+    /// When the first queued upload, already counted against the upload limit, may be sent.
+    upload_due: Option<Instant>,
+}
+
+impl PeerSession {
+    /// Exchanges messages with the peer: uploads requested blocks and downloads missing pieces.
+    ///
+    /// Returns `Ok(())` when the torrent stops, or an error when the connection fails.
+    /// Once the download completes the peer is told we are no longer interested, and
+    /// the connection stays open so the peer can download from us.
+    ///
+    /// # Arguments
+    ///
+    /// * `choke_rx` - The choker's decision for this peer; `true` while it should be choked.
+    async fn exchange(&mut self, mut choke_rx: watch::Receiver<bool>) -> PeerResult<()> {
+        let mut shutdown_rx = self.ctx.shutdown.subscribe();
+        let mut block_rx = self.ctx.block_tx.subscribe();
+        let mut state_rx = self.ctx.state.clone();
+        let mut completed_rx = self.ctx.completed.subscribe();
+        let mut pex_timer = tokio::time::interval(PEX_INTERVAL);
+
+        self.advertise().await;
+        self.send_bitfield().await?;
+        self.send_extended_handshake().await?;
+        let state = *state_rx.borrow_and_update();
+        self.apply_state(state).await?;
+
+        loop {
+            let (request_due, upload_due) = (self.request_due, self.upload_due);
+            let keepalive_due = self.peer.keepalive_due();
+            let request_timeout = self
+                .requests
+                .iter()
+                .map(|&(_, sent)| sent + self.ctx.request_timeout)
+                .min();
+            tokio::select! {
+                res = state_rx.changed() => {
+                    if res.is_err() {
+                        return Ok(());
+                    }
+                    let state = *state_rx.borrow_and_update();
+                    self.apply_state(state).await?;
+                }
+                res = completed_rx.changed() => {
+                    if res.is_err() {
+                        return Ok(());
+                    }
+                    completed_rx.borrow_and_update();
+                    self.update_interest().await?;
+                }
+                res = choke_rx.changed() => {
+                    if res.is_err() {
+                        return Ok(());
+                    }
+                    self.unchoked = !*choke_rx.borrow_and_update();
+                    self.update_choke().await?;
+                }
+                res = self.peer.read_message() => self.handle_message(res?).await?,
+                res = block_rx.recv() => match res {
+                    Ok(block) => self.cancel_request(block).await?,
+                    // Missed cancels only cost some duplicate data
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = pex_timer.tick() => self.send_pex().await?,
+                // Keeps idle connections, such as those of a paused or seeding torrent, open
+                _ = tokio::time::sleep_until(keepalive_due) => {
+                    self.peer.send_message(Message::KeepAlive).await?
+                }
+                _ = sleep_until(request_due) => self.request_due = None,
+                _ = sleep_until(request_timeout) => self.time_out_requests().await?,
+                _ = sleep_until(upload_due) => {
+                    self.upload_due = None;
+                    if let Some(req) = self.uploads.pop_front() {
+                        self.upload(req).await?;
+                    }
+                }
+                _ = control::wait_until(&mut shutdown_rx, |&stop| stop) => return Ok(()),
+            }
+
+            self.fill_pipeline().await?;
+            self.serve_uploads().await?;
+        }
+    }
+
+    /// Follows the download state: a paused download chokes the peer, cancels our
+    /// requests and stops being interested; resuming expresses interest again, unless
+    /// we are seeding, and restores the choker's decision.
+    async fn apply_state(&mut self, state: DownloadState) -> PeerResult<()> {
+        match state {
+            DownloadState::Running => {
+                self.paused = false;
+                self.update_choke().await?;
+                self.update_interest().await?;
+            }
+            DownloadState::Paused => {
+                self.paused = true;
+                self.update_choke().await?;
+                for (req, _) in self.requests.clone() {
+                    self.cancel_request(req).await?;
+                }
+                self.update_interest().await?;
+            }
+            // The manager disconnects every peer
+            DownloadState::Stopped => {}
+        }
+        Ok(())
+    }
+
+    /// Chokes or unchokes the peer to match the choker's decision. While the download
+    /// is paused the peer stays choked.
+    async fn update_choke(&mut self) -> PeerResult<()> {
+        let choke = self.paused || !self.unchoked;
+        if choke != self.peer.am_choking {
+            let msg = if choke {
+                Message::Choke
+            } else {
+                Message::Unchoke
+            };
+            self.peer.send_message(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the peer whether we are interested: while the download runs and wanted
+    /// pieces are missing. A seeding torrent is not interested in anyone.
+    async fn update_interest(&mut self) -> PeerResult<()> {
+        let interested = !self.paused && !*self.ctx.completed.borrow();
+        if interested != self.peer.am_interested {
+            let msg = if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            self.peer.send_message(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends our bitfield if we have any pieces.
+    ///
+    /// With the Fast extension, `HaveAll` or `HaveNone` replaces it when we have every
+    /// piece or none, and the pieces of the peer's allowed fast set that we have follow.
+    async fn send_bitfield(&mut self) -> PeerResult<()> {
+        let fast = self.peer.supports_fast();
+        let (msg, allowed) = {
+            let status = self.ctx.piece_status.lock().await;
+            let msg = if !status.contains(&PieceStatus::Have) {
+                fast.then_some(Message::HaveNone)
+            } else if fast && status.iter().all(|&s| s == PieceStatus::Have) {
+                Some(Message::HaveAll)
+            } else {
+                let mut bitfield = vec![0u8; status.len().div_ceil(8)];
+                for (i, s) in status.iter().enumerate() {
+                    if *s == PieceStatus::Have {
+                        bitfield[i / 8] |= 1 << (7 - (i % 8));
+                    }
+                }
+                Some(Message::Bitfield(bitfield))
+            };
+            let allowed: Vec<u32> = self
+                .offered_fast
+                .iter()
+                .copied()
+                .filter(|&i| status[i as usize] == PieceStatus::Have)
+                .collect();
+            (msg, allowed)
+        };
+        if let Some(msg) = msg {
+            self.peer.send_message(msg).await?;
+        }
+        for index in allowed {
+            self.peer.send_message(Message::AllowedFast(index)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the BEP 10 extended handshake, advertising PEX support and, when we have
+    /// the info dictionary, the metadata and its size (BEP 9).
+    ///
+    /// It also tells the peer its address as we see it (`yourip`), and where else it can
+    /// reach us: our listen port (`p`) and our IPv6 address (`ipv6`).
+    async fn send_extended_handshake(&mut self) -> PeerResult<()> {
+        let mut m = BTreeMap::new();
+        m.insert(b"ut_pex".to_vec(), Bencode::Int(UT_PEX_ID as i64));
+        let info_len = self.ctx.torrent.info.len();
+        if info_len > 0 {
+            m.insert(b"ut_metadata".to_vec(), Bencode::Int(UT_METADATA_ID as i64));
+        }
+        let mut handshake = BTreeMap::new();
+        handshake.insert(b"m".to_vec(), Bencode::Dict(m));
+        if info_len > 0 {
+            handshake.insert(b"metadata_size".to_vec(), Bencode::Int(info_len as i64));
+        }
+        if let Some(port) = self.ctx.listen_port {
+            handshake.insert(b"p".to_vec(), Bencode::Int(port as i64));
+        }
+        if let Some(ipv6) = self.ctx.local_ipv6 {
+            handshake.insert(b"ipv6".to_vec(), Bencode::Bytes(ipv6.octets().to_vec()));
+        }
+        let yourip = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        handshake.insert(b"yourip".to_vec(), Bencode::Bytes(yourip));
+        let payload = Bencode::Dict(handshake).encode();
+        self.peer
+            .send_message(Message::Extended { id: 0, payload })
+            .await
+    }
+
+    async fn handle_message(&mut self, msg: Message) -> PeerResult<()> {
+        match msg {
+            Message::Choke if self.peer.supports_fast() => {
+                // Every request stays outstanding: the peer either serves it or rejects
+                // it, and a `Reject` hands the block back to the pool
+            }
+            Message::Choke => {
+                // A choking peer discards our pending requests
+                self.release_requests().await;
+            }
+            Message::Unchoke => {
+                println!("{} unchoked us", self.addr);
+            }
+            Message::Interested | Message::NotInterested => {
+                self.stats
+                    .interested
+                    .store(self.peer.peer_interested, Ordering::Relaxed);
+            }
+            Message::Have(_) | Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                self.update_availability().await
+            }
+            Message::Suggest(index) => self.remember_fast_piece(index, true).await,
+            Message::AllowedFast(index) => self.remember_fast_piece(index, false).await,
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => {
+                if let Some(pos) = self
+                    .requests
+                    .iter()
+                    .position(|(r, _)| r.index == index && r.begin == begin && r.length == length)
+                {
+                    let (req, _) = self.requests.swap_remove(pos);
+                    self.ctx.blocks.lock().await.release(&req);
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                self.queue_request(BlockRequest {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let req = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                if let Some(pos) = self.uploads.iter().position(|r| *r == req) {
+                    self.uploads.remove(pos);
+                    // The Fast extension answers every request, cancelled ones too
+                    self.reject(index, begin, length).await?;
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // The block was counted against the download limit when it was requested
+                self.stats
+                    .downloaded
+                    .fetch_add(block.len() as u64, Ordering::Relaxed);
+                self.receive_block(index, begin, block).await?
+            }
+            Message::Extended { id, payload } => self.handle_extended(id, &payload).await?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Queues a request of the peer if we have the piece and the peer is unchoked, or
+    /// the piece is in its allowed fast set.
+    ///
+    /// Requests we do not serve are rejected if the peer supports the Fast extension,
+    /// and dropped otherwise.
+    async fn queue_request(&mut self, req: BlockRequest) -> PeerResult<()> {
+        if !self.may_upload(req.index) {
+            // Requests of a choked peer are discarded
+            return self.reject(req.index, req.begin, req.length).await;
+        }
+        if req.length > 128 * 1024 {
+            eprintln!("Requested block too large: {}", req.length);
+            return self.reject(req.index, req.begin, req.length).await;
+        }
+        if self.uploads.len() >= MAX_QUEUED_UPLOADS {
+            return self.reject(req.index, req.begin, req.length).await;
+        }
+
+        let have = self
+            .ctx
+            .piece_status
+            .lock()
+            .await
+            .get(req.index as usize)
+            .is_some_and(|&s| s == PieceStatus::Have);
+        if !have {
+            return self.reject(req.index, req.begin, req.length).await;
+        }
+        self.uploads.push_back(req);
+        Ok(())
+    }
+
+    /// Returns `true` if the peer may download `index` from us right now.
+    fn may_upload(&self, index: u32) -> bool {
+        !self.peer.am_choking || (!self.paused && self.offered_fast.contains(&index))
+    }
+
+    /// Uploads queued requests for as long as the upload limit lets us.
+    ///
+    /// Once the limit runs dry, the next request is counted against it and waits in
+    /// `upload_due` for the exchange loop, which meanwhile goes on handling messages.
+    async fn serve_uploads(&mut self) -> PeerResult<()> {
+        while self.upload_due.is_none()
+            && let Some(&req) = self.uploads.front()
+        {
+            if self.may_upload(req.index) {
+                let delay = self.ctx.upload_limit.reserve(req.length as usize).await;
+                if !delay.is_zero() {
+                    self.upload_due = Some(Instant::now() + delay);
+                    return Ok(());
+                }
+            }
+            self.uploads.pop_front();
+            self.upload(req).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a queued block, unless the peer was choked since it asked for it.
+    async fn upload(&mut self, req: BlockRequest) -> PeerResult<()> {
+        let BlockRequest {
+            index,
+            begin,
+            length,
+        } = req;
+        if !self.may_upload(index) {
+            return self.reject(index, begin, length).await;
+        }
+
+        let read = self
+            .ctx
+            .files
+            .lock()
+            .await
+            .read_block(index as usize, begin as u64, length as u64)
+            .await;
+        let block = match read {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!("Read error: {}", e);
+                return self.reject(index, begin, length).await;
+            }
+        };
+
+        self.peer
+            .send_message(Message::Piece {
+                index,
+                begin,
+                block,
+            })
+            .await?;
+
+        let mut uploaded = self.ctx.uploaded_total.lock().await;
+        *uploaded += length as u64;
+        self.uploaded_session += length as u64;
+        self.stats
+            .uploaded
+            .fetch_add(length as u64, Ordering::Relaxed);
+        println!(
+            "Uploaded {} bytes to {} (Session: {}, Total: {})",
+            length, self.addr, self.uploaded_session, *uploaded
+        );
+        Ok(())
+    }
+
+    /// Tells the peer that we will not serve its request, if it supports the Fast extension.
+    async fn reject(&mut self, index: u32, begin: u32, length: u32) -> PeerResult<()> {
+        if !self.peer.supports_fast() {
+            return Ok(());
+        }
+        self.peer
+            .send_message(Message::Reject {
+                index,
+                begin,
+                length,
+            })
+            .await
+    }
+
+    /// Records a piece of a `Suggest` or `AllowedFast` message from the peer.
+    ///
+    /// At most `MAX_FAST_PIECES` of each are kept; suggestions of pieces we got since
+    /// make room for new ones.
+    async fn remember_fast_piece(&mut self, index: u32, suggested: bool) {
+        if index as usize >= self.ctx.torrent.pieces.len() {
+            return;
+        }
+        let pieces = if suggested {
+            &mut self.suggested
+        } else {
+            &mut self.allowed_fast
+        };
+        if suggested && pieces.len() >= MAX_FAST_PIECES {
+            let status = self.ctx.piece_status.lock().await;
+            pieces.retain(|&i| status[i as usize] != PieceStatus::Have);
+        }
+        if pieces.len() < MAX_FAST_PIECES {
+            pieces.insert(index);
+        }
+    }
+
+    /// Stores a received block and, if it completes a piece, verifies and writes the piece.
+    async fn receive_block(&mut self, index: u32, begin: u32, block: Vec<u8>) -> PeerResult<()> {
+        let Some(pos) = self
+            .requests
+            .iter()
+            .position(|(r, _)| r.index == index && r.begin == begin)
+        else {
+            // Unsolicited or already released block
+            return Ok(());
+        };
+        if self.requests[pos].0.length as usize != block.len() {
+            // Not the block we asked for; the request stays outstanding
+            return Ok(());
+        }
+        let (req, _) = self.requests.swap_remove(pos);
+        self.snubbed = false;
+
+        let (outcome, duplicated) = {
+            let mut blocks = self.ctx.blocks.lock().await;
+            let duplicated = blocks.requesters(index, begin) > 1;
+            (blocks.block_received(index, begin, &block), duplicated)
+        };
+        if duplicated && outcome != BlockOutcome::Ignored {
+            // End game: the block is also requested from other peers
+            let _ = self.ctx.block_tx.send(req);
+        }
+        if let BlockOutcome::PieceComplete(data) = outcome {
+            self.complete_piece(index as usize, data).await?;
+        }
+        Ok(())
+    }
+
+    /// Verifies a fully assembled piece, writes it to disk and tells the peer we have it.
+    async fn complete_piece(&mut self, index: usize, data: Vec<u8>) -> PeerResult<()> {
+        if self.ctx.store_piece(index, &data, self.addr).await? {
+            self.peer.send_message(Message::Have(index as u32)).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles BEP 10 extended messages (handshake, metadata requests and PEX).
+    async fn handle_extended(&mut self, id: u8, payload: &[u8]) -> PeerResult<()> {
+        let mut pos = 0;
+        let Ok(Bencode::Dict(dict)) = decode(payload, &mut pos) else {
+            return Ok(());
+        };
+
+        if id == 0 {
+            if let Some(Bencode::Dict(m)) = dict.get(&b"m"[..]) {
+                if let Some(Bencode::Int(pex_id)) = m.get(&b"ut_pex"[..])
+                    && *pex_id > 0
+                {
+                    self.pex_id = Some(*pex_id as u8);
+                    println!("Peer {} supports PEX with ID {}", self.addr, pex_id);
+                }
+                // An ID of 0 means the peer disabled the extension
+                self.metadata_id = match m.get(&b"ut_metadata"[..]) {
+                    Some(Bencode::Int(id)) if *id > 0 => Some(*id as u8),
+                    _ => None,
+                };
+            }
+            if let Some(Bencode::Int(port)) = dict.get(&b"p"[..])
+                && let Ok(port) = u16::try_from(*port)
+                && port > 0
+            {
+                if self.listen_addr.is_none() {
+                    self.listen_addr = Some(SocketAddr::new(self.addr.ip(), port));
+                    self.advertise().await;
+                }
+                // The peer may also be reachable over IPv6, at its listen port
+                if let Some(Bencode::Bytes(ipv6)) = dict.get(&b"ipv6"[..])
+                    && let Ok(ipv6) = <[u8; 16]>::try_from(ipv6.as_slice())
+                {
+                    let addr = SocketAddr::new(Ipv6Addr::from(ipv6).into(), port);
+                    if addr != self.addr {
+                        let _ = self.ctx.peer_tx.send(addr.into()).await;
+                    }
+                }
+            }
+            // The first PEX message need not wait for the timer
+            self.send_pex().await?;
+        } else if id == UT_METADATA_ID {
+            // Requests are the only metadata messages sent to a peer that has the metadata
+            if let Some(Bencode::Int(0)) = dict.get(&b"msg_type"[..])
+                && let Some(Bencode::Int(piece)) = dict.get(&b"piece"[..])
+                && let Some(metadata_id) = self.metadata_id
+            {
+                let payload = metadata_reply(&self.ctx.torrent.info, *piece);
+                self.peer
+                    .send_message(Message::Extended {
+                        id: metadata_id,
+                        payload,
+                    })
+                    .await?;
+            }
+        } else if id == UT_PEX_ID {
+            for candidate in pex::parse_message(&dict) {
+                println!("PEX found peer: {}", candidate.addr);
+                let _ = self.ctx.peer_tx.send(candidate).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the peer the changes to our swarm since our previous PEX message, if it
+    /// supports PEX and `PEX_INTERVAL` has passed.
+    async fn send_pex(&mut self) -> PeerResult<()> {
+        let Some(pex_id) = self.pex_id else {
+            return Ok(());
+        };
+        let exclude: Vec<SocketAddr> = [Some(self.addr), self.listen_addr]
+            .into_iter()
+            .flatten()
+            .collect();
+        let payload = {
+            let swarm = self.ctx.pex_swarm.lock().await;
+            self.pex
+                .next_message(&swarm, &exclude, Instant::now().into_std())
+        };
+        match payload {
+            Some(payload) => {
+                self.peer
+                    .send_message(Message::Extended {
+                        id: pex_id,
+                        payload,
+                    })
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Records the peer in the PEX swarm, with its current flags, once we know where it
+    /// accepts connections.
+    async fn advertise(&self) {
+        let Some(listen_addr) = self.listen_addr else {
+            return;
+        };
+        let mut flags = PexFlags::default();
+        if self.peer.is_outbound() {
+            flags.insert(PexFlags::REACHABLE);
+        }
+        if self.peer.is_encrypted() {
+            flags.insert(PexFlags::ENCRYPTION);
+        }
+        if self.peer.is_utp() {
+            flags.insert(PexFlags::UTP);
+        }
+        if self.counted.iter().all(|&c| c) {
+            flags.insert(PexFlags::SEED);
+        }
+        self.ctx.pex_swarm.lock().await.insert(listen_addr, flags);
+    }
+
+    /// Counts pieces the peer announced since the last update in the picker's availability.
+    async fn update_availability(&mut self) {
+        let was_seed = self.counted.iter().all(|&c| c);
+        let mut picker = self.ctx.picker.lock().await;
+        for (i, counted) in self.counted.iter_mut().enumerate() {
+            if !*counted && self.peer.has_piece(i as u32) {
+                picker.add_piece(i);
+                *counted = true;
+            }
+        }
+        drop(picker);
+        if !was_seed && self.counted.iter().all(|&c| c) {
+            self.advertise().await;
+        }
+    }
+
+    /// Removes the peer's pieces from the picker's availability when it disconnects.
+    async fn forget_availability(&mut self) {
+        let mut picker = self.ctx.picker.lock().await;
+        for (i, counted) in self.counted.iter_mut().enumerate() {
+            if *counted {
+                picker.remove_piece(i);
+                *counted = false;
+            }
+        }
+    }
+
+    /// Withdraws an outstanding request: sends `Cancel` and returns the block to the pool.
+    ///
+    /// Used when another peer delivered the block first (end game), when the peer let
+    /// the request time out and when pausing.
+    async fn cancel_request(&mut self, block: BlockRequest) -> PeerResult<()> {
+        let Some(pos) = self.requests.iter().position(|(r, _)| *r == block) else {
+            return Ok(());
+        };
+        let (req, _) = self.requests.swap_remove(pos);
+        self.ctx.blocks.lock().await.release(&req);
+        self.peer
+            .send_message(Message::Cancel {
+                index: req.index,
+                begin: req.begin,
+                length: req.length,
+            })
+            .await
+    }
+
+    /// Withdraws the requests the peer left unanswered for `request_timeout`, so other
+    /// peers can fetch the blocks, and marks the peer snubbed.
+    ///
+    /// This also catches a peer with the Fast extension that chokes us and never
+    /// rejects our requests.
+    async fn time_out_requests(&mut self) -> PeerResult<()> {
+        let now = Instant::now();
+        let expired: Vec<BlockRequest> = self
+            .requests
+            .iter()
+            .filter(|&&(_, sent)| sent + self.ctx.request_timeout <= now)
+            .map(|&(req, _)| req)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "{} left {} requests unanswered; requesting one block at a time",
+            self.addr,
+            expired.len()
+        );
+        self.snubbed = true;
+        for req in expired {
+            self.cancel_request(req).await?;
+        }
+        Ok(())
+    }
+
+    /// Keeps up to `pipeline_depth` block requests in flight while the peer is unchoking
+    /// us, or a single one while it snubs us.
+    ///
+    /// While the peer chokes us, only pieces of its allowed fast set are requested.
+    /// Pieces it suggested come first. In end-game mode, blocks already requested from
+    /// other peers are requested too.
+    async fn fill_pipeline(&mut self) -> PeerResult<()> {
+        let choked = self.peer.peer_choking;
+        if self.paused
+            || self.request_due.is_some()
+            || *self.ctx.completed.borrow()
+            || (choked && self.allowed_fast.is_empty())
+        {
+            return Ok(());
+        }
+
+        let mut new_requests = Vec::new();
+        {
+            let mut status = self.ctx.piece_status.lock().await;
+            let picker = self.ctx.picker.lock().await;
+            let mut blocks = self.ctx.blocks.lock().await;
+            let has_piece = |i: usize| {
+                self.peer.has_piece(i as u32)
+                    && (!choked || self.allowed_fast.contains(&(i as u32)))
+            };
+            let suggested = |i: usize| has_piece(i) && self.suggested.contains(&(i as u32));
+            let depth = if self.snubbed {
+                1
+            } else {
+                self.ctx.pipeline_depth
+            };
+            while self.requests.len() + new_requests.len() < depth {
+                if let Some(req) = blocks
+                    .next_request(&mut status, &picker, suggested)
+                    .or_else(|| blocks.next_request(&mut status, &picker, has_piece))
+                {
+                    new_requests.push(req);
+                    continue;
+                }
+                if !blocks.is_endgame(&status, &picker) {
+                    break;
+                }
+                let already_requested = |r: &BlockRequest| {
+                    self.requests.iter().any(|(sent, _)| sent == r) || new_requests.contains(r)
+                };
+                match blocks.next_endgame_request(has_piece, already_requested) {
+                    Some(req) => new_requests.push(req),
+                    None => break,
+                }
+            }
+        }
+
+        // Track the requests before sending so they are released if sending fails
+        let now = Instant::now();
+        self.requests
+            .extend(new_requests.iter().map(|&req| (req, now)));
+        let mut unsent = new_requests.into_iter();
+        for req in unsent.by_ref() {
+            self.peer
+                .send_message(Message::Request {
+                    index: req.index,
+                    begin: req.begin,
+                    length: req.length,
+                })
+                .await?;
+            // Requested blocks count against the download limit; once it runs dry,
+            // further requests wait until it has refilled
+            let delay = self.ctx.download_limit.reserve(req.length as usize).await;
+            if !delay.is_zero() {
+                self.request_due = Some(Instant::now() + delay);
+                break;
+            }
+        }
+
+        let unsent: Vec<_> = unsent.collect();
+        if !unsent.is_empty() {
+            self.requests.retain(|(r, _)| !unsent.contains(r));
+            let mut blocks = self.ctx.blocks.lock().await;
+            for req in &unsent {
+                blocks.release(req);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns all outstanding requests to the shared pool.
+    async fn release_requests(&mut self) {
+        if self.requests.is_empty() {
+            return;
+        }
+        let mut blocks = self.ctx.blocks.lock().await;
+        for (req, _) in self.requests.drain(..) {
+            blocks.release(&req);
+        }
+    }
+}
+
+/// Waits until `due`, or forever if it is `None`.
+async fn sleep_until(due: Option<Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Builds our answer to a ut_metadata request (BEP 9).
+///
+/// # Arguments
+///
+/// * `info` - The bencoded info dictionary.
+/// * `piece` - The requested metadata piece.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - A data message followed by the piece, or a reject message if there is no
+///   such piece.
+fn metadata_reply(info: &[u8], piece: i64) -> Vec<u8> {
+    let mut reply = BTreeMap::new();
+    reply.insert(b"piece".to_vec(), Bencode::Int(piece));
+    let start = usize::try_from(piece)
+        .ok()
+        .and_then(|p| p.checked_mul(METADATA_PIECE_LEN))
+        .filter(|&start| start < info.len());
+    let Some(start) = start else {
+        reply.insert(b"msg_type".to_vec(), Bencode::Int(2));
+        return Bencode::Dict(reply).encode();
+    };
+
+    let end = (start + METADATA_PIECE_LEN).min(info.len());
+    reply.insert(b"msg_type".to_vec(), Bencode::Int(1));
+    reply.insert(b"total_size".to_vec(), Bencode::Int(info.len() as i64));
+    let mut payload = Bencode::Dict(reply).encode();
+    payload.extend_from_slice(&info[start..end]);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_reply_sends_pieces_and_rejects_others() {
+        let info: Vec<u8> = (0..METADATA_PIECE_LEN + 10).map(|i| i as u8).collect();
+
+        let reply = metadata_reply(&info, 1);
+        let mut pos = 0;
+        let Bencode::Dict(dict) = decode(&reply, &mut pos).unwrap() else {
+            panic!("reply is not a dictionary");
+        };
+        assert_eq!(dict.get(&b"msg_type"[..]), Some(&Bencode::Int(1)));
+        assert_eq!(
+            dict.get(&b"total_size"[..]),
+            Some(&Bencode::Int(info.len() as i64))
+        );
+        assert_eq!(&reply[pos..], &info[METADATA_PIECE_LEN..]);
+
+        for piece in [2, -1] {
+            let reply = metadata_reply(&info, piece);
+            let Bencode::Dict(dict) = decode(&reply, &mut 0).unwrap() else {
+                panic!("reply is not a dictionary");
+            };
+            assert_eq!(dict.get(&b"msg_type"[..]), Some(&Bencode::Int(2)));
+            assert_eq!(reply.len(), Bencode::Dict(dict).encode().len());
+        }
+    }
+}
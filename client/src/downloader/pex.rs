@@ -0,0 +1,259 @@
+//! Peer exchange (BEP 11).
+//!
+//! Peers that support `ut_pex` are told which peers we connected to and which we
+//! dropped since our previous message. A message goes out at most once every
+//! `PEX_INTERVAL` and lists at most `MAX_PEX_PEERS` added and dropped peers each; the
+//! rest follow in later messages. Peers learned this way are queued for connection
+//! with the flags the sender gave them.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tds_core::bencoding::Bencode;
+use tds_core::compact;
+
+/// The extended message ID we assign to ut_pex; peers send us PEX messages with it.
+pub const UT_PEX_ID: u8 = 1;
+
+/// The minimum time between two PEX messages to the same peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of added peers, and of dropped peers, in one PEX message. Received
+/// messages are cut to the same size.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// What a PEX message says about a peer, in the `added.f` and `added6.f` keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    /// The peer prefers encrypted connections.
+    pub const ENCRYPTION: PexFlags = PexFlags(0x01);
+    /// The peer is a seed.
+    pub const SEED: PexFlags = PexFlags(0x02);
+    /// The peer supports uTP.
+    pub const UTP: PexFlags = PexFlags(0x04);
+    /// The peer supports the holepunch extension.
+    pub const HOLEPUNCH: PexFlags = PexFlags(0x08);
+    /// The sender connected to the peer, so it accepts incoming connections.
+    pub const REACHABLE: PexFlags = PexFlags(0x10);
+
+    /// Returns `true` if every flag of `other` is set.
+    pub fn contains(self, other: PexFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Sets the flags of `other`.
+    pub fn insert(&mut self, other: PexFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// A peer to connect to, with what its source told us about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Candidate {
+    pub addr: SocketAddr,
+    pub flags: PexFlags,
+}
+
+impl From<SocketAddr> for Candidate {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            flags: PexFlags::default(),
+        }
+    }
+}
+
+/// What we told one peer in our PEX messages.
+#[derive(Debug, Default)]
+pub(super) struct PexState {
+    /// The peers the receiver knows from us to be connected.
+    advertised: HashSet<SocketAddr>,
+    /// When our previous message went out.
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    /// Builds the next PEX message for a peer, and records it as sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `swarm` - The peers we are connected to, at the address they accept
+    ///   connections on, with their flags.
+    /// * `exclude` - The addresses of the receiver itself.
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<u8>>` - The bencoded message, or `None` if the previous one went out
+    ///   less than `PEX_INTERVAL` ago or nothing changed since.
+    pub fn next_message(
+        &mut self,
+        swarm: &HashMap<SocketAddr, PexFlags>,
+        exclude: &[SocketAddr],
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        if self
+            .last_sent
+            .is_some_and(|sent| now.duration_since(sent) < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let added: Vec<(SocketAddr, PexFlags)> = swarm
+            .iter()
+            .filter(|(addr, _)| !self.advertised.contains(addr) && !exclude.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !swarm.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.advertised.extend(added.iter().map(|(addr, _)| *addr));
+        for addr in &dropped {
+            self.advertised.remove(addr);
+        }
+        self.last_sent = Some(now);
+        Some(encode_message(&added, &dropped))
+    }
+}
+
+/// Encodes a PEX message, with each family under its own keys.
+fn encode_message(added: &[(SocketAddr, PexFlags)], dropped: &[SocketAddr]) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    for (ipv6, suffix) in [(false, ""), (true, "6")] {
+        let (peers, flags): (Vec<SocketAddr>, Vec<u8>) = added
+            .iter()
+            .filter(|(addr, _)| compact::canonical(*addr).is_ipv6() == ipv6)
+            .map(|(addr, flags)| (*addr, flags.0))
+            .unzip();
+        dict.insert(
+            format!("added{}", suffix).into_bytes(),
+            Bencode::Bytes(compact::encode_peers(&peers, ipv6)),
+        );
+        dict.insert(
+            format!("added{}.f", suffix).into_bytes(),
+            Bencode::Bytes(flags),
+        );
+        dict.insert(
+            format!("dropped{}", suffix).into_bytes(),
+            Bencode::Bytes(compact::encode_peers(dropped, ipv6)),
+        );
+    }
+    Bencode::Dict(dict).encode()
+}
+
+/// Reads the added peers of a received PEX message, with their flags.
+///
+/// Dropped peers are ignored: the sender lost them, which does not mean we cannot
+/// connect to them. At most `MAX_PEX_PEERS` peers of each family are returned.
+pub(super) fn parse_message(dict: &BTreeMap<Vec<u8>, Bencode>) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for (ipv6, key, flags_key) in [
+        (false, &b"added"[..], &b"added.f"[..]),
+        (true, &b"added6"[..], &b"added6.f"[..]),
+    ] {
+        let Some(Bencode::Bytes(peers)) = dict.get(key) else {
+            continue;
+        };
+        let flags = match dict.get(flags_key) {
+            Some(Bencode::Bytes(flags)) => flags.as_slice(),
+            _ => &[],
+        };
+        candidates.extend(
+            compact::decode_peers(peers, ipv6)
+                .into_iter()
+                .take(MAX_PEX_PEERS)
+                .enumerate()
+                .map(|(i, addr)| Candidate {
+                    addr,
+                    flags: PexFlags(flags.get(i).copied().unwrap_or(0)),
+                }),
+        );
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tds_core::bencoding::decode;
+
+    fn peer(i: u16) -> SocketAddr {
+        SocketAddr::new([10, 0, 0, 1].into(), i)
+    }
+
+    fn parse(message: &[u8]) -> Vec<Candidate> {
+        let Bencode::Dict(dict) = decode(message, &mut 0).unwrap() else {
+            panic!("PEX message is not a dictionary");
+        };
+        parse_message(&dict)
+    }
+
+    #[test]
+    fn test_messages_carry_changes_within_limits() {
+        let now = Instant::now();
+        let mut state = PexState::default();
+        let mut swarm: HashMap<SocketAddr, PexFlags> = (0..MAX_PEX_PEERS as u16 + 5)
+            .map(|i| (peer(i), PexFlags::REACHABLE))
+            .collect();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        swarm.insert(v6, PexFlags::SEED);
+
+        let first = parse(&state.next_message(&swarm, &[peer(0)], now).unwrap());
+        assert_eq!(first.len(), MAX_PEX_PEERS);
+        assert!(!first.iter().any(|c| c.addr == peer(0)));
+        // Too early for another message
+        assert!(state.next_message(&swarm, &[peer(0)], now).is_none());
+
+        let later = now + PEX_INTERVAL;
+        let second = parse(&state.next_message(&swarm, &[peer(0)], later).unwrap());
+        assert_eq!(first.len() + second.len(), swarm.len() - 1);
+        let seed = first.iter().chain(&second).find(|c| c.addr == v6).unwrap();
+        assert!(seed.flags.contains(PexFlags::SEED));
+        assert!(!seed.flags.contains(PexFlags::REACHABLE));
+
+        swarm.remove(&peer(1));
+        let much_later = later + PEX_INTERVAL;
+        let message = state.next_message(&swarm, &[peer(0)], much_later).unwrap();
+        let Bencode::Dict(dict) = decode(&message, &mut 0).unwrap() else {
+            panic!("PEX message is not a dictionary");
+        };
+        assert_eq!(
+            dict.get(&b"dropped"[..]),
+            Some(&Bencode::Bytes(compact::encode_peer(&peer(1))))
+        );
+        assert!(parse(&message).is_empty());
+        assert!(
+            state
+                .next_message(&swarm, &[peer(0)], much_later + PEX_INTERVAL)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_missing_flags_default_to_none() {
+        let mut dict = BTreeMap::new();
+        let peers = [peer(1), peer(2)];
+        dict.insert(
+            b"added".to_vec(),
+            Bencode::Bytes(compact::encode_peers(&peers, false)),
+        );
+        dict.insert(b"added.f".to_vec(), Bencode::Bytes(vec![0x12]));
+
+        let candidates = parse_message(&dict);
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates[0].flags.contains(PexFlags::SEED));
+        assert!(candidates[0].flags.contains(PexFlags::REACHABLE));
+        assert_eq!(candidates[1].flags, PexFlags::default());
+    }
+}
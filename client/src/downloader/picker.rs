@@ -0,0 +1,242 @@
+//! Piece selection: random-first, then rarest-first, with per-piece priorities.
+
+use super::state::PieceStatus;
+use rand::Rng;
+
+/// Number of pieces picked at random before switching to rarest-first.
+///
+/// Random pieces complete quickly and give us something to trade with;
+/// rarity only starts to matter once we can upload.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// The download priority of a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PiecePriority {
+    /// Never download the piece.
+    Skip,
+    /// Download after every higher priority piece.
+    Low,
+    /// The default priority.
+    Normal,
+    /// Download before every lower priority piece.
+    High,
+}
+
+/// Chooses which piece to start next.
+///
+/// The picker tracks how many connected peers have each piece, from their
+/// `Bitfield` and `Have` messages. Among the missing pieces a peer can give us,
+/// the highest priority ones are considered first. Until `RANDOM_FIRST_PIECES`
+/// pieces are complete one of them is chosen at random; afterwards the rarest
+/// is chosen, with ties broken at random.
+///
+/// It also counts the pieces still wanted, neither verified nor skipped, so that
+/// completion is known without scanning every piece.
+pub struct PiecePicker {
+    /// Number of connected peers that have each piece.
+    availability: Vec<u32>,
+    /// The priority of each piece.
+    priorities: Vec<PiecePriority>,
+    /// Whether each piece is verified, as reported to `piece_verified`.
+    verified: Vec<bool>,
+    /// Number of pieces neither verified nor skipped.
+    remaining: usize,
+}
+
+impl PiecePicker {
+    /// Creates a picker for `piece_count` pieces, all with `Normal` priority and no availability.
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            priorities: vec![PiecePriority::Normal; piece_count],
+            verified: vec![false; piece_count],
+            remaining: piece_count,
+        }
+    }
+
+    /// Records that a peer has the piece at `index`.
+    pub fn add_piece(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Records that a peer which had the piece at `index` went away.
+    pub fn remove_piece(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Returns how many connected peers have the piece at `index`.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    /// Sets the priority of the piece at `index`.
+    pub fn set_priority(&mut self, index: usize, priority: PiecePriority) {
+        let Some(p) = self.priorities.get_mut(index) else {
+            return;
+        };
+        if !self.verified[index] {
+            match (*p == PiecePriority::Skip, priority == PiecePriority::Skip) {
+                (false, true) => self.remaining -= 1,
+                (true, false) => self.remaining += 1,
+                _ => {}
+            }
+        }
+        *p = priority;
+    }
+
+    /// Records that the piece at `index` is downloaded and verified.
+    pub fn piece_verified(&mut self, index: usize) {
+        if let Some(verified) = self.verified.get_mut(index)
+            && !*verified
+        {
+            *verified = true;
+            if self.priorities[index] != PiecePriority::Skip {
+                self.remaining -= 1;
+            }
+        }
+    }
+
+    /// Returns how many pieces are neither verified nor skipped.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Returns the priority of the piece at `index`.
+    pub fn priority(&self, index: usize) -> PiecePriority {
+        self.priorities
+            .get(index)
+            .copied()
+            .unwrap_or(PiecePriority::Skip)
+    }
+
+    /// Picks the next piece to start downloading from a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `piece_status` - The download status of every piece. Only `Missing` pieces are candidates.
+    /// * `has_piece` - Whether the peer has the piece at the given index.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<usize>` - The index of the chosen piece, or `None` if the peer has nothing we want.
+    pub fn pick(
+        &self,
+        piece_status: &[PieceStatus],
+        has_piece: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let candidates: Vec<usize> = piece_status
+            .iter()
+            .enumerate()
+            .filter(|&(i, s)| {
+                *s == PieceStatus::Missing
+                    && self.priority(i) != PiecePriority::Skip
+                    && has_piece(i)
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        let best_priority = candidates.iter().map(|&i| self.priority(i)).max()?;
+        let mut pool: Vec<usize> = candidates
+            .into_iter()
+            .filter(|&i| self.priority(i) == best_priority)
+            .collect();
+
+        let completed = piece_status
+            .iter()
+            .filter(|&&s| s == PieceStatus::Have)
+            .count();
+        if completed >= RANDOM_FIRST_PIECES {
+            let rarest = pool.iter().map(|&i| self.availability(i)).min()?;
+            pool.retain(|&i| self.availability(i) == rarest);
+        }
+
+        Some(pool[rand::rng().random_range(0..pool.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_with_completed(count: usize, completed: usize) -> Vec<PieceStatus> {
+        let mut status = vec![PieceStatus::Missing; count];
+        for s in status.iter_mut().take(completed) {
+            *s = PieceStatus::Have;
+        }
+        status
+    }
+
+    #[test]
+    fn test_rarest_first_after_random_phase() {
+        let mut picker = PiecePicker::new(8);
+        let status = status_with_completed(8, RANDOM_FIRST_PIECES);
+        for i in 4..8 {
+            picker.add_piece(i);
+            picker.add_piece(i);
+        }
+        picker.remove_piece(6);
+
+        for _ in 0..20 {
+            assert_eq!(picker.pick(&status, |_| true), Some(6));
+        }
+    }
+
+    #[test]
+    fn test_random_first_ignores_rarity() {
+        let mut picker = PiecePicker::new(4);
+        let status = status_with_completed(4, 0);
+        picker.add_piece(0);
+        for _ in 0..5 {
+            picker.add_piece(1);
+            picker.add_piece(2);
+            picker.add_piece(3);
+        }
+
+        let mut picked_common = false;
+        for _ in 0..200 {
+            if picker.pick(&status, |_| true) != Some(0) {
+                picked_common = true;
+            }
+        }
+        assert!(picked_common);
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut picker = PiecePicker::new(3);
+        let status = status_with_completed(3, 0);
+        picker.set_priority(0, PiecePriority::Skip);
+        picker.set_priority(2, PiecePriority::High);
+
+        assert_eq!(picker.pick(&status, |_| true), Some(2));
+        assert_eq!(picker.pick(&status, |i| i != 2), Some(1));
+        assert_eq!(picker.pick(&status, |i| i == 0), None);
+    }
+
+    #[test]
+    fn test_remaining_counts_wanted_pieces() {
+        let mut picker = PiecePicker::new(3);
+        assert_eq!(picker.remaining(), 3);
+
+        picker.piece_verified(0);
+        picker.piece_verified(0);
+        assert_eq!(picker.remaining(), 2);
+
+        picker.set_priority(1, PiecePriority::Skip);
+        picker.set_priority(1, PiecePriority::Skip);
+        assert_eq!(picker.remaining(), 1);
+
+        // Skipping a verified piece, or verifying a skipped one, changes nothing
+        picker.set_priority(0, PiecePriority::Skip);
+        picker.piece_verified(1);
+        assert_eq!(picker.remaining(), 1);
+
+        picker.set_priority(0, PiecePriority::High);
+        picker.piece_verified(2);
+        assert_eq!(picker.remaining(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! Fast resume: the download state saved next to the payload between runs.
+//!
+//! The resume file records which pieces were verified, together with the size and
+//! modification time of every file at the moment it was written. On the next start
+//! the bitfield is trusted only if every file still matches; otherwise the whole
+//! payload is rehashed.
+
+use super::state::{Downloader, PieceStatus};
+use crate::storage::write_atomic;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+use tds_core::bencoding::{Bencode, decode};
+use tds_core::compact;
+use tokio::fs;
+
+type ResumeResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// The most peers remembered in a resume file.
+const MAX_SAVED_PEERS: usize = 200;
+
+/// The size and modification time of a file when the resume data was saved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileStamp {
+    /// The length of the file in bytes.
+    pub length: u64,
+    /// The modification time in nanoseconds since the Unix epoch.
+    pub mtime: i64,
+}
+
+/// The contents of a resume file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeData {
+    /// The info hash of the torrent the data belongs to.
+    pub info_hash: [u8; 20],
+    /// Verified pieces, one bit per piece (high bit first).
+    pub bitfield: Vec<u8>,
+    /// The state of every file, in layout order.
+    pub files: Vec<FileStamp>,
+    /// Total bytes uploaded.
+    pub uploaded: u64,
+    /// Total bytes of verified pieces.
+    pub downloaded: u64,
+    /// Peers we managed to connect to, IPv4 ones first.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl ResumeData {
+    /// Encodes the resume data as a bencoded dictionary.
+    pub fn encode(&self) -> Vec<u8> {
+        let files = self
+            .files
+            .iter()
+            .map(|f| {
+                let mut d = BTreeMap::new();
+                d.insert(b"length".to_vec(), Bencode::Int(f.length as i64));
+                d.insert(b"mtime".to_vec(), Bencode::Int(f.mtime));
+                Bencode::Dict(d)
+            })
+            .collect();
+
+        let mut dict = BTreeMap::new();
+        dict.insert(
+            b"info-hash".to_vec(),
+            Bencode::Bytes(self.info_hash.to_vec()),
+        );
+        dict.insert(b"pieces".to_vec(), Bencode::Bytes(self.bitfield.clone()));
+        dict.insert(b"files".to_vec(), Bencode::List(files));
+        dict.insert(b"uploaded".to_vec(), Bencode::Int(self.uploaded as i64));
+        dict.insert(b"downloaded".to_vec(), Bencode::Int(self.downloaded as i64));
+        dict.insert(
+            b"peers".to_vec(),
+            Bencode::Bytes(compact::encode_peers(&self.peers, false)),
+        );
+        dict.insert(
+            b"peers6".to_vec(),
+            Bencode::Bytes(compact::encode_peers(&self.peers, true)),
+        );
+        Bencode::Dict(dict).encode()
+    }
+
+    /// Decodes resume data written by `encode`.
+    pub fn decode(buf: &[u8]) -> ResumeResult<Self> {
+        let mut pos = 0;
+        let Bencode::Dict(dict) = decode(buf, &mut pos)? else {
+            return Err("Resume data is not a dictionary".into());
+        };
+
+        let bytes = |key: &[u8]| match dict.get(key) {
+            Some(Bencode::Bytes(b)) => Ok(b.clone()),
+            _ => Err(format!("Missing {}", String::from_utf8_lossy(key))),
+        };
+        let int = |key: &[u8]| match dict.get(key) {
+            Some(Bencode::Int(i)) => Ok(*i),
+            _ => Err(format!("Missing {}", String::from_utf8_lossy(key))),
+        };
+
+        let info_hash: [u8; 20] = bytes(b"info-hash")?
+            .try_into()
+            .map_err(|_| "Invalid info-hash")?;
+
+        let mut files = Vec::new();
+        if let Some(Bencode::List(list)) = dict.get(&b"files"[..]) {
+            for item in list {
+                let Bencode::Dict(f) = item else {
+                    return Err("Invalid file entry".into());
+                };
+                match (f.get(&b"length"[..]), f.get(&b"mtime"[..])) {
+                    (Some(Bencode::Int(length)), Some(Bencode::Int(mtime))) => {
+                        files.push(FileStamp {
+                            length: *length as u64,
+                            mtime: *mtime,
+                        })
+                    }
+                    _ => return Err("Invalid file entry".into()),
+                }
+            }
+        }
+
+        let mut peers = compact::decode_peers(&bytes(b"peers").unwrap_or_default(), false);
+        peers.extend(compact::decode_peers(
+            &bytes(b"peers6").unwrap_or_default(),
+            true,
+        ));
+
+        Ok(Self {
+            info_hash,
+            bitfield: bytes(b"pieces")?,
+            files,
+            uploaded: int(b"uploaded")? as u64,
+            downloaded: int(b"downloaded")? as u64,
+            peers,
+        })
+    }
+}
+
+/// Reads the current size and modification time of every file of the download.
+async fn file_stamps(downloader: &Downloader) -> ResumeResult<Vec<FileStamp>> {
+    let metadata = downloader.files.lock().await.metadata().await?;
+    metadata
+        .iter()
+        .map(|m| match m {
+            Some(m) => {
+                let mtime = m.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as i64;
+                Ok(FileStamp {
+                    length: m.len(),
+                    mtime,
+                })
+            }
+            // Not created yet: nothing was written to it
+            None => Ok(FileStamp {
+                length: 0,
+                mtime: 0,
+            }),
+        })
+        .collect()
+}
+
+/// Writes the resume file of a download.
+///
+/// The bitfield is captured before the file stamps, so every piece recorded as
+/// verified was already on disk when the stamps were taken.
+pub async fn save(downloader: &Downloader) -> ResumeResult<()> {
+    let bitfield = {
+        let status = downloader.piece_status.lock().await;
+        let mut bitfield = vec![0u8; status.len().div_ceil(8)];
+        for (i, s) in status.iter().enumerate() {
+            if *s == PieceStatus::Have {
+                bitfield[i / 8] |= 1 << (7 - (i % 8));
+            }
+        }
+        bitfield
+    };
+    let files = file_stamps(downloader).await?;
+    let peers = downloader
+        .known_peers
+        .lock()
+        .await
+        .iter()
+        .take(MAX_SAVED_PEERS)
+        .copied()
+        .collect();
+
+    let data = ResumeData {
+        info_hash: downloader.torrent.info_hash,
+        bitfield,
+        files,
+        uploaded: *downloader.uploaded_bytes.lock().await,
+        downloaded: *downloader.downloaded_bytes.lock().await,
+        peers,
+    };
+
+    // A crash never leaves a truncated resume file
+    let path = downloader.files.lock().await.layout().resume_path();
+    write_atomic(&path, &data.encode()).await?;
+    Ok(())
+}
+
+/// Applies the resume file of a download if it is still valid.
+///
+/// # Returns
+///
+/// * `Ok(true)` - The files are unchanged; piece status, totals and known peers were restored.
+/// * `Ok(false)` - There is no resume file, or it no longer matches the files on disk.
+pub async fn load(downloader: &Downloader) -> ResumeResult<bool> {
+    let path = downloader.files.lock().await.layout().resume_path();
+    let buf = match fs::read(&path).await {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let data = ResumeData::decode(&buf)?;
+
+    let piece_count = downloader.torrent.pieces.len();
+    if data.info_hash != downloader.torrent.info_hash
+        || data.bitfield.len() != piece_count.div_ceil(8)
+    {
+        return Ok(false);
+    }
+    if data.files != file_stamps(downloader).await? {
+        println!("Files changed since the resume data was saved");
+        return Ok(false);
+    }
+
+    let mut status = downloader.piece_status.lock().await;
+    let mut picker = downloader.picker.lock().await;
+    for (i, s) in status.iter_mut().enumerate() {
+        if (data.bitfield[i / 8] >> (7 - (i % 8))) & 1 == 1 {
+            *s = PieceStatus::Have;
+            picker.piece_verified(i);
+        }
+    }
+    *downloader.downloaded_bytes.lock().await = data.downloaded;
+    *downloader.uploaded_bytes.lock().await = data.uploaded;
+    downloader.known_peers.lock().await.extend(data.peers);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_data_roundtrip() {
+        let data = ResumeData {
+            info_hash: [5u8; 20],
+            bitfield: vec![0b1010_0000],
+            files: vec![
+                FileStamp {
+                    length: 10,
+                    mtime: 1_700_000_000_123_456_789,
+                },
+                FileStamp {
+                    length: 0,
+                    mtime: 42,
+                },
+            ],
+            uploaded: 7,
+            downloaded: 20,
+            peers: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ],
+        };
+
+        let decoded = ResumeData::decode(&data.encode()).unwrap();
+        assert_eq!(decoded, data);
+        assert!(ResumeData::decode(b"i3e").is_err());
+    }
+}
@@ -1,6 +1,11 @@
-use crate::storage::Storage;
+use super::config::DownloaderConfig;
+use super::control::DownloadHandle;
+use super::picker::PiecePicker;
+use crate::peer::PeerConnection;
+use crate::storage::{Storage, TorrentFiles};
+use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::fs::File;
 use tokio::sync::Mutex;
 
 /// Represents the download status of a specific piece of the torrent.
@@ -17,7 +22,7 @@ pub enum PieceStatus {
 /// The main structure managing the download state of a torrent.
 ///
 /// It holds shared state accessible by multiple components (tracker manager, peer connections, etc.),
-/// including the torrent metadata, storage file handle, and bitfield of piece statuses.
+/// including the torrent metadata, storage file handles, and bitfield of piece statuses.
 pub struct Downloader {
     /// The parsed torrent metadata.
     pub torrent: Arc<tds_core::Torrent>,
@@ -25,18 +30,29 @@ pub struct Downloader {
     pub peer_id: [u8; 20],
     /// The storage manager handle.
     pub storage: Storage,
-    /// The open file handle where data is written.
+    /// The open files of the torrent, addressed by piece.
     /// Wrapped in a Mutex for concurrent access.
-    pub file: Arc<Mutex<File>>,
+    pub files: Arc<Mutex<TorrentFiles>>,
     /// A vector tracking the status of each piece.
     /// Wrapped in a Mutex for concurrent updates.
     pub piece_status: Arc<Mutex<Vec<PieceStatus>>>,
+    /// Chooses which pieces to download next, from swarm availability and priorities.
+    pub picker: Arc<Mutex<PiecePicker>>,
     /// total number of bytes downloaded in this session.
     pub downloaded_bytes: Arc<Mutex<u64>>,
     /// total number of bytes uploaded in this session.
     pub uploaded_bytes: Arc<Mutex<u64>>,
+    /// Peers we managed to connect to, remembered in the resume file.
+    pub known_peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    /// Connections made before the download started, such as while resolving a magnet
+    /// link; `run` takes them over.
+    pub(super) connections: Mutex<Vec<PeerConnection>>,
     /// The total size of the torrent content in bytes.
     pub total_length: u64,
+    /// Tunable settings, applied when `run` is called.
+    pub config: DownloaderConfig,
+    /// Pauses, resumes and stops the download; see `Downloader::handle`.
+    pub(super) control: DownloadHandle,
 }
 
 #[cfg(test)]
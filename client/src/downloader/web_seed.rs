@@ -0,0 +1,564 @@
+//! Downloading from web seeds: HTTP servers hosting the torrent's content.
+//!
+//! A `url-list` seed (BEP 19) serves the files themselves. A range of the piece space
+//! is fetched with one `Range` request per file it overlaps. An `httpseeds` seed
+//! (BEP 17) serves whole pieces by index. Either way the data goes through the same
+//! block bookkeeping as data from peers, so peers and web seeds can share a piece, and
+//! every piece is verified against its hash before it is written.
+
+use reqwest::StatusCode;
+use reqwest::header::RANGE;
+use std::fmt;
+use std::time::Duration;
+use tds_core::Torrent;
+use url::Url;
+
+use super::blocks::{BlockOutcome, BlockRequest};
+use super::control::{self, DownloadState};
+use super::peer_task::PeerContext;
+use crate::storage::FileLayout;
+
+type SeedResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How long a single HTTP request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait before retrying a seed after its first failure. The wait doubles
+/// with every failure in a row, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The longest wait between retries of a failing seed.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How often a seed with nothing left to fetch checks again, as blocks are handed
+/// back when peers disconnect.
+const IDLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A web seed of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum WebSeed {
+    /// A server hosting the files (BEP 19). For a multi-file torrent, or when the URL
+    /// ends in `/`, the URL is the directory holding the torrent's `name`.
+    Url(String),
+    /// A script serving pieces at `<url>?info_hash=<hash>&piece=<index>` (BEP 17).
+    HttpSeed(String),
+}
+
+impl WebSeed {
+    /// Returns the web seeds listed in the torrent, `url-list` first.
+    pub fn from_torrent(torrent: &Torrent) -> Vec<Self> {
+        let urls = torrent.url_list.iter().cloned().map(Self::Url);
+        let seeds = torrent.http_seeds.iter().cloned().map(Self::HttpSeed);
+        urls.chain(seeds).collect()
+    }
+}
+
+impl fmt::Display for WebSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Url(url) | Self::HttpSeed(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Downloads missing pieces from a web seed until the download completes or stops.
+///
+/// The seed counts in the picker's availability for every piece while it runs. Blocks
+/// are claimed from the shared block tracker a run of consecutive blocks of one piece
+/// at a time, and handed back when a request fails or the download is paused. A seed
+/// that fails is retried with an exponential backoff; a seed that sends data failing
+/// the hash check is given up on, as it hosts different content.
+///
+/// # Arguments
+///
+/// * `ctx` - The download state shared with the peer tasks.
+/// * `seed` - The web seed to download from.
+pub(super) async fn run(ctx: PeerContext, seed: WebSeed) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Cannot use web seed {}: {}", seed, e);
+            return;
+        }
+    };
+    let layout = ctx.files.lock().await.layout().clone();
+    let mut shutdown_rx = ctx.shutdown.subscribe();
+    let mut state_rx = ctx.state.clone();
+    let mut backoff = MIN_BACKOFF;
+
+    {
+        let mut picker = ctx.picker.lock().await;
+        for index in 0..layout.piece_count {
+            picker.add_piece(index);
+        }
+    }
+
+    loop {
+        let state = *state_rx.borrow_and_update();
+        if *shutdown_rx.borrow() || state == DownloadState::Stopped {
+            break;
+        }
+        if state == DownloadState::Paused {
+            tokio::select! {
+                _ = control::wait_until(&mut state_rx, |&s| s != DownloadState::Paused) => continue,
+                _ = control::wait_until(&mut shutdown_rx, |&stop| stop) => break,
+            }
+        }
+
+        let claimed = claim(&ctx).await;
+        if claimed.is_empty() {
+            if ctx.is_complete().await {
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(IDLE_INTERVAL) => continue,
+                _ = control::wait_until(&mut shutdown_rx, |&stop| stop) => break,
+            }
+        }
+
+        let length = claimed.iter().map(|r| r.length as usize).sum();
+        let result = tokio::select! {
+            res = async {
+                ctx.download_limit.acquire(length).await;
+                fetch(&client, &seed, &ctx.torrent, &layout, &claimed).await
+            } => res,
+            _ = control::wait_until(&mut shutdown_rx, |&stop| stop) => {
+                release(&ctx, &claimed).await;
+                break;
+            }
+            _ = control::wait_until(&mut state_rx, |&s| s != DownloadState::Running) => {
+                release(&ctx, &claimed).await;
+                continue;
+            }
+        };
+
+        let delivered = match result {
+            Ok(data) => deliver(&ctx, &seed, &claimed, &data).await,
+            Err(e) => {
+                release(&ctx, &claimed).await;
+                Err(e)
+            }
+        };
+        match delivered {
+            Ok(true) => backoff = MIN_BACKOFF,
+            Ok(false) => {
+                eprintln!("Web seed {} sent corrupt data, no longer using it", seed);
+                break;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Web seed {} failed: {}, retrying in {}s",
+                    seed,
+                    e,
+                    backoff.as_secs()
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = control::wait_until(&mut shutdown_rx, |&stop| stop) => break,
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        }
+
+        // The manager announces completion and keeps seeding; there is nothing left
+        // to fetch from this seed
+        if *ctx.completed.borrow() {
+            break;
+        }
+    }
+
+    let mut picker = ctx.picker.lock().await;
+    for index in 0..layout.piece_count {
+        picker.remove_piece(index);
+    }
+}
+
+/// Claims consecutive blocks of one piece from the block tracker, up to the whole piece.
+///
+/// In end game, blocks already requested from peers are claimed too.
+///
+/// # Returns
+///
+/// * `Vec<BlockRequest>` - The claimed blocks, in order; empty if there is nothing to fetch.
+async fn claim(ctx: &PeerContext) -> Vec<BlockRequest> {
+    let mut status = ctx.piece_status.lock().await;
+    let picker = ctx.picker.lock().await;
+    let mut blocks = ctx.blocks.lock().await;
+    let mut claimed: Vec<BlockRequest> = Vec::new();
+
+    loop {
+        if let Some(last) = claimed.last()
+            && (last.begin + last.length) as u64 == blocks.layout().piece_len(last.index as usize)
+        {
+            break;
+        }
+        let next = match blocks.next_request(&mut status, &picker, |_| true) {
+            Some(req) => Some(req),
+            None if blocks.is_endgame(&status, &picker) => {
+                blocks.next_endgame_request(|_| true, |r| claimed.contains(r))
+            }
+            None => None,
+        };
+        let Some(req) = next else {
+            break;
+        };
+        match claimed.last() {
+            Some(last) if last.index != req.index || last.begin + last.length != req.begin => {
+                blocks.release(&req);
+                break;
+            }
+            _ => claimed.push(req),
+        }
+    }
+    claimed
+}
+
+/// Hands claimed blocks back to the block tracker.
+async fn release(ctx: &PeerContext, claimed: &[BlockRequest]) {
+    let mut blocks = ctx.blocks.lock().await;
+    for req in claimed {
+        blocks.release(req);
+    }
+}
+
+/// Stores fetched blocks and verifies and writes the pieces they complete.
+///
+/// # Returns
+///
+/// * `bool` - `false` if a completed piece failed its hash check.
+///
+/// # Errors
+///
+/// Returns an error if a piece could not be written.
+async fn deliver(
+    ctx: &PeerContext,
+    seed: &WebSeed,
+    claimed: &[BlockRequest],
+    data: &[u8],
+) -> SeedResult<bool> {
+    let mut verified = true;
+    let mut pos = 0;
+    for req in claimed {
+        let block = &data[pos..pos + req.length as usize];
+        pos += req.length as usize;
+
+        let (outcome, duplicated) = {
+            let mut blocks = ctx.blocks.lock().await;
+            let duplicated = blocks.requesters(req.index, req.begin) > 1;
+            (
+                blocks.block_received(req.index, req.begin, block),
+                duplicated,
+            )
+        };
+        if duplicated && outcome != BlockOutcome::Ignored {
+            // End game: peers cancel their requests for the block
+            let _ = ctx.block_tx.send(*req);
+        }
+        if let BlockOutcome::PieceComplete(piece) = outcome {
+            verified &= ctx.store_piece(req.index as usize, &piece, seed).await?;
+        }
+    }
+    Ok(verified)
+}
+
+/// Fetches the bytes of consecutive blocks of one piece from a web seed.
+///
+/// # Errors
+///
+/// Returns an error if a request fails, the server answers with an unexpected status,
+/// or it sends fewer or more bytes than asked for.
+async fn fetch(
+    client: &reqwest::Client,
+    seed: &WebSeed,
+    torrent: &Torrent,
+    layout: &FileLayout,
+    claimed: &[BlockRequest],
+) -> SeedResult<Vec<u8>> {
+    let index = claimed[0].index;
+    let begin = claimed[0].begin as u64;
+    let length: u64 = claimed.iter().map(|r| r.length as u64).sum();
+
+    match seed {
+        WebSeed::Url(url) => {
+            let offset = index as u64 * layout.piece_length + begin;
+            let mut data = Vec::with_capacity(length as usize);
+            for slice in layout.slices(offset, length) {
+                let file_url = file_url(url, torrent, slice.file_index)?;
+                let end = slice.file_offset + slice.length - 1;
+                let response = client
+                    .get(file_url)
+                    .header(RANGE, format!("bytes={}-{}", slice.file_offset, end))
+                    .send()
+                    .await?;
+                let status = response.status();
+                let body = response.bytes().await?;
+                let range = match status {
+                    StatusCode::PARTIAL_CONTENT => 0..body.len(),
+                    // The server ignored the range and sent the whole file
+                    StatusCode::OK => slice.file_offset as usize..(end + 1) as usize,
+                    status => return Err(format!("HTTP {}", status).into()),
+                };
+                match body.get(range) {
+                    Some(bytes) if bytes.len() as u64 == slice.length => {
+                        data.extend_from_slice(bytes)
+                    }
+                    _ => return Err("Response does not match the requested range".into()),
+                }
+            }
+            Ok(data)
+        }
+        WebSeed::HttpSeed(url) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let info_hash: String =
+                url::form_urlencoded::byte_serialize(&torrent.info_hash).collect();
+            let response = client
+                .get(format!(
+                    "{}{}info_hash={}&piece={}",
+                    url, separator, info_hash, index
+                ))
+                .send()
+                .await?;
+            let status = response.status();
+            if status != StatusCode::OK {
+                // A busy seed answers 503 with the seconds to wait; the backoff covers it
+                return Err(format!("HTTP {}", status).into());
+            }
+            let body = response.bytes().await?;
+            if body.len() as u64 != layout.piece_len(index as usize) {
+                return Err("Response is not a whole piece".into());
+            }
+            Ok(body[begin as usize..(begin + length) as usize].to_vec())
+        }
+    }
+}
+
+/// Builds the URL of a file of the torrent on a BEP 19 web seed.
+///
+/// # Arguments
+///
+/// * `base` - The URL listed in the torrent's `url-list`.
+/// * `torrent` - The torrent the file belongs to.
+/// * `file_index` - The index of the file in the torrent's file list.
+fn file_url(base: &str, torrent: &Torrent, file_index: usize) -> SeedResult<Url> {
+    let mut url = Url::parse(base)?;
+    let multi_file = torrent.files.is_some() && torrent.length.is_none();
+    if multi_file || base.ends_with('/') {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| format!("Web seed URL cannot hold a path: {}", base))?;
+        segments.pop_if_empty().push(&torrent.name);
+        if let Some(files) = torrent.files.as_ref().filter(|_| multi_file) {
+            segments.extend(&files[file_index].path);
+        }
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::{Downloader, PieceStatus};
+    use crate::session::SessionConfig;
+    use sha1::{Digest, Sha1};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tds_core::FileInfo;
+    use tempfile::tempdir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn torrent(name: &str, files: Option<Vec<FileInfo>>, content: &[u8]) -> Torrent {
+        let piece_length = 32 * 1024;
+        let pieces = content
+            .chunks(piece_length)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: Sha1::digest(name.as_bytes()).into(),
+            piece_length: piece_length as u64,
+            pieces,
+            name: name.to_string(),
+            length: files.is_none().then_some(content.len() as u64),
+            files,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        }
+    }
+
+    /// Serves `files` by path, honouring single `Range` requests, and the pieces of
+    /// `pieces` at `/seed?...&piece=<index>`. Returns the server's base URL.
+    async fn serve(files: HashMap<String, Vec<u8>>, pieces: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let files = Arc::new(files);
+        let pieces = Arc::new(pieces);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let files = files.clone();
+                let pieces = pieces.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0u8; 1];
+                        if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        request.push(byte[0]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let target = request.split(' ').nth(1).unwrap();
+                    let range = request
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("range: bytes=")
+                                .map(String::from)
+                        })
+                        .map(|range| {
+                            let (start, end) = range.split_once('-').unwrap();
+                            start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1
+                        });
+
+                    let (status, body) = match target.split_once("piece=") {
+                        Some((_, index)) => {
+                            ("200 OK", pieces[index.parse::<usize>().unwrap()].clone())
+                        }
+                        None => match (files.get(target), range) {
+                            (Some(file), Some(range)) => {
+                                ("206 Partial Content", file[range].to_vec())
+                            }
+                            (Some(file), None) => ("200 OK", file.clone()),
+                            (None, _) => ("404 Not Found", Vec::new()),
+                        },
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        base
+    }
+
+    async fn download(torrent: Torrent, dir: &std::path::Path) {
+        let downloader = Downloader::from_torrent(torrent, Some(dir.to_str().unwrap().to_string()))
+            .await
+            .unwrap();
+        let config = SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        };
+        // The torrent seeds once complete; stop it then
+        let handle = downloader.handle();
+        let piece_status = downloader.piece_status.clone();
+        let stop_when_complete = async move {
+            while piece_status
+                .lock()
+                .await
+                .iter()
+                .any(|&s| s != PieceStatus::Have)
+            {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            handle.stop();
+        };
+        let run = async { tokio::join!(downloader.run_with_config(config), stop_when_complete) };
+        tokio::time::timeout(Duration::from_secs(20), run)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_file_urls() {
+        let single = torrent("a b.iso", None, b"x");
+        assert_eq!(
+            file_url("http://m/pub/", &single, 0).unwrap().as_str(),
+            "http://m/pub/a%20b.iso"
+        );
+        assert_eq!(
+            file_url("http://m/pub/other.iso", &single, 0)
+                .unwrap()
+                .as_str(),
+            "http://m/pub/other.iso"
+        );
+
+        let files = vec![
+            FileInfo {
+                length: 1,
+                path: vec!["a".to_string()],
+            },
+            FileInfo {
+                length: 0,
+                path: vec!["sub".to_string(), "#b".to_string()],
+            },
+        ];
+        let multi = torrent("dir", Some(files), b"x");
+        assert_eq!(
+            file_url("http://m/pub", &multi, 1).unwrap().as_str(),
+            "http://m/pub/dir/sub/%23b"
+        );
+        assert_eq!(
+            file_url("http://m/pub/", &multi, 0).unwrap().as_str(),
+            "http://m/pub/dir/a"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_from_url_list() {
+        // Three files spread over five pieces, the last one short
+        let content: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        let sizes = [70_000, 1, 79_999];
+        let mut files = HashMap::new();
+        let mut infos = Vec::new();
+        let mut offset = 0;
+        for (i, size) in sizes.into_iter().enumerate() {
+            let name = format!("file{}", i);
+            files.insert(
+                format!("/mirror/dir/sub/{}", name),
+                content[offset..offset + size].to_vec(),
+            );
+            infos.push(FileInfo {
+                length: size as u64,
+                path: vec!["sub".to_string(), name],
+            });
+            offset += size;
+        }
+        let base = serve(files, Vec::new()).await;
+
+        let mut torrent = torrent("dir", Some(infos), &content);
+        torrent.url_list = vec![format!("{}/mirror/", base)];
+        let dir = tempdir().unwrap();
+        download(torrent, dir.path()).await;
+
+        let mut downloaded = Vec::new();
+        for i in 0..sizes.len() {
+            let path = dir.path().join(format!("dir/sub/file{}", i));
+            downloaded.extend(std::fs::read(path).unwrap());
+        }
+        assert!(downloaded == content);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_http_seed() {
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        let pieces = content.chunks(32 * 1024).map(<[u8]>::to_vec).collect();
+        let base = serve(HashMap::new(), pieces).await;
+
+        let mut torrent = torrent("single.bin", None, &content);
+        torrent.http_seeds = vec![format!("{}/seed", base)];
+        let dir = tempdir().unwrap();
+        download(torrent, dir.path()).await;
+
+        assert!(std::fs::read(dir.path().join("single.bin")).unwrap() == content);
+    }
+}
@@ -1,6 +1,9 @@
 pub mod cli;
 pub mod dht;
 pub mod downloader;
+pub mod listener;
 pub mod magnet;
 pub mod peer;
+pub mod session;
 pub mod storage;
+pub mod utp;
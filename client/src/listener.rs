@@ -0,0 +1,290 @@
+//! Accepts inbound peer connections and routes them to the torrent they ask for.
+
+use crate::peer::{EncryptionPolicy, PeerConnection, Transport, TransportPolicy};
+use crate::utp::{UtpSocket, UtpStream};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tds_core::net;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The port we listen on for peer connections unless configured otherwise.
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+
+/// An active torrent that accepts inbound peers.
+struct Registration {
+    /// The peer ID we present for this torrent.
+    peer_id: [u8; 20],
+    /// Where handshaken connections are delivered.
+    tx: mpsc::Sender<PeerConnection>,
+}
+
+/// A TCP listener, with a uTP socket on the UDP port of the same number, shared by all
+/// active torrents.
+///
+/// Each inbound connection goes through the inbound side of the handshake
+/// (`PeerConnection::accept`), encrypted or not as the policy allows. Its info hash is
+/// checked against the registered torrents, and the connection is then handed to that torrent's channel.
+/// Connections for unknown info hashes are dropped.
+#[derive(Clone)]
+pub struct PeerListener {
+    /// The port the listener is bound to.
+    port: u16,
+    /// The uTP socket, which outbound uTP connections are opened from as well.
+    utp: Option<UtpSocket>,
+    /// Active torrents keyed by info hash.
+    torrents: Arc<Mutex<HashMap<[u8; 20], Registration>>>,
+    /// The accept loop task.
+    task: Arc<JoinHandle<()>>,
+}
+
+impl PeerListener {
+    /// Binds to `port` on all interfaces and starts the accept loop in a background task.
+    ///
+    /// The listener accepts IPv4 and IPv6 peers alike, or IPv4 only on a system without
+    /// IPv6. When the policy allows uTP, the
+    /// UDP port of the same number is opened as well; failing to open it is logged and
+    /// the listener goes on with TCP only.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The TCP port to listen on. Use `0` to let the OS choose a free port.
+    /// * `encryption` - Whether inbound peers may, or must, encrypt their connection.
+    /// * `transport` - Which transports inbound peers may connect over.
+    pub async fn start(
+        port: u16,
+        encryption: EncryptionPolicy,
+        transport: TransportPolicy,
+    ) -> io::Result<Self> {
+        let listener = net::bind_tcp(port)?;
+        let port = listener.local_addr()?.port();
+        let utp = if transport.allows_utp() {
+            match UtpSocket::bind_all(port) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    eprintln!("Failed to open uTP socket on port {}: {}", port, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let torrents: Arc<Mutex<HashMap<[u8; 20], Registration>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
+        let registry = torrents.clone();
+        let utp_socket = utp.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    res = listener.accept() => {
+                        res.map(|(stream, addr)| (Transport::Tcp(stream), addr))
+                    }
+                    res = accept_utp(utp_socket.as_ref()) => {
+                        res.map(|stream| {
+                            let addr = stream.peer_addr();
+                            (Transport::Utp(stream), addr)
+                        })
+                    }
+                };
+                let (stream, addr) = match accepted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        eprintln!("Accept error: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                if matches!(stream, Transport::Tcp(_)) && !transport.allows_tcp() {
+                    continue;
+                }
+
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    // An encrypted handshake only reveals its info hash to a responder
+                    // that tries every registered one
+                    let torrents: HashMap<[u8; 20], [u8; 20]> = {
+                        let guard = registry.lock().unwrap();
+                        guard.iter().map(|(h, r)| (*h, r.peer_id)).collect()
+                    };
+                    let peer =
+                        match PeerConnection::accept(stream, addr, &torrents, encryption).await {
+                            Ok(p) => p,
+                            Err(e) => {
+                                eprintln!("Rejected inbound peer {}: {}", addr, e);
+                                return;
+                            }
+                        };
+
+                    let tx = {
+                        let guard = registry.lock().unwrap();
+                        guard.get(&peer.info_hash()).map(|r| r.tx.clone())
+                    };
+                    if let Some(tx) = tx {
+                        println!("Accepted inbound peer {}", addr);
+                        let _ = tx.send(peer).await;
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            port,
+            utp,
+            torrents,
+            task: Arc::new(task),
+        })
+    }
+
+    /// Returns the port the listener is bound to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the uTP socket, if the policy allows uTP and the UDP port could be opened.
+    pub fn utp(&self) -> Option<&UtpSocket> {
+        self.utp.as_ref()
+    }
+
+    /// Registers a torrent so that inbound peers asking for `info_hash` are accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `info_hash` - The info hash of the torrent.
+    /// * `peer_id` - The peer ID to answer the handshake with.
+    ///
+    /// # Returns
+    ///
+    /// * `mpsc::Receiver<PeerConnection>` - Receives every accepted connection for the torrent.
+    pub fn register(
+        &self,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> mpsc::Receiver<PeerConnection> {
+        let (tx, rx) = mpsc::channel(16);
+        self.torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash, Registration { peer_id, tx });
+        rx
+    }
+
+    /// Stops accepting inbound peers for `info_hash`.
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    /// Stops the accept loop. Connections already handed out are not affected.
+    pub fn shutdown(&self) {
+        self.task.abort();
+    }
+}
+
+/// Waits for the next inbound uTP connection, or forever without a uTP socket.
+async fn accept_utp(socket: Option<&UtpSocket>) -> io::Result<UtpStream> {
+    match socket {
+        Some(socket) => socket.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Finds our global IPv6 address, the one peers on the internet can reach us at.
+///
+/// The address is the source the system would pick for a public IPv6 destination;
+/// nothing is sent to find it.
+///
+/// # Returns
+///
+/// * `Option<Ipv6Addr>` - The address, or `None` if the host has no route to the IPv6 internet.
+pub async fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").await.ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").await.ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip)
+            if !ip.is_loopback() && !ip.is_unspecified() && !ip.is_unicast_link_local() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::Message;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    #[tokio::test]
+    async fn test_routes_inbound_peer_by_info_hash() {
+        let listener = PeerListener::start(0, EncryptionPolicy::Preferred, TransportPolicy::Tcp)
+            .await
+            .unwrap();
+        assert!(listener.utp().is_none());
+        let mut rx = listener.register([7u8; 20], [8u8; 20]);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.port());
+
+        let outgoing =
+            PeerConnection::connect(addr, &[7u8; 20], &[3u8; 20], EncryptionPolicy::Preferred)
+                .await
+                .unwrap();
+        assert_eq!(outgoing.peer_id, [8u8; 20]);
+
+        let inbound = rx.recv().await.unwrap();
+        assert_eq!(inbound.peer_id, [3u8; 20]);
+        assert_eq!(inbound.info_hash(), [7u8; 20]);
+
+        listener.unregister(&[7u8; 20]);
+        assert!(
+            PeerConnection::connect(addr, &[7u8; 20], &[3u8; 20], EncryptionPolicy::Preferred)
+                .await
+                .is_err()
+        );
+        listener.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_accepts_utp_peers() {
+        let listener = PeerListener::start(0, EncryptionPolicy::Preferred, TransportPolicy::Utp)
+            .await
+            .unwrap();
+        let mut rx = listener.register([7u8; 20], [8u8; 20]);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.port());
+
+        // TCP connections are dropped under a uTP-only policy
+        assert!(
+            PeerConnection::connect(addr, &[7u8; 20], &[3u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .is_err()
+        );
+
+        let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut outgoing = PeerConnection::dial(
+            addr,
+            &[7u8; 20],
+            &[3u8; 20],
+            EncryptionPolicy::Preferred,
+            TransportPolicy::PreferUtp,
+            Some(&socket),
+        )
+        .await
+        .unwrap();
+        assert!(outgoing.is_utp() && outgoing.is_encrypted());
+
+        let mut inbound = rx.recv().await.unwrap();
+        assert!(inbound.is_utp());
+        assert_eq!(inbound.addr(), socket.local_addr().unwrap());
+        assert_eq!(inbound.peer_id, [3u8; 20]);
+
+        outgoing.send_message(Message::Have(3)).await.unwrap();
+        assert!(matches!(
+            inbound.read_message().await.unwrap(),
+            Message::Have(3)
+        ));
+        listener.shutdown();
+    }
+}
@@ -1,70 +1,193 @@
-use crate::dht::Dht;
-use crate::peer::{Message, PeerConnection};
+use crate::dht::{Dht, DhtConfig};
+use crate::listener::DEFAULT_LISTEN_PORT;
+use crate::peer::{EncryptionPolicy, Message, PeerConnection, TransportPolicy};
+use crate::utp::UtpSocket;
+use rand::Rng;
 use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::time::Duration;
+use tds_core::Torrent;
 use tds_core::bencoding::{Bencode, decode};
 use tokio::sync::mpsc;
+use tracker::{TrackerRequest, get_tracker_client};
 use url::Url;
 
-/// Resolves a magnet link to the raw bytes of the info dictionary (metadata).
+/// The extended message ID we assign to ut_metadata; peers send us metadata messages with it.
+pub(crate) const UT_METADATA_ID: u8 = 2;
+
+/// The size of a metadata piece; only the last piece may be shorter.
+pub(crate) const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// How often the trackers of a magnet link are asked for peers again while resolving.
+const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A parsed magnet link (BEP 9).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    /// The info hash from `xt=urn:btih:`, given in hex or base32.
+    pub info_hash: [u8; 20],
+    /// The display name from `dn=`, to show until the metadata gives the real name.
+    pub display_name: Option<String>,
+    /// Tracker URLs from `tr=`.
+    pub trackers: Vec<String>,
+    /// Peer addresses from `x.pe=`, as `host:port`, `ipv4:port` or `[ipv6]:port`.
+    pub peers: Vec<String>,
+    /// The files to download from `so=` (BEP 53), as inclusive ranges of file indices.
+    /// `None` selects every file.
+    pub select_only: Option<Vec<RangeInclusive<usize>>>,
+}
+
+impl MagnetLink {
+    /// Returns `true` if the file at `index` is to be downloaded.
+    pub fn selects(&self, index: usize) -> bool {
+        self.select_only
+            .as_ref()
+            .is_none_or(|ranges| ranges.iter().any(|r| r.contains(&index)))
+    }
+}
+
+/// A magnet link resolved to a torrent, with the peers found on the way.
+///
+/// Pass it to `Downloader::from_magnet` to start the download over the same connections.
+pub struct ResolvedMagnet {
+    /// The link that was resolved.
+    pub magnet: MagnetLink,
+    /// The torrent built from the metadata, announcing to the link's trackers.
+    pub torrent: Torrent,
+    /// The peer ID the connections were made with.
+    pub peer_id: [u8; 20],
+    /// Handshaken connections to peers of the torrent, ready to download from.
+    pub connections: Vec<PeerConnection>,
+    /// Other peers found, not connected.
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Resolves a magnet link to a torrent.
 ///
 /// This process involves:
-/// 1. Parsing the magnet link to get the info hash and initial trackers.
-/// 2. Starting the DHT node to find peers associated with the info hash.
-/// 3. Connecting to discovered peers.
-/// 4. Using the BitTorrent Extension Protocol (BEP 10) to request the metadata (ut_metadata).
+/// 1. Finding peers through every source the link allows, in parallel: the peer
+///    addresses it carries, its trackers, and the DHT.
+/// 2. Connecting to discovered peers.
+/// 3. Using the BitTorrent Extension Protocol (BEP 10) to request the metadata (ut_metadata),
+///    which is verified against the info hash.
+///
+/// The connections made on the way are kept open, so the download can start without
+/// connecting again. Without a DHT node, the link must carry a tracker or a peer address.
 ///
 /// # Arguments
 ///
-/// * `magnet_link` - A string containing the magnet URI.
+/// * `magnet` - The parsed magnet link.
+/// * `dht_config` - Settings of the DHT node started to find peers, or `None` to not use the DHT.
+/// * `encryption` - Whether connections to peers are encrypted.
+/// * `transport` - Which transports connections to peers use. uTP connections go out
+///   from a socket of their own, on a random port.
 ///
 /// # Returns
 ///
-/// * `Result<Vec<u8>, ...>` - The raw bytes of the info dictionary if successful, or an error.
+/// * `Result<ResolvedMagnet, ...>` - The torrent and its peers if successful, or an error.
 ///
 /// # Errors
 ///
 /// Returns error if:
-/// * Magnet link is invalid.
+/// * The link gives no way to find peers.
+/// * The metadata matches the info hash but is not a valid info dictionary.
 /// * DHT fails to start.
 /// * Timeout occurs finding peers or metadata (current timeout 60s).
 pub async fn resolve(
-    magnet_link: &str,
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    let (info_hash, _initial_trackers) = parse_magnet_link(magnet_link)?;
-    println!(
-        "Resolving magnet link for info_hash: {}",
-        hex::encode(info_hash)
-    );
-
-    // Start DHT
-    // Use port 0 to let OS pick a random free port to avoid conflicts
-    let dht = std::sync::Arc::new(Dht::new(0).await?);
-
-    dht.start().await;
-
-    // Bootstrap DHT
-    println!("Bootstrapping DHT...");
-    dht.bootstrap().await;
-    tokio::time::sleep(Duration::from_secs(1)).await;
-
-    // Start searching for peers
-    println!("Searching for peers...");
-    let dht_search = dht.clone();
-    let hash_clone = info_hash;
-
-    // Periodically query DHT
+    magnet: &MagnetLink,
+    dht_config: Option<DhtConfig>,
+    encryption: EncryptionPolicy,
+    transport: TransportPolicy,
+) -> Result<ResolvedMagnet, Box<dyn std::error::Error + Send + Sync>> {
+    let info_hash = magnet.info_hash;
+    let peer_id: [u8; 20] = rand::rng().random();
+    if dht_config.is_none() && magnet.trackers.is_empty() && magnet.peers.is_empty() {
+        return Err("Magnet link has no trackers or peers, and the DHT is disabled".into());
+    }
+    let utp = if transport.allows_utp() {
+        UtpSocket::bind_all(0)
+            .inspect_err(|e| eprintln!("Failed to open uTP socket: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    match &magnet.display_name {
+        Some(name) => println!(
+            "Resolving magnet link for {} ({})",
+            name,
+            hex::encode(info_hash)
+        ),
+        None => println!(
+            "Resolving magnet link for info_hash: {}",
+            hex::encode(info_hash)
+        ),
+    }
+
+    let (found_tx, mut found_rx) = mpsc::channel(64);
+
+    // Peers named in the link
+    let hints = magnet.peers.clone();
+    let hint_tx = found_tx.clone();
     tokio::spawn(async move {
-        loop {
-            dht_search.get_peers(hash_clone).await;
-            tokio::time::sleep(Duration::from_secs(2)).await;
+        for hint in hints {
+            match tokio::net::lookup_host(hint.as_str()).await {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        let _ = hint_tx.send(addr).await;
+                    }
+                }
+                Err(e) => eprintln!("Failed to resolve peer {}: {}", hint, e),
+            }
         }
     });
 
-    // We need a channel to receive the metadata result
-    let (tx, mut rx) = mpsc::channel(1);
+    // Trackers, asked again until the metadata is found
+    for url in magnet.trackers.clone() {
+        let tracker_tx = found_tx.clone();
+        tokio::spawn(async move {
+            while !tracker_tx.is_closed() {
+                for peer in announce(&url, info_hash, peer_id).await {
+                    if tracker_tx.send(peer).await.is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(TRACKER_RETRY_INTERVAL).await;
+            }
+        });
+    }
+
+    let mut resolver_dht = None;
+    if let Some(dht_config) = dht_config {
+        let dht = std::sync::Arc::new(Dht::with_config(dht_config).await?);
+        dht.start().await;
+        resolver_dht = Some(dht.clone());
+
+        // Repeat the lookup until the metadata is found; new nodes join the table meanwhile
+        let dht_tx = found_tx.clone();
+        tokio::spawn(async move {
+            println!("Bootstrapping DHT...");
+            dht.bootstrap().await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            println!("Searching for peers...");
+            while !dht_tx.is_closed() {
+                let mut lookup = dht.get_peers(info_hash).await;
+                while let Some(peer) = lookup.next().await {
+                    if dht_tx.send(peer).await.is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        });
+    }
+    drop(found_tx);
+
+    // Connected peers, one of which eventually brings the metadata
+    let (tx, mut rx) = mpsc::channel::<Fetched>(16);
+    let mut connections = Vec::new();
 
     // Limit concurrency
     let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(50));
@@ -73,60 +196,146 @@ pub async fn resolve(
     let timeout = tokio::time::sleep(Duration::from_secs(60));
     tokio::pin!(timeout);
 
-    loop {
+    let result = loop {
         tokio::select! {
             _ = &mut timeout => {
-                return Err("Timeout resolving magnet link".into());
+                break Err("Timeout resolving magnet link".into());
             }
-            metadata = rx.recv() => {
-                if let Some(data) = metadata {
-                    return Ok(data);
+            Some(fetched) = rx.recv() => {
+                connections.push(fetched.peer);
+                if let Some(metadata) = fetched.metadata {
+                    let torrent = match tds_core::parse_info_dict(&metadata, &magnet.trackers) {
+                        Ok(torrent) => torrent,
+                        Err(e) => break Err(format!("Invalid metadata: {}", e).into()),
+                    };
+                    let peers = searched_peers
+                        .into_iter()
+                        .filter(|addr| connections.iter().all(|c| c.addr() != *addr))
+                        .collect();
+                    break Ok(ResolvedMagnet {
+                        magnet: magnet.clone(),
+                        torrent,
+                        peer_id,
+                        connections,
+                        peers,
+                    });
                 }
             }
-            _ = tokio::time::sleep(Duration::from_millis(500)) => {
-                 let peers = dht.get_found_peers().await;
-                 for peer in peers {
-                    if searched_peers.contains(&peer) {
-                        continue;
-                    }
-                    searched_peers.insert(peer);
+            Some(peer) = found_rx.recv() => {
+                if !searched_peers.insert(peer) {
+                    continue;
+                }
 
-                    // println!("Found peer: {}", peer);
-                    let sem = semaphore.clone();
-                    let tx = tx.clone();
-                    let info_hash = info_hash;
+                let sem = semaphore.clone();
+                let tx = tx.clone();
+                let utp = utp.clone();
 
-                    tokio::spawn(async move {
-                        if let Ok(_permit) = sem.acquire().await {
-                            if let Err(_e) = attempt_metadata_fetch(peer, info_hash, tx).await {
-                                // println!("Failed to fetch metadata from {}: {}", peer, e);
-                            }
+                tokio::spawn(async move {
+                    if let Ok(_permit) = sem.acquire().await
+                        && let Err(_e) = attempt_metadata_fetch(peer, info_hash, peer_id, encryption, transport, utp, tx).await {
+                            // println!("Failed to fetch metadata from {}: {}", peer, e);
                         }
-                    });
-                 }
+                });
             }
         }
+    };
+
+    // The session runs a node of its own; this one would otherwise keep its socket open
+    if let Some(dht) = resolver_dht {
+        dht.shutdown().await;
     }
+    result
 }
 
-/// Attempts to fetch metadata from a single peer using the extension protocol (ut_metadata).
+/// Asks a tracker for peers of `info_hash`.
+///
+/// We do not accept connections while resolving, and the size of the torrent is not
+/// known yet; `left` is nonzero so that the tracker hands out seeds.
+///
+/// # Returns
+///
+/// * `Vec<SocketAddr>` - The peers returned, or none if the tracker failed.
+async fn announce(url: &str, info_hash: [u8; 20], peer_id: [u8; 20]) -> Vec<SocketAddr> {
+    let request = TrackerRequest {
+        info_hash,
+        peer_id,
+        port: DEFAULT_LISTEN_PORT,
+        uploaded: 0,
+        downloaded: 0,
+        left: 1,
+        compact: true,
+        no_peer_id: false,
+        event: None,
+        ip: None,
+        numwant: Some(50),
+        key: None,
+        tracker_id: None,
+    };
+
+    let url = url.to_string();
+    // Tracker requests are blocking HTTP/UDP calls
+    tokio::task::spawn_blocking(move || match get_tracker_client(&url) {
+        Some(client) => match client.announce(&request) {
+            Ok(response) => {
+                println!(
+                    "Tracker response from {}: {} peers",
+                    url,
+                    response.peers.len()
+                );
+                response.peers
+            }
+            Err(e) => {
+                eprintln!("Tracker {} failed: {}", url, e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// A peer connected while resolving, with the metadata if it sent it.
+struct Fetched {
+    peer: PeerConnection,
+    metadata: Option<Vec<u8>>,
+}
+
+/// Connects to a peer and attempts to fetch the metadata from it using the extension
+/// protocol (ut_metadata).
+///
+/// The connection is reported whether or not the peer had the metadata, so that the
+/// download can go on with it. Messages read from it meanwhile are put back.
 ///
 /// # Arguments
 ///
 /// * `peer` - The address of the peer to connect to.
 /// * `info_hash` - The target info hash.
-/// * `tx` - A channel sender to report success.
+/// * `peer_id` - Our peer ID.
+/// * `encryption` - Whether the connection is encrypted.
+/// * `transport` - Which transports to try.
+/// * `utp` - The socket to open uTP connections from.
+/// * `tx` - A channel sender to report the connection.
 async fn attempt_metadata_fetch(
-    peer: SocketAddrV4,
+    peer: SocketAddr,
     info_hash: [u8; 20],
-    tx: mpsc::Sender<Vec<u8>>,
+    peer_id: [u8; 20],
+    encryption: EncryptionPolicy,
+    transport: TransportPolicy,
+    utp: Option<UtpSocket>,
+    tx: mpsc::Sender<Fetched>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut client_id = [0u8; 20];
-    rand::Rng::fill(&mut rand::rng(), &mut client_id);
-
+    // Long enough to fall back to a second transport
     let mut peer_conn = match tokio::time::timeout(
-        Duration::from_secs(3),
-        PeerConnection::connect(peer, &info_hash, &client_id),
+        Duration::from_secs(10),
+        PeerConnection::dial(
+            peer,
+            &info_hash,
+            &peer_id,
+            encryption,
+            transport,
+            utp.as_ref(),
+        ),
     )
     .await
     {
@@ -134,11 +343,41 @@ async fn attempt_metadata_fetch(
         Err(_) => return Err("Connect timeout".into()),
     };
 
+    let mut other = Vec::new();
+    let metadata = fetch_metadata(&mut peer_conn, info_hash, &mut other).await?;
+    if metadata.is_some() {
+        println!("Metadata acquired from {}!", peer);
+    }
+    peer_conn.unread(other);
+    let _ = tx
+        .send(Fetched {
+            peer: peer_conn,
+            metadata,
+        })
+        .await;
+    Ok(())
+}
+
+/// Requests the metadata over an established connection.
+///
+/// # Arguments
+///
+/// * `peer_conn` - The connection.
+/// * `info_hash` - The hash the metadata must match.
+/// * `other` - Receives the messages read that are not part of the exchange.
+///
+/// # Returns
+///
+/// * `Ok(None)` - The peer does not offer the metadata.
+/// * `Err(...)` - The connection failed or the peer sent metadata that does not match.
+async fn fetch_metadata(
+    peer_conn: &mut PeerConnection,
+    info_hash: [u8; 20],
+    other: &mut Vec<Message>,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
     // Send our extended handshake
     let mut m = BTreeMap::new();
-    let mut ut_metadata_dict = BTreeMap::new();
-    ut_metadata_dict.insert(b"ut_metadata".to_vec(), Bencode::Int(2)); // We assign ID 2 for ut_metadata
-    m.insert(b"ut_metadata".to_vec(), Bencode::Dict(ut_metadata_dict));
+    m.insert(b"ut_metadata".to_vec(), Bencode::Int(UT_METADATA_ID as i64));
 
     let mut handshake_payload = BTreeMap::new();
     handshake_payload.insert(b"m".to_vec(), Bencode::Dict(m));
@@ -155,43 +394,46 @@ async fn attempt_metadata_fetch(
     let mut ut_metadata_id = 0;
     let mut metadata_size = 0;
 
-    // Wait for handshake response (timeout 10s)
+    // Wait for handshake response (timeout 5s)
     let handshake_fut = async {
         loop {
             let msg = peer_conn.read_message().await?;
-            match msg {
-                Message::Extended { id, payload } => {
-                    if id == 0 {
-                        // Handshake response
-                        let mut pos = 0;
-                        let root = decode(&payload, &mut pos)?;
-                        if let Bencode::Dict(d) = root {
-                            if let Some(Bencode::Dict(m)) = d.get(b"m".as_slice()) {
-                                if let Some(Bencode::Int(id)) = m.get(b"ut_metadata".as_slice()) {
-                                    ut_metadata_id = *id as u8;
-                                }
-                            }
-                            if let Some(Bencode::Int(size)) = d.get(b"metadata_size".as_slice()) {
-                                metadata_size = *size as u32;
-                            }
-                        }
-                        return Ok::<(), Box<dyn std::error::Error + Send + Sync>>(());
+            if let Message::Extended { id: 0, payload } = &msg {
+                let mut pos = 0;
+                if let Bencode::Dict(d) = decode(payload, &mut pos)? {
+                    if let Some(Bencode::Dict(m)) = d.get(b"m".as_slice())
+                        && let Some(Bencode::Int(id)) = m.get(b"ut_metadata".as_slice())
+                    {
+                        ut_metadata_id = *id as u8;
+                    }
+                    if let Some(Bencode::Int(size)) = d.get(b"metadata_size".as_slice()) {
+                        metadata_size = *size as u32;
                     }
                 }
-                _ => continue,
+                // The download needs the peer's handshake as well
+                other.push(msg);
+                return Ok::<(), Box<dyn std::error::Error + Send + Sync>>(());
             }
+            other.push(msg);
         }
     };
 
-    tokio::time::timeout(Duration::from_secs(5), handshake_fut).await??;
+    if tokio::time::timeout(Duration::from_secs(5), handshake_fut)
+        .await
+        .is_err()
+    {
+        // A peer without the extension protocol; it may still have pieces
+        return Ok(None);
+    }
 
     if ut_metadata_id == 0 || metadata_size == 0 {
-        return Err("Peer does not support ut_metadata or didn't send size".into());
+        // Peer does not support ut_metadata or didn't send size
+        return Ok(None);
     }
 
     // Request metadata pieces
-    let piece_size = 16 * 1024;
-    let num_pieces = (metadata_size + piece_size - 1) / piece_size;
+    let piece_size = METADATA_PIECE_LEN as u32;
+    let num_pieces = metadata_size.div_ceil(piece_size);
     let mut metadata = vec![0u8; metadata_size as usize];
     let mut received_pieces = 0;
 
@@ -215,47 +457,45 @@ async fn attempt_metadata_fetch(
         while received_pieces < num_pieces {
             let msg = peer_conn.read_message().await?;
             match msg {
-                Message::Extended { id, payload } => {
-                    if id == ut_metadata_id {
-                        let mut pos = 0;
-                        let root = decode(&payload, &mut pos)?;
-
-                        let mut piece_index = 0;
-                        if let Bencode::Dict(d) = root {
-                            if let Some(Bencode::Int(type_)) = d.get(b"msg_type".as_slice()) {
-                                if *type_ == 1 {
-                                    // 1 = data
-                                    if let Some(Bencode::Int(idx)) = d.get(b"piece".as_slice()) {
-                                        piece_index = *idx as u32;
-                                    }
-
-                                    // Data starts at pos
-                                    if pos < payload.len() {
-                                        let data = &payload[pos..];
-                                        let start = (piece_index * piece_size) as usize;
-                                        let end = std::cmp::min(start + data.len(), metadata.len());
-                                        if start < metadata.len() {
-                                            metadata[start..end]
-                                                .copy_from_slice(&data[0..(end - start)]);
-                                            received_pieces += 1;
-                                        }
-                                    }
-                                } else if *type_ == 2 {
-                                    return Err::<(), Box<dyn std::error::Error + Send + Sync>>(
-                                        "Peer rejected metadata request".into(),
-                                    );
+                Message::Extended { id, payload } if id == UT_METADATA_ID => {
+                    let mut pos = 0;
+                    let root = decode(&payload, &mut pos)?;
+
+                    let mut piece_index = 0;
+                    if let Bencode::Dict(d) = root
+                        && let Some(Bencode::Int(type_)) = d.get(b"msg_type".as_slice())
+                    {
+                        if *type_ == 1 {
+                            // 1 = data
+                            if let Some(Bencode::Int(idx)) = d.get(b"piece".as_slice()) {
+                                piece_index = *idx as u32;
+                            }
+
+                            // Data starts at pos
+                            if pos < payload.len() {
+                                let data = &payload[pos..];
+                                let start = (piece_index * piece_size) as usize;
+                                let end = std::cmp::min(start + data.len(), metadata.len());
+                                if start < metadata.len() {
+                                    metadata[start..end].copy_from_slice(&data[0..(end - start)]);
+                                    received_pieces += 1;
                                 }
                             }
+                        } else if *type_ == 2 {
+                            return Ok(false);
                         }
                     }
                 }
-                _ => {}
+                msg => other.push(msg),
             }
         }
-        Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+        Ok::<bool, Box<dyn std::error::Error + Send + Sync>>(true)
     };
 
-    tokio::time::timeout(Duration::from_secs(10), download_fut).await??;
+    if !tokio::time::timeout(Duration::from_secs(10), download_fut).await?? {
+        // Peer rejected metadata request
+        return Ok(None);
+    }
 
     // Verify hash
     let mut hasher = Sha1::new();
@@ -263,9 +503,7 @@ async fn attempt_metadata_fetch(
     let hash: [u8; 20] = hasher.finalize().into();
 
     if hash == info_hash {
-        println!("Metadata acquired from {}!", peer);
-        let _ = tx.send(metadata).await;
-        Ok(())
+        Ok(Some(metadata))
     } else {
         Err("Hash mismatch".into())
     }
@@ -273,7 +511,8 @@ async fn attempt_metadata_fetch(
 
 /// Parses a magnet URI scheme.
 ///
-/// Supports standard `magnet:?xt=urn:btih:<hex_hash>` format.
+/// Supports `magnet:?xt=urn:btih:<hash>` with the hash in hex (40 characters) or
+/// base32 (32 characters), plus the `dn`, `tr`, `x.pe` and `so` parameters.
 ///
 /// # Arguments
 ///
@@ -281,55 +520,232 @@ async fn attempt_metadata_fetch(
 ///
 /// # Returns
 ///
-/// * `Result<([u8; 20], Vec<String>), ...>` - A tuple containing the 20-byte info hash and a list of tracker URLs.
-fn parse_magnet_link(
+/// * `Result<MagnetLink, ...>` - The parsed link.
+pub fn parse_magnet_link(
     uri: &str,
-) -> Result<([u8; 20], Vec<String>), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<MagnetLink, Box<dyn std::error::Error + Send + Sync>> {
     let url = Url::parse(uri)?;
     if url.scheme() != "magnet" {
         return Err("Not a magnet link".into());
     }
 
     let mut hash = None;
+    let mut display_name = None;
     let mut trackers = Vec::new();
+    let mut peers = Vec::new();
+    let mut select_only = None;
 
     for (k, v) in url.query_pairs() {
-        if k == "xt" {
-            if v.starts_with("urn:btih:") {
-                let h = &v["urn:btih:".len()..];
-                if h.len() == 40 {
-                    let mut arr = [0u8; 20];
-                    hex::decode_to_slice(h, &mut arr).map_err(|_| "Invalid hex hash")?;
-                    hash = Some(arr);
-                } else if h.len() == 32 {
-                    return Err("Base32 magnet links not yet supported".into());
+        match k.as_ref() {
+            "xt" => {
+                if let Some(h) = v.strip_prefix("urn:btih:") {
+                    if h.len() == 40 {
+                        let mut arr = [0u8; 20];
+                        hex::decode_to_slice(h, &mut arr).map_err(|_| "Invalid hex hash")?;
+                        hash = Some(arr);
+                    } else if h.len() == 32 {
+                        hash = Some(decode_base32(h).ok_or("Invalid base32 hash")?);
+                    }
                 }
             }
-        } else if k == "tr" {
-            trackers.push(v.to_string());
+            "dn" => display_name = Some(v.to_string()),
+            "tr" => trackers.push(v.to_string()),
+            "x.pe" => peers.push(v.to_string()),
+            "so" => select_only = Some(parse_file_selection(&v)?),
+            _ => {}
         }
     }
 
-    if let Some(h) = hash {
-        Ok((h, trackers))
-    } else {
-        Err("Missing info hash".into())
+    match hash {
+        Some(info_hash) => Ok(MagnetLink {
+            info_hash,
+            display_name,
+            trackers,
+            peers,
+            select_only,
+        }),
+        None => Err("Missing info hash".into()),
+    }
+}
+
+/// Decodes a 32-character base32 info hash (RFC 4648 alphabet, any case).
+fn decode_base32(s: &str) -> Option<[u8; 20]> {
+    let mut out = [0u8; 20];
+    let (mut bits, mut value, mut len) = (0u32, 0u64, 0);
+    for c in s.bytes() {
+        let digit = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        value = (value << 5) | digit as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            *out.get_mut(len)? = (value >> bits) as u8;
+            len += 1;
+        }
     }
+    (len == 20).then_some(out)
+}
+
+/// Parses the `so` parameter: file indices and inclusive ranges, separated by commas
+/// (`0,2,4-6`).
+fn parse_file_selection(
+    s: &str,
+) -> Result<Vec<RangeInclusive<usize>>, Box<dyn std::error::Error + Send + Sync>> {
+    s.split(',')
+        .map(|part| {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last) = (first.trim().parse()?, last.trim().parse()?);
+            if first > last {
+                return Err(format!("Invalid file range {}", part).into());
+            }
+            Ok(first..=last)
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_parse_magnet_link_valid_hex() {
-        let uri = "magnet:?xt=urn:btih:5b635ca35e4d2847a83709033333333333333333&tr=http://tracker.com";
+        let uri =
+            "magnet:?xt=urn:btih:5b635ca35e4d2847a83709033333333333333333&tr=http://tracker.com";
         let res = parse_magnet_link(uri);
         assert!(res.is_ok());
-        let (hash, trackers) = res.unwrap();
-        assert_eq!(hex::encode(hash), "5b635ca35e4d2847a83709033333333333333333");
-        assert_eq!(trackers.len(), 1);
-        assert_eq!(trackers[0], "http://tracker.com");
+        let magnet = res.unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "5b635ca35e4d2847a83709033333333333333333"
+        );
+        assert_eq!(magnet.trackers.len(), 1);
+        assert_eq!(magnet.trackers[0], "http://tracker.com");
+        assert!(magnet.selects(7));
+    }
+
+    #[test]
+    fn test_parse_magnet_link_base32_name_peers_and_selection() {
+        let uri = "magnet:?xt=urn:btih:LNRVZI26JUUEPKBXBEBTGMZTGMZTGMZT&dn=Some+File&x.pe=10.0.0.1:6881&x.pe=%5B2001:db8::1%5D:6881&so=0,2,4-6";
+        let magnet = parse_magnet_link(uri).unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "5b635ca35e4d2847a83709033333333333333333"
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("Some File"));
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881", "[2001:db8::1]:6881"]);
+        let selected: Vec<usize> = (0..8).filter(|&i| magnet.selects(i)).collect();
+        assert_eq!(selected, vec![0, 2, 4, 5, 6]);
+
+        assert!(parse_magnet_link("magnet:?xt=urn:btih:LNRVZI26JUUEPKBXBEBTGMZTGMZTGM01").is_err());
+        assert!(
+            parse_magnet_link("magnet:?xt=urn:btih:LNRVZI26JUUEPKBXBEBTGMZTGMZTGMZT&so=3-1")
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_peer_hint_without_dht() {
+        let mut metadata = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        metadata.extend_from_slice(&[b'X'; 20]);
+        metadata.push(b'e');
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+        // A peer that serves the metadata in one piece
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = metadata.clone();
+        tokio::spawn(async move {
+            let (stream, from) = listener.accept().await.unwrap();
+            let mut peer = PeerConnection::accept(
+                stream,
+                from,
+                &HashMap::from([(info_hash, [9u8; 20])]),
+                EncryptionPolicy::Preferred,
+            )
+            .await
+            .unwrap();
+            peer.send_message(Message::Bitfield(vec![0x80]))
+                .await
+                .unwrap();
+            let mut m = BTreeMap::new();
+            m.insert(b"ut_metadata".to_vec(), Bencode::Int(3));
+            let mut handshake = BTreeMap::new();
+            handshake.insert(b"m".to_vec(), Bencode::Dict(m));
+            handshake.insert(b"metadata_size".to_vec(), Bencode::Int(served.len() as i64));
+            let payload = Bencode::Dict(handshake).encode();
+            peer.send_message(Message::Extended { id: 0, payload })
+                .await
+                .unwrap();
+            loop {
+                if let Message::Extended { id: 3, .. } = peer.read_message().await.unwrap() {
+                    let mut data = BTreeMap::new();
+                    data.insert(b"msg_type".to_vec(), Bencode::Int(1));
+                    data.insert(b"piece".to_vec(), Bencode::Int(0));
+                    data.insert(b"total_size".to_vec(), Bencode::Int(served.len() as i64));
+                    let mut payload = Bencode::Dict(data).encode();
+                    payload.extend_from_slice(&served);
+                    let id = UT_METADATA_ID;
+                    peer.send_message(Message::Extended { id, payload })
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let magnet = MagnetLink {
+            info_hash,
+            display_name: None,
+            trackers: Vec::new(),
+            peers: vec![addr.to_string()],
+            select_only: None,
+        };
+        let mut resolved = tokio::time::timeout(
+            Duration::from_secs(10),
+            resolve(
+                &magnet,
+                None,
+                EncryptionPolicy::Preferred,
+                TransportPolicy::Tcp,
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(resolved.torrent.info_hash, info_hash);
+        assert_eq!(resolved.torrent.name, "a");
+        assert!(resolved.peers.is_empty());
+
+        // The connection is handed over with what the peer sent before the metadata
+        assert_eq!(resolved.connections.len(), 1);
+        let peer = &mut resolved.connections[0];
+        assert!(peer.has_piece(0));
+        assert!(matches!(
+            peer.read_message().await.unwrap(),
+            Message::Bitfield(_)
+        ));
+        assert!(matches!(
+            peer.read_message().await.unwrap(),
+            Message::Extended { id: 0, .. }
+        ));
+
+        let unreachable = MagnetLink {
+            peers: Vec::new(),
+            ..magnet
+        };
+        assert!(
+            resolve(
+                &unreachable,
+                None,
+                EncryptionPolicy::Preferred,
+                TransportPolicy::Tcp
+            )
+            .await
+            .is_err()
+        );
     }
 
     #[test]
@@ -337,7 +753,7 @@ mod tests {
         let uri = "magnet:?xt=urn:btih:5b635ca35e4d2847a83709033333333333333333&tr=http://t1.com&tr=http://t2.com";
         let res = parse_magnet_link(uri);
         assert!(res.is_ok());
-        let (_, trackers) = res.unwrap();
+        let trackers = res.unwrap().trackers;
         assert_eq!(trackers.len(), 2);
         assert_eq!(trackers[0], "http://t1.com");
         assert_eq!(trackers[1], "http://t2.com");
@@ -352,16 +768,16 @@ mod tests {
     fn test_parse_magnet_link_missing_xt() {
         assert!(parse_magnet_link("magnet:?tr=http://tracker.com").is_err());
     }
-    
+
     #[test]
     fn test_parse_magnet_link_invalid_hex_len() {
         // Too short
         let uri = "magnet:?xt=urn:btih:12345&tr=http://tracker.com";
-        assert!(parse_magnet_link(uri).is_ok() == false); // Should just fail to find hash or error
+        assert!(parse_magnet_link(uri).is_err()); // Should just fail to find hash or error
         // Actually code checks for len==40. If len != 40 and != 32, it falls through loops and returns Missing info hash
         match parse_magnet_link(uri) {
-             Err(e) => assert_eq!(e.to_string(), "Missing info hash"),
-             Ok(_) => panic!("Should have failed"),
+            Err(e) => assert_eq!(e.to_string(), "Missing info hash"),
+            Ok(_) => panic!("Should have failed"),
         }
     }
 }
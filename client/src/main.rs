@@ -5,39 +5,76 @@
 //! ```bash
 //! cargo run --bin client -- --torrent <path/to/file.torrent or magnet_link> [--output <path/to/download>]
 //! ```
+//!
+//! Once the download completes the client keeps seeding it; press Ctrl+C to stop.
+//!
+//! To join a private DHT network, point the client at its bootstrap node:
+//!
+//! ```bash
+//! cargo run --bin client -- --torrent <...> --dht-bootstrap 10.0.0.1:6881
+//! ```
+//!
+//! Without the DHT, a magnet link needs a tracker (`tr=`) or a peer address (`x.pe=`):
+//!
+//! ```bash
+//! cargo run --bin client -- --no-dht --torrent "magnet:?xt=urn:btih:<hash>&x.pe=10.0.0.2:6881"
+//! ```
+//!
+//! To only talk to peers over encrypted connections:
+//!
+//! ```bash
+//! cargo run --bin client -- --torrent <...> --encryption required
+//! ```
+//!
+//! To keep a download in the background of a shared link, prefer uTP:
+//!
+//! ```bash
+//! cargo run --bin client -- --torrent <...> --transport prefer-utp
+//! ```
 
 use clap::Parser;
 
 use client::cli::Args;
+use client::dht::DhtConfig;
 use client::downloader::Downloader;
 use client::magnet;
-use tds_core::Torrent;
+use client::peer::EncryptionConfig;
+use client::session::SessionConfig;
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let torrent_struct = if args.torrent.starts_with("magnet:") {
-        println!("Magnet link detected, resolving metadata...");
-        match magnet::resolve(&args.torrent).await {
-            Ok(info_bytes) => {
-                println!("Metadata resolved! Parsing...");
-                // Construct a synthetic .torrent file content in memory
-                // structure: d8:announce0:4:info<INFO_BYTES>e
-                // This wraps the raw info dictionary into a valid bencoded structure
-                // so the parser can handle it.
-                let mut wrapper = Vec::new();
-                wrapper.extend_from_slice(b"d8:announce0:4:info");
-                wrapper.extend_from_slice(&info_bytes);
-                wrapper.push(b'e');
+    let mut dht_config = DhtConfig {
+        state_file: Some(args.dht_state.clone()),
+        ..DhtConfig::default()
+    };
+    if !args.dht_bootstrap.is_empty() {
+        dht_config.bootstrap_nodes = args.dht_bootstrap.clone();
+    }
+    let dht_config = (!args.no_dht).then_some(dht_config);
 
-                match tds_core::parse_torrent_from_bytes(&wrapper) {
-                    Ok(t) => t,
-                    Err(e) => {
-                        eprintln!("Error parsing resolved metadata: {}", e);
-                        return;
-                    }
-                }
+    let downloader = if args.torrent.starts_with("magnet:") {
+        println!("Magnet link detected, resolving metadata...");
+        let magnet = match magnet::parse_magnet_link(&args.torrent) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("Error parsing magnet link: {}", e);
+                return;
+            }
+        };
+        // A node of its own, on a random port, so it does not hold the session's port.
+        // It keeps no state file: taking over the session's node ID would put two nodes
+        // with the same ID on the network
+        let resolver_dht = dht_config.clone().map(|config| DhtConfig {
+            port: 0,
+            state_file: None,
+            ..config
+        });
+        match magnet::resolve(&magnet, resolver_dht, args.encryption, args.transport).await {
+            Ok(resolved) => {
+                println!("Metadata resolved: {}", resolved.torrent.name);
+                Downloader::from_magnet(resolved, args.output).await
             }
             Err(e) => {
                 eprintln!("Error resolving magnet link: {}", e);
@@ -46,7 +83,7 @@ async fn main() {
         }
     } else {
         match tds_core::parse_torrent(&args.torrent) {
-            Ok(t) => t,
+            Ok(t) => Downloader::from_torrent(t, args.output).await,
             Err(e) => {
                 eprintln!("Error parsing torrent file: {}", e);
                 return;
@@ -54,7 +91,7 @@ async fn main() {
         }
     };
 
-    let mut downloader = match Downloader::from_torrent(torrent_struct, args.output).await {
+    let mut downloader = match downloader {
         Ok(d) => d,
         Err(e) => {
             eprintln!("Error initializing downloader: {}", e);
@@ -62,6 +99,12 @@ async fn main() {
         }
     };
 
+    if let Some(depth) = args.pipeline_depth {
+        downloader.config.pipeline_depth = depth;
+    }
+    downloader.config.download_rate_limit = args.download_limit.map(|kib| kib as f64 * 1024.0);
+    downloader.config.upload_rate_limit = args.upload_limit.map(|kib| kib as f64 * 1024.0);
+
     if let Err(e) = downloader.check_existing_data().await {
         eprintln!("Error checking existing data: {}", e);
         // We continue even if check fails, maybe? Or return?
@@ -69,6 +112,17 @@ async fn main() {
     }
 
     println!("Starting download...");
-    downloader.run().await;
-    println!("Download finished.");
+    downloader
+        .run_with_config(SessionConfig {
+            max_connections: 50,
+            dht: dht_config,
+            encryption: EncryptionConfig {
+                outbound: args.encryption,
+                inbound: args.encryption,
+            },
+            transport: args.transport,
+            ..SessionConfig::default()
+        })
+        .await;
+    println!("Torrent stopped.");
 }
@@ -1,7 +1,36 @@
-use std::net::SocketAddrV4;
+mod mse;
+mod transport;
+
+pub use mse::{EncryptionConfig, EncryptionPolicy};
+pub use transport::{Transport, TransportPolicy};
+
+use crate::utp::{UtpSocket, UtpStream};
+use bytes::{Buf, BufMut, BytesMut};
+use mse::Cipher;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
+use transport::Protocol;
+
+/// The largest message we accept from a peer (a 16 KiB block plus generous headroom).
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// How long a peer may stay silent before we drop it. Peers send a keep-alive at least
+/// every two minutes.
+const READ_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// How long we stay silent before sending a keep-alive, well within the two minutes
+/// after which peers drop a silent connection.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The protocol string at the start of the handshake.
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// The Fast extension bit (BEP 6), in the last reserved byte of the handshake.
+const FAST_EXTENSION_BIT: u8 = 0x04;
 
 /// Represents the messages exchanged in the BitTorrent protocol.
 ///
@@ -80,16 +109,59 @@ pub enum Message {
     /// * `id`: The extended message ID (0 for handshake).
     /// * `payload`: The extended message payload (often bencoded dictionary).
     Extended { id: u8, payload: Vec<u8> },
+
+    /// Suggests a piece to download, typically one the sender has in its cache (BEP 6).
+    /// Id: 13
+    ///
+    /// # Fields
+    /// * `0` (u32): The index of the suggested piece.
+    Suggest(u32),
+
+    /// Replaces the bitfield when the sender has every piece (BEP 6).
+    /// Id: 14
+    HaveAll,
+
+    /// Replaces the bitfield when the sender has no pieces (BEP 6).
+    /// Id: 15
+    HaveNone,
+
+    /// Tells the receiver that a request will not be answered (BEP 6).
+    /// Id: 16
+    ///
+    /// # Fields
+    /// * `index`: The piece index.
+    /// * `begin`: The byte offset.
+    /// * `length`: The length.
+    Reject { index: u32, begin: u32, length: u32 },
+
+    /// Allows the receiver to request a piece even while choked (BEP 6).
+    /// Id: 17
+    ///
+    /// # Fields
+    /// * `0` (u32): The index of the piece.
+    AllowedFast(u32),
 }
 
-/// Manages a TCP connection to a peer in the BitTorrent swarm.
+/// Manages a connection to a peer in the BitTorrent swarm.
+///
+/// Handles the handshake, optionally encrypted (MSE/PE), state tracking
+/// (choked/interested), and message framing. Messages with IDs we do not know are skipped.
 ///
-/// Handles the handshake, state tracking (choked/interested), and message framing.
-pub struct PeerConnection {
+/// The connection runs over any byte stream; peers are normally reached over a
+/// `Transport`, either TCP or uTP.
+pub struct PeerConnection<S = Transport> {
     /// The IP address and port of the peer.
-    addr: SocketAddrV4,
-    /// The underlying TCP stream.
-    stream: TcpStream,
+    addr: SocketAddr,
+    /// The underlying stream.
+    stream: S,
+    /// The RC4 streams, if the connection is encrypted.
+    cipher: Option<Cipher>,
+    /// The info hash agreed on during the handshake.
+    info_hash: [u8; 20],
+    /// Whether we dialed the peer, as opposed to accepting its connection.
+    outbound: bool,
+    /// Whether the peer's handshake has the Fast extension bit; ours always does.
+    fast: bool,
     /// The peer's ID from the handshake.
     pub peer_id: [u8; 20],
 
@@ -105,9 +177,23 @@ pub struct PeerConnection {
 
     /// A bitfield representing the pieces this peer possesses.
     pub bitfield: Vec<u8>,
+    /// Whether the peer sent `HaveAll`; it then has every piece whatever `bitfield` says.
+    pub have_all: bool,
+
+    /// Bytes received from the peer, decrypted, that do not form a complete message yet.
+    read_buf: BytesMut,
+    /// Messages put back with `unread`, returned before anything else.
+    unread: VecDeque<Message>,
+    /// When we last sent something to the peer.
+    last_sent: Instant,
 }
 
-impl PeerConnection {
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerConnection<S> {
+    /// Returns the socket address of the remote peer.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     /// Checks if the peer has a specific piece.
     ///
     /// # Arguments
@@ -118,6 +204,9 @@ impl PeerConnection {
     ///
     /// * `bool` - `true` if the peer has the piece, `false` otherwise.
     pub fn has_piece(&self, index: u32) -> bool {
+        if self.have_all {
+            return true;
+        }
         let byte_index = (index / 8) as usize;
         let bit_index = 7 - (index % 8);
         if byte_index < self.bitfield.len() {
@@ -127,67 +216,191 @@ impl PeerConnection {
         }
     }
 
-    /// Establishes a connection to a peer and performs the BitTorrent handshake.
+    /// Returns when a keep-alive is due: `KEEPALIVE_INTERVAL` after we last sent
+    /// something to the peer.
+    pub fn keepalive_due(&self) -> Instant {
+        self.last_sent + KEEPALIVE_INTERVAL
+    }
+
+    /// Performs the outbound side of the BitTorrent handshake over streams that `open`
+    /// connects to the peer.
     ///
     /// # Arguments
     ///
+    /// * `open` - Opens a new stream to the peer. It is called a second time when an
+    ///   encrypted attempt fails under the `Preferred` policy.
     /// * `addr` - The socket address of the peer.
     /// * `info_hash` - The 20-byte SHA1 hash of the info dictionary from the torrent file.
     /// * `client_id` - Our 20-byte peer ID.
-    ///
-    /// # Returns
-    ///
-    /// * `Result<Self, Box<dyn std::error::Error + Send + Sync>>` - The established connection or an error.
+    /// * `encryption` - Whether to encrypt the connection. With `Preferred`, a peer that
+    ///   fails the key exchange is connected to again in plaintext.
     ///
     /// # Errors
     ///
     /// Returns an error if:
-    /// * Connection times out (5 seconds).
+    /// * `open` fails.
+    /// * The peer's handshake does not arrive within 5 seconds.
     /// * Handshake fails (invalid protocol string, info hash mismatch).
-    pub async fn connect(
-        addr: SocketAddrV4,
+    /// * Encryption is required and the peer does not support it.
+    pub async fn connect_with<F, Fut>(
+        open: F,
+        addr: SocketAddr,
         info_hash: &[u8; 20],
         client_id: &[u8; 20],
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let mut stream =
-            tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(addr)).await??;
-
-        // Handshake
-        let mut handshake = Vec::new();
-        handshake.push(19);
-        handshake.extend_from_slice(b"BitTorrent protocol");
-        let mut reserved = [0u8; 8];
-        reserved[5] |= 0x10; // Extension protocol bit
-        handshake.extend_from_slice(&reserved);
-        handshake.extend_from_slice(info_hash);
-        handshake.extend_from_slice(client_id);
-
-        stream.write_all(&handshake).await?;
-
-        let mut response = vec![0u8; 68];
-        stream.read_exact(&mut response).await?;
-
-        if response[0] != 19 || &response[1..20] != b"BitTorrent protocol" {
-            return Err("Invalid handshake".into());
-        }
+        encryption: EncryptionPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<S, Box<dyn std::error::Error + Send + Sync>>>,
+    {
+        let handshake = build_handshake(info_hash, client_id);
+        let mut stream = open().await?;
+        let mut conn = match encryption {
+            EncryptionPolicy::Disabled => {
+                stream.write_all(&handshake).await?;
+                Self::new(addr, stream, true, None, Vec::new())
+            }
+            policy => match mse::initiate(&mut stream, info_hash, &handshake, policy).await {
+                Ok(session) => Self::new(addr, stream, true, session.cipher, session.leftover),
+                Err(e) if policy == EncryptionPolicy::Preferred => {
+                    println!(
+                        "Encryption failed with {} ({}), retrying in plaintext",
+                        addr, e
+                    );
+                    let mut stream = open().await?;
+                    stream.write_all(&handshake).await?;
+                    Self::new(addr, stream, true, None, Vec::new())
+                }
+                Err(e) => return Err(e),
+            },
+        };
 
-        if &response[28..48] != info_hash {
+        tokio::time::timeout(Duration::from_secs(5), conn.receive_handshake()).await??;
+        if &conn.info_hash != info_hash {
             return Err("Info hash mismatch".into());
         }
+        Ok(conn)
+    }
+
+    /// Performs the inbound side of the handshake on a connection accepted by a listener.
+    ///
+    /// The remote peer speaks first, either with a plaintext handshake or with an MSE key
+    /// exchange. Its info hash must be one of `torrents`; only then do we send our own
+    /// handshake back, with the client ID we use for that torrent.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The accepted stream.
+    /// * `addr` - The socket address of the remote peer.
+    /// * `torrents` - Our peer ID for each info hash we serve.
+    /// * `encryption` - Which kinds of connection to accept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// * The remote handshake does not arrive within 5 seconds.
+    /// * Handshake is invalid, or the info hash belongs to no active torrent.
+    /// * The connection is encrypted and encryption is disabled, or the other way round.
+    pub async fn accept(
+        mut stream: S,
+        addr: SocketAddr,
+        torrents: &HashMap<[u8; 20], [u8; 20]>,
+        encryption: EncryptionPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        // A plaintext handshake starts with the protocol string; anything else is taken
+        // for the initiator's public key
+        let mut first = [0u8; 20];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut first)).await??;
+        let mut conn = if first[0] == 19 && &first[1..] == PROTOCOL {
+            if encryption == EncryptionPolicy::Required {
+                return Err("Plaintext connection refused".into());
+            }
+            Self::new(addr, stream, false, None, first.to_vec())
+        } else {
+            if encryption == EncryptionPolicy::Disabled {
+                return Err("Encrypted connection refused".into());
+            }
+            let session = mse::respond(&mut stream, &first, torrents.keys(), encryption).await?;
+            Self::new(addr, stream, false, session.cipher, session.leftover)
+        };
 
-        let mut peer_id = [0u8; 20];
-        peer_id.copy_from_slice(&response[48..68]);
+        tokio::time::timeout(Duration::from_secs(5), conn.receive_handshake()).await??;
+        let client_id = match torrents.get(&conn.info_hash) {
+            Some(id) => *id,
+            None => return Err("Unknown info hash".into()),
+        };
+        conn.write_all(build_handshake(&conn.info_hash, &client_id))
+            .await?;
+        Ok(conn)
+    }
 
-        Ok(Self {
+    /// Wraps a connection whose handshake is still to be received.
+    ///
+    /// # Arguments
+    ///
+    /// * `cipher` - The RC4 streams if the key exchange selected encryption.
+    /// * `received` - Bytes already received and decrypted.
+    fn new(
+        addr: SocketAddr,
+        stream: S,
+        outbound: bool,
+        cipher: Option<Cipher>,
+        received: Vec<u8>,
+    ) -> Self {
+        Self {
             addr,
             stream,
-            peer_id,
+            cipher,
+            info_hash: [0u8; 20],
+            outbound,
+            fast: false,
+            peer_id: [0u8; 20],
             peer_choking: true,
             peer_interested: false,
             am_choking: true,
             am_interested: false,
             bitfield: Vec::new(),
-        })
+            have_all: false,
+            read_buf: BytesMut::from(&received[..]),
+            unread: VecDeque::new(),
+            last_sent: Instant::now(),
+        }
+    }
+
+    /// Reads the peer's handshake and records its info hash, peer ID and extensions.
+    async fn receive_handshake(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        while self.read_buf.len() < 68 {
+            self.fill_read_buf().await?;
+        }
+        let handshake: [u8; 68] = self.read_buf.split_to(68)[..].try_into().unwrap();
+        let handshake = parse_handshake(&handshake)?;
+        self.info_hash = handshake.info_hash;
+        self.peer_id = handshake.peer_id;
+        self.fast = handshake.reserved[7] & FAST_EXTENSION_BIT != 0;
+        Ok(())
+    }
+
+    /// Returns `true` if the connection is encrypted with RC4.
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Returns the info hash of the torrent this connection was established for.
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Returns `true` if we dialed the peer, `false` if the listener accepted it.
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// Returns `true` if both sides support the Fast extension (BEP 6).
+    ///
+    /// Without it, the peer must not be sent `Suggest`, `HaveAll`, `HaveNone`, `Reject`
+    /// or `AllowedFast`.
+    pub fn supports_fast(&self) -> bool {
+        self.fast
     }
 
     /// Sends a BitTorrent message to the peer.
@@ -199,41 +412,46 @@ impl PeerConnection {
         &mut self,
         msg: Message,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut buf = Vec::new();
         match msg {
             Message::KeepAlive => {
-                self.stream.write_u32(0).await?;
+                buf.put_u32(0);
             }
             Message::Choke => {
-                self.stream.write_u32(1).await?;
-                self.stream.write_u8(0).await?;
+                buf.put_u32(1);
+                buf.put_u8(0);
+                self.am_choking = true;
             }
             Message::Unchoke => {
-                self.stream.write_u32(1).await?;
-                self.stream.write_u8(1).await?;
+                buf.put_u32(1);
+                buf.put_u8(1);
+                self.am_choking = false;
             }
             Message::Interested => {
-                self.stream.write_u32(1).await?;
-                self.stream.write_u8(2).await?;
+                buf.put_u32(1);
+                buf.put_u8(2);
+                self.am_interested = true;
             }
             Message::NotInterested => {
-                self.stream.write_u32(1).await?;
-                self.stream.write_u8(3).await?;
+                buf.put_u32(1);
+                buf.put_u8(3);
+                self.am_interested = false;
             }
             Message::Have(index) => {
-                self.stream.write_u32(5).await?;
-                self.stream.write_u8(4).await?;
-                self.stream.write_u32(index).await?;
+                buf.put_u32(5);
+                buf.put_u8(4);
+                buf.put_u32(index);
             }
             Message::Request {
                 index,
                 begin,
                 length,
             } => {
-                self.stream.write_u32(13).await?;
-                self.stream.write_u8(6).await?;
-                self.stream.write_u32(index).await?;
-                self.stream.write_u32(begin).await?;
-                self.stream.write_u32(length).await?;
+                buf.put_u32(13);
+                buf.put_u8(6);
+                buf.put_u32(index);
+                buf.put_u32(begin);
+                buf.put_u32(length);
             }
             Message::Piece {
                 index,
@@ -241,137 +459,636 @@ impl PeerConnection {
                 block,
             } => {
                 let len = 9 + block.len() as u32;
-                self.stream.write_u32(len).await?;
-                self.stream.write_u8(7).await?;
-                self.stream.write_u32(index).await?;
-                self.stream.write_u32(begin).await?;
-                self.stream.write_all(&block).await?;
+                buf.put_u32(len);
+                buf.put_u8(7);
+                buf.put_u32(index);
+                buf.put_u32(begin);
+                buf.extend_from_slice(&block);
             }
             Message::Bitfield(bitfield) => {
                 let len = 1 + bitfield.len() as u32;
-                self.stream.write_u32(len).await?;
-                self.stream.write_u8(5).await?;
-                self.stream.write_all(&bitfield).await?;
+                buf.put_u32(len);
+                buf.put_u8(5);
+                buf.extend_from_slice(&bitfield);
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                buf.put_u32(13);
+                buf.put_u8(8);
+                buf.put_u32(index);
+                buf.put_u32(begin);
+                buf.put_u32(length);
             }
             Message::Extended { id, payload } => {
                 let len = 2 + payload.len() as u32;
-                self.stream.write_u32(len).await?;
-                self.stream.write_u8(20).await?;
-                self.stream.write_u8(id).await?;
-                self.stream.write_all(&payload).await?;
+                buf.put_u32(len);
+                buf.put_u8(20);
+                buf.put_u8(id);
+                buf.extend_from_slice(&payload);
+            }
+            Message::Suggest(index) => {
+                buf.put_u32(5);
+                buf.put_u8(13);
+                buf.put_u32(index);
             }
-            _ => { /* Ignore messages we don't send actively yet */ }
+            Message::HaveAll => {
+                buf.put_u32(1);
+                buf.put_u8(14);
+            }
+            Message::HaveNone => {
+                buf.put_u32(1);
+                buf.put_u8(15);
+            }
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => {
+                buf.put_u32(13);
+                buf.put_u8(16);
+                buf.put_u32(index);
+                buf.put_u32(begin);
+                buf.put_u32(length);
+            }
+            Message::AllowedFast(index) => {
+                buf.put_u32(5);
+                buf.put_u8(17);
+                buf.put_u32(index);
+            }
+        }
+        self.write_all(buf).await
+    }
+
+    /// Puts messages back, so that `read_message` returns them, in order, before reading
+    /// from the peer again.
+    ///
+    /// This lets a connection change hands without losing what was read from it so far,
+    /// such as the bitfield received while fetching metadata. The state the messages
+    /// changed when they were first read is kept.
+    pub fn unread(&mut self, messages: impl IntoIterator<Item = Message>) {
+        let mut messages: VecDeque<Message> = messages.into_iter().collect();
+        messages.append(&mut self.unread);
+        self.unread = messages;
+    }
+
+    /// Sends raw bytes to the peer, encrypting them if the connection is encrypted.
+    async fn write_all(
+        &mut self,
+        mut data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(cipher) = &mut self.cipher {
+            cipher.encrypt(&mut data);
+        }
+        self.stream.write_all(&data).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Reads more bytes from the peer into `read_buf`, decrypting them if the connection
+    /// is encrypted. Bytes are only ever decrypted once they are in the buffer, so this
+    /// is cancel-safe.
+    async fn fill_read_buf(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let start = self.read_buf.len();
+        self.read_buf.reserve(16 * 1024);
+        if self.stream.read_buf(&mut self.read_buf).await? == 0 {
+            return Err("Connection closed by peer".into());
+        }
+        if let Some(cipher) = &mut self.cipher {
+            cipher.decrypt(&mut self.read_buf[start..]);
         }
         Ok(())
     }
 
     /// Reads the next message from the peer.
     ///
-    /// This method includes a 30-second timeout to detect dead peers.
+    /// This method times out after `READ_TIMEOUT` of silence to detect dead peers.
     /// It also automatically updates the internal state for `Have` and `Bitfield` messages
     /// and choke/interest states.
+    ///
+    /// Received bytes are buffered until a whole message is available, so the method is
+    /// cancel-safe: it can be used in `tokio::select!` without losing a partially read message.
     pub async fn read_message(
         &mut self,
     ) -> Result<Message, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(msg) = self.unread.pop_front() {
+            return Ok(msg);
+        }
         let fut = async {
-            let len = self.stream.read_u32().await?;
+            loop {
+                if let Some(msg) = self.parse_buffered()? {
+                    return Ok(msg);
+                }
+                self.fill_read_buf().await?;
+            }
+        };
+
+        match tokio::time::timeout(READ_TIMEOUT, fut).await {
+            Ok(res) => res,
+            Err(_) => Err("Read timeout".into()),
+        }
+    }
+
+    /// Takes one complete message out of the read buffer, if there is one.
+    fn parse_buffered(
+        &mut self,
+    ) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            if self.read_buf.len() < 4 {
+                return Ok(None);
+            }
+            let len = u32::from_be_bytes(self.read_buf[..4].try_into().unwrap()) as usize;
+            if len > MAX_MESSAGE_LEN {
+                return Err(format!("Message too large: {} bytes", len).into());
+            }
+            if self.read_buf.len() < 4 + len {
+                return Ok(None);
+            }
+
+            let mut frame = self.read_buf.split_to(4 + len);
+            frame.advance(4);
             if len == 0 {
-                return Ok(Message::KeepAlive);
+                return Ok(Some(Message::KeepAlive));
+            }
+            if let Some(msg) = self.parse_frame(frame)? {
+                return Ok(Some(msg));
             }
+        }
+    }
 
-            let id = self.stream.read_u8().await?;
-            match id {
-                0 => {
-                    self.peer_choking = true;
-                    Ok(Message::Choke)
-                }
-                1 => {
-                    self.peer_choking = false;
-                    Ok(Message::Unchoke)
-                }
-                2 => {
-                    self.peer_interested = true;
-                    Ok(Message::Interested)
-                }
-                3 => {
-                    self.peer_interested = false;
-                    Ok(Message::NotInterested)
-                }
-                4 => {
-                    let index = self.stream.read_u32().await?;
-                    let byte_index = (index / 8) as usize;
-                    let bit_index = 7 - (index % 8);
-                    if byte_index >= self.bitfield.len() {
-                        self.bitfield.resize(byte_index + 1, 0);
-                    }
-                    self.bitfield[byte_index] |= 1 << bit_index;
-                    Ok(Message::Have(index))
-                }
-                5 => {
-                    let mut payload = vec![0u8; (len - 1) as usize];
-                    self.stream.read_exact(&mut payload).await?;
-                    self.bitfield = payload.clone();
-                    Ok(Message::Bitfield(payload))
+    /// Decodes the message in `frame`, which holds its ID and payload, and applies it
+    /// to the connection state.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` - The message ID is unknown; the message is skipped.
+    fn parse_frame(
+        &mut self,
+        mut frame: BytesMut,
+    ) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        let len = frame.len();
+        let id = frame.get_u8();
+        let expect = |min: usize| -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            if len - 1 < min {
+                Err(format!("Message {} too short: {} bytes", id, len).into())
+            } else {
+                Ok(())
+            }
+        };
+        let msg = match id {
+            0 => {
+                self.peer_choking = true;
+                Message::Choke
+            }
+            1 => {
+                self.peer_choking = false;
+                Message::Unchoke
+            }
+            2 => {
+                self.peer_interested = true;
+                Message::Interested
+            }
+            3 => {
+                self.peer_interested = false;
+                Message::NotInterested
+            }
+            4 => {
+                expect(4)?;
+                let index = frame.get_u32();
+                let byte_index = (index / 8) as usize;
+                let bit_index = 7 - (index % 8);
+                if byte_index >= self.bitfield.len() {
+                    self.bitfield.resize(byte_index + 1, 0);
                 }
-                6 => {
-                    let index = self.stream.read_u32().await?;
-                    let begin = self.stream.read_u32().await?;
-                    let length = self.stream.read_u32().await?;
-                    Ok(Message::Request {
-                        index,
-                        begin,
-                        length,
-                    })
+                self.bitfield[byte_index] |= 1 << bit_index;
+                Message::Have(index)
+            }
+            5 => {
+                self.bitfield = frame.to_vec();
+                self.have_all = false;
+                Message::Bitfield(frame.to_vec())
+            }
+            6 => {
+                expect(12)?;
+                Message::Request {
+                    index: frame.get_u32(),
+                    begin: frame.get_u32(),
+                    length: frame.get_u32(),
                 }
-                7 => {
-                    let index = self.stream.read_u32().await?;
-                    let begin = self.stream.read_u32().await?;
-                    let mut block = vec![0u8; (len - 9) as usize];
-                    self.stream.read_exact(&mut block).await?;
-                    Ok(Message::Piece {
-                        index,
-                        begin,
-                        block,
-                    })
+            }
+            7 => {
+                expect(8)?;
+                let index = frame.get_u32();
+                let begin = frame.get_u32();
+                Message::Piece {
+                    index,
+                    begin,
+                    block: frame.to_vec(),
                 }
-                8 => {
-                    let index = self.stream.read_u32().await?;
-                    let begin = self.stream.read_u32().await?;
-                    let length = self.stream.read_u32().await?;
-                    Ok(Message::Cancel {
-                        index,
-                        begin,
-                        length,
-                    })
+            }
+            8 => {
+                expect(12)?;
+                Message::Cancel {
+                    index: frame.get_u32(),
+                    begin: frame.get_u32(),
+                    length: frame.get_u32(),
                 }
-                20 => {
-                    let ext_id = self.stream.read_u8().await?;
-                    let mut payload = vec![0u8; (len - 2) as usize];
-                    self.stream.read_exact(&mut payload).await?;
-                    Ok(Message::Extended {
-                        id: ext_id,
-                        payload,
-                    })
+            }
+            20 => {
+                expect(1)?;
+                let ext_id = frame.get_u8();
+                Message::Extended {
+                    id: ext_id,
+                    payload: frame.to_vec(),
                 }
-                _ => {
-                    // Skip unknown message
-                    let mut buf = vec![0u8; (len - 1) as usize];
-                    self.stream.read_exact(&mut buf).await?;
-                    Err(format!("Unknown message id: {}", id).into())
+            }
+            13 => {
+                expect(4)?;
+                Message::Suggest(frame.get_u32())
+            }
+            14 => {
+                self.have_all = true;
+                Message::HaveAll
+            }
+            15 => {
+                self.bitfield.clear();
+                self.have_all = false;
+                Message::HaveNone
+            }
+            16 => {
+                expect(12)?;
+                Message::Reject {
+                    index: frame.get_u32(),
+                    begin: frame.get_u32(),
+                    length: frame.get_u32(),
                 }
             }
+            17 => {
+                expect(4)?;
+                Message::AllowedFast(frame.get_u32())
+            }
+            // An extension we do not support
+            _ => return Ok(None),
         };
+        Ok(Some(msg))
+    }
+}
 
-        match tokio::time::timeout(Duration::from_secs(30), fut).await {
-            Ok(res) => res,
-            Err(_) => Err("Read timeout".into()),
+impl PeerConnection {
+    /// Establishes a TCP connection to a peer and performs the BitTorrent handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The socket address of the peer.
+    /// * `info_hash` - The 20-byte SHA1 hash of the info dictionary from the torrent file.
+    /// * `client_id` - Our 20-byte peer ID.
+    /// * `encryption` - Whether to encrypt the connection. With `Preferred`, a peer that
+    ///   fails the key exchange is connected to again in plaintext.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, Box<dyn std::error::Error + Send + Sync>>` - The established connection or an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// * Connection times out (5 seconds).
+    /// * Handshake fails (invalid protocol string, info hash mismatch).
+    /// * Encryption is required and the peer does not support it.
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        client_id: &[u8; 20],
+        encryption: EncryptionPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let open = || async move {
+            let stream =
+                tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(addr)).await??;
+            Ok(Transport::Tcp(stream))
+        };
+        Self::connect_with(open, addr, info_hash, client_id, encryption).await
+    }
+
+    /// Establishes a uTP connection to a peer and performs the BitTorrent handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The socket to open the connection from.
+    /// * `addr` - The socket address of the peer.
+    /// * `info_hash` - The 20-byte SHA1 hash of the info dictionary from the torrent file.
+    /// * `client_id` - Our 20-byte peer ID.
+    /// * `encryption` - Whether to encrypt the connection, as for `connect`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the peer does not answer over uTP within 5 seconds, or for the
+    /// same reasons as `connect`.
+    pub async fn connect_utp(
+        socket: &UtpSocket,
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        client_id: &[u8; 20],
+        encryption: EncryptionPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let open = || async move {
+            let stream: UtpStream =
+                tokio::time::timeout(Duration::from_secs(5), socket.connect(addr)).await??;
+            Ok(Transport::Utp(stream))
+        };
+        Self::connect_with(open, addr, info_hash, client_id, encryption).await
+    }
+
+    /// Connects to a peer over the transports `transport` allows, trying them in its order
+    /// of preference.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The socket address of the peer.
+    /// * `info_hash` - The 20-byte SHA1 hash of the info dictionary from the torrent file.
+    /// * `client_id` - Our 20-byte peer ID.
+    /// * `encryption` - Whether to encrypt the connection.
+    /// * `transport` - Which transports to use, in which order.
+    /// * `utp` - The socket uTP connections are opened from, or `None` to skip uTP.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last transport tried.
+    pub async fn dial(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        client_id: &[u8; 20],
+        encryption: EncryptionPolicy,
+        transport: TransportPolicy,
+        utp: Option<&UtpSocket>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut error = None;
+        for protocol in transport.order() {
+            let result = match (protocol, utp) {
+                (Protocol::Tcp, _) => Self::connect(addr, info_hash, client_id, encryption).await,
+                (Protocol::Utp, Some(socket)) => {
+                    Self::connect_utp(socket, addr, info_hash, client_id, encryption).await
+                }
+                (Protocol::Utp, None) => continue,
+            };
+            match result {
+                Ok(peer) => return Ok(peer),
+                Err(e) => error = Some(e),
+            }
         }
+        Err(error.unwrap_or_else(|| "No transport allowed to reach the peer".into()))
+    }
+
+    /// Returns `true` if the connection runs over uTP.
+    pub fn is_utp(&self) -> bool {
+        matches!(self.stream, Transport::Utp(_))
+    }
+}
+
+/// Builds the 68-byte BitTorrent handshake.
+fn build_handshake(info_hash: &[u8; 20], client_id: &[u8; 20]) -> Vec<u8> {
+    let mut handshake = Vec::with_capacity(68);
+    handshake.push(19);
+    handshake.extend_from_slice(PROTOCOL);
+    let mut reserved = [0u8; 8];
+    reserved[5] |= 0x10; // Extension protocol bit
+    reserved[7] |= FAST_EXTENSION_BIT;
+    handshake.extend_from_slice(&reserved);
+    handshake.extend_from_slice(info_hash);
+    handshake.extend_from_slice(client_id);
+    handshake
+}
+
+/// The fields of a received handshake.
+struct Handshake {
+    reserved: [u8; 8],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+}
+
+/// Validates a received handshake and extracts its reserved bytes, info hash and peer ID.
+fn parse_handshake(buf: &[u8; 68]) -> Result<Handshake, Box<dyn std::error::Error + Send + Sync>> {
+    if buf[0] != 19 || &buf[1..20] != PROTOCOL {
+        return Err("Invalid handshake".into());
     }
+    let mut handshake = Handshake {
+        reserved: [0u8; 8],
+        info_hash: [0u8; 20],
+        peer_id: [0u8; 20],
+    };
+    handshake.reserved.copy_from_slice(&buf[20..28]);
+    handshake.info_hash.copy_from_slice(&buf[28..48]);
+    handshake.peer_id.copy_from_slice(&buf[48..68]);
+    Ok(handshake)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::net::TcpListener;
+
+    /// We serve info hash `[1; 20]` with peer ID `[9; 20]`.
+    fn torrents() -> HashMap<[u8; 20], [u8; 20]> {
+        HashMap::from([([1u8; 20], [9u8; 20])])
+    }
+
+    /// Connects a client to an inbound connection with the given policies.
+    async fn connect_pair(
+        outbound: EncryptionPolicy,
+        inbound: EncryptionPolicy,
+    ) -> (
+        Result<PeerConnection, Box<dyn std::error::Error + Send + Sync>>,
+        Result<PeerConnection<TcpStream>, Box<dyn std::error::Error + Send + Sync>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            PeerConnection::accept(stream, addr, &torrents(), inbound).await
+        });
+        let client = PeerConnection::connect(local, &[1u8; 20], &[2u8; 20], outbound).await;
+        (client, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_accept_inbound_handshake() {
+        let (client, inbound) =
+            connect_pair(EncryptionPolicy::Preferred, EncryptionPolicy::Preferred).await;
+        let mut client = client.unwrap();
+        let mut inbound = inbound.unwrap();
+        assert_eq!(client.peer_id, [9u8; 20]);
+        assert_eq!(inbound.peer_id, [2u8; 20]);
+        assert_eq!(inbound.info_hash(), [1u8; 20]);
+        assert!(client.is_encrypted() && inbound.is_encrypted());
+
+        client.send_message(Message::Have(7)).await.unwrap();
+        inbound.send_message(Message::Unchoke).await.unwrap();
+        assert!(matches!(
+            inbound.read_message().await.unwrap(),
+            Message::Have(7)
+        ));
+        assert!(matches!(
+            client.read_message().await.unwrap(),
+            Message::Unchoke
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encryption_policies() {
+        let (client, inbound) =
+            connect_pair(EncryptionPolicy::Disabled, EncryptionPolicy::Preferred).await;
+        assert!(!client.unwrap().is_encrypted());
+        assert!(!inbound.unwrap().is_encrypted());
+
+        let (client, inbound) =
+            connect_pair(EncryptionPolicy::Disabled, EncryptionPolicy::Required).await;
+        assert!(client.is_err() && inbound.is_err());
+
+        let (client, inbound) =
+            connect_pair(EncryptionPolicy::Required, EncryptionPolicy::Disabled).await;
+        assert!(client.is_err() && inbound.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_rejects_unknown_info_hash() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let torrents = HashMap::new();
+            PeerConnection::accept(stream, addr, &torrents, EncryptionPolicy::Disabled).await
+        });
+
+        assert!(
+            PeerConnection::connect(local, &[1u8; 20], &[2u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .is_err()
+        );
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_is_due_after_a_quiet_interval() {
+        let (stream, _other) = tokio::io::duplex(1024);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut conn = PeerConnection::new(addr, stream, true, None, Vec::new());
+        assert_eq!(conn.keepalive_due(), Instant::now() + KEEPALIVE_INTERVAL);
+
+        // Anything we send restarts the interval
+        tokio::time::advance(Duration::from_secs(45)).await;
+        conn.send_message(Message::Have(0)).await.unwrap();
+        assert_eq!(conn.keepalive_due(), Instant::now() + KEEPALIVE_INTERVAL);
+        assert!(KEEPALIVE_INTERVAL < READ_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_survives_cancellation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            PeerConnection::accept(stream, addr, &torrents(), EncryptionPolicy::Disabled)
+                .await
+                .unwrap()
+        });
+        let mut client =
+            PeerConnection::connect(local, &[1u8; 20], &[2u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        let mut inbound = server.await.unwrap();
+
+        // Half of a Cancel message arrives, then the read is abandoned
+        client
+            .stream
+            .write_all(&[0, 0, 0, 13, 8, 0, 0])
+            .await
+            .unwrap();
+        let pending = tokio::time::timeout(Duration::from_millis(50), inbound.read_message()).await;
+        assert!(pending.is_err());
+
+        client
+            .stream
+            .write_all(&[0, 3, 0, 0, 0, 0, 0, 0, 64, 0])
+            .await
+            .unwrap();
+        match inbound.read_message().await.unwrap() {
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => assert_eq!((index, begin, length), (3, 0, 16384)),
+            other => panic!("Expected Cancel, got {:?}", other),
+        }
+
+        // Messages put back come first, in order
+        client.send_message(Message::Have(5)).await.unwrap();
+        inbound.unread([Message::Unchoke, Message::KeepAlive]);
+        inbound.unread([Message::Choke]);
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(format!("{:?}", inbound.read_message().await.unwrap()));
+        }
+        assert_eq!(order, ["Choke", "Unchoke", "KeepAlive", "Have(5)"]);
+    }
+
+    #[tokio::test]
+    async fn test_fast_messages_and_unknown_ids() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            PeerConnection::accept(stream, addr, &torrents(), EncryptionPolicy::Disabled)
+                .await
+                .unwrap()
+        });
+        let mut client =
+            PeerConnection::connect(local, &[1u8; 20], &[2u8; 20], EncryptionPolicy::Disabled)
+                .await
+                .unwrap();
+        let mut inbound = server.await.unwrap();
+        assert!(client.supports_fast() && inbound.supports_fast());
+
+        // A message of an unknown extension is skipped without closing the connection
+        client
+            .stream
+            .write_all(&[0, 0, 0, 3, 99, 1, 2])
+            .await
+            .unwrap();
+        client.send_message(Message::HaveAll).await.unwrap();
+        client.send_message(Message::Suggest(3)).await.unwrap();
+        client
+            .send_message(Message::Reject {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            })
+            .await
+            .unwrap();
+        client.send_message(Message::AllowedFast(7)).await.unwrap();
+        client.send_message(Message::HaveNone).await.unwrap();
+
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(format!("{:?}", inbound.read_message().await.unwrap()));
+        }
+        assert_eq!(
+            order,
+            [
+                "HaveAll",
+                "Suggest(3)",
+                "Reject { index: 1, begin: 16384, length: 16384 }",
+                "AllowedFast(7)"
+            ]
+        );
+        assert!(inbound.has_piece(1000));
+        inbound.read_message().await.unwrap();
+        assert!(!inbound.has_piece(0));
+    }
 
     // Mock struct to allow testing methods that don't depend on stream if we could instantiate it.
     // However, PeerConnection fields are private/pub but creating one requires a TcpStream.
@@ -0,0 +1,490 @@
+//! Message Stream Encryption, also known as Protocol Encryption (MSE/PE).
+//!
+//! Before the BitTorrent handshake, both sides run a Diffie-Hellman key exchange
+//! over a 768-bit prime and derive two RC4 keys from the shared secret and the info
+//! hash, one per direction. The initiator offers the methods it accepts in
+//! `crypto_provide` (RC4 and/or plaintext) and the responder picks one in
+//! `crypto_select`. Random padding after each public key and the RC4-encrypted
+//! verification constant keep the exchange free of fixed byte patterns.
+
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+type MseResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// The prime modulus of the key exchange.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// The generator of the key exchange.
+const GENERATOR: u32 = 2;
+
+/// Length of a public key, and of the shared secret.
+const KEY_LEN: usize = 96;
+
+/// Maximum length of the random padding after each step.
+const MAX_PAD: usize = 512;
+
+/// The verification constant, sent encrypted so that the other side can find where
+/// its RC4 stream starts.
+const VC: [u8; 8] = [0; 8];
+
+/// `crypto_provide` / `crypto_select` bit for a plaintext stream after the handshake.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+
+/// `crypto_provide` / `crypto_select` bit for an RC4-encrypted stream.
+const CRYPTO_RC4: u32 = 0x02;
+
+/// How long the whole key exchange may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether peer connections are encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Only plaintext connections. Encrypted inbound connections are refused.
+    Disabled,
+    /// Encrypt when the peer supports it. Outbound connections try encryption first
+    /// and fall back to plaintext; inbound connections of either kind are accepted.
+    #[default]
+    Preferred,
+    /// Only RC4-encrypted connections. Plaintext peers are refused.
+    Required,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "preferred" => Ok(Self::Preferred),
+            "required" => Ok(Self::Required),
+            _ => Err(format!(
+                "Unknown encryption policy '{}', expected disabled, preferred or required",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Disabled => "disabled",
+            Self::Preferred => "preferred",
+            Self::Required => "required",
+        })
+    }
+}
+
+/// The encryption policies of connections we dial and connections we accept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// Policy of connections we dial.
+    pub outbound: EncryptionPolicy,
+    /// Policy of connections the listener accepts.
+    pub inbound: EncryptionPolicy,
+}
+
+/// The RC4 stream cipher.
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// XORs `data` with the next bytes of the key stream.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// The RC4 streams of an encrypted connection, one per direction.
+pub(super) struct Cipher {
+    encrypt: Rc4,
+    decrypt: Rc4,
+}
+
+impl Cipher {
+    /// Encrypts bytes we are about to send, in place.
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.encrypt.apply(data);
+    }
+
+    /// Decrypts bytes we received, in place.
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.decrypt.apply(data);
+    }
+}
+
+/// The outcome of a key exchange.
+pub(super) struct Established {
+    /// The ciphers of the connection, or `None` if plaintext was selected.
+    pub cipher: Option<Cipher>,
+    /// Payload received after the key exchange, already decrypted: the start of the
+    /// peer's BitTorrent handshake.
+    pub leftover: Vec<u8>,
+}
+
+/// Runs the key exchange of a connection we dialed.
+///
+/// # Arguments
+///
+/// * `stream` - The freshly opened connection.
+/// * `info_hash` - The torrent we want; the responder identifies it by its hash.
+/// * `initial` - Payload sent along with the last step, normally our BitTorrent handshake.
+/// * `policy` - `Required` offers RC4 only; otherwise plaintext is offered as well.
+pub(super) async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+    initial: &[u8],
+    policy: EncryptionPolicy,
+) -> MseResult<Established> {
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        run_initiator(stream, info_hash, initial, policy),
+    )
+    .await?
+}
+
+async fn run_initiator<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+    initial: &[u8],
+    policy: EncryptionPolicy,
+) -> MseResult<Established> {
+    let (private, public) = key_pair();
+    let mut out = public;
+    out.extend_from_slice(&random_pad());
+    stream.write_all(&out).await?;
+
+    let mut buf = BytesMut::new();
+    fill(stream, &mut buf, KEY_LEN).await?;
+    let secret = shared_secret(&buf.split_to(KEY_LEN), &private);
+    let mut encrypt = rc4_key(b"keyA", &secret, info_hash);
+    let mut decrypt = rc4_key(b"keyB", &secret, info_hash);
+
+    let provide = match policy {
+        EncryptionPolicy::Required => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+    let mut out = hash(&[b"req1", &secret]).to_vec();
+    out.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut payload = VC.to_vec();
+    payload.extend_from_slice(&provide.to_be_bytes());
+    payload.extend_from_slice(&0u16.to_be_bytes()); // No padding
+    payload.extend_from_slice(&(initial.len() as u16).to_be_bytes());
+    payload.extend_from_slice(initial);
+    encrypt.apply(&mut payload);
+    out.extend(payload);
+    stream.write_all(&out).await?;
+
+    // The responder's padding ends where its encrypted VC starts
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    sync(stream, &mut buf, &vc, MAX_PAD).await?;
+    decrypt.apply(&mut VC.clone());
+
+    fill(stream, &mut buf, 6).await?;
+    let mut head = buf.split_to(6);
+    decrypt.apply(&mut head);
+    let select = u32::from_be_bytes(head[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([head[4], head[5]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err("Encryption padding too long".into());
+    }
+    fill(stream, &mut buf, pad_len).await?;
+    decrypt.apply(&mut buf.split_to(pad_len));
+
+    let mut cipher = match select {
+        CRYPTO_RC4 if provide & CRYPTO_RC4 != 0 => Some(Cipher { encrypt, decrypt }),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => None,
+        _ => return Err(format!("Peer selected unsupported encryption {:#x}", select).into()),
+    };
+    let mut leftover = buf.to_vec();
+    if let Some(cipher) = &mut cipher {
+        cipher.decrypt(&mut leftover);
+    }
+    Ok(Established { cipher, leftover })
+}
+
+/// Runs the key exchange of a connection we accepted.
+///
+/// # Arguments
+///
+/// * `stream` - The accepted connection.
+/// * `received` - Bytes of the initiator's public key already read from `stream`.
+/// * `info_hashes` - The torrents we serve; the initiator must ask for one of them.
+/// * `policy` - Which of the offered methods we accept. RC4 is chosen when offered,
+///   unless encryption is disabled.
+pub(super) async fn respond<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    received: &[u8],
+    info_hashes: impl IntoIterator<Item = &'a [u8; 20]>,
+    policy: EncryptionPolicy,
+) -> MseResult<Established> {
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        run_responder(stream, received, info_hashes, policy),
+    )
+    .await?
+}
+
+async fn run_responder<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    received: &[u8],
+    info_hashes: impl IntoIterator<Item = &'a [u8; 20]>,
+    policy: EncryptionPolicy,
+) -> MseResult<Established> {
+    let (private, public) = key_pair();
+    let mut out = public;
+    out.extend_from_slice(&random_pad());
+
+    let mut buf = BytesMut::from(received);
+    fill(stream, &mut buf, KEY_LEN).await?;
+    stream.write_all(&out).await?;
+    let secret = shared_secret(&buf.split_to(KEY_LEN), &private);
+
+    // The initiator's padding ends where its first hash starts
+    sync(stream, &mut buf, &hash(&[b"req1", &secret]), MAX_PAD).await?;
+    fill(stream, &mut buf, 20).await?;
+    let requested = xor(&buf.split_to(20), &hash(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .into_iter()
+        .find(|info_hash| hash(&[b"req2", *info_hash]).as_slice() == requested)
+        .ok_or("Unknown info hash")?;
+    let mut decrypt = rc4_key(b"keyA", &secret, info_hash);
+    let mut encrypt = rc4_key(b"keyB", &secret, info_hash);
+
+    fill(stream, &mut buf, 14).await?;
+    let mut head = buf.split_to(14);
+    decrypt.apply(&mut head);
+    if head[..8] != VC {
+        return Err("Invalid encryption verification constant".into());
+    }
+    let provide = u32::from_be_bytes(head[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([head[12], head[13]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err("Encryption padding too long".into());
+    }
+    fill(stream, &mut buf, pad_len + 2).await?;
+    decrypt.apply(&mut buf.split_to(pad_len));
+    let mut len = buf.split_to(2);
+    decrypt.apply(&mut len);
+    let initial_len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    fill(stream, &mut buf, initial_len).await?;
+    let mut leftover = buf.split_to(initial_len).to_vec();
+    decrypt.apply(&mut leftover);
+
+    let select = if provide & CRYPTO_RC4 != 0 && policy != EncryptionPolicy::Disabled {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Required {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(format!("No acceptable encryption offered ({:#x})", provide).into());
+    };
+    let mut reply = VC.to_vec();
+    reply.extend_from_slice(&select.to_be_bytes());
+    reply.extend_from_slice(&0u16.to_be_bytes()); // No padding
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let mut rest = buf.to_vec();
+    let cipher = if select == CRYPTO_RC4 {
+        decrypt.apply(&mut rest);
+        Some(Cipher { encrypt, decrypt })
+    } else {
+        None
+    };
+    leftover.extend(rest);
+    Ok(Established { cipher, leftover })
+}
+
+/// Generates a private key and the matching public key, padded to `KEY_LEN` bytes.
+fn key_pair() -> (BigUint, Vec<u8>) {
+    let private = BigUint::from_bytes_be(&rand::rng().random::<[u8; 20]>());
+    let public = BigUint::from(GENERATOR).modpow(&private, &prime());
+    (private, to_key_bytes(&public))
+}
+
+/// Computes the shared secret from the other side's public key and our private key.
+fn shared_secret(public: &[u8], private: &BigUint) -> Vec<u8> {
+    to_key_bytes(&BigUint::from_bytes_be(public).modpow(private, &prime()))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+/// Encodes a number as `KEY_LEN` big-endian bytes.
+fn to_key_bytes(n: &BigUint) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut out = vec![0u8; KEY_LEN - bytes.len()];
+    out.extend(bytes);
+    out
+}
+
+/// Random padding of random length, up to `MAX_PAD` bytes.
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::rng();
+    let len = rng.random_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.random()).collect()
+}
+
+/// Creates the RC4 stream for one direction. The first 1024 bytes of key stream are
+/// discarded, as they leak information about the key.
+fn rc4_key(direction: &[u8], secret: &[u8], info_hash: &[u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[direction, secret, info_hash]));
+    rc4.apply(&mut [0u8; 1024]);
+    rc4
+}
+
+/// SHA-1 of the concatenated parts.
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+/// Reads until `buf` holds at least `len` bytes.
+async fn fill<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    len: usize,
+) -> MseResult<()> {
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            return Err("Connection closed during encryption handshake".into());
+        }
+    }
+    Ok(())
+}
+
+/// Reads until `pattern` shows up within the first `max_skip` bytes plus its length,
+/// and drops everything up to and including it.
+async fn sync<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    pattern: &[u8],
+    max_skip: usize,
+) -> MseResult<()> {
+    loop {
+        if let Some(pos) = buf.windows(pattern.len()).position(|w| w == pattern) {
+            buf.advance(pos + pattern.len());
+            return Ok(());
+        }
+        if buf.len() >= max_skip + pattern.len() {
+            return Err("Encryption handshake out of sync".into());
+        }
+        let len = buf.len() + 1;
+        fill(stream, buf, len).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rc4_known_answer() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange_selects_by_policy() {
+        let info_hash = [5u8; 20];
+        let cases = [
+            (
+                EncryptionPolicy::Preferred,
+                EncryptionPolicy::Preferred,
+                true,
+            ),
+            (
+                EncryptionPolicy::Preferred,
+                EncryptionPolicy::Disabled,
+                false,
+            ),
+            (EncryptionPolicy::Required, EncryptionPolicy::Required, true),
+        ];
+        for (outbound, inbound, encrypted) in cases {
+            let (mut a, mut b) = tokio::io::duplex(4096);
+            let initiator = tokio::spawn(async move {
+                let mut session = initiate(&mut a, &info_hash, b"hello", outbound)
+                    .await
+                    .unwrap();
+                let mut data = b"world".to_vec();
+                if let Some(cipher) = &mut session.cipher {
+                    cipher.encrypt(&mut data);
+                }
+                a.write_all(&data).await.unwrap();
+                session.cipher.is_some()
+            });
+
+            let mut first = [0u8; 20];
+            b.read_exact(&mut first).await.unwrap();
+            let mut session = respond(&mut b, &first, [&[1u8; 20], &info_hash], inbound)
+                .await
+                .unwrap();
+            assert_eq!(session.leftover, b"hello");
+            let mut data = [0u8; 5];
+            b.read_exact(&mut data).await.unwrap();
+            if let Some(cipher) = &mut session.cipher {
+                cipher.decrypt(&mut data);
+            }
+            assert_eq!(&data, b"world");
+            assert_eq!(session.cipher.is_some(), encrypted);
+            assert_eq!(initiator.await.unwrap(), encrypted);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_required_refuses_plaintext_only_peer() {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let _ = respond(&mut b, &[], [&[5u8; 20]], EncryptionPolicy::Disabled).await;
+        });
+        assert!(
+            initiate(&mut a, &[5u8; 20], &[], EncryptionPolicy::Required)
+                .await
+                .is_err()
+        );
+    }
+}
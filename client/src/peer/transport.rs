@@ -0,0 +1,128 @@
+//! The transports peer connections run over: TCP, or uTP on top of UDP.
+
+use crate::utp::UtpStream;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// The stream under a peer connection.
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Which transports peer connections use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportPolicy {
+    /// TCP only. No uTP socket is opened.
+    Tcp,
+    /// Dial over TCP first and fall back to uTP; accept both.
+    #[default]
+    PreferTcp,
+    /// Dial over uTP first and fall back to TCP; accept both. Transfers then yield to
+    /// other traffic on the link.
+    PreferUtp,
+    /// uTP only. Inbound TCP connections are refused.
+    Utp,
+}
+
+/// A transport to dial a peer over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Protocol {
+    Tcp,
+    Utp,
+}
+
+impl TransportPolicy {
+    /// Returns `true` if connections may run over TCP.
+    pub fn allows_tcp(self) -> bool {
+        self != Self::Utp
+    }
+
+    /// Returns `true` if connections may run over uTP.
+    pub fn allows_utp(self) -> bool {
+        self != Self::Tcp
+    }
+
+    /// The transports to dial a peer over, in the order they are tried.
+    pub(super) fn order(self) -> &'static [Protocol] {
+        match self {
+            Self::Tcp => &[Protocol::Tcp],
+            Self::PreferTcp => &[Protocol::Tcp, Protocol::Utp],
+            Self::PreferUtp => &[Protocol::Utp, Protocol::Tcp],
+            Self::Utp => &[Protocol::Utp],
+        }
+    }
+}
+
+impl FromStr for TransportPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "prefer-tcp" => Ok(Self::PreferTcp),
+            "prefer-utp" => Ok(Self::PreferUtp),
+            "utp" => Ok(Self::Utp),
+            _ => Err(format!(
+                "Unknown transport policy '{}', expected tcp, prefer-tcp, prefer-utp or utp",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TransportPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::PreferTcp => "prefer-tcp",
+            Self::PreferUtp => "prefer-utp",
+            Self::Utp => "utp",
+        })
+    }
+}
@@ -0,0 +1,413 @@
+//! Running many torrents side by side.
+//!
+//! A `Session` owns a set of downloads keyed by info hash. All of them share one
+//! listen port, one DHT node, the global rate limits and the global connection cap.
+
+use crate::dht::{Dht, DhtConfig};
+use crate::downloader::{DownloadState, Downloader, bucket};
+use crate::listener::{DEFAULT_LISTEN_PORT, PeerListener};
+use crate::peer::{EncryptionConfig, TransportPolicy};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tds_core::rate_limit::TokenBucket;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinHandle;
+
+type SessionResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Settings shared by every torrent of a `Session`.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// TCP port for inbound peer connections. Use `0` to let the OS choose.
+    pub listen_port: u16,
+    /// Settings of the DHT node, or `None` to run without DHT.
+    pub dht: Option<DhtConfig>,
+    /// Maximum number of peer connections across all torrents.
+    pub max_connections: usize,
+    /// Maximum download rate across all torrents in bytes per second, or `None` for no limit.
+    pub download_rate_limit: Option<f64>,
+    /// Maximum upload rate across all torrents in bytes per second, or `None` for no limit.
+    pub upload_rate_limit: Option<f64>,
+    /// Whether peer connections are encrypted (MSE/PE), in each direction.
+    pub encryption: EncryptionConfig,
+    /// Which transports peer connections use, TCP and/or uTP.
+    pub transport: TransportPolicy,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: DEFAULT_LISTEN_PORT,
+            dht: Some(DhtConfig::default()),
+            max_connections: 200,
+            download_rate_limit: None,
+            upload_rate_limit: Some(2_000_000.0),
+            encryption: EncryptionConfig::default(),
+            transport: TransportPolicy::default(),
+        }
+    }
+}
+
+/// The network resources and limits a torrent runs with.
+///
+/// A standalone `Downloader::run` starts its own; a `Session` starts one set and
+/// hands it to every torrent.
+#[derive(Clone)]
+pub(crate) struct SharedResources {
+    /// The listener accepting inbound peers, if the port could be bound.
+    pub listener: Option<PeerListener>,
+    /// The DHT node used to find peers, if enabled and started.
+    pub dht: Option<Arc<Dht>>,
+    /// Global download rate limit.
+    pub download_limiter: Arc<Mutex<TokenBucket>>,
+    /// Global upload rate limit.
+    pub upload_limiter: Arc<Mutex<TokenBucket>>,
+    /// Global cap on active peer connections.
+    pub connection_limit: Arc<Semaphore>,
+    /// Whether peer connections are encrypted.
+    pub encryption: EncryptionConfig,
+    /// Which transports peer connections use.
+    pub transport: TransportPolicy,
+}
+
+impl SharedResources {
+    /// Binds the listen port, starts the DHT node and creates the global limits.
+    ///
+    /// Failing to bind the listener or the DHT socket is logged, and the resources
+    /// are created without them.
+    pub async fn start(config: &SessionConfig) -> Self {
+        let listener = match PeerListener::start(
+            config.listen_port,
+            config.encryption.inbound,
+            config.transport,
+        )
+        .await
+        {
+            Ok(l) => {
+                println!("Listening for peers on port {}", l.port());
+                Some(l)
+            }
+            Err(e) => {
+                eprintln!("Failed to start peer listener: {}", e);
+                None
+            }
+        };
+
+        let dht = match &config.dht {
+            Some(dht_config) => match Dht::with_config(dht_config.clone()).await {
+                Ok(dht) => {
+                    println!("DHT started on port {}", dht.port());
+                    let dht = Arc::new(dht);
+                    dht.start().await;
+                    let bootstrap = dht.clone();
+                    tokio::spawn(async move { bootstrap.bootstrap().await });
+                    Some(dht)
+                }
+                Err(e) => {
+                    eprintln!("Failed to start DHT: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Self {
+            listener,
+            dht,
+            download_limiter: Arc::new(Mutex::new(bucket(
+                config.download_rate_limit,
+                "global download",
+            ))),
+            upload_limiter: Arc::new(Mutex::new(bucket(
+                config.upload_rate_limit,
+                "global upload",
+            ))),
+            connection_limit: Arc::new(Semaphore::new(config.max_connections)),
+            encryption: config.encryption,
+            transport: config.transport,
+        }
+    }
+
+    /// Stops accepting inbound connections, saves the DHT state and stops the DHT node.
+    pub async fn shutdown(&self) {
+        if let Some(listener) = &self.listener {
+            listener.shutdown();
+        }
+        if let Some(dht) = &self.dht {
+            if let Err(e) = dht.save_state().await {
+                eprintln!("Failed to save DHT state: {}", e);
+            }
+            dht.shutdown().await;
+        }
+    }
+}
+
+/// A torrent owned by the session.
+struct TorrentEntry {
+    downloader: Arc<Downloader>,
+    /// The task driving `Downloader::run_with`.
+    task: JoinHandle<()>,
+}
+
+/// Owns and runs many torrents, keyed by info hash.
+///
+/// # Examples
+///
+/// ```no_run
+/// use client::downloader::Downloader;
+/// use client::session::{Session, SessionConfig};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let session = Session::new(SessionConfig::default()).await;
+///     let downloader = Downloader::new("example.torrent", None).await.unwrap();
+///     downloader.check_existing_data().await.unwrap();
+///     let info_hash = session.add(downloader).await.unwrap().torrent.info_hash;
+///
+///     session.pause(&info_hash).await.unwrap();
+///     session.resume(&info_hash).await.unwrap();
+///     session.remove(&info_hash).await.unwrap();
+/// }
+/// ```
+pub struct Session {
+    shared: SharedResources,
+    torrents: Mutex<HashMap<[u8; 20], TorrentEntry>>,
+}
+
+impl Session {
+    /// Creates a session and starts its shared listener and DHT node.
+    pub async fn new(config: SessionConfig) -> Self {
+        Self {
+            shared: SharedResources::start(&config).await,
+            torrents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the port the session accepts peers on, if the listener is running.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.shared.listener.as_ref().map(|l| l.port())
+    }
+
+    /// Adds a torrent to the session and starts downloading it.
+    ///
+    /// # Arguments
+    ///
+    /// * `downloader` - A downloader created with `Downloader::new` or `Downloader::from_torrent`,
+    ///   usually after `check_existing_data`.
+    ///
+    /// # Returns
+    ///
+    /// * `SessionResult<Arc<Downloader>>` - The shared downloader, for reading its progress.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a torrent with the same info hash is already in the session, or
+    /// if the downloader's rate limits are not positive and finite.
+    pub async fn add(&self, downloader: Downloader) -> SessionResult<Arc<Downloader>> {
+        TokenBucket::check_rate(downloader.config.download_rate_limit)?;
+        TokenBucket::check_rate(downloader.config.upload_rate_limit)?;
+        let info_hash = downloader.torrent.info_hash;
+        let mut torrents = self.torrents.lock().await;
+        if torrents.contains_key(&info_hash) {
+            return Err(format!("Torrent {} is already added", hex::encode(info_hash)).into());
+        }
+
+        let downloader = Arc::new(downloader);
+        let run = downloader.clone();
+        let shared = self.shared.clone();
+        let task = tokio::spawn(async move { run.run_with(&shared).await });
+        torrents.insert(
+            info_hash,
+            TorrentEntry {
+                downloader: downloader.clone(),
+                task,
+            },
+        );
+        Ok(downloader)
+    }
+
+    /// Stops a torrent and removes it from the session. Downloaded data is kept on disk.
+    ///
+    /// Waits until the torrent has announced `stopped` to its trackers and saved its state.
+    pub async fn remove(&self, info_hash: &[u8; 20]) -> SessionResult<()> {
+        let entry = self
+            .torrents
+            .lock()
+            .await
+            .remove(info_hash)
+            .ok_or("Unknown torrent")?;
+        entry.downloader.handle().stop();
+        let _ = entry.task.await;
+        Ok(())
+    }
+
+    /// Pauses a torrent: its peers stay connected but are choked, and nothing is requested.
+    ///
+    /// Pausing a paused torrent does nothing.
+    pub async fn pause(&self, info_hash: &[u8; 20]) -> SessionResult<()> {
+        self.get(info_hash)
+            .await
+            .ok_or("Unknown torrent")?
+            .handle()
+            .pause();
+        Ok(())
+    }
+
+    /// Resumes a paused torrent. Resuming a running torrent does nothing.
+    pub async fn resume(&self, info_hash: &[u8; 20]) -> SessionResult<()> {
+        self.get(info_hash)
+            .await
+            .ok_or("Unknown torrent")?
+            .handle()
+            .resume();
+        Ok(())
+    }
+
+    /// Returns `true` if the torrent is in the session and paused.
+    pub async fn is_paused(&self, info_hash: &[u8; 20]) -> bool {
+        self.get(info_hash)
+            .await
+            .is_some_and(|d| d.handle().state() == DownloadState::Paused)
+    }
+
+    /// Returns the downloader of a torrent in the session.
+    pub async fn get(&self, info_hash: &[u8; 20]) -> Option<Arc<Downloader>> {
+        self.torrents
+            .lock()
+            .await
+            .get(info_hash)
+            .map(|e| e.downloader.clone())
+    }
+
+    /// Changes the download limit shared by all torrents.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - The new limit in bytes per second, or `None` for no limit.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the limit as it was, if `rate` is not positive and finite.
+    pub async fn set_download_rate_limit(&self, rate: Option<f64>) -> SessionResult<()> {
+        TokenBucket::check_rate(rate)?;
+        *self.shared.download_limiter.lock().await = TokenBucket::with_rate(rate);
+        Ok(())
+    }
+
+    /// Changes the upload limit shared by all torrents.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - The new limit in bytes per second, or `None` for no limit.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the limit as it was, if `rate` is not positive and finite.
+    pub async fn set_upload_rate_limit(&self, rate: Option<f64>) -> SessionResult<()> {
+        TokenBucket::check_rate(rate)?;
+        *self.shared.upload_limiter.lock().await = TokenBucket::with_rate(rate);
+        Ok(())
+    }
+
+    /// Returns the info hashes of all torrents in the session.
+    pub async fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrents.lock().await.keys().copied().collect()
+    }
+
+    /// Stops and removes every torrent, then closes the shared listener and saves the DHT state.
+    pub async fn shutdown(&self) {
+        let entries: Vec<_> = self.torrents.lock().await.drain().map(|(_, e)| e).collect();
+        for entry in &entries {
+            entry.downloader.handle().stop();
+        }
+        for entry in entries {
+            let _ = entry.task.await;
+        }
+        self.shared.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tds_core::Torrent;
+    use tempfile::tempdir;
+
+    fn torrent(info_hash: [u8; 20]) -> Torrent {
+        Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash,
+            name: format!("file-{}", info_hash[0]),
+            pieces: vec![[0u8; 20]],
+            piece_length: 16,
+            length: Some(16),
+            files: None,
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_pause_resume_remove() {
+        let dir = tempdir().unwrap();
+        let path = Some(dir.path().to_str().unwrap().to_string());
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        assert!(session.listen_port().is_some());
+
+        for hash in [[1u8; 20], [2u8; 20]] {
+            let downloader = Downloader::from_torrent(torrent(hash), path.clone())
+                .await
+                .unwrap();
+            session.add(downloader).await.unwrap();
+        }
+        let duplicate = Downloader::from_torrent(torrent([1u8; 20]), path.clone())
+            .await
+            .unwrap();
+        assert!(session.add(duplicate).await.is_err());
+        assert_eq!(session.info_hashes().await.len(), 2);
+
+        session.pause(&[1u8; 20]).await.unwrap();
+        assert!(session.is_paused(&[1u8; 20]).await);
+        assert!(!session.is_paused(&[2u8; 20]).await);
+        session.resume(&[1u8; 20]).await.unwrap();
+        assert!(!session.is_paused(&[1u8; 20]).await);
+
+        session.remove(&[1u8; 20]).await.unwrap();
+        assert!(session.get(&[1u8; 20]).await.is_none());
+        assert!(session.pause(&[1u8; 20]).await.is_err());
+
+        let remaining = session.get(&[2u8; 20]).await.unwrap();
+        session.shutdown().await;
+        assert!(session.info_hashes().await.is_empty());
+        assert_eq!(remaining.handle().state(), DownloadState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_that_never_refill_are_rejected() {
+        let dir = tempdir().unwrap();
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            dht: None,
+            ..SessionConfig::default()
+        })
+        .await;
+        assert!(session.set_download_rate_limit(Some(0.0)).await.is_err());
+        assert!(session.set_upload_rate_limit(Some(-1.0)).await.is_err());
+        session.set_upload_rate_limit(None).await.unwrap();
+
+        let mut downloader =
+            Downloader::from_torrent(torrent([3u8; 20]), dir.path().to_str().map(String::from))
+                .await
+                .unwrap();
+        downloader.config.download_rate_limit = Some(0.0);
+        assert!(session.add(downloader).await.is_err());
+        session.shutdown().await;
+    }
+}
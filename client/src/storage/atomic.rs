@@ -0,0 +1,48 @@
+//! Replacing small state files without ever leaving them half written.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Replaces the file at `path` with `data`, through a temporary file next to it.
+///
+/// The temporary file is synced to disk before it is renamed over `path`, so a crash
+/// or power loss leaves either the old contents or the new ones, never a truncated file.
+///
+/// # Arguments
+///
+/// * `path` - The file to replace.
+/// * `data` - Its new contents.
+///
+/// # Errors
+///
+/// Returns an error if the temporary file cannot be written or synced, or not renamed.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_write_atomic_replaces_the_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state");
+
+        write_atomic(&path, b"first").await.unwrap();
+        write_atomic(&path, b"second").await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), b"second");
+        assert!(!dir.path().join("state.tmp").exists());
+    }
+}
@@ -0,0 +1,293 @@
+//! Mapping between the torrent's flat piece space and the files on disk.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tds_core::Torrent;
+
+/// A single file of the torrent, placed in the contiguous piece space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    /// The full path of the file on disk.
+    pub path: PathBuf,
+    /// The length of the file in bytes.
+    pub length: u64,
+    /// The offset of the first byte of this file in the torrent's piece space.
+    pub offset: u64,
+}
+
+/// A contiguous range of bytes inside one file.
+///
+/// A read or write that spans several files is split into one `FileSlice` per file,
+/// in order, so the slices can be processed sequentially against a single buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileSlice {
+    /// Index of the file in `FileLayout::files`.
+    pub file_index: usize,
+    /// Offset inside the file where the range starts.
+    pub file_offset: u64,
+    /// Number of bytes in the range.
+    pub length: u64,
+}
+
+/// Describes how the pieces of a torrent map onto the files on disk.
+///
+/// Single-file torrents are stored as `<download_dir>/<name>`, while
+/// multi-file torrents are stored under `<download_dir>/<name>/<path...>`.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    /// The top-level path of the download: the file of a single-file torrent,
+    /// or the directory holding the files of a multi-file torrent.
+    pub root: PathBuf,
+    /// The files of the torrent in the order they appear in the piece space.
+    pub files: Vec<FileEntry>,
+    /// The nominal length of a piece in bytes.
+    pub piece_length: u64,
+    /// The number of pieces in the torrent.
+    pub piece_count: usize,
+    /// The total size of all files in bytes.
+    pub total_length: u64,
+}
+
+impl FileLayout {
+    /// Builds the layout of a torrent rooted at `download_dir`.
+    ///
+    /// Path components that could escape the download directory (`..`, roots,
+    /// prefixes) are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `torrent` - The parsed torrent metadata.
+    /// * `download_dir` - The directory the torrent is saved into.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the piece hashes do not cover the files (see
+    /// `Torrent::check_pieces`), or if the name of the torrent or of one of its files
+    /// has no component left once sanitized, like `..` or `/`.
+    pub fn new(torrent: &Torrent, download_dir: &Path) -> io::Result<Self> {
+        torrent.check_pieces()?;
+        let name = sanitize(&torrent.name);
+        if name.as_os_str().is_empty() {
+            return Err(unusable_name(&torrent.name));
+        }
+        let root = download_dir.join(name);
+        let mut files = Vec::new();
+        let mut offset = 0;
+
+        match &torrent.files {
+            Some(infos) if torrent.length.is_none() => {
+                for info in infos {
+                    let mut path = root.clone();
+                    for component in &info.path {
+                        path.push(sanitize(component));
+                    }
+                    if path == root {
+                        return Err(unusable_name(&info.path.join("/")));
+                    }
+                    files.push(FileEntry {
+                        path,
+                        length: info.length,
+                        offset,
+                    });
+                    offset += info.length;
+                }
+            }
+            _ => {
+                let length = torrent.length.unwrap_or(0);
+                files.push(FileEntry {
+                    path: root.clone(),
+                    length,
+                    offset,
+                });
+                offset += length;
+            }
+        }
+
+        Ok(Self {
+            root,
+            files,
+            piece_length: torrent.piece_length,
+            piece_count: torrent.pieces.len(),
+            total_length: offset,
+        })
+    }
+
+    /// Returns the path of the resume file, stored next to the download as `<root>.resume`.
+    pub fn resume_path(&self) -> PathBuf {
+        let mut path = self.root.clone().into_os_string();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// Returns the length of the piece at `index`, accounting for a shorter last piece.
+    pub fn piece_len(&self, index: usize) -> u64 {
+        if index + 1 == self.piece_count {
+            let rem = self.total_length % self.piece_length;
+            if rem == 0 { self.piece_length } else { rem }
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// Splits the byte range `[offset, offset + length)` of the piece space into per-file slices.
+    ///
+    /// The range is clamped to the end of the torrent. Zero-length files never appear in the result.
+    pub fn slices(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let end = (offset + length).min(self.total_length);
+        let mut slices = Vec::new();
+        if offset >= end {
+            return slices;
+        }
+
+        for (file_index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file.length == 0 || file_end <= offset {
+                continue;
+            }
+            if file.offset >= end {
+                break;
+            }
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            slices.push(FileSlice {
+                file_index,
+                file_offset: start - file.offset,
+                length: stop - start,
+            });
+        }
+        slices
+    }
+
+    /// Returns the per-file slices covering the piece at `index`.
+    pub fn piece_slices(&self, index: usize) -> Vec<FileSlice> {
+        self.slices(index as u64 * self.piece_length, self.piece_len(index))
+    }
+}
+
+/// The error for a name that would save data outside of its place in the download.
+fn unusable_name(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unusable name in torrent: {:?}", name),
+    )
+}
+
+/// Strips any component that is not a plain file or directory name.
+fn sanitize(name: &str) -> PathBuf {
+    Path::new(name)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tds_core::FileInfo;
+
+    fn multi_file_torrent() -> Torrent {
+        Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: [0u8; 20],
+            piece_length: 10,
+            pieces: vec![[0u8; 20]; 3],
+            name: "dir".to_string(),
+            length: None,
+            files: Some(vec![
+                FileInfo {
+                    length: 4,
+                    path: vec!["a.txt".to_string()],
+                },
+                FileInfo {
+                    length: 0,
+                    path: vec!["empty".to_string()],
+                },
+                FileInfo {
+                    length: 21,
+                    path: vec!["sub".to_string(), "b.txt".to_string()],
+                },
+            ]),
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_multi_file_paths() {
+        let layout = FileLayout::new(&multi_file_torrent(), Path::new("/dl")).unwrap();
+        assert_eq!(layout.total_length, 25);
+        assert_eq!(layout.files[0].path, Path::new("/dl/dir/a.txt"));
+        assert_eq!(layout.files[2].path, Path::new("/dl/dir/sub/b.txt"));
+        assert_eq!(layout.files[2].offset, 4);
+        assert_eq!(layout.piece_len(2), 5);
+        assert_eq!(layout.resume_path(), Path::new("/dl/dir.resume"));
+    }
+
+    #[test]
+    fn test_piece_spanning_files() {
+        let layout = FileLayout::new(&multi_file_torrent(), Path::new("/dl")).unwrap();
+        let slices = layout.piece_slices(0);
+        assert_eq!(
+            slices,
+            vec![
+                FileSlice {
+                    file_index: 0,
+                    file_offset: 0,
+                    length: 4
+                },
+                FileSlice {
+                    file_index: 2,
+                    file_offset: 0,
+                    length: 6
+                },
+            ]
+        );
+
+        let last = layout.piece_slices(2);
+        assert_eq!(
+            last,
+            vec![FileSlice {
+                file_index: 2,
+                file_offset: 16,
+                length: 5
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sanitize_drops_parent_components() {
+        let mut torrent = multi_file_torrent();
+        torrent.files.as_mut().unwrap()[0].path = vec!["..".to_string(), "evil".to_string()];
+        let layout = FileLayout::new(&torrent, Path::new("/dl")).unwrap();
+        assert_eq!(layout.files[0].path, Path::new("/dl/dir/evil"));
+    }
+
+    #[test]
+    fn test_names_that_sanitize_to_nothing_are_rejected() {
+        for name in ["..", "/", ""] {
+            let mut torrent = multi_file_torrent();
+            torrent.name = name.to_string();
+            assert!(FileLayout::new(&torrent, Path::new("/dl")).is_err());
+
+            let mut torrent = multi_file_torrent();
+            torrent.files.as_mut().unwrap()[0].path = vec![name.to_string()];
+            assert!(FileLayout::new(&torrent, Path::new("/dl")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_pieces_must_cover_the_files() {
+        let mut torrent = multi_file_torrent();
+        torrent.piece_length = 0;
+        assert!(FileLayout::new(&torrent, Path::new("/dl")).is_err());
+
+        let mut torrent = multi_file_torrent();
+        torrent.pieces.pop();
+        assert!(FileLayout::new(&torrent, Path::new("/dl")).is_err());
+    }
+}
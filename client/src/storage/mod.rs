@@ -1,5 +1,14 @@
+mod atomic;
+mod layout;
+mod torrent_files;
+
+pub use atomic::write_atomic;
+pub use layout::{FileEntry, FileLayout, FileSlice};
+pub use torrent_files::TorrentFiles;
+
 use std::io;
 use std::path::PathBuf;
+use tds_core::Torrent;
 use tokio::fs;
 
 /// Manages the file system storage for downloaded files.
@@ -104,6 +113,23 @@ impl Storage {
     pub fn get_download_dir_str(&self) -> String {
         self.download_dir.to_string_lossy().to_string()
     }
+
+    /// Builds the piece-to-file layout of a torrent inside the download directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `torrent` - The parsed torrent metadata.
+    ///
+    /// # Returns
+    ///
+    /// * `FileLayout` - The mapping used to split pieces across the torrent's files.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the torrent's pieces or names are unusable; see `FileLayout::new`.
+    pub fn layout(&self, torrent: &Torrent) -> io::Result<FileLayout> {
+        FileLayout::new(torrent, &self.download_dir)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,242 @@
+//! Reading and writing piece data across the files of a torrent.
+
+use super::layout::FileLayout;
+use std::io::{self, SeekFrom};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// The open files of a torrent, addressed through its `FileLayout`.
+///
+/// All reads and writes are expressed in the torrent's piece space and split
+/// across the underlying files, so callers never need to know where file
+/// boundaries fall.
+pub struct TorrentFiles {
+    layout: FileLayout,
+    /// The files opened so far, in layout order.
+    handles: Vec<Option<File>>,
+}
+
+impl TorrentFiles {
+    /// Prepares access to the files of the layout without touching the disk.
+    ///
+    /// Files are opened when first used. A missing file is only created, along with
+    /// its parent directories, once data is written to it, so the files a selective
+    /// download skips are never created.
+    pub fn new(layout: FileLayout) -> Self {
+        let handles = layout.files.iter().map(|_| None).collect();
+        Self { layout, handles }
+    }
+
+    /// Returns the file at `index` of the layout, opening it if needed.
+    ///
+    /// An opened file is resized to its expected length, so that pieces can be written
+    /// in any order.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the file in the layout.
+    /// * `create` - Whether to create the file if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NotFound` error if the file does not exist and `create` is false.
+    async fn file(&mut self, index: usize, create: bool) -> io::Result<&mut File> {
+        if self.handles[index].is_none() {
+            let entry = &self.layout.files[index];
+            if create && let Some(parent) = entry.path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false)
+                .open(&entry.path)
+                .await?;
+            if file.metadata().await?.len() != entry.length {
+                file.set_len(entry.length).await?;
+            }
+            self.handles[index] = Some(file);
+        }
+        Ok(self.handles[index].as_mut().unwrap())
+    }
+
+    /// Returns the layout these files were opened with.
+    pub fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    /// Reads `length` bytes starting at `offset` in the piece space.
+    pub async fn read_at(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        if offset + length > self.layout.total_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Read past the end of the torrent",
+            ));
+        }
+        let mut buf = vec![0u8; length as usize];
+        let mut pos = 0;
+        for slice in self.layout.slices(offset, length) {
+            let file = self.file(slice.file_index, false).await?;
+            file.seek(SeekFrom::Start(slice.file_offset)).await?;
+            let end = pos + slice.length as usize;
+            file.read_exact(&mut buf[pos..end]).await?;
+            pos = end;
+        }
+        Ok(buf)
+    }
+
+    /// Writes `data` starting at `offset` in the piece space.
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if offset + data.len() as u64 > self.layout.total_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Write past the end of the torrent",
+            ));
+        }
+        let mut pos = 0;
+        for slice in self.layout.slices(offset, data.len() as u64) {
+            let file = self.file(slice.file_index, true).await?;
+            file.seek(SeekFrom::Start(slice.file_offset)).await?;
+            let end = pos + slice.length as usize;
+            file.write_all(&data[pos..end]).await?;
+            pos = end;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole piece at `index`.
+    pub async fn read_piece(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let offset = index as u64 * self.layout.piece_length;
+        let length = self.layout.piece_len(index);
+        self.read_at(offset, length).await
+    }
+
+    /// Reads a block of `length` bytes at `begin` inside the piece at `index`.
+    pub async fn read_block(
+        &mut self,
+        index: usize,
+        begin: u64,
+        length: u64,
+    ) -> io::Result<Vec<u8>> {
+        if begin + length > self.layout.piece_len(index) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Block exceeds piece bounds",
+            ));
+        }
+        let offset = index as u64 * self.layout.piece_length + begin;
+        self.read_at(offset, length).await
+    }
+
+    /// Returns the metadata (size, modification time, ...) of every file, in layout
+    /// order, or `None` for a file that does not exist.
+    pub async fn metadata(&self) -> io::Result<Vec<Option<std::fs::Metadata>>> {
+        let mut metadata = Vec::with_capacity(self.layout.files.len());
+        for entry in &self.layout.files {
+            match fs::metadata(&entry.path).await {
+                Ok(m) => metadata.push(Some(m)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => metadata.push(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Flushes every open file to disk.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        for file in self.handles.iter_mut().flatten() {
+            file.flush().await?;
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes a complete, verified piece at `index` and flushes it to disk.
+    pub async fn write_piece(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        let offset = index as u64 * self.layout.piece_length;
+        self.write_at(offset, data).await?;
+        for slice in self.layout.piece_slices(index) {
+            self.file(slice.file_index, true).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tds_core::{FileInfo, Torrent};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_write_and_read_across_files() {
+        let dir = tempdir().unwrap();
+        let torrent = Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: [0u8; 20],
+            piece_length: 4,
+            pieces: vec![[0u8; 20]; 2],
+            name: "multi".to_string(),
+            length: None,
+            files: Some(vec![
+                FileInfo {
+                    length: 3,
+                    path: vec!["one".to_string()],
+                },
+                FileInfo {
+                    length: 3,
+                    path: vec!["nested".to_string(), "two".to_string()],
+                },
+            ]),
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        };
+        let layout = FileLayout::new(&torrent, dir.path()).unwrap();
+        let mut files = TorrentFiles::new(layout);
+        assert!(files.read_piece(0).await.is_err());
+        assert!(!dir.path().join("multi").exists());
+
+        files.write_piece(0, b"abcd").await.unwrap();
+        files.write_piece(1, b"ef").await.unwrap();
+
+        let root = dir.path().join("multi");
+        assert_eq!(fs::read(root.join("one")).await.unwrap(), b"abc");
+        assert_eq!(fs::read(root.join("nested/two")).await.unwrap(), b"def");
+
+        assert_eq!(files.read_piece(0).await.unwrap(), b"abcd");
+        assert_eq!(files.read_block(0, 2, 2).await.unwrap(), b"cd");
+        assert!(files.read_block(1, 1, 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_files_are_created_once_written_to() {
+        let dir = tempdir().unwrap();
+        let file = |name: &str| FileInfo {
+            length: 4,
+            path: vec![name.to_string()],
+        };
+        let torrent = Torrent {
+            announce: String::new(),
+            announce_list: None,
+            info_hash: [0u8; 20],
+            piece_length: 4,
+            pieces: vec![[0u8; 20]; 2],
+            name: "pair".to_string(),
+            length: None,
+            files: Some(vec![file("a"), file("b")]),
+            info: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+        };
+        let mut files = TorrentFiles::new(FileLayout::new(&torrent, dir.path()).unwrap());
+
+        files.write_piece(0, b"aaaa").await.unwrap();
+        let metadata = files.metadata().await.unwrap();
+        assert_eq!(metadata[0].as_ref().map(|m| m.len()), Some(4));
+        assert!(metadata[1].is_none());
+        assert!(!dir.path().join("pair/b").exists());
+    }
+}
@@ -0,0 +1,479 @@
+//! The state machine of one uTP connection.
+//!
+//! Each connection runs in a task of its own. The application talks to it through an
+//! in-memory duplex stream: bytes the application writes are cut into data packets as
+//! the window allows, and payload received in order is written back for it to read.
+//! When the application stops reading, received data piles up, our advertised window
+//! closes and the peer stops sending.
+
+use super::Registry;
+use super::ledbat::{Ledbat, MAX_PAYLOAD};
+use super::packet::{Packet, PacketType};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tds_core::net::DualStack;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+/// How much received data we hold for the application before our window closes.
+const RECV_WINDOW: usize = 1 << 20;
+
+/// The retransmission timeout until a round trip has been measured.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bounds of the retransmission timeout.
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The connection fails when a packet went out this many times without being acknowledged.
+const MAX_TRANSMISSIONS: u32 = 6;
+
+/// A `Syn` is given up on sooner, so that dialing a peer without uTP fails fast.
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+
+/// Packets further ahead of the last in-order one are dropped.
+const MAX_REORDER: u16 = 1024;
+
+/// The number of duplicate acknowledgements taken as a lost packet.
+const DUPLICATE_ACKS: u32 = 3;
+
+/// The current time in microseconds, for packet timestamps. Only differences matter.
+fn now_micros() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// We sent a `Syn` and wait for the answer.
+    SynSent,
+    Connected,
+}
+
+/// A packet that takes a sequence number, kept until it is acknowledged.
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+pub(super) struct Connection {
+    socket: Arc<DualStack<UdpSocket>>,
+    registry: Registry,
+    /// The peer's address, plain IPv4 for IPv4 peers.
+    addr: SocketAddr,
+    /// The connection ID on packets we receive.
+    recv_id: u16,
+    /// The connection ID on packets we send.
+    send_id: u16,
+    state: State,
+    /// The sequence number of our next `Data` or `Fin` packet.
+    seq_nr: u16,
+    /// The last sequence number we received in order.
+    ack_nr: u16,
+    /// Our packets not acknowledged yet, oldest first.
+    in_flight: VecDeque<Sent>,
+    /// The payload bytes in `in_flight`.
+    bytes_in_flight: usize,
+    /// How many bytes the peer can still take in.
+    peer_window: usize,
+    /// The delay we measured on the peer's last packet, sent back in `timestamp_diff`.
+    reply_delay: u32,
+    ledbat: Ledbat,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    /// When the oldest packet in flight is due to be sent again.
+    resend_at: Option<Instant>,
+    duplicate_acks: u32,
+    /// Packets received ahead of a gap, by sequence number.
+    reorder: HashMap<u16, Packet>,
+    /// Payload received in order that the application has not taken yet.
+    pending: Vec<u8>,
+    /// Whether the application stopped reading; received payload is then dropped.
+    discard: bool,
+    /// Whether everything up to the peer's `Fin` was received.
+    eof: bool,
+    fin_sent: bool,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<DualStack<UdpSocket>>,
+        registry: Registry,
+        addr: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+    ) -> Self {
+        Self {
+            socket,
+            registry,
+            addr,
+            recv_id,
+            send_id,
+            state: State::SynSent,
+            seq_nr: 1,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            peer_window: MAX_PAYLOAD,
+            reply_delay: 0,
+            ledbat: Ledbat::default(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_TIMEOUT,
+            resend_at: None,
+            duplicate_acks: 0,
+            reorder: HashMap::new(),
+            pending: Vec::new(),
+            discard: false,
+            eof: false,
+            fin_sent: false,
+        }
+    }
+
+    /// A connection we dial. The peer sends to us on `recv_id` and we to it on the next ID.
+    pub fn initiator(
+        socket: Arc<DualStack<UdpSocket>>,
+        registry: Registry,
+        addr: SocketAddr,
+        recv_id: u16,
+    ) -> Self {
+        Self::new(socket, registry, addr, recv_id, recv_id.wrapping_add(1))
+    }
+
+    /// A connection the peer opened with `syn`.
+    pub fn responder(
+        socket: Arc<DualStack<UdpSocket>>,
+        registry: Registry,
+        addr: SocketAddr,
+        syn: &Packet,
+    ) -> Self {
+        let mut connection = Self::new(
+            socket,
+            registry,
+            addr,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+        );
+        connection.state = State::Connected;
+        connection.seq_nr = rand::rng().random();
+        connection.ack_nr = syn.seq_nr;
+        connection.peer_window = syn.wnd_size as usize;
+        connection.reply_delay = now_micros().wrapping_sub(syn.timestamp);
+        connection
+    }
+
+    /// Runs the connection until both sides closed it or it failed, then unregisters it.
+    ///
+    /// # Arguments
+    ///
+    /// * `packets` - The packets the socket receives for this connection.
+    /// * `app` - The task's end of the application stream.
+    /// * `connected` - For a connection we dial, told once the peer accepted it.
+    pub async fn run(
+        mut self,
+        mut packets: mpsc::Receiver<Packet>,
+        app: DuplexStream,
+        mut connected: Option<oneshot::Sender<io::Result<()>>>,
+    ) {
+        if let Err(e) = self.drive(&mut packets, app, &mut connected).await {
+            if self.state == State::Connected && e.kind() != io::ErrorKind::ConnectionReset {
+                self.send_control(PacketType::Reset).await;
+            }
+            if let Some(tx) = connected.take() {
+                let _ = tx.send(Err(e));
+            }
+        }
+        self.registry
+            .lock()
+            .unwrap()
+            .remove(&(self.addr, self.recv_id));
+    }
+
+    async fn drive(
+        &mut self,
+        packets: &mut mpsc::Receiver<Packet>,
+        app: DuplexStream,
+        connected: &mut Option<oneshot::Sender<io::Result<()>>>,
+    ) -> io::Result<()> {
+        match self.state {
+            State::SynSent => self.send_new(PacketType::Syn, Vec::new()).await,
+            State::Connected => self.send_control(PacketType::State).await,
+        }
+
+        let (mut app_read, mut app_write) = tokio::io::split(app);
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        let mut eof_delivered = false;
+        loop {
+            if self.state == State::Connected
+                && let Some(tx) = connected.take()
+            {
+                let _ = tx.send(Ok(()));
+            }
+            if self.eof && self.pending.is_empty() && !eof_delivered {
+                let _ = app_write.shutdown().await;
+                eof_delivered = true;
+            }
+            if self.fin_sent && self.in_flight.is_empty() && (eof_delivered || self.discard) {
+                return Ok(());
+            }
+
+            let can_send = self.state == State::Connected
+                && !self.fin_sent
+                && (self.bytes_in_flight == 0
+                    || self.bytes_in_flight + MAX_PAYLOAD <= self.send_window());
+            let resend_at = self
+                .resend_at
+                .unwrap_or_else(|| Instant::now() + MAX_TIMEOUT);
+
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => self.on_packet(packet).await?,
+                    None => return Err(io::ErrorKind::ConnectionAborted.into()),
+                },
+                read = app_read.read(&mut buf), if can_send => match read {
+                    Ok(0) | Err(_) => {
+                        self.send_new(PacketType::Fin, Vec::new()).await;
+                        self.fin_sent = true;
+                    }
+                    Ok(n) => self.send_new(PacketType::Data, buf[..n].to_vec()).await,
+                },
+                written = app_write.write(&self.pending), if !self.pending.is_empty() => match written {
+                    Ok(n) => {
+                        let was_closed = self.recv_window() < MAX_PAYLOAD;
+                        self.pending.drain(..n);
+                        // Tell the peer it can send again
+                        if was_closed && self.recv_window() >= MAX_PAYLOAD {
+                            self.send_control(PacketType::State).await;
+                        }
+                    }
+                    Err(_) => {
+                        self.discard = true;
+                        self.pending.clear();
+                    }
+                },
+                _ = tokio::time::sleep_until(resend_at.into()), if self.resend_at.is_some() => {
+                    self.on_timeout().await?
+                }
+            }
+        }
+    }
+
+    /// Handles a packet from the peer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the peer reset the connection.
+    async fn on_packet(&mut self, packet: Packet) -> io::Result<()> {
+        self.reply_delay = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size as usize;
+        match packet.ty {
+            PacketType::Reset => return Err(io::ErrorKind::ConnectionReset.into()),
+            // Our answer to the peer's `Syn` was lost
+            PacketType::Syn => {
+                self.send_control(PacketType::State).await;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            // The answer to our `Syn` carries the sequence number the peer starts at
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            self.state = State::Connected;
+        }
+
+        if self.on_ack(&packet) {
+            self.resend_oldest().await;
+        }
+        if matches!(packet.ty, PacketType::Data | PacketType::Fin) {
+            self.receive(packet);
+            self.send_control(PacketType::State).await;
+        }
+        Ok(())
+    }
+
+    /// Drops the packets `packet` acknowledges and feeds the congestion window.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if duplicate acknowledgements show the oldest packet in flight
+    ///   was lost.
+    fn on_ack(&mut self, packet: &Packet) -> bool {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut progressed = false;
+        while let Some(sent) = self.in_flight.front()
+            && packet.ack_nr.wrapping_sub(sent.packet.seq_nr) < 0x8000
+        {
+            let sent = self.in_flight.pop_front().unwrap();
+            // Karn's rule: a packet sent again gives no reliable round trip
+            if sent.transmissions == 1 {
+                self.update_rtt(now.duration_since(sent.sent_at));
+            }
+            acked += sent.packet.payload.len();
+            progressed = true;
+        }
+
+        if progressed {
+            self.bytes_in_flight -= acked;
+            self.duplicate_acks = 0;
+            self.resend_at = (!self.in_flight.is_empty()).then(|| now + self.rto);
+            if packet.timestamp_diff != 0 {
+                self.ledbat.on_ack(acked, packet.timestamp_diff, now);
+            }
+            return false;
+        }
+        let Some(oldest) = self.in_flight.front() else {
+            return false;
+        };
+        if packet.ty == PacketType::State && packet.ack_nr == oldest.packet.seq_nr.wrapping_sub(1) {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                self.ledbat.on_loss();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Updates the round-trip estimate and the retransmission timeout (RFC 6298).
+    fn update_rtt(&mut self, sample: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = sample / 2;
+                sample
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(sample)) / 4;
+                (srtt * 7 + sample) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// Puts received payload in order for the application; a `Fin` ends the stream.
+    fn receive(&mut self, packet: Packet) {
+        let ahead = packet.seq_nr.wrapping_sub(self.ack_nr);
+        // A duplicate, or too far ahead to be trusted
+        if self.eof || ahead == 0 || ahead > MAX_REORDER {
+            return;
+        }
+        // No room: the peer sends it again once our window opens
+        if packet.ty == PacketType::Data && self.pending.len() >= RECV_WINDOW {
+            return;
+        }
+        self.reorder.insert(packet.seq_nr, packet);
+        while let Some(packet) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = packet.seq_nr;
+            if packet.ty == PacketType::Fin {
+                self.eof = true;
+                self.reorder.clear();
+                break;
+            }
+            if !self.discard {
+                self.pending.extend_from_slice(&packet.payload);
+            }
+        }
+    }
+
+    /// Resends the oldest packet in flight after its timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packet went out too often already.
+    async fn on_timeout(&mut self) -> io::Result<()> {
+        let Some(oldest) = self.in_flight.front() else {
+            self.resend_at = None;
+            return Ok(());
+        };
+        let limit = match oldest.packet.ty {
+            PacketType::Syn => MAX_SYN_TRANSMISSIONS,
+            _ => MAX_TRANSMISSIONS,
+        };
+        if oldest.transmissions >= limit {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.rto = (self.rto * 2).min(MAX_TIMEOUT);
+        self.ledbat.on_timeout();
+        self.resend_oldest().await;
+        self.resend_at = Some(Instant::now() + self.rto);
+        Ok(())
+    }
+
+    /// The bytes we may have in flight.
+    fn send_window(&self) -> usize {
+        self.ledbat.window().min(self.peer_window)
+    }
+
+    /// The bytes we can still take in.
+    fn recv_window(&self) -> usize {
+        RECV_WINDOW.saturating_sub(self.pending.len())
+    }
+
+    /// Fills in our clock, delay, window and acknowledgement before a packet goes out.
+    fn stamp(&self, packet: &mut Packet) {
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_delay;
+        packet.wnd_size = self.recv_window() as u32;
+        packet.ack_nr = self.ack_nr;
+    }
+
+    /// Sends a datagram. A failed send is treated like a lost packet.
+    async fn send(&self, packet: &Packet) {
+        let _ = self.socket.send_to(&packet.encode(), self.addr).await;
+    }
+
+    /// Sends a `State` or `Reset` packet, which take no sequence number.
+    async fn send_control(&self, ty: PacketType) {
+        let mut packet = Packet::new(ty, self.send_id);
+        packet.seq_nr = self.seq_nr;
+        self.stamp(&mut packet);
+        self.send(&packet).await;
+    }
+
+    /// Sends a packet that takes a sequence number and keeps it until acknowledged.
+    async fn send_new(&mut self, ty: PacketType, payload: Vec<u8>) {
+        // A `Syn` names the ID we receive on; the peer derives its own from it
+        let id = match ty {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        let mut packet = Packet::new(ty, id);
+        packet.seq_nr = self.seq_nr;
+        packet.payload = payload;
+        self.stamp(&mut packet);
+        self.send(&packet).await;
+
+        let now = Instant::now();
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.bytes_in_flight += packet.payload.len();
+        if self.in_flight.is_empty() {
+            self.resend_at = Some(now + self.rto);
+        }
+        self.in_flight.push_back(Sent {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+        });
+    }
+
+    /// Sends the oldest packet in flight again.
+    async fn resend_oldest(&mut self) {
+        let Some(mut sent) = self.in_flight.pop_front() else {
+            return;
+        };
+        self.stamp(&mut sent.packet);
+        self.send(&sent.packet).await;
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        self.in_flight.push_front(sent);
+    }
+}
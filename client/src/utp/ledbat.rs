@@ -0,0 +1,141 @@
+//! LEDBAT congestion control (RFC 6817), as used by uTP.
+//!
+//! The sender keeps the lowest one-way delay it has seen as the base delay; anything
+//! above it is taken for queuing delay in some buffer along the path. The window grows
+//! while the queuing delay stays below `TARGET_DELAY` and shrinks as it rises above,
+//! so a uTP transfer yields to TCP and interactive traffic sharing the link.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The queuing delay LEDBAT aims for, in microseconds.
+pub const TARGET_DELAY: u32 = 100_000;
+
+/// The largest payload of a packet, and the unit the window grows by.
+pub const MAX_PAYLOAD: usize = 1380;
+
+/// The window never shrinks below two packets.
+pub const MIN_WINDOW: usize = 2 * MAX_PAYLOAD;
+
+/// The window never grows above 1 MiB.
+const MAX_WINDOW: usize = 1 << 20;
+
+/// How many bytes the window can grow by per round trip, at most.
+const GAIN: f64 = 1.0;
+
+/// The base delay is the lowest delay of the last `BASE_HISTORY` minutes, so it follows
+/// route changes and clock drift.
+const BASE_HISTORY: usize = 2;
+
+/// A congestion window driven by one-way delay.
+#[derive(Debug)]
+pub struct Ledbat {
+    /// The congestion window in bytes.
+    window: f64,
+    /// The lowest delay of each of the last minutes, the current minute last.
+    base_delays: VecDeque<u32>,
+    /// When the current minute started.
+    minute_start: Option<Instant>,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Self {
+            window: MIN_WINDOW as f64,
+            base_delays: VecDeque::new(),
+            minute_start: None,
+        }
+    }
+}
+
+impl Ledbat {
+    /// Returns the congestion window in bytes.
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// Adjusts the window for an acknowledgement.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes_acked` - The payload bytes the acknowledgement covers.
+    /// * `delay` - The one-way delay the receiver measured, in microseconds. It includes
+    ///   the offset between both clocks, which the base delay cancels out.
+    /// * `now` - The current time.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        if self
+            .minute_start
+            .is_none_or(|start| now.duration_since(start) >= Duration::from_secs(60))
+        {
+            self.minute_start = Some(now);
+            self.base_delays.push_back(delay);
+            if self.base_delays.len() > BASE_HISTORY {
+                self.base_delays.pop_front();
+            }
+        }
+        let current = self.base_delays.back_mut().unwrap();
+        *current = (*current).min(delay);
+        let base = *self.base_delays.iter().min().unwrap();
+
+        let queuing = delay.wrapping_sub(base).min(2 * TARGET_DELAY);
+        let off_target = (TARGET_DELAY as f64 - queuing as f64) / TARGET_DELAY as f64;
+        self.window += GAIN * off_target * bytes_acked as f64 * MAX_PAYLOAD as f64 / self.window;
+        self.window = self.window.clamp(MIN_WINDOW as f64, MAX_WINDOW as f64);
+    }
+
+    /// Halves the window after a packet was lost.
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW as f64);
+    }
+
+    /// Falls back to the smallest window after a retransmission timeout.
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_follows_queuing_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::default();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+
+        // The clocks are 5 s apart; only the delay above the lowest one counts
+        for _ in 0..400 {
+            ledbat.on_ack(MAX_PAYLOAD, 5_000_000 + 10_000, now);
+        }
+        let grown = ledbat.window();
+        assert!(grown > 10 * MIN_WINDOW);
+
+        // Other traffic fills a queue on the path
+        for _ in 0..50 {
+            ledbat.on_ack(MAX_PAYLOAD, 5_000_000 + 10_000 + 2 * TARGET_DELAY, now);
+        }
+        let backed_off = ledbat.window();
+        assert!(backed_off < grown);
+
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), (backed_off / 2).max(MIN_WINDOW));
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+    }
+
+    #[test]
+    fn test_base_delay_expires() {
+        let start = Instant::now();
+        let mut ledbat = Ledbat::default();
+        ledbat.on_ack(MAX_PAYLOAD, 1_000, start);
+
+        // The route got slower for good: after two minutes the new delay is the base
+        let later = start + Duration::from_secs(3 * 60);
+        ledbat.on_ack(MAX_PAYLOAD, 300_000, later - Duration::from_secs(60));
+        ledbat.on_ack(MAX_PAYLOAD, 300_000, later);
+        let window = ledbat.window();
+        ledbat.on_ack(MAX_PAYLOAD, 300_000, later);
+        assert!(ledbat.window() > window);
+    }
+}
@@ -0,0 +1,335 @@
+//! The Micro Transport Protocol (uTP, BEP 29).
+//!
+//! uTP carries peer connections over UDP, with LEDBAT congestion control: transfers
+//! back off as soon as they add queuing delay to the link, so a download running in
+//! the background does not slow down other traffic the way TCP does.
+//!
+//! A `UtpSocket` multiplexes every connection over one UDP port, normally the same
+//! port as the TCP listener. Each connection is a `UtpStream`, which reads
+//! and writes like a `TcpStream`.
+
+mod connection;
+mod ledbat;
+mod packet;
+
+use connection::Connection;
+use packet::{Packet, PacketType};
+use rand::Rng;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tds_core::compact;
+use tds_core::net::{self, DualStack};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// How many bytes the application can write ahead of what is sent, and read behind
+/// what is received, per connection.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// Received packets queued for a connection; more are dropped like lost packets.
+const PACKET_QUEUE: usize = 256;
+
+/// Inbound connections waiting for `accept`; more are refused.
+const ACCEPT_QUEUE: usize = 32;
+
+/// The channels of the connections, keyed by peer address and the ID we receive on.
+type Registry = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::Sender<Packet>>>>;
+
+/// A UDP socket carrying uTP connections, both dialed and accepted.
+///
+/// Clones share the socket. It stays open as long as a clone or one of its streams
+/// is alive.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    socket: Arc<DualStack<UdpSocket>>,
+    registry: Registry,
+    /// Connections opened by peers, with their address.
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(DuplexStream, SocketAddr)>>,
+    /// The task reading the socket and handing packets to the connections.
+    task: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl UtpSocket {
+    /// Binds the UDP socket and starts dispatching the packets it receives.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The local address to bind. Use port `0` to let the OS choose.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self::with_sockets(DualStack {
+            primary: socket,
+            ipv4: None,
+        }))
+    }
+
+    /// Binds `port` on all interfaces, for IPv4 and IPv6 peers, and starts dispatching
+    /// the packets received.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The UDP port to bind. Use `0` to let the OS choose.
+    pub fn bind_all(port: u16) -> io::Result<Self> {
+        Ok(Self::with_sockets(net::bind_udp(port)?))
+    }
+
+    fn with_sockets(sockets: DualStack<UdpSocket>) -> Self {
+        let socket = Arc::new(sockets);
+        let registry: Registry = Arc::new(Mutex::new(HashMap::new()));
+        let (incoming_tx, incoming_rx) = mpsc::channel(ACCEPT_QUEUE);
+        let task = tokio::spawn(dispatch(socket.clone(), registry.clone(), incoming_tx));
+        Self {
+            inner: Arc::new(Inner {
+                socket,
+                registry,
+                incoming: tokio::sync::Mutex::new(incoming_rx),
+                task,
+            }),
+        }
+    }
+
+    /// Returns the local address of the socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    /// Waits for a peer to open a connection.
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        match self.inner.incoming.lock().await.recv().await {
+            Some((stream, addr)) => Ok(UtpStream {
+                stream,
+                peer_addr: addr,
+                _socket: self.clone(),
+            }),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Opens a connection to `addr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the peer does not answer our `Syn`, which is sent three times
+    /// over about seven seconds. Callers normally give up sooner with a timeout of their own.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let to = compact::canonical(addr);
+        let (tx, rx) = mpsc::channel(PACKET_QUEUE);
+        let recv_id = {
+            let mut registry = self.inner.registry.lock().unwrap();
+            let mut rng = rand::rng();
+            let id = loop {
+                let id: u16 = rng.random();
+                if !registry.contains_key(&(to, id)) {
+                    break id;
+                }
+            };
+            registry.insert((to, id), tx);
+            id
+        };
+
+        let (stream, app) = tokio::io::duplex(STREAM_BUFFER);
+        let (connected_tx, connected_rx) = oneshot::channel();
+        let connection = Connection::initiator(
+            self.inner.socket.clone(),
+            self.inner.registry.clone(),
+            to,
+            recv_id,
+        );
+        tokio::spawn(connection.run(rx, app, Some(connected_tx)));
+        connected_rx
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))??;
+
+        Ok(UtpStream {
+            stream,
+            peer_addr: to,
+            _socket: self.clone(),
+        })
+    }
+}
+
+/// Reads the socket and hands each packet to its connection. A `Syn` for no known
+/// connection opens a new one, queued for `accept`.
+async fn dispatch(
+    socket: Arc<DualStack<UdpSocket>>,
+    registry: Registry,
+    incoming: mpsc::Sender<(DuplexStream, SocketAddr)>,
+) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // Some systems report ICMP errors of earlier sends here
+            Err(_) => continue,
+        };
+        let Some(packet) = Packet::decode(&buf[..n]) else {
+            continue;
+        };
+
+        // A `Syn` names the initiator's ID; we receive on the next one
+        let id = match packet.ty {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let tx = registry.lock().unwrap().get(&(from, id)).cloned();
+        if let Some(tx) = tx {
+            let _ = tx.try_send(packet);
+            continue;
+        }
+        if packet.ty != PacketType::Syn {
+            continue;
+        }
+
+        let (stream, app) = tokio::io::duplex(STREAM_BUFFER);
+        if incoming.try_send((stream, from)).is_err() {
+            continue;
+        }
+        let (tx, rx) = mpsc::channel(PACKET_QUEUE);
+        registry.lock().unwrap().insert((from, id), tx);
+        let connection = Connection::responder(socket.clone(), registry.clone(), from, &packet);
+        tokio::spawn(connection.run(rx, app, None));
+    }
+}
+
+/// A uTP connection.
+///
+/// Writes are sent as fast as the congestion window allows; shutting down the write
+/// side sends a `Fin`, after which reads on the other end return end of file.
+pub struct UtpStream {
+    stream: DuplexStream,
+    peer_addr: SocketAddr,
+    /// Keeps the socket dispatching packets while the stream is alive.
+    _socket: UtpSocket,
+}
+
+impl UtpStream {
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_transfer_in_both_directions() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let accepting = tokio::spawn(async move {
+            let mut stream = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(b"thanks").await.unwrap();
+            stream.shutdown().await.unwrap();
+            (received, stream.peer_addr())
+        });
+
+        let mut stream = client.connect(server_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"thanks");
+
+        let (received, peer) = accepting.await.unwrap();
+        assert!(received == expected);
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_lost_packets_are_sent_again() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        // A relay that drops a few of the client's datagrams, the `Syn` among them
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            let mut client_addr = None;
+            let mut count = 0;
+            loop {
+                let (n, from) = relay.recv_from(&mut buf).await.unwrap();
+                if from == server_addr {
+                    let _ = relay.send_to(&buf[..n], client_addr.unwrap()).await;
+                    continue;
+                }
+                client_addr = Some(from);
+                count += 1;
+                if ![1, 5, 12].contains(&count) {
+                    let _ = relay.send_to(&buf[..n], server_addr).await;
+                }
+            }
+        });
+
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 253) as u8).collect();
+        let expected = data.clone();
+        let accepting = tokio::spawn(async move {
+            let mut stream = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut stream = client.connect(relay_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let received = tokio::time::timeout(std::time::Duration::from_secs(20), accepting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == expected);
+    }
+}
@@ -0,0 +1,157 @@
+//! The uTP packet header.
+//!
+//! Every packet starts with a 20-byte header, optionally followed by a chain of
+//! extensions, and then the payload. All fields are big-endian.
+
+/// Length of the header without extensions.
+pub const HEADER_LEN: usize = 20;
+
+/// The protocol version in the low nibble of the first byte.
+const VERSION: u8 = 1;
+
+/// The kind of a packet, in the high nibble of the first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    /// Carries payload and takes a sequence number.
+    Data = 0,
+    /// Closes the sender's direction; takes a sequence number.
+    Fin = 1,
+    /// A bare acknowledgement; takes no sequence number.
+    State = 2,
+    /// Tears the connection down.
+    Reset = 3,
+    /// Opens a connection.
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_nibble(nibble: u8) -> Option<Self> {
+        match nibble {
+            0 => Some(Self::Data),
+            1 => Some(Self::Fin),
+            2 => Some(Self::State),
+            3 => Some(Self::Reset),
+            4 => Some(Self::Syn),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded packet. Extensions of received packets are skipped, and none are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub ty: PacketType,
+    /// The receiver's ID of the connection, or the initiator's own ID in a `Syn`.
+    pub connection_id: u16,
+    /// When the packet was sent, in microseconds of the sender's clock.
+    pub timestamp: u32,
+    /// The delay the sender measured on the last packet it received from us.
+    pub timestamp_diff: u32,
+    /// How many bytes the sender can still take in.
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    /// The last sequence number the sender received in order.
+    pub ack_nr: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Creates a packet of type `ty` with every other field zero.
+    pub fn new(ty: PacketType, connection_id: u16) -> Self {
+        Self {
+            ty,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Encodes the packet for the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push((self.ty as u8) << 4 | VERSION);
+        buf.push(0);
+        buf.extend_from_slice(&self.connection_id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        buf.extend_from_slice(&self.wnd_size.to_be_bytes());
+        buf.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&self.ack_nr.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// Decodes a received datagram.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Packet>` - The packet, or `None` if the datagram is not a uTP packet of
+    ///   our version.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION {
+            return None;
+        }
+        let ty = PacketType::from_nibble(buf[0] >> 4)?;
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        // Each extension is a type byte for the next one, a length and the data
+        let mut extension = buf[1];
+        let mut pos = HEADER_LEN;
+        while extension != 0 {
+            if buf.len() < pos + 2 || buf.len() < pos + 2 + buf[pos + 1] as usize {
+                return None;
+            }
+            extension = buf[pos];
+            pos += 2 + buf[pos + 1] as usize;
+        }
+
+        Some(Self {
+            ty,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: buf[pos..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_roundtrip_and_extensions() {
+        let packet = Packet {
+            ty: PacketType::Data,
+            connection_id: 0x1234,
+            timestamp: 1_000_000,
+            timestamp_diff: 250,
+            wnd_size: 65536,
+            seq_nr: 7,
+            ack_nr: 65535,
+            payload: b"hello".to_vec(),
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded[0], 0x01);
+        assert_eq!(Packet::decode(&encoded), Some(packet.clone()));
+
+        // A selective ack extension in front of the payload is skipped
+        let mut extended = encoded[..HEADER_LEN].to_vec();
+        extended[1] = 1;
+        extended.extend_from_slice(&[0, 4, 0xff, 0, 0, 0]);
+        extended.extend_from_slice(b"hello");
+        assert_eq!(Packet::decode(&extended), Some(packet));
+
+        assert!(Packet::decode(&extended[..HEADER_LEN + 3]).is_none());
+        assert!(Packet::decode(&[0x02; HEADER_LEN]).is_none());
+        assert!(Packet::decode(&[0x51; HEADER_LEN]).is_none());
+    }
+}
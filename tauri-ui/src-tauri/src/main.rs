@@ -1,20 +1,24 @@
 //! Tauri Backend for tds-ui.
 //!
 //! Handles communication between the frontend and the `client` library.
-//! Manages the state of the active downloader.
+//! Runs every download in a shared `Session`.
 
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use client::downloader::Downloader;
+use client::dht::DhtConfig;
+use client::downloader::{DownloadState, Downloader};
 use client::magnet;
-use std::sync::Arc;
-use tauri::State;
+use client::peer::{EncryptionPolicy, TransportPolicy};
+use client::session::{Session, SessionConfig};
+use tauri::{Manager, RunEvent, State};
 use tokio::sync::Mutex;
 
 /// Application state managed by Tauri.
 struct AppState {
-    /// The currently active downloader instance, if any.
-    downloader: Mutex<Option<Arc<Downloader>>>,
+    /// The session running all downloads.
+    session: Session,
+    /// The info hash of the most recently started download, shown by `get_status`.
+    current: Mutex<Option<[u8; 20]>>,
 }
 
 /// Status of the current torrent download.
@@ -30,6 +34,8 @@ struct TorrentStatus {
     total: u64,
     /// Progress percentage (0.0 to 100.0).
     progress: f64,
+    /// Whether the download is paused.
+    paused: bool,
 }
 
 /// Starts a download from a torrent file or magnet link.
@@ -49,29 +55,35 @@ async fn start_download(
 ) -> Result<String, String> {
     println!("Starting download for: {}", torrent_input);
 
-    let torrent_struct = if torrent_input.starts_with("magnet:") {
-        match magnet::resolve(&torrent_input).await {
-            Ok(info_bytes) => {
-                let mut wrapper = Vec::new();
-                wrapper.extend_from_slice(b"d8:announce0:4:info");
-                wrapper.extend_from_slice(&info_bytes);
-                wrapper.push(b'e');
-
-                match tds_core::parse_torrent_from_bytes(&wrapper) {
-                    Ok(t) => t,
-                    Err(e) => return Err(format!("Error parsing resolved metadata: {}", e)),
-                }
-            }
+    let downloader = if torrent_input.starts_with("magnet:") {
+        let magnet = magnet::parse_magnet_link(&torrent_input)
+            .map_err(|e| format!("Error parsing magnet link: {}", e))?;
+        // Use port 0 to let the OS pick a free port; the session's DHT holds the default one.
+        // No state file either, or the resolver would share the session node's ID
+        let resolver_dht = DhtConfig {
+            port: 0,
+            state_file: None,
+            ..DhtConfig::default()
+        };
+        match magnet::resolve(
+            &magnet,
+            Some(resolver_dht),
+            EncryptionPolicy::default(),
+            TransportPolicy::default(),
+        )
+        .await
+        {
+            Ok(resolved) => Downloader::from_magnet(resolved, output_path).await,
             Err(e) => return Err(format!("Error resolving magnet link: {}", e)),
         }
     } else {
         match tds_core::parse_torrent(&torrent_input) {
-            Ok(t) => t,
+            Ok(t) => Downloader::from_torrent(t, output_path).await,
             Err(e) => return Err(format!("Error parsing torrent file: {}", e)),
         }
     };
 
-    let downloader = match Downloader::from_torrent(torrent_struct, output_path).await {
+    let downloader = match downloader {
         Ok(d) => d,
         Err(e) => return Err(format!("Error initializing downloader: {}", e)),
     };
@@ -80,17 +92,11 @@ async fn start_download(
         return Err(format!("Error checking existing data: {}", e));
     }
 
-    let downloader_arc = Arc::new(downloader);
-
-    {
-        let mut d = state.downloader.lock().await;
-        *d = Some(downloader_arc.clone());
+    let info_hash = downloader.torrent.info_hash;
+    if let Err(e) = state.session.add(downloader).await {
+        return Err(format!("Error starting download: {}", e));
     }
-
-    let d_clone = downloader_arc.clone();
-    tokio::spawn(async move {
-        d_clone.run().await;
-    });
+    *state.current.lock().await = Some(info_hash);
 
     Ok("Download started".to_string())
 }
@@ -104,9 +110,13 @@ async fn start_download(
 /// A `TorrentStatus` struct containing progress, downloaded bytes, etc.
 #[tauri::command]
 async fn get_status(state: State<'_, AppState>) -> Result<TorrentStatus, String> {
-    let d_lock = state.downloader.lock().await;
+    let current = *state.current.lock().await;
+    let downloader = match current {
+        Some(info_hash) => state.session.get(&info_hash).await,
+        None => None,
+    };
 
-    if let Some(downloader) = &*d_lock {
+    if let Some(downloader) = downloader {
         let downloaded = *downloader.downloaded_bytes.lock().await;
         let uploaded = *downloader.uploaded_bytes.lock().await;
         let total = downloader.total_length;
@@ -123,6 +133,7 @@ async fn get_status(state: State<'_, AppState>) -> Result<TorrentStatus, String>
             uploaded,
             total,
             progress,
+            paused: downloader.handle().state() == DownloadState::Paused,
         })
     } else {
         Ok(TorrentStatus {
@@ -131,17 +142,87 @@ async fn get_status(state: State<'_, AppState>) -> Result<TorrentStatus, String>
             uploaded: 0,
             total: 0,
             progress: 0.0,
+            paused: false,
         })
     }
 }
 
+/// Returns the info hash of the current download.
+async fn current_download(state: &State<'_, AppState>) -> Result<[u8; 20], String> {
+    state
+        .current
+        .lock()
+        .await
+        .ok_or_else(|| "No active download".to_string())
+}
+
+/// Pauses the current download. Peers stay connected but nothing is exchanged.
+///
+/// # Arguments
+/// * `state` - The application state.
+#[tauri::command]
+async fn pause_download(state: State<'_, AppState>) -> Result<(), String> {
+    let info_hash = current_download(&state).await?;
+    state
+        .session
+        .pause(&info_hash)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Resumes the current download after `pause_download`.
+///
+/// # Arguments
+/// * `state` - The application state.
+#[tauri::command]
+async fn resume_download(state: State<'_, AppState>) -> Result<(), String> {
+    let info_hash = current_download(&state).await?;
+    state
+        .session
+        .resume(&info_hash)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Stops the current download and removes it from the session.
+///
+/// Returns once trackers were told we stopped and the resume data is saved.
+///
+/// # Arguments
+/// * `state` - The application state.
+#[tauri::command]
+async fn stop_download(state: State<'_, AppState>) -> Result<(), String> {
+    let info_hash = current_download(&state).await?;
+    state
+        .session
+        .remove(&info_hash)
+        .await
+        .map_err(|e| e.to_string())?;
+    *state.current.lock().await = None;
+    Ok(())
+}
+
 /// Main entry point for the Tauri application.
 pub fn main() {
     tauri::Builder::default()
         .manage(AppState {
-            downloader: Mutex::new(None),
+            session: tauri::async_runtime::block_on(Session::new(SessionConfig::default())),
+            current: Mutex::new(None),
         })
-        .invoke_handler(tauri::generate_handler![start_download, get_status])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .invoke_handler(tauri::generate_handler![
+            start_download,
+            get_status,
+            pause_download,
+            resume_download,
+            stop_download
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Stop the torrents cleanly and save the DHT state before exiting
+            if let RunEvent::Exit = event {
+                let state = app.state::<AppState>();
+                tauri::async_runtime::block_on(state.session.shutdown());
+            }
+        });
 }
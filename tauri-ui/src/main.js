@@ -13,6 +13,9 @@ const downloadedVal = document.getElementById("downloaded-val");
 const totalVal = document.getElementById("total-val");
 const progressVal = document.getElementById("progress-val");
 const logs = document.getElementById("logs");
+const pauseBtn = document.getElementById("pause-btn");
+const stopBtn = document.getElementById("stop-btn");
+let isPaused = false;
 
 // Theme toggling
 themeToggle.addEventListener("click", () => {
@@ -48,6 +51,8 @@ async function updateStatus() {
       progressVal.textContent = pct;
       downloadedVal.textContent = formatBytes(status.downloaded);
       totalVal.textContent = formatBytes(status.total);
+      isPaused = status.paused;
+      pauseBtn.textContent = isPaused ? "Resume" : "Pause";
 
       if (status.progress >= 100) {
         log("Download completed!");
@@ -59,6 +64,33 @@ async function updateStatus() {
   }
 }
 
+pauseBtn.addEventListener("click", async () => {
+  try {
+    await invoke(isPaused ? "resume_download" : "pause_download");
+    log(isPaused ? "Download resumed." : "Download paused.");
+    await updateStatus();
+  } catch (error) {
+    log(`Error: ${error}`);
+  }
+});
+
+stopBtn.addEventListener("click", async () => {
+  stopBtn.disabled = true;
+  log("Stopping download...");
+  try {
+    await invoke("stop_download");
+    log("Download stopped.");
+    clearInterval(pollingInterval);
+    isDownloading = false;
+    statusCard.classList.add("hidden");
+    startBtn.disabled = false;
+    startBtn.textContent = "Download";
+  } catch (error) {
+    log(`Error: ${error}`);
+  }
+  stopBtn.disabled = false;
+});
+
 startBtn.addEventListener("click", async () => {
   const input = torrentInput.value.trim();
   if (!input) {
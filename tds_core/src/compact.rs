@@ -0,0 +1,102 @@
+//! Compact peer info, as sent by trackers and DHT nodes.
+//!
+//! An IPv4 peer takes 6 bytes: the address followed by the port, both big endian
+//! (BEP 23). An IPv6 peer takes 18 bytes in the same layout (BEP 7, BEP 32).
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Length of a compact IPv4 peer.
+pub const IPV4_PEER_LEN: usize = 6;
+
+/// Length of a compact IPv6 peer.
+pub const IPV6_PEER_LEN: usize = 18;
+
+/// Returns the address with IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) turned into
+/// plain IPv4 ones, as a dual-stack socket reports IPv4 peers in mapped form.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Encodes one peer in compact form: 6 bytes for IPv4, 18 bytes for IPv6.
+pub fn encode_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut out = match canonical(*addr) {
+        SocketAddr::V4(v4) => v4.ip().octets().to_vec(),
+        SocketAddr::V6(v6) => v6.ip().octets().to_vec(),
+    };
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Decodes one compact peer of either length.
+///
+/// # Returns
+///
+/// * `Option<SocketAddr>` - The peer, or `None` if `data` is neither 6 nor 18 bytes long.
+pub fn decode_peer(data: &[u8]) -> Option<SocketAddr> {
+    match data.len() {
+        IPV4_PEER_LEN => {
+            let ip: [u8; 4] = data[..4].try_into().ok()?;
+            let port = u16::from_be_bytes([data[4], data[5]]);
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        IPV6_PEER_LEN => {
+            let ip: [u8; 16] = data[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([data[16], data[17]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        _ => None,
+    }
+}
+
+/// Encodes the peers of one address family into a single string, as in the `peers`
+/// (IPv4) and `peers6` (IPv6) keys of a tracker response.
+///
+/// # Arguments
+///
+/// * `peers` - The peers to encode; those of the other family are skipped.
+/// * `ipv6` - Which family to encode.
+pub fn encode_peers<'a>(peers: impl IntoIterator<Item = &'a SocketAddr>, ipv6: bool) -> Vec<u8> {
+    peers
+        .into_iter()
+        .map(|p| canonical(*p))
+        .filter(|p| p.is_ipv6() == ipv6)
+        .flat_map(|p| encode_peer(&p))
+        .collect()
+}
+
+/// Decodes a string of concatenated compact peers of one family.
+///
+/// # Arguments
+///
+/// * `data` - The peers, 6 bytes each for IPv4 or 18 bytes each for IPv6.
+/// * `ipv6` - Which family the string holds. A trailing partial entry is ignored.
+pub fn decode_peers(data: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let len = if ipv6 { IPV6_PEER_LEN } else { IPV4_PEER_LEN };
+    data.chunks_exact(len).filter_map(decode_peer).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_roundtrip_by_family() {
+        let peers: Vec<SocketAddr> = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+            "[::ffff:5.6.7.8]:80".parse().unwrap(),
+        ];
+
+        let v4 = encode_peers(&peers, false);
+        assert_eq!(v4.len(), 2 * IPV4_PEER_LEN);
+        assert_eq!(
+            decode_peers(&v4, false),
+            vec![peers[0], "5.6.7.8:80".parse().unwrap()]
+        );
+
+        let v6 = encode_peers(&peers, true);
+        assert_eq!(decode_peers(&v6, true), vec![peers[1]]);
+        assert_eq!(decode_peer(&v6), Some(peers[1]));
+        assert_eq!(decode_peer(&v6[..7]), None);
+    }
+}
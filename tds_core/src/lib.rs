@@ -4,10 +4,13 @@
 //! and handling bencoded data.
 
 pub mod bencoding;
+pub mod compact;
+pub mod net;
 pub mod rate_limit;
 
 use bencoding::{Bencode, decode, find_info_slice, info_hash};
 pub use rate_limit::TokenBucket;
+use std::collections::BTreeMap;
 use std::io::{self, Read};
 
 /// Information about a single file in a multi-file torrent.
@@ -20,7 +23,7 @@ pub struct FileInfo {
 }
 
 /// Represents the metadata of a torrent.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     /// The URL of the tracker.
     pub announce: String,
@@ -38,6 +41,52 @@ pub struct Torrent {
     pub length: Option<u64>,
     /// List of files (multi-file mode).
     pub files: Option<Vec<FileInfo>>,
+    /// The bencoded info dictionary, served to peers that fetch the metadata (BEP 9).
+    pub info: Vec<u8>,
+    /// Web seeds serving the torrent's files over HTTP (BEP 19 `url-list`).
+    pub url_list: Vec<String>,
+    /// HTTP seeds serving pieces by index (BEP 17 `httpseeds`).
+    pub http_seeds: Vec<String>,
+}
+
+impl Torrent {
+    /// Returns the total size of the torrent's files in bytes.
+    pub fn total_length(&self) -> u64 {
+        match (&self.files, self.length) {
+            (Some(files), None) => files.iter().map(|f| f.length).sum(),
+            (_, length) => length.unwrap_or(0),
+        }
+    }
+
+    /// Checks that the piece hashes cover the files exactly.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the piece length is zero, or if there is not one hash for
+    /// every piece of the total length.
+    pub fn check_pieces(&self) -> io::Result<()> {
+        if self.piece_length == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Piece length is zero",
+            ));
+        }
+        let total_length = self.total_length();
+        let expected = total_length.div_ceil(self.piece_length);
+        if self.pieces.len() as u64 != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} piece hashes for {} bytes in pieces of {}, expected {}",
+                    self.pieces.len(),
+                    total_length,
+                    self.piece_length,
+                    expected
+                ),
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// Parses a `.torrent` file from the disk.
@@ -66,7 +115,6 @@ pub fn parse_torrent_from_bytes(buf: &[u8]) -> io::Result<Torrent> {
     let root = decode(buf, &mut pos)?;
 
     let info_bytes = find_info_slice(buf)?;
-    let hash = info_hash(info_bytes);
 
     if let Bencode::Dict(ref dict) = root {
         let announce = match dict.get(&b"announce"[..]) {
@@ -104,94 +152,160 @@ pub fn parse_torrent_from_bytes(buf: &[u8]) -> io::Result<Torrent> {
             }
         };
 
-        let name = match info_dict.get(&b"name"[..]) {
-            Some(Bencode::Bytes(b)) => String::from_utf8_lossy(b).to_string(),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing name")),
-        };
+        let mut torrent = parse_info(info_dict, info_bytes, announce, announce_list)?;
+        torrent.url_list = url_list(dict.get(&b"url-list"[..]));
+        torrent.http_seeds = url_list(dict.get(&b"httpseeds"[..]));
+        Ok(torrent)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Torrent file root is not a dictionary",
+        ))
+    }
+}
 
-        let piece_length = match info_dict.get(&b"piece length"[..]) {
-            Some(Bencode::Int(i)) => *i as u64,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Missing piece length",
-                ));
-            }
-        };
+/// Reads a list of URLs, which may also be given as a single string.
+///
+/// Empty strings are dropped, as some tools write `url-list` as an empty string.
+fn url_list(value: Option<&Bencode>) -> Vec<String> {
+    let items: Vec<&Vec<u8>> = match value {
+        Some(Bencode::Bytes(bytes)) => vec![bytes],
+        Some(Bencode::List(list)) => list
+            .iter()
+            .filter_map(|item| match item {
+                Bencode::Bytes(bytes) => Some(bytes),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    items
+        .into_iter()
+        .filter(|bytes| !bytes.is_empty())
+        .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+        .collect()
+}
 
-        let pieces_bytes = match info_dict.get(&b"pieces"[..]) {
-            Some(Bencode::Bytes(b)) => b,
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing pieces")),
-        };
+/// Parses a bare info dictionary, as fetched from peers for a magnet link (BEP 9).
+///
+/// # Arguments
+///
+/// * `info_bytes` - The bencoded info dictionary.
+/// * `trackers` - The tracker URLs to announce to; the first becomes `announce` and
+///   each gets a tier of its own in `announce_list`.
+pub fn parse_info_dict(info_bytes: &[u8], trackers: &[String]) -> io::Result<Torrent> {
+    let Bencode::Dict(info_dict) = decode(info_bytes, &mut 0)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Info is not a dictionary",
+        ));
+    };
+    let announce = trackers.first().cloned().unwrap_or_default();
+    let announce_list =
+        (trackers.len() > 1).then(|| trackers.iter().map(|t| vec![t.clone()]).collect());
+    parse_info(&info_dict, info_bytes, announce, announce_list)
+}
+
+/// Builds a `Torrent` from its info dictionary, decoded and raw, and its trackers.
+fn parse_info(
+    info_dict: &BTreeMap<Vec<u8>, Bencode>,
+    info_bytes: &[u8],
+    announce: String,
+    announce_list: Option<Vec<Vec<String>>>,
+) -> io::Result<Torrent> {
+    let name = match info_dict.get(&b"name"[..]) {
+        Some(Bencode::Bytes(b)) => String::from_utf8_lossy(b).to_string(),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing name")),
+    };
 
-        if pieces_bytes.len() % 20 != 0 {
+    let piece_length = match info_dict.get(&b"piece length"[..]) {
+        Some(Bencode::Int(i)) if *i > 0 => *i as u64,
+        Some(Bencode::Int(_)) => {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                "Invalid pieces length",
+                "Invalid piece length",
             ));
         }
-
-        let mut pieces = Vec::new();
-        for chunk in pieces_bytes.chunks(20) {
-            let mut hash = [0u8; 20];
-            hash.copy_from_slice(chunk);
-            pieces.push(hash);
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Missing piece length",
+            ));
         }
+    };
 
-        let length = match info_dict.get(&b"length"[..]) {
-            Some(Bencode::Int(i)) => Some(*i as u64),
-            _ => None,
-        };
+    let pieces_bytes = match info_dict.get(&b"pieces"[..]) {
+        Some(Bencode::Bytes(b)) => b,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing pieces")),
+    };
 
-        let files = if let Some(Bencode::List(files_list)) = info_dict.get(&b"files"[..]) {
-            let mut files = Vec::new();
-            for file in files_list {
-                if let Bencode::Dict(f) = file {
-                    let len = match f.get(&b"length"[..]) {
-                        Some(Bencode::Int(i)) => *i as u64,
-                        _ => continue,
-                    };
-                    let path_list = match f.get(&b"path"[..]) {
-                        Some(Bencode::List(l)) => l,
-                        _ => continue,
-                    };
-                    let mut path = Vec::new();
-                    for p in path_list {
-                        if let Bencode::Bytes(b) = p {
-                            path.push(String::from_utf8_lossy(b).to_string());
-                        }
+    if pieces_bytes.len() % 20 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid pieces length",
+        ));
+    }
+
+    let mut pieces = Vec::new();
+    for chunk in pieces_bytes.chunks(20) {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(chunk);
+        pieces.push(hash);
+    }
+
+    let length = match info_dict.get(&b"length"[..]) {
+        Some(Bencode::Int(i)) => Some(*i as u64),
+        _ => None,
+    };
+
+    let files = if let Some(Bencode::List(files_list)) = info_dict.get(&b"files"[..]) {
+        let mut files = Vec::new();
+        for file in files_list {
+            if let Bencode::Dict(f) = file {
+                let len = match f.get(&b"length"[..]) {
+                    Some(Bencode::Int(i)) => *i as u64,
+                    _ => continue,
+                };
+                let path_list = match f.get(&b"path"[..]) {
+                    Some(Bencode::List(l)) => l,
+                    _ => continue,
+                };
+                let mut path = Vec::new();
+                for p in path_list {
+                    if let Bencode::Bytes(b) = p {
+                        path.push(String::from_utf8_lossy(b).to_string());
                     }
-                    files.push(FileInfo { length: len, path });
                 }
+                files.push(FileInfo { length: len, path });
             }
-            Some(files)
-        } else {
-            None
-        };
-
-        if length.is_none() && files.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Missing length or files",
-            ));
         }
-
-        Ok(Torrent {
-            announce,
-            announce_list,
-            info_hash: hash,
-            piece_length,
-            pieces,
-            name,
-            length,
-            files,
-        })
+        Some(files)
     } else {
+        None
+    };
+
+    if length.is_none() && files.is_none() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "Torrent file root is not a dictionary",
+            "Missing length or files",
         ));
     }
+
+    let torrent = Torrent {
+        announce,
+        announce_list,
+        info_hash: info_hash(info_bytes),
+        piece_length,
+        pieces,
+        name,
+        length,
+        files,
+        info: info_bytes.to_vec(),
+        url_list: Vec::new(),
+        http_seeds: Vec::new(),
+    };
+    torrent.check_pieces()?;
+    Ok(torrent)
 }
 
 #[cfg(test)]
@@ -210,8 +324,8 @@ mod tests {
     }
 
     fn create_dummy_multifile_torrent() -> Vec<u8> {
-        // d8:announce15:http://track.er4:infod5:filesld6:lengthi1000e4:pathl5:fileAeed6:lengthi2000e4:pathl3:sub5:fileBeeee4:name7:testdir12:piece lengthi16384e6:pieces20:....................ee
-        let mut t = "d8:announce15:http://track.er4:infod5:filesld6:lengthi1000e4:pathl5:fileAeed6:lengthi2000e4:pathl3:sub5:fileBeeee4:name7:testdir12:piece lengthi16384e6:pieces20:".as_bytes().to_vec();
+        // d8:announce15:http://track.er4:infod5:filesld6:lengthi1000e4:pathl5:fileAeed6:lengthi2000e4:pathl3:sub5:fileBeee4:name7:testdir12:piece lengthi16384e6:pieces20:....................ee
+        let mut t = "d8:announce15:http://track.er4:infod5:filesld6:lengthi1000e4:pathl5:fileAeed6:lengthi2000e4:pathl3:sub5:fileBeee4:name7:testdir12:piece lengthi16384e6:pieces20:".as_bytes().to_vec();
         t.extend_from_slice(&[b'X'; 20]);
         t.extend_from_slice(b"ee");
         t
@@ -247,6 +361,72 @@ mod tests {
         assert_eq!(files[1].path, vec!["sub", "fileB"]);
     }
 
+    #[test]
+    fn test_parse_info_dict_with_trackers() {
+        let buf = create_dummy_torrent();
+        let info = find_info_slice(&buf).unwrap();
+        let trackers = vec!["http://a.tr".to_string(), "udp://b.tr:80".to_string()];
+        let t = parse_info_dict(info, &trackers).expect("Should parse");
+        assert_eq!(
+            t.info_hash,
+            parse_torrent_from_bytes(&buf).unwrap().info_hash
+        );
+        assert_eq!(t.name, "testfile");
+        assert_eq!(t.info, info);
+        assert_eq!(t.announce, "http://a.tr");
+        assert_eq!(
+            t.announce_list,
+            Some(vec![
+                vec!["http://a.tr".to_string()],
+                vec!["udp://b.tr:80".to_string()]
+            ])
+        );
+        assert!(parse_info_dict(b"i1e", &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_web_seeds() {
+        let buf = create_dummy_torrent();
+        let t = parse_torrent_from_bytes(&buf).unwrap();
+        assert!(t.url_list.is_empty());
+        assert!(t.http_seeds.is_empty());
+
+        // `url-list` as a single string, `httpseeds` as a list
+        let mut t = b"d9:httpseedsl17:http://seed/s.php0:e".to_vec();
+        t.extend_from_slice(&buf[1..buf.len() - 1]);
+        t.extend_from_slice(b"8:url-list14:http://mirror/e");
+        let t = parse_torrent_from_bytes(&t).unwrap();
+        assert_eq!(t.url_list, vec!["http://mirror/"]);
+        assert_eq!(t.http_seeds, vec!["http://seed/s.php"]);
+        assert_eq!(t.name, "testfile");
+
+        // `url-list` as a list
+        let mut t = buf[..buf.len() - 1].to_vec();
+        t.extend_from_slice(b"8:url-listl10:http://a/f10:http://b/fee");
+        let t = parse_torrent_from_bytes(&t).unwrap();
+        assert_eq!(t.url_list, vec!["http://a/f", "http://b/f"]);
+    }
+
+    #[test]
+    fn test_pieces_must_cover_the_length() {
+        let info = |piece_length: &str, pieces: usize| {
+            let mut info = format!(
+                "d6:lengthi40000e4:name4:file12:piece lengthi{}e6:pieces{}:",
+                piece_length,
+                pieces * 20
+            )
+            .into_bytes();
+            info.extend(vec![b'X'; pieces * 20]);
+            info.push(b'e');
+            info
+        };
+        assert!(parse_info_dict(&info("16384", 3), &[]).is_ok());
+        assert!(parse_info_dict(&info("0", 3), &[]).is_err());
+        assert!(parse_info_dict(&info("-16384", 3), &[]).is_err());
+        assert!(parse_info_dict(&info("16384", 2), &[]).is_err());
+        assert!(parse_info_dict(&info("16384", 4), &[]).is_err());
+    }
+
     #[test]
     fn test_parse_invalid_torrent() {
         let buf = b"invalid";
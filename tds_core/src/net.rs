@@ -0,0 +1,252 @@
+//! Listening sockets that reach peers of both IP families.
+//!
+//! A socket bound to `[::]` also carries IPv4 only while `IPV6_V6ONLY` is off, which
+//! is not the default on Windows, the BSDs or Linux with `net.ipv6.bindv6only=1`. The
+//! helpers here turn it off, and where that fails they bind an IPv4 socket to the same
+//! port next to the IPv6 one. Either way, IPv4 addresses are handed out and taken in
+//! their plain form, never as IPv4-mapped IPv6 addresses.
+
+use crate::compact;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Sockets bound to one port, which together reach IPv4 and IPv6 peers.
+#[derive(Debug)]
+pub struct DualStack<T> {
+    /// Bound to `[::]`, or to `0.0.0.0` on a system without IPv6. It carries IPv4
+    /// traffic as well unless `ipv4` is set.
+    pub primary: T,
+    /// An IPv4 socket on the same port, where `primary` is IPv6 only.
+    pub ipv4: Option<T>,
+}
+
+impl<T> DualStack<T> {
+    fn try_map<U>(self, mut f: impl FnMut(T) -> io::Result<U>) -> io::Result<DualStack<U>> {
+        Ok(DualStack {
+            primary: f(self.primary)?,
+            ipv4: self.ipv4.map(f).transpose()?,
+        })
+    }
+}
+
+/// Binds TCP listeners to `port` on all interfaces, for both IP families.
+///
+/// Must be called from within a Tokio runtime.
+///
+/// # Arguments
+///
+/// * `port` - The port to listen on. Use `0` to let the OS choose a free port.
+///
+/// # Errors
+///
+/// Returns an error if the port cannot be bound for IPv4.
+pub fn bind_tcp(port: u16) -> io::Result<DualStack<TcpListener>> {
+    bind(port, Type::STREAM, Protocol::TCP, true)?.try_map(|socket| {
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    })
+}
+
+/// Binds UDP sockets to `port` on all interfaces, for both IP families.
+///
+/// Must be called from within a Tokio runtime.
+///
+/// # Arguments
+///
+/// * `port` - The port to bind. Use `0` to let the OS choose a free port.
+///
+/// # Errors
+///
+/// Returns an error if the port cannot be bound for IPv4.
+pub fn bind_udp(port: u16) -> io::Result<DualStack<UdpSocket>> {
+    bind(port, Type::DGRAM, Protocol::UDP, true)?
+        .try_map(|socket| UdpSocket::from_std(socket.into()))
+}
+
+/// Binds `[::]:port` dual-stack, or IPv6 only with `0.0.0.0` on the same port beside it,
+/// or `0.0.0.0:port` alone where IPv6 is unavailable.
+///
+/// With `dual_stack` false the IPv6 socket is not even tried dual-stack.
+fn bind(
+    port: u16,
+    ty: Type,
+    protocol: Protocol,
+    dual_stack: bool,
+) -> io::Result<DualStack<Socket>> {
+    let ipv6 = open(Domain::IPV6, ty, protocol).and_then(|socket| {
+        let dual = dual_stack && socket.set_only_v6(false).is_ok();
+        if !dual {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok((socket, dual))
+    });
+    match ipv6 {
+        Ok((socket, true)) => Ok(DualStack {
+            primary: socket,
+            ipv4: None,
+        }),
+        Ok((socket, false)) => {
+            // With port 0, the IPv4 socket takes the port the OS gave the IPv6 one
+            let port = socket.local_addr()?.as_socket().map_or(port, |a| a.port());
+            let ipv4 = open(Domain::IPV4, ty, protocol)?;
+            ipv4.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+            Ok(DualStack {
+                primary: socket,
+                ipv4: Some(ipv4),
+            })
+        }
+        Err(_) => {
+            let socket = open(Domain::IPV4, ty, protocol)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+            Ok(DualStack {
+                primary: socket,
+                ipv4: None,
+            })
+        }
+    }
+}
+
+/// Creates a non-blocking socket, set up the way Tokio's own `bind` does.
+fn open(domain: Domain, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(domain, ty, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    #[cfg(unix)]
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    Ok(socket)
+}
+
+impl DualStack<TcpListener> {
+    /// Returns the local address of the primary listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.primary.local_addr()
+    }
+
+    /// Accepts the next connection on either listener.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<(TcpStream, SocketAddr)>` - The connection and the peer's address,
+    ///   plain IPv4 for IPv4 peers.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = match &self.ipv4 {
+            Some(ipv4) => tokio::select! {
+                res = self.primary.accept() => res?,
+                res = ipv4.accept() => res?,
+            },
+            None => self.primary.accept().await?,
+        };
+        Ok((stream, compact::canonical(addr)))
+    }
+}
+
+impl DualStack<UdpSocket> {
+    /// Returns the local address of the primary socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.primary.local_addr()
+    }
+
+    /// Returns `true` if the sockets reach IPv6 peers as well as IPv4 ones.
+    pub fn is_dual_stack(&self) -> bool {
+        self.primary.local_addr().is_ok_and(|a| a.is_ipv6())
+    }
+
+    /// Sends a datagram through the socket of `to`'s family.
+    pub async fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let to = compact::canonical(to);
+        match (&self.ipv4, to) {
+            (Some(ipv4), SocketAddr::V4(_)) => ipv4.send_to(buf, to).await,
+            (None, SocketAddr::V4(v4)) if self.is_dual_stack() => {
+                let mapped = SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port());
+                self.primary.send_to(buf, mapped).await
+            }
+            _ => self.primary.send_to(buf, to).await,
+        }
+    }
+
+    /// Receives the next datagram on either socket.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<(usize, SocketAddr)>` - The length of the datagram and its sender,
+    ///   plain IPv4 for IPv4 senders.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some(ipv4) = &self.ipv4 else {
+            let (n, from) = self.primary.recv_from(buf).await?;
+            return Ok((n, compact::canonical(from)));
+        };
+        loop {
+            let socket = tokio::select! {
+                res = self.primary.readable() => res.map(|_| &self.primary)?,
+                res = ipv4.readable() => res.map(|_| ipv4)?,
+            };
+            match socket.try_recv_from(buf) {
+                Ok((n, from)) => return Ok((n, compact::canonical(from))),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_reaches_ipv4_peers_by_their_plain_address() {
+        let sockets = bind_udp(0).unwrap();
+        let port = sockets.local_addr().unwrap().port();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        peer.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (n, from) = sockets.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, peer_addr);
+
+        sockets.send_to(b"pong", from).await.unwrap();
+        let (n, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        assert_eq!(from.port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_sockets_get_an_ipv4_neighbour() {
+        let sockets = bind(0, Type::DGRAM, Protocol::UDP, false)
+            .unwrap()
+            .try_map(|socket| UdpSocket::from_std(socket.into()))
+            .unwrap();
+        let Some(ipv4) = &sockets.ipv4 else {
+            // No IPv6 on this system
+            assert!(!sockets.is_dual_stack());
+            return;
+        };
+        let port = sockets.local_addr().unwrap().port();
+        assert_eq!(ipv4.local_addr().unwrap().port(), port);
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (n, from) = sockets.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        sockets.send_to(b"pong", from).await.unwrap();
+        let (n, _) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_accepts_ipv4_peers() {
+        let listeners = bind_tcp(0).unwrap();
+        let port = listeners.local_addr().unwrap().port();
+
+        let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_stream, addr) = listeners.accept().await.unwrap();
+        assert_eq!(addr, client.local_addr().unwrap());
+    }
+}
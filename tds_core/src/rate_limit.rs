@@ -1,6 +1,7 @@
 //! A simple token bucket implementation for rate limiting.
 
-use std::time::Instant;
+use std::io;
+use std::time::{Duration, Instant};
 
 /// A token bucket rate limiter.
 ///
@@ -29,6 +30,43 @@ impl TokenBucket {
         }
     }
 
+    /// Creates a `TokenBucket` that never limits.
+    pub fn unlimited() -> Self {
+        Self::new(f64::INFINITY, f64::INFINITY)
+    }
+
+    /// Creates a `TokenBucket` that allows `rate` tokens per second with bursts of one
+    /// second, or never limits if `rate` is `None`.
+    ///
+    /// `rate` must pass `check_rate`.
+    pub fn with_rate(rate: Option<f64>) -> Self {
+        match rate {
+            Some(rate) => Self::new(rate, rate),
+            None => Self::unlimited(),
+        }
+    }
+
+    /// Checks that `rate` can be given to `with_rate`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error unless `rate` is `None` or a positive, finite number of tokens
+    /// per second. A bucket that never refills would make every caller wait forever.
+    pub fn check_rate(rate: Option<f64>) -> io::Result<()> {
+        match rate {
+            Some(rate) if !(rate > 0.0 && rate.is_finite()) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Rate limit must be positive, got {}", rate),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns `true` if the bucket never limits.
+    pub fn is_unlimited(&self) -> bool {
+        self.refill_rate.is_infinite()
+    }
+
     /// Attempts to consume a specified number of tokens from the bucket.
     ///
     /// # Arguments
@@ -48,6 +86,44 @@ impl TokenBucket {
         }
     }
 
+    /// Consumes tokens unconditionally, going into debt if there are not enough.
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - The number of tokens to consume. May exceed the capacity.
+    ///
+    /// # Returns
+    ///
+    /// How long to wait before the debt is paid back. Callers should not proceed before then.
+    pub fn reserve(&mut self, amount: f64) -> Duration {
+        if self.is_unlimited() {
+            return Duration::ZERO;
+        }
+        self.refill();
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.refill_rate)
+        }
+    }
+
+    /// Waits until `amount` tokens are available and consumes them.
+    ///
+    /// Requests larger than the capacity are allowed; they wait for as long as the
+    /// tokens take to refill. The bucket stays borrowed while waiting: callers sharing
+    /// it behind a mutex should `reserve` under the lock and sleep after releasing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - The number of tokens to consume.
+    pub async fn acquire(&mut self, amount: f64) {
+        let delay = self.reserve(amount);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
     fn refill(&mut self) {
         let now = Instant::now();
         let elapsed = now.duration_since(self.last_refill).as_secs_f64();
@@ -69,7 +145,7 @@ mod tests {
     #[test]
     fn test_token_bucket() {
         let mut bucket = TokenBucket::new(10.0, 1.0);
-        
+
         // Initial capacity is full
         assert!(bucket.consume(10.0));
         // Should be empty now
@@ -79,4 +155,30 @@ mod tests {
         thread::sleep(Duration::from_millis(1100));
         assert!(bucket.consume(1.0));
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_waits_for_refill() {
+        let mut bucket = TokenBucket::new(100.0, 100.0);
+        let start = tokio::time::Instant::now();
+
+        bucket.acquire(100.0).await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+
+        // Larger than the capacity: 150 tokens at 100 per second
+        bucket.acquire(150.0).await;
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+
+        let mut unlimited = TokenBucket::with_rate(None);
+        assert!(unlimited.is_unlimited());
+        assert_eq!(unlimited.reserve(1e12), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rates_that_never_refill_are_rejected() {
+        assert!(TokenBucket::check_rate(None).is_ok());
+        assert!(TokenBucket::check_rate(Some(1.0)).is_ok());
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(TokenBucket::check_rate(Some(rate)).is_err());
+        }
+    }
 }
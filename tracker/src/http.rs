@@ -1,8 +1,9 @@
 //! HTTP Tracker Client implementation.
 
 use super::{TrackerClient, TrackerEvent, TrackerRequest, TrackerResponse};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr};
 use tds_core::bencoding::{Bencode, decode};
+use tds_core::compact;
 
 /// Client for communicating with HTTP/HTTPS trackers.
 pub struct HttpTracker {
@@ -102,18 +103,10 @@ fn parse_http_response(root: Bencode) -> Result<TrackerResponse, String> {
             _ => None,
         };
 
-        let peers = match dict.get(&b"peers"[..]) {
+        let mut peers = match dict.get(&b"peers"[..]) {
             Some(Bencode::Bytes(b)) => {
                 // Compact model
-                let mut peers = Vec::new();
-                for chunk in b.chunks(6) {
-                    if chunk.len() == 6 {
-                        let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
-                        let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                        peers.push(SocketAddrV4::new(ip, port));
-                    }
-                }
-                peers
+                compact::decode_peers(b, false)
             }
             Some(Bencode::List(l)) => {
                 // Dictionary model
@@ -125,7 +118,7 @@ fn parse_http_response(root: Bencode) -> Result<TrackerResponse, String> {
                             _ => continue,
                         };
                         let ip_str = String::from_utf8_lossy(ip_bytes);
-                        let ip: Ipv4Addr = match ip_str.parse() {
+                        let ip: IpAddr = match ip_str.parse() {
                             Ok(addr) => addr,
                             Err(_) => continue,
                         };
@@ -134,7 +127,7 @@ fn parse_http_response(root: Bencode) -> Result<TrackerResponse, String> {
                             Some(Bencode::Int(i)) => *i as u16,
                             _ => continue,
                         };
-                        peers.push(SocketAddrV4::new(ip, port));
+                        peers.push(SocketAddr::new(ip, port));
                     }
                 }
                 peers
@@ -142,6 +135,11 @@ fn parse_http_response(root: Bencode) -> Result<TrackerResponse, String> {
             _ => Vec::new(), // Some trackers might return empty peers or omit it if empty?
         };
 
+        // IPv6 peers come in a separate compact string (BEP 7)
+        if let Some(Bencode::Bytes(b)) = dict.get(&b"peers6"[..]) {
+            peers.extend(compact::decode_peers(b, true));
+        }
+
         Ok(TrackerResponse {
             interval,
             peers,
@@ -152,3 +150,30 @@ fn parse_http_response(root: Bencode) -> Result<TrackerResponse, String> {
         Err("Invalid response format".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_parse_response_with_ipv4_and_ipv6_peers() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"interval".to_vec(), Bencode::Int(1800));
+        dict.insert(
+            b"peers".to_vec(),
+            Bencode::Bytes(vec![10, 0, 0, 1, 0x1a, 0xe1]),
+        );
+        let mut peers6 = vec![0u8; 16];
+        peers6[15] = 1;
+        peers6.extend_from_slice(&6881u16.to_be_bytes());
+        dict.insert(b"peers6".to_vec(), Bencode::Bytes(peers6));
+
+        let response = parse_http_response(Bencode::Dict(dict)).unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[::1]:6881".parse().unwrap(),
+        ];
+        assert_eq!(response.peers, expected);
+    }
+}
@@ -4,7 +4,7 @@
 //! as well as data structures like `TrackerRequest` and `TrackerResponse`.
 //! It also includes a factory function `get_tracker_client` to instantiate the correct client based on the URL scheme.
 
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 
 pub mod http;
 pub mod server;
@@ -60,8 +60,8 @@ pub enum TrackerEvent {
 pub struct TrackerResponse {
     /// The interval in seconds the client should wait before sending the next request.
     pub interval: u32,
-    /// List of peers received from the tracker.
-    pub peers: Vec<SocketAddrV4>,
+    /// List of peers received from the tracker, IPv4 and IPv6.
+    pub peers: Vec<SocketAddr>,
     /// Number of seeders (complete peers).
     pub complete: Option<u32>,
     /// Number of leechers (incomplete peers).
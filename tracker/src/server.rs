@@ -2,6 +2,7 @@
 //!
 //! This module implements a basic BitTorrent tracker server that handles HTTP GET announce requests.
 //! It maintains a list of peers for each torrent info hash and performs rate limiting based on IP address.
+//! It listens on IPv4 and IPv6 and returns IPv6 peers in `peers6` (BEP 7).
 
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
@@ -9,8 +10,10 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tds_core::TokenBucket;
 use tds_core::bencoding::Bencode;
+use tds_core::compact;
+use tds_core::net;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use url::Url;
 
@@ -65,10 +68,11 @@ impl TrackerServer {
     /// Starts the tracker server.
     ///
     /// This function binds to the configured port and starts accepting incoming TCP connections.
+    /// The port is bound for IPv4 and IPv6 clients, or IPv4 only on a system without IPv6.
     /// It runs until `running` is set to false or an error occurs.
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
-        println!("Tracker server listening on 0.0.0.0:{}", self.port);
+        let listener = net::bind_tcp(self.port)?;
+        println!("Tracker server listening on {}", listener.local_addr()?);
 
         {
             let mut running = self.running.lock().await;
@@ -225,18 +229,22 @@ async fn handle_announce(
         }
     }
 
-    let mut peers_bytes = Vec::new();
-    for p in response_peers {
-        if let IpAddr::V4(ipv4) = p.ip {
-            peers_bytes.extend_from_slice(&ipv4.octets());
-            peers_bytes.extend_from_slice(&p.port.to_be_bytes());
-        }
-    }
+    let addrs: Vec<SocketAddr> = response_peers
+        .iter()
+        .map(|p| SocketAddr::new(p.ip, p.port))
+        .collect();
 
     use std::collections::BTreeMap;
     let mut resp_dict = BTreeMap::new();
     resp_dict.insert(b"interval".to_vec(), Bencode::Int(1800));
-    resp_dict.insert(b"peers".to_vec(), Bencode::Bytes(peers_bytes));
+    resp_dict.insert(
+        b"peers".to_vec(),
+        Bencode::Bytes(compact::encode_peers(&addrs, false)),
+    );
+    resp_dict.insert(
+        b"peers6".to_vec(),
+        Bencode::Bytes(compact::encode_peers(&addrs, true)),
+    );
 
     let resp_bencode = Bencode::Dict(resp_dict);
     let body = resp_bencode.encode();
@@ -269,7 +277,10 @@ mod tests {
         // Simulate announce
         {
             let mut guard = state.lock().await;
-            let swarm = guard.torrents.entry(info_hash.clone()).or_insert_with(Vec::new);
+            let swarm = guard
+                .torrents
+                .entry(info_hash.clone())
+                .or_insert_with(Vec::new);
             swarm.push(Peer {
                 id: peer_id.clone(),
                 ip,
@@ -299,7 +310,7 @@ mod tests {
                 }
             }
             if !found {
-                 swarm.push(Peer {
+                swarm.push(Peer {
                     id: peer_id.clone(),
                     ip,
                     port: 6882,
@@ -307,8 +318,8 @@ mod tests {
                 });
             }
         }
-        
-         // Verify peer updated
+
+        // Verify peer updated
         {
             let guard = state.lock().await;
             let swarm = guard.torrents.get(&info_hash).unwrap();
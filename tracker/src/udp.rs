@@ -4,10 +4,14 @@ use super::{TrackerClient, TrackerEvent, TrackerRequest, TrackerResponse};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rand::Rng;
 use std::io::{Cursor, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::net::{ToSocketAddrs, UdpSocket};
 use std::time::Duration;
+use tds_core::compact;
 
 /// Client for communicating with UDP trackers (BEP 15).
+///
+/// Trackers are reached over IPv4 or IPv6, whichever their host name resolves to first.
+/// Over IPv6 the announce response lists 18-byte IPv6 peers instead of 6-byte IPv4 ones.
 pub struct UdpTracker {
     url: String,
 }
@@ -37,13 +41,22 @@ impl TrackerClient for UdpTracker {
         let url_parsed = url::Url::parse(&self.url).map_err(|e| e.to_string())?;
         let host = url_parsed.host_str().ok_or("Missing host")?;
         let port = url_parsed.port().ok_or("Missing port")?;
-        let addr_str = format!("{}:{}", host, port);
-
-        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        let addr = (host.trim_matches(|c| c == '[' || c == ']'), port)
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or("Tracker host did not resolve")?;
+
+        let bind_addr = if addr.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
         socket
             .set_read_timeout(Some(Duration::from_secs(15)))
             .map_err(|e| e.to_string())?;
-        socket.connect(&addr_str).map_err(|e| e.to_string())?;
+        socket.connect(addr).map_err(|e| e.to_string())?;
 
         let mut rng = rand::rng();
         let transaction_id: u32 = rng.random();
@@ -144,16 +157,9 @@ impl TrackerClient for UdpTracker {
         let leechers = rdr.read_u32::<BigEndian>().unwrap();
         let seeders = rdr.read_u32::<BigEndian>().unwrap();
 
-        let mut peers = Vec::new();
-        while rdr.position() < amt as u64 {
-            if amt as u64 - rdr.position() < 6 {
-                break;
-            }
-            let ip_int = rdr.read_u32::<BigEndian>().unwrap();
-            let port = rdr.read_u16::<BigEndian>().unwrap();
-            let ip = Ipv4Addr::from(ip_int);
-            peers.push(SocketAddrV4::new(ip, port));
-        }
+        // The peers are of the address family the announce was sent over
+        let start = rdr.position() as usize;
+        let peers = compact::decode_peers(&buf[start..amt], addr.is_ipv6());
 
         Ok(TrackerResponse {
             interval,